@@ -0,0 +1,22 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS public.email_outbox
+(
+    id bigserial PRIMARY KEY NOT NULL,
+    recipients text[] NOT NULL,
+    subject character varying(255) COLLATE pg_catalog."default" NOT NULL,
+    body character varying COLLATE pg_catalog."default" NOT NULL,
+    template character varying(255) COLLATE pg_catalog."default",
+    status character varying(50) COLLATE pg_catalog."default" NOT NULL DEFAULT 'pending'::character varying,
+    attempts integer NOT NULL DEFAULT 0,
+    last_error character varying COLLATE pg_catalog."default",
+    next_attempt_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    sent_at timestamp with time zone,
+    updated_by bigint,
+    updated_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    created_by bigint,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS email_outbox_status_next_attempt_at_idx
+    ON public.email_outbox (status, next_attempt_at);
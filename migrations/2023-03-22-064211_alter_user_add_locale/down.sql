@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE IF EXISTS public.users
+    DROP COLUMN locale,
+    DROP COLUMN timezone;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+
+ALTER TABLE IF EXISTS public.users
+    ADD COLUMN locale character varying(20) COLLATE pg_catalog."default" NOT NULL DEFAULT 'en'::character varying,
+    ADD COLUMN timezone character varying(64) COLLATE pg_catalog."default" NOT NULL DEFAULT 'UTC'::character varying;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE IF EXISTS public.email_outbox
+    DROP COLUMN text_body;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+ALTER TABLE IF EXISTS public.email_outbox
+    ADD COLUMN text_body character varying COLLATE pg_catalog."default";
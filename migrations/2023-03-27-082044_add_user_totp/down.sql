@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE public.recovery_codes;
+ALTER TABLE IF EXISTS public.users
+    DROP COLUMN totp_secret,
+    DROP COLUMN totp_enabled;
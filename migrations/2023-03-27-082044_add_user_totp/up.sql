@@ -0,0 +1,17 @@
+-- Your SQL goes here
+
+ALTER TABLE IF EXISTS public.users
+    ADD COLUMN totp_secret character varying(255) COLLATE pg_catalog."default",
+    ADD COLUMN totp_enabled boolean NOT NULL DEFAULT false;
+
+CREATE TABLE IF NOT EXISTS public.recovery_codes
+(
+    id bigserial PRIMARY KEY NOT NULL,
+    user_id bigint NOT NULL,
+    value character varying(255) COLLATE pg_catalog."default" NOT NULL,
+    consumed_at timestamp with time zone,
+    updated_by bigint,
+    updated_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    created_by bigint,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,17 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS public.webauthn_credentials
+(
+    id bigserial PRIMARY KEY NOT NULL,
+    user_id bigint NOT NULL,
+    credential_id character varying(1024) COLLATE pg_catalog."default" NOT NULL,
+    name character varying(255) COLLATE pg_catalog."default" NOT NULL,
+    passkey jsonb NOT NULL,
+    last_used_at timestamp with time zone,
+    updated_by bigint,
+    updated_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    created_by bigint,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT webauthn_credentials_credential_id_key UNIQUE (credential_id)
+);
+CREATE INDEX IF NOT EXISTS webauthn_credentials_user_id_idx ON public.webauthn_credentials (user_id);
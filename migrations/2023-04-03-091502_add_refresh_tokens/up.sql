@@ -0,0 +1,19 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS public.refresh_tokens
+(
+    id bigserial PRIMARY KEY NOT NULL,
+    user_id bigint NOT NULL,
+    family_id character varying(64) COLLATE pg_catalog."default" NOT NULL,
+    value character varying(255) COLLATE pg_catalog."default" NOT NULL,
+    replaced_by bigint,
+    revoked_at timestamp with time zone,
+    expired_at timestamp with time zone NOT NULL,
+    updated_by bigint,
+    updated_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    created_by bigint,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT refresh_tokens_value_key UNIQUE (value)
+);
+CREATE INDEX IF NOT EXISTS refresh_tokens_family_id_idx ON public.refresh_tokens (family_id);
+CREATE INDEX IF NOT EXISTS refresh_tokens_user_id_idx ON public.refresh_tokens (user_id);
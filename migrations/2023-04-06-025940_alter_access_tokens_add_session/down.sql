@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS access_tokens_family_id_idx;
+ALTER TABLE IF EXISTS public.access_tokens
+    DROP COLUMN ip_address,
+    DROP COLUMN last_seen_at,
+    DROP COLUMN family_id;
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+
+ALTER TABLE IF EXISTS public.access_tokens
+    ADD COLUMN ip_address character varying(64) COLLATE pg_catalog."default",
+    ADD COLUMN last_seen_at timestamp with time zone,
+    ADD COLUMN family_id character varying(64) COLLATE pg_catalog."default";
+CREATE INDEX IF NOT EXISTS access_tokens_family_id_idx ON public.access_tokens (family_id);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX IF EXISTS access_tokens_api_value_idx;
+ALTER TABLE IF EXISTS public.access_tokens
+    DROP COLUMN scopes;
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+
+ALTER TABLE IF EXISTS public.access_tokens
+    ADD COLUMN scopes text[] NOT NULL DEFAULT '{}';
+-- Old api tokens stored part of a JWT that never authenticated anything, they can not be migrated.
+DELETE FROM public.access_tokens WHERE kind = 'api';
+CREATE UNIQUE INDEX IF NOT EXISTS access_tokens_api_value_idx ON public.access_tokens (value) WHERE kind = 'api';
@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE IF EXISTS public.security_codes
+    DROP COLUMN failed_attempts;
+ALTER TABLE IF EXISTS public.users
+    DROP COLUMN failed_attempts,
+    DROP COLUMN locked_until;
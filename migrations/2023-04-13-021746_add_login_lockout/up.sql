@@ -0,0 +1,7 @@
+-- Your SQL goes here
+
+ALTER TABLE IF EXISTS public.users
+    ADD COLUMN failed_attempts integer NOT NULL DEFAULT 0,
+    ADD COLUMN locked_until timestamp with time zone;
+ALTER TABLE IF EXISTS public.security_codes
+    ADD COLUMN failed_attempts integer NOT NULL DEFAULT 0;
@@ -0,0 +1,9 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS public.rate_limit_buckets
+(
+    key character varying(255) COLLATE pg_catalog."default" PRIMARY KEY NOT NULL,
+    tokens double precision NOT NULL,
+    updated_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS rate_limit_buckets_updated_at_idx ON public.rate_limit_buckets (updated_at);
@@ -0,0 +1,17 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS public.user_identities
+(
+    id bigserial PRIMARY KEY NOT NULL,
+    user_id bigint NOT NULL,
+    provider character varying(64) COLLATE pg_catalog."default" NOT NULL,
+    subject character varying(255) COLLATE pg_catalog."default" NOT NULL,
+    email character varying(255) COLLATE pg_catalog."default",
+    last_login_at timestamp with time zone,
+    updated_by bigint,
+    updated_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    created_by bigint,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT user_identities_provider_subject_key UNIQUE (provider, subject)
+);
+CREATE INDEX IF NOT EXISTS user_identities_user_id_idx ON public.user_identities (user_id);
@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM public.access_tokens WHERE kind = 'oauth';
+DROP INDEX IF EXISTS access_tokens_oauth_value_idx;
+ALTER TABLE IF EXISTS public.access_tokens
+    DROP COLUMN oauth_client_id;
+DROP TABLE public.oauth_consents;
+DROP TABLE public.oauth_authorization_codes;
+DROP TABLE public.oauth_clients;
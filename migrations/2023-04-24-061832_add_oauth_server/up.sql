@@ -0,0 +1,54 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS public.oauth_clients
+(
+    id bigserial PRIMARY KEY NOT NULL,
+    client_id character varying(64) COLLATE pg_catalog."default" NOT NULL,
+    secret character varying(255) COLLATE pg_catalog."default",
+    name character varying(255) COLLATE pg_catalog."default" NOT NULL,
+    redirect_uris text[] NOT NULL DEFAULT '{}',
+    scopes text[] NOT NULL DEFAULT '{}',
+    is_disabled boolean NOT NULL DEFAULT false,
+    updated_by bigint,
+    updated_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    created_by bigint,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT oauth_clients_client_id_key UNIQUE (client_id)
+);
+
+CREATE TABLE IF NOT EXISTS public.oauth_authorization_codes
+(
+    id bigserial PRIMARY KEY NOT NULL,
+    oauth_client_id bigint NOT NULL,
+    user_id bigint NOT NULL,
+    value character varying(255) COLLATE pg_catalog."default" NOT NULL,
+    redirect_uri text COLLATE pg_catalog."default" NOT NULL,
+    scopes text[] NOT NULL DEFAULT '{}',
+    code_challenge character varying(255) COLLATE pg_catalog."default" NOT NULL,
+    nonce character varying(255) COLLATE pg_catalog."default",
+    family_id character varying(255) COLLATE pg_catalog."default",
+    consumed_at timestamp with time zone,
+    expired_at timestamp with time zone NOT NULL,
+    updated_by bigint,
+    updated_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    created_by bigint,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT oauth_authorization_codes_value_key UNIQUE (value)
+);
+
+CREATE TABLE IF NOT EXISTS public.oauth_consents
+(
+    id bigserial PRIMARY KEY NOT NULL,
+    user_id bigint NOT NULL,
+    oauth_client_id bigint NOT NULL,
+    scopes text[] NOT NULL DEFAULT '{}',
+    updated_by bigint,
+    updated_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    created_by bigint,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT oauth_consents_user_id_oauth_client_id_key UNIQUE (user_id, oauth_client_id)
+);
+
+ALTER TABLE IF EXISTS public.access_tokens
+    ADD COLUMN oauth_client_id bigint;
+CREATE UNIQUE INDEX IF NOT EXISTS access_tokens_oauth_value_idx ON public.access_tokens (value) WHERE kind = 'oauth';
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+DELETE FROM public.access_tokens WHERE kind = 'impersonation';
+DROP TABLE public.impersonation_logs;
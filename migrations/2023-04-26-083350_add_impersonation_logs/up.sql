@@ -0,0 +1,16 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS public.impersonation_logs
+(
+    id bigserial PRIMARY KEY NOT NULL,
+    actor_id bigint NOT NULL,
+    user_id bigint NOT NULL,
+    access_token_id bigint,
+    method character varying(16) COLLATE pg_catalog."default" NOT NULL,
+    path text COLLATE pg_catalog."default" NOT NULL,
+    status_code integer,
+    reason text COLLATE pg_catalog."default",
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX IF NOT EXISTS impersonation_logs_actor_id_idx ON public.impersonation_logs (actor_id);
+CREATE INDEX IF NOT EXISTS impersonation_logs_user_id_idx ON public.impersonation_logs (user_id);
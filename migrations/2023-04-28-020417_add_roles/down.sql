@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE public.user_roles;
+DROP TABLE public.permissions;
+DROP TABLE public.roles;
@@ -0,0 +1,45 @@
+-- Your SQL goes here
+
+CREATE TABLE IF NOT EXISTS public.roles
+(
+    id bigserial PRIMARY KEY NOT NULL,
+    ident_name character varying(255) COLLATE pg_catalog."default" NOT NULL,
+    name character varying(255) COLLATE pg_catalog."default" NOT NULL,
+    description text COLLATE pg_catalog."default",
+    updated_by bigint,
+    updated_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    created_by bigint,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT roles_ident_name_key UNIQUE (ident_name)
+);
+
+CREATE TABLE IF NOT EXISTS public.permissions
+(
+    id bigserial PRIMARY KEY NOT NULL,
+    role_id bigint NOT NULL,
+    resource character varying(255) COLLATE pg_catalog."default" NOT NULL,
+    action character varying(255) COLLATE pg_catalog."default" NOT NULL,
+    created_by bigint,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT permissions_role_id_resource_action_key UNIQUE (role_id, resource, action)
+);
+
+CREATE TABLE IF NOT EXISTS public.user_roles
+(
+    id bigserial PRIMARY KEY NOT NULL,
+    user_id bigint NOT NULL,
+    role_id bigint NOT NULL,
+    created_by bigint,
+    created_at timestamp with time zone NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT user_roles_user_id_role_id_key UNIQUE (user_id, role_id)
+);
+CREATE INDEX IF NOT EXISTS user_roles_role_id_idx ON public.user_roles (role_id);
+
+INSERT INTO public.roles (ident_name, name, description)
+    VALUES ('admin', 'Administrator', 'May do everything, like in_kernel staff.'),
+        ('user_manager', 'User manager', 'May update, disable and delete users.');
+INSERT INTO public.permissions (role_id, resource, action)
+    SELECT id, '*', '*' FROM public.roles WHERE ident_name = 'admin';
+INSERT INTO public.permissions (role_id, resource, action)
+    SELECT id, 'users', action FROM public.roles, unnest(ARRAY['update', 'disable', 'delete']) AS action
+        WHERE ident_name = 'user_manager';
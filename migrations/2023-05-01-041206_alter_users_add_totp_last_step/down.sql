@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE IF EXISTS public.users
+    DROP COLUMN totp_last_step;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+
+ALTER TABLE IF EXISTS public.users
+    ADD COLUMN totp_last_step bigint;
@@ -2,12 +2,77 @@ use salvo::http::StatusCode;
 use salvo::prelude::*;
 
 use crate::models::*;
-use crate::{AppResult, ErrorWrap, StatusWrap};
+use crate::things;
+use crate::things::session::ClientInfo;
+use crate::{i18n, AppResult, ErrorWrap, StatusWrap};
 
 #[inline]
 pub fn current_user(depot: &Depot) -> Option<&User> {
     depot.get::<User>("current_user")
 }
+/// The in_kernel user acting as `current_user` when the request uses an impersonation token.
+#[inline]
+pub fn impersonator(depot: &Depot) -> Option<&User> {
+    depot.get::<User>("impersonator")
+}
+/// The `access_tokens` row the current request was authenticated with.
+#[inline]
+pub fn current_access_token(depot: &Depot) -> Option<&AccessToken> {
+    depot.get::<AccessToken>("current_access_token")
+}
+
+/// Web and impersonation sessions have every scope, API and OAuth tokens only the ones they were granted.
+pub fn has_scope(depot: &Depot, scope: &str) -> bool {
+    current_access_token(depot)
+        .map(|token| things::api_token::has_scope(token, scope))
+        .unwrap_or(false)
+}
+pub fn render_insufficient_scope_json(res: &mut Response, scope: &str) -> AppResult<()> {
+    render_status_json(
+        res,
+        StatusCode::FORBIDDEN,
+        "insufficient_scope",
+        "insufficient scope",
+        format!("missing scope: {}", scope),
+    )
+}
+
+/// User agent and client address of the request. `X-Forwarded-For` is only informational here, it is shown to
+/// the user in their session list and never used for access decisions.
+pub fn client_info(req: &Request) -> ClientInfo {
+    let user_agent = req.header::<String>("user-agent").map(|ua| ua.chars().take(255).collect());
+    let ip_address = req
+        .header::<String>("x-forwarded-for")
+        .and_then(|v| v.split(',').next().map(|ip| ip.trim().to_owned()))
+        .filter(|ip| !ip.is_empty())
+        .or_else(|| {
+            req.remote_addr().and_then(|addr| {
+                addr.as_ipv4()
+                    .map(|addr| addr.ip().to_string())
+                    .or_else(|| addr.as_ipv6().map(|addr| addr.ip().to_string()))
+            })
+        });
+    ClientInfo { user_agent, ip_address }
+}
+
+/// Client address for throttling. Unlike `client_info` this only believes `X-Forwarded-For` when
+/// `TRUST_PROXY_HEADERS` is set, and then takes the last hop, which is the one our own proxy appended.
+pub fn client_ip(req: &Request) -> Option<String> {
+    if crate::trust_proxy_headers() {
+        if let Some(ip) = req
+            .header::<String>("x-forwarded-for")
+            .and_then(|v| v.rsplit(',').next().map(|ip| ip.trim().to_owned()))
+            .filter(|ip| !ip.is_empty())
+        {
+            return Some(ip);
+        }
+    }
+    req.remote_addr().and_then(|addr| {
+        addr.as_ipv4()
+            .map(|addr| addr.ip().to_string())
+            .or_else(|| addr.as_ipv6().map(|addr| addr.ip().to_string()))
+    })
+}
 
 #[inline]
 pub fn render_status_json<N: Into<String>, S: Into<String>, D: Into<String>>(
@@ -26,18 +91,45 @@ pub fn render_status_json<N: Into<String>, S: Into<String>, D: Into<String>>(
     Ok(())
 }
 
+/// A `parse_data_error` listing every problem in `details`; `detail` carries the first one for older clients.
+pub fn render_parse_data_error_json_with_details(res: &mut Response, details: Vec<String>) -> AppResult<()> {
+    let detail = details.first().cloned().unwrap_or_default();
+    let mut wrap = ErrorWrap::new(StatusCode::BAD_REQUEST, "parse_data_error", "parse data error", detail);
+    wrap.error.details = Some(details);
+    res.set_status_code(StatusCode::BAD_REQUEST);
+    res.render(Json(wrap));
+    Ok(())
+}
+
+tokio::task_local! {
+    static LOCALE: String;
+}
+
+/// Run `f` with `locale` as the language of the status messages it renders, see `routers::localize`.
+pub async fn with_locale<F: std::future::Future>(locale: String, f: F) -> F::Output {
+    LOCALE.scope(locale, f).await
+}
+
+/// `statuses.<name>.<part>` from the catalog of the request locale, falling back to the English `default`.
+fn status_text(name: &str, part: &str, default: &str) -> String {
+    LOCALE
+        .try_with(|locale| i18n::translate_or(locale, &format!("statuses.{}.{}", name, part), default))
+        .unwrap_or_else(|_| default.to_owned())
+}
+
 macro_rules! render_statuses {
     ($($fname: ident, $fdname: ident, $code: expr, $name: expr, $summary: expr, $detail: expr);+) => {
         $(
             #[inline]
             // #[allow(dead_code)]
             pub fn $fdname<D: Into<String>>(res: &mut ::salvo::http::Response, detail: D) -> AppResult<()> {
-                render_status_json(res, $code, $name, $summary, detail)
+                render_status_json(res, $code, $name, status_text($name, "summary", $summary), detail)
             }
             #[inline]
             // #[allow(dead_code)]
             pub fn $fname(res: &mut ::salvo::http::Response) -> AppResult<()> {
-                render_status_json(res, $code, $name, $summary, $detail)
+                let summary = status_text($name, "summary", $summary);
+                render_status_json(res, $code, $name, summary, status_text($name, "detail", $detail))
             }
         )+
     }
@@ -55,6 +147,8 @@ render_statuses! {
     render_invalid_data_json, render_invalid_data_json_with_detail, StatusCode::BAD_REQUEST, "invalid_data", "invalid data", "data is an invalid";
     render_invalid_user_json, render_invalid_user_json_with_detail, StatusCode::BAD_REQUEST, "invalid_user", "invalid user", "current user is an invalid user";
     render_access_denied_json, render_access_denied_json_with_detail, StatusCode::FORBIDDEN, "access_denied", "access denied", "no permission to access this record";
+    render_locked_or_disabled_json, render_locked_or_disabled_json_with_detail, StatusCode::BAD_REQUEST, "locked_or_disabled", "user locked or disabled", "this user is locked or disabled";
+    render_too_many_attempts_json, render_too_many_attempts_json_with_detail, StatusCode::TOO_MANY_REQUESTS, "too_many_attempts", "too many attempts", "too many failed attempts, please try again later";
     render_done_json, render_done_json_with_detail, StatusCode::OK, "done", "done", "done"
 }
 pub async fn parse_ids_from_request(req: &mut Request, sg_name: &str, pl_name: &str) -> Vec<i64> {
@@ -0,0 +1,64 @@
+//! CSRF protection for requests authenticated with the `jwt_token` cookie. Browsers attach that cookie to
+//! cross-site requests too, so a state-changing request authenticated by it must also carry the session's CSRF
+//! token in the `x-csrf-token` header, which another site can neither read nor set.
+//!
+//! The token is a synchronizer token derived from the session, `HMAC-SHA256(SECRET_KEY, session)`, so nothing has
+//! to be stored and it stays the same while the session's JWT is refreshed. SPAs get it from `auth/csrf`.
+//! Requests authenticated with the `Authorization` header are not affected.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use salvo::http::Method;
+use salvo::prelude::*;
+use salvo::routing::FlowCtrl;
+
+use crate::models::*;
+use crate::utils::sign_with_secret_key;
+use crate::{context, AppResult};
+
+pub const HEADER_NAME: &str = "x-csrf-token";
+
+/// Where the JWT of the current request was found, put in the depot by `routers::jwt_auth`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum JwtSource {
+    Header,
+    Query,
+    Cookie,
+}
+
+pub fn token_for(session: &AccessToken) -> AppResult<String> {
+    let mac = sign_with_secret_key(format!("csrf:{}:{}", session.user_id, session.id).as_bytes())?;
+    Ok(URL_SAFE_NO_PAD.encode(mac))
+}
+
+fn verify(session: &AccessToken, presented: &str) -> AppResult<bool> {
+    let expected = token_for(session)?;
+    Ok(expected.len() == presented.len() && openssl::memcmp::eq(expected.as_bytes(), presented.as_bytes()))
+}
+
+/// Hoop rejecting unsafe requests authenticated by cookie without a valid CSRF token. Goes after
+/// `set_user_handler`.
+#[handler]
+pub async fn protect(req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+    let is_safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    let from_cookie = depot.get::<JwtSource>("jwt_auth_source") == Some(&JwtSource::Cookie);
+    if is_safe || !from_cookie {
+        ctrl.call_next(req, depot, res).await;
+        return;
+    }
+    let presented = req.header::<String>(HEADER_NAME).unwrap_or_default();
+    let valid = match context::current_access_token(depot) {
+        Some(session) => verify(session, &presented).unwrap_or_else(|e| {
+            tracing::error!(error = ?e, "csrf token verification failed");
+            false
+        }),
+        // Not authenticated at all, `auth_final` answers that.
+        None => true,
+    };
+    if valid {
+        ctrl.call_next(req, depot, res).await;
+    } else {
+        ctrl.skip_rest();
+        context::render_access_denied_json_with_detail(res, "csrf token is missing or invalid").ok();
+    }
+}
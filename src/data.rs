@@ -1,12 +1,47 @@
-use serde::Serialize;
+use serde::ser::{Error as _, SerializeStruct};
+use serde::{Serialize, Serializer};
 use diesel::prelude::*;
 
-#[derive(Serialize, Debug)]
+#[derive(Debug)]
 pub struct PagedData<T> {
     pub records: Vec<T>,
     pub limit: i64,
     pub offset: i64,
-    pub total: i64,
+    /// Left out with `count=none`, estimated with `count=estimate`.
+    pub total: Option<i64>,
     pub sort: Option<String>,
+    /// Pass as `cursor` for the page after this one; `None` on the last page.
+    pub next_cursor: Option<String>,
+    /// Pass as `cursor` for the page before this one; `None` on the first page.
+    pub prev_cursor: Option<String>,
+    /// Only these fields of the records are serialized, from `fields=id,ident_name`.
+    pub fields: Option<Vec<String>>,
 }
 
+impl<T: Serialize> Serialize for PagedData<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("PagedData", 7)?;
+        match &self.fields {
+            Some(fields) => {
+                let mut records = Vec::with_capacity(self.records.len());
+                for record in &self.records {
+                    let mut record = match serde_json::to_value(record).map_err(S::Error::custom)? {
+                        serde_json::Value::Object(record) => record,
+                        _ => return Err(S::Error::custom("records must serialize as objects to pick fields")),
+                    };
+                    record.retain(|name, _| fields.contains(name));
+                    records.push(record);
+                }
+                state.serialize_field("records", &records)?;
+            }
+            None => state.serialize_field("records", &self.records)?,
+        }
+        state.serialize_field("limit", &self.limit)?;
+        state.serialize_field("offset", &self.offset)?;
+        state.serialize_field("total", &self.total)?;
+        state.serialize_field("sort", &self.sort)?;
+        state.serialize_field("next_cursor", &self.next_cursor)?;
+        state.serialize_field("prev_cursor", &self.prev_cursor)?;
+        state.end()
+    }
+}
@@ -15,24 +15,99 @@ use diesel::r2d2::{ConnectionManager, Pool, PoolError, PooledConnection};
 use diesel::sql_types::*;
 use diesel_migrations::{EmbeddedMigrations, MigrationHarness};
 use once_cell::sync::OnceCell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::AppResult;
 
 pub type PgPool = Pool<ConnectionManager<PgConnection>>;
 
 pub static DB_POOL: OnceCell<PgPool> = OnceCell::new();
 pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
+static POOL_METRICS: PoolMetrics = PoolMetrics {
+    waiting: AtomicU64::new(0),
+    acquired: AtomicU64::new(0),
+    timeouts: AtomicU64::new(0),
+    wait_micros: AtomicU64::new(0),
+};
+
+struct PoolMetrics {
+    waiting: AtomicU64,
+    acquired: AtomicU64,
+    timeouts: AtomicU64,
+    wait_micros: AtomicU64,
+}
+
+/// Snapshot of the pool, served on `admin/db_pool`. The counters are totals since start.
+#[derive(Serialize, Debug)]
+pub struct PoolStatus {
+    pub max_size: u32,
+    pub connections: u32,
+    pub idle_connections: u32,
+    pub waiting: u64,
+    pub acquired: u64,
+    pub acquire_timeouts: u64,
+    pub acquire_wait_ms: u64,
+}
 
 // pub fn connect()? -> PgConnection {
 //     PgConnection::establish(&crate::database_url()).expect("connect database error")
 // }
+/// Blocks for up to `DATABASE_ACQUIRE_TIMEOUT_SECS`, so call it through `run` or `run_in_place` from async code.
 pub fn connect() -> Result<PooledConnection<ConnectionManager<PgConnection>>, PoolError> {
     // println!("==========get db conn");
-    DB_POOL.get().unwrap().get()
+    let started = Instant::now();
+    POOL_METRICS.waiting.fetch_add(1, Ordering::Relaxed);
+    let conn = DB_POOL.get().unwrap().get();
+    POOL_METRICS.waiting.fetch_sub(1, Ordering::Relaxed);
+    POOL_METRICS
+        .wait_micros
+        .fetch_add(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+    if conn.is_ok() {
+        POOL_METRICS.acquired.fetch_add(1, Ordering::Relaxed);
+    } else {
+        POOL_METRICS.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+    conn
+}
+
+/// Run `f` with a pooled connection on tokio's blocking threads, so a slow query does not stall other requests.
+pub async fn run<F, T>(f: F) -> AppResult<T>
+where
+    F: FnOnce(&mut PgConnection) -> AppResult<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(move || f(&mut *connect()?)).await?
+}
+
+/// Like `run`, for handler code that borrows the request or response. The worker hands its other tasks over to
+/// another thread while `f` blocks, which needs the multi thread runtime.
+pub fn run_in_place<F, T>(f: F) -> AppResult<T>
+where
+    F: FnOnce(&mut PgConnection) -> AppResult<T>,
+{
+    tokio::task::block_in_place(|| f(&mut *connect()?))
+}
+
+pub fn pool_status() -> PoolStatus {
+    let pool = DB_POOL.get().unwrap();
+    let state = pool.state();
+    PoolStatus {
+        max_size: pool.max_size(),
+        connections: state.connections,
+        idle_connections: state.idle_connections,
+        waiting: POOL_METRICS.waiting.load(Ordering::Relaxed),
+        acquired: POOL_METRICS.acquired.load(Ordering::Relaxed),
+        acquire_timeouts: POOL_METRICS.timeouts.load(Ordering::Relaxed),
+        acquire_wait_ms: POOL_METRICS.wait_micros.load(Ordering::Relaxed) / 1000,
+    }
 }
 
 pub fn build_pool(database_url: &str) -> Result<PgPool, PoolError> {
     let manager = ConnectionManager::<PgConnection>::new(database_url);
     diesel::r2d2::Pool::builder()
         .max_size(crate::database_conns())
+        .connection_timeout(Duration::from_secs(crate::database_acquire_timeout_secs()))
         .build(manager)
 }
 
@@ -6,6 +6,14 @@ use crate::{AppResult};
 pub fn delete_user(id: i64, conn: &mut PgConnection) -> AppResult<()> {
     conn.transaction::<_, crate::Error, _>(|conn| {
         diesel::delete(security_codes::table.filter(security_codes::user_id.eq(id))).execute(conn)?;
+        diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(id))).execute(conn)?;
+        diesel::delete(refresh_tokens::table.filter(refresh_tokens::user_id.eq(id))).execute(conn)?;
+        diesel::delete(webauthn_credentials::table.filter(webauthn_credentials::user_id.eq(id))).execute(conn)?;
+        diesel::delete(user_identities::table.filter(user_identities::user_id.eq(id))).execute(conn)?;
+        diesel::delete(oauth_authorization_codes::table.filter(oauth_authorization_codes::user_id.eq(id)))
+            .execute(conn)?;
+        diesel::delete(oauth_consents::table.filter(oauth_consents::user_id.eq(id))).execute(conn)?;
+        diesel::delete(user_roles::table.filter(user_roles::user_id.eq(id))).execute(conn)?;
         diesel::delete(emails::table.filter(emails::user_id.eq(id))).execute(conn)?;
         diesel::delete(users::table.find(id)).execute(conn)?;
         Ok(())
@@ -16,7 +24,39 @@ pub fn delete_access_token(id: i64, conn: &mut PgConnection) -> Result<(), diese
     diesel::delete(access_tokens::table.filter(access_tokens::id.eq(id))).execute(conn)?;
     Ok(())
 }
+pub fn delete_webauthn_credential(id: i64, conn: &mut PgConnection) -> Result<(), diesel::result::Error> {
+    diesel::delete(webauthn_credentials::table.filter(webauthn_credentials::id.eq(id))).execute(conn)?;
+    Ok(())
+}
+pub fn delete_user_identity(id: i64, conn: &mut PgConnection) -> Result<(), diesel::result::Error> {
+    diesel::delete(user_identities::table.filter(user_identities::id.eq(id))).execute(conn)?;
+    Ok(())
+}
+pub fn delete_oauth_client(id: i64, conn: &mut PgConnection) -> AppResult<()> {
+    conn.transaction::<_, crate::Error, _>(|conn| {
+        let family_ids = access_tokens::table
+            .filter(access_tokens::oauth_client_id.eq(id))
+            .filter(access_tokens::family_id.is_not_null())
+            .select(access_tokens::family_id.assume_not_null())
+            .get_results::<String>(conn)?;
+        diesel::delete(refresh_tokens::table.filter(refresh_tokens::family_id.eq_any(&family_ids))).execute(conn)?;
+        diesel::delete(access_tokens::table.filter(access_tokens::oauth_client_id.eq(id))).execute(conn)?;
+        diesel::delete(oauth_authorization_codes::table.filter(oauth_authorization_codes::oauth_client_id.eq(id)))
+            .execute(conn)?;
+        diesel::delete(oauth_consents::table.filter(oauth_consents::oauth_client_id.eq(id))).execute(conn)?;
+        diesel::delete(oauth_clients::table.find(id)).execute(conn)?;
+        Ok(())
+    })
+}
 pub fn delete_notification(id: i64, conn: &mut PgConnection) -> Result<(), diesel::result::Error> {
     diesel::delete(notifications::table.filter(notifications::id.eq(id))).execute(conn)?;
     Ok(())
 }
+pub fn delete_role(id: i64, conn: &mut PgConnection) -> AppResult<()> {
+    conn.transaction::<_, crate::Error, _>(|conn| {
+        diesel::delete(user_roles::table.filter(user_roles::role_id.eq(id))).execute(conn)?;
+        diesel::delete(permissions::table.filter(permissions::role_id.eq(id))).execute(conn)?;
+        diesel::delete(roles::table.find(id)).execute(conn)?;
+        Ok(())
+    })
+}
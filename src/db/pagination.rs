@@ -1,50 +1,333 @@
+//! Paging of list endpoints, by `offset` or by `cursor`.
+//!
+//! A cursor is opaque to clients: the sort key values of a boundary record, signed so they can not be forged into
+//! arbitrary SQL values. Paging by cursor stays fast deep into large tables and does not skip or repeat records
+//! when rows are inserted meanwhile. `next_cursor` and `prev_cursor` are returned in offset mode too, so clients
+//! can switch after the first page.
+//!
+//! The total is counted with `count=exact` (default), estimated from the planner with `count=estimate` or left
+//! out with `count=none`.
+//!
+//! `sort` is a list like `-updated_at,id` (`-` for descending, `updated_at desc` works too) of filter fields or
+//! joined fields, and `fields=id,ident_name` trims the returned records to these fields.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
 use diesel::pg::Pg;
 use diesel::prelude::*;
 use diesel::query_builder::*;
 use diesel::query_dsl::methods::LoadQuery;
-use diesel::sql_types::BigInt;
+use diesel::sql_types::{BigInt, Json, Nullable};
+use salvo::Request;
+use serde::{Deserialize, Serialize};
+
+use crate::data::PagedData;
+use crate::db::url_filter::{FieldKind, FilterField, JoinedOption, Value};
+use crate::utils::sign_with_secret_key;
+use crate::AppResult;
 
-pub trait Paginate: Sized {
-    fn paginate(self, offset: i64) -> Paginated<Self>;
+const MAX_LIMIT: i64 = 200;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CountMode {
+    Exact,
+    Estimate,
+    None,
 }
 
-impl<T> Paginate for T {
-    fn paginate(self, offset: i64) -> Paginated<Self> {
-        Paginated {
-            query: self,
-            limit: DEFAULT_LIMIT,
+#[derive(Clone, Debug)]
+pub struct SortKey {
+    /// As given in `sort`, e.g. `updated_at` or `e.value`.
+    pub name: String,
+    pub column: String,
+    /// `None` for columns of the default sort which are not filter fields.
+    pub kind: Option<FieldKind>,
+    pub joined: Option<JoinedOption>,
+    pub desc: bool,
+}
+impl SortKey {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        match &self.joined {
+            // One-to-many, so records sort by their smallest joined value.
+            Some(option) => {
+                out.push_sql("(SELECT MIN(");
+                out.push_identifier(&self.column)?;
+                out.push_sql(") FROM ");
+                out.push_identifier(&option.outer_table)?;
+                out.push_sql(" WHERE ");
+                out.push_identifier(&option.outer_table)?;
+                out.push_sql(".");
+                out.push_identifier(&option.outer_key)?;
+                out.push_sql(" = t.");
+                out.push_identifier(&option.inner_key)?;
+                out.push_sql(")");
+            }
+            None => out.push_identifier(&self.column)?,
+        }
+        Ok(())
+    }
+}
+
+/// Paging parameters of a list request, see `PageRequest::from_request`.
+#[derive(Debug)]
+pub struct PageRequest {
+    pub limit: i64,
+    pub offset: i64,
+    /// Ends with `id`, so the order is total.
+    pub sort_keys: Vec<SortKey>,
+    /// Kinds of the sort keys, `None` if a sort key is joined or not a filter field and so can not go into a
+    /// cursor.
+    pub sort_kinds: Option<Vec<FieldKind>>,
+    pub cursor: Option<Cursor>,
+    pub count: CountMode,
+    pub fields: Option<Vec<String>>,
+}
+
+#[derive(Debug)]
+pub struct Cursor {
+    /// `None` for a sort key which is NULL in the boundary record.
+    pub values: Vec<Option<Value>>,
+    /// Page before the record instead of after it.
+    pub before: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct CursorData {
+    /// The sort the cursor was made for.
+    s: String,
+    k: Vec<serde_json::Value>,
+    b: bool,
+}
+
+impl PageRequest {
+    /// Read `limit`, `offset`, `sort`, `cursor`, `count` and `fields`. Sorting by fields which are not filter
+    /// fields, or a cursor made for another sort or tampered with, is an error.
+    pub fn from_request(
+        req: &Request,
+        default_sort: &str,
+        filter_fields: &[FilterField],
+        joined_options: &[JoinedOption],
+    ) -> Result<PageRequest, String> {
+        let offset = req.query::<i64>("offset").map(|l| l.max(0)).unwrap_or(0);
+        let limit = req
+            .query::<i64>("limit")
+            .map(|l| if l > MAX_LIMIT || l <= 0 { MAX_LIMIT } else { l })
+            .unwrap_or(MAX_LIMIT);
+        let sort_keys = match req.query::<String>("sort").filter(|sort| !sort.is_empty()) {
+            Some(sort) => {
+                crate::utils::validator::validate_db_sort(&sort)?;
+                parse_sort(&sort, filter_fields, joined_options, false)?
+            }
+            None => parse_sort(default_sort, filter_fields, joined_options, true)?,
+        };
+        let sort_kinds = sort_keys
+            .iter()
+            .map(|key| key.kind.filter(|_| key.joined.is_none()))
+            .collect::<Option<Vec<_>>>();
+        let count = match req.query::<String>("count").as_deref() {
+            None | Some("") | Some("exact") => CountMode::Exact,
+            Some("estimate") => CountMode::Estimate,
+            Some("none") => CountMode::None,
+            Some(other) => return Err(format!("count `{}` is not supported, use exact, estimate or none", other)),
+        };
+        let cursor = match req.query::<String>("cursor").filter(|c| !c.is_empty()) {
+            Some(cursor) => {
+                let kinds = sort_kinds.as_ref().ok_or("this sort can not be paged by cursor")?;
+                Some(decode_cursor(&cursor, &sort_keys, kinds)?)
+            }
+            None => None,
+        };
+        let fields = match req.query::<String>("fields").filter(|f| !f.is_empty()) {
+            Some(fields) => {
+                let fields = fields.split(',').map(|f| f.trim().to_owned()).collect::<Vec<_>>();
+                if let Some(field) = fields
+                    .iter()
+                    .find(|f| f.is_empty() || !f.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'))
+                {
+                    return Err(format!("field `{}` in fields is invalid", field));
+                }
+                Some(fields)
+            }
+            None => None,
+        };
+        Ok(PageRequest {
+            limit,
             offset,
+            sort_keys,
+            sort_kinds,
+            cursor,
+            count,
+            fields,
+        })
+    }
+
+    /// The sort as given by clients, e.g. `-updated_at,id`.
+    pub fn sort(&self) -> String {
+        sort_string(&self.sort_keys)
+    }
+
+    fn make_cursor<U: Serialize>(&self, record: &U, before: bool) -> Option<String> {
+        self.sort_kinds.as_ref()?;
+        let record = serde_json::to_value(record).ok()?;
+        let mut keys = Vec::with_capacity(self.sort_keys.len());
+        for key in &self.sort_keys {
+            match record.get(&key.column) {
+                Some(value) => keys.push(value.clone()),
+                None => {
+                    tracing::warn!(column = %key.column, "sort key is not in the record, no cursor for this page");
+                    return None;
+                }
+            }
+        }
+        let payload = serde_json::to_vec(&CursorData {
+            s: self.sort(),
+            k: keys,
+            b: before,
+        })
+        .ok()?;
+        let payload = URL_SAFE_NO_PAD.encode(payload);
+        let mac = sign_with_secret_key(format!("cursor:{}", payload).as_bytes()).ok()?;
+        Some(format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(mac)))
+    }
+}
+
+/// Parse a sort like `-updated_at,id` or `updated_at desc, id`. `trusted` sorts, the defaults of endpoints, may
+/// use columns which are not filter fields.
+fn parse_sort(
+    sort: &str,
+    filter_fields: &[FilterField],
+    joined_options: &[JoinedOption],
+    trusted: bool,
+) -> Result<Vec<SortKey>, String> {
+    let mut keys: Vec<SortKey> = vec![];
+    for part in sort.split(',') {
+        let part = part.trim();
+        let (name, desc) = if let Some(name) = part.strip_prefix('-') {
+            (name, true)
+        } else if let Some(name) = part.strip_prefix('+') {
+            (name, false)
+        } else {
+            let mut words = part.split_whitespace();
+            let name = words.next().unwrap_or_default();
+            match words.next().map(|d| d.to_ascii_lowercase()).as_deref() {
+                None | Some("asc") => (name, false),
+                Some("desc") => (name, true),
+                Some(_) => return Err(format!("sort `{}` is invalid", part)),
+            }
+        };
+        if keys.iter().any(|key| key.name == name) {
+            return Err(format!("sort field `{}` is repeated", name));
         }
+        let key = if name.contains('.') {
+            let option = joined_options.iter().find(|o| o.url_name_map.contains_key(name));
+            match option {
+                Some(option) => SortKey {
+                    name: name.to_owned(),
+                    column: option.url_name_map[name].name.clone(),
+                    kind: Some(option.url_name_map[name].kind),
+                    joined: Some(option.clone()),
+                    desc,
+                },
+                None => return Err(format!("sorting by `{}` is not allowed", name)),
+            }
+        } else {
+            let kind = match filter_fields.iter().find(|f| f.name == name) {
+                Some(field) => Some(field.kind),
+                None if name == "id" => Some(FieldKind::BigInt),
+                None if trusted => None,
+                None => return Err(format!("sorting by `{}` is not allowed", name)),
+            };
+            SortKey {
+                name: name.to_owned(),
+                column: name.to_owned(),
+                kind,
+                joined: None,
+                desc,
+            }
+        };
+        keys.push(key);
+    }
+    // `id` makes the order total, which cursors rely on.
+    if !keys.iter().any(|key| key.name == "id") {
+        let desc = keys.last().map(|key| key.desc).unwrap_or(false);
+        keys.push(SortKey {
+            name: "id".into(),
+            column: "id".into(),
+            kind: Some(FieldKind::BigInt),
+            joined: None,
+            desc,
+        });
     }
+    Ok(keys)
+}
+fn sort_string(keys: &[SortKey]) -> String {
+    keys.iter()
+        .map(|key| format!("{}{}", if key.desc { "-" } else { "" }, key.name))
+        .collect::<Vec<_>>()
+        .join(",")
 }
 
-const DEFAULT_LIMIT: i64 = 10;
+fn decode_cursor(cursor: &str, sort_keys: &[SortKey], kinds: &[FieldKind]) -> Result<Cursor, String> {
+    let invalid = || "cursor is invalid".to_owned();
+    let (payload, mac) = cursor.split_once('.').ok_or_else(invalid)?;
+    let mac = URL_SAFE_NO_PAD.decode(mac).map_err(|_| invalid())?;
+    let expected = sign_with_secret_key(format!("cursor:{}", payload).as_bytes()).map_err(|_| invalid())?;
+    if mac.len() != expected.len() || !openssl::memcmp::eq(&mac, &expected) {
+        return Err(invalid());
+    }
+    let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| invalid())?;
+    let data = serde_json::from_slice::<CursorData>(&payload).map_err(|_| invalid())?;
+    if data.s != sort_string(sort_keys) || data.k.len() != kinds.len() {
+        return Err("cursor was made for another sort".into());
+    }
+    let values = data
+        .k
+        .iter()
+        .zip(kinds)
+        .map(|(value, kind)| match value {
+            serde_json::Value::Null => Some(None),
+            value => json_to_value(value, *kind).map(Some),
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(invalid)?;
+    Ok(Cursor {
+        values,
+        before: data.b,
+    })
+}
+fn json_to_value(value: &serde_json::Value, kind: FieldKind) -> Option<Value> {
+    match kind {
+        FieldKind::Bool => value.as_bool().map(Value::Bool),
+        FieldKind::Int => value.as_i64().and_then(|v| i32::try_from(v).ok()).map(Value::Int),
+        FieldKind::BigInt => value.as_i64().map(Value::BigInt),
+        FieldKind::Text => value.as_str().map(|v| Value::Text(v.to_owned())),
+        FieldKind::Timestamptz => value
+            .as_str()
+            .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+            .map(|v| Value::Timestamptz(v.with_timezone(&Utc))),
+    }
+}
 
-#[derive(Debug, Clone, Copy, QueryId)]
+/// `SELECT *, COUNT(*) OVER () FROM (query) t [WHERE keyset] ORDER BY sort LIMIT limit [OFFSET offset]`. The
+/// count column is NULL unless counted in the window, which only makes sense when paging by offset.
 pub struct Paginated<T> {
     query: T,
+    sort_keys: Vec<SortKey>,
+    keyset: Option<Vec<Option<Value>>>,
+    before: bool,
     limit: i64,
     offset: i64,
+    with_total: bool,
 }
 
-impl<T> Paginated<T> {
-    pub fn limit(self, limit: i64) -> Self {
-        Paginated { limit, ..self }
-    }
-
-    pub fn load_and_total<'a, U>(self, conn: &mut PgConnection) -> QueryResult<(Vec<U>, i64)>
-    where
-        Self: LoadQuery<'a, PgConnection, (U, i64)>,
-    {
-        let results = self.load::<(U, i64)>(conn)?;
-        let total = results.get(0).map(|x| x.1).unwrap_or(0);
-        let records = results.into_iter().map(|x| x.0).collect();
-        Ok((records, total))
-    }
+impl<T> QueryId for Paginated<T> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
 }
 
 impl<T: Query> Query for Paginated<T> {
-    type SqlType = (T::SqlType, BigInt);
+    type SqlType = (T::SqlType, Nullable<BigInt>);
 }
 
 impl<T> RunQueryDsl<PgConnection> for Paginated<T> {}
@@ -54,12 +337,348 @@ where
     T: QueryFragment<Pg>,
 {
     fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
-        out.push_sql("SELECT *, COUNT(*) OVER () FROM (");
+        if self.with_total {
+            out.push_sql("SELECT *, COUNT(*) OVER () FROM (");
+        } else {
+            out.push_sql("SELECT *, CAST(NULL AS bigint) FROM (");
+        }
         self.query.walk_ast(out.reborrow())?;
-        out.push_sql(") t LIMIT ");
+        out.push_sql(") t");
+        // Reading backwards, every comparison and the order is reversed.
+        let desc = |key: &SortKey| key.desc != self.before;
+        if let Some(values) = &self.keyset {
+            // (k1 > v1) OR (k1 = v1 AND k2 > v2) OR ..., which works for mixed directions unlike a row comparison.
+            out.push_sql(" WHERE (");
+            for (i, key) in self.sort_keys.iter().enumerate() {
+                if i > 0 {
+                    out.push_sql(" OR ");
+                }
+                out.push_sql("(");
+                for (prev, value) in self.sort_keys.iter().zip(values).take(i) {
+                    walk_equal(prev, value, out.reborrow())?;
+                    out.push_sql(" AND ");
+                }
+                walk_after(key, &values[i], desc(key), out.reborrow())?;
+                out.push_sql(")");
+            }
+            out.push_sql(")");
+        }
+        out.push_sql(" ORDER BY ");
+        for (i, key) in self.sort_keys.iter().enumerate() {
+            if i > 0 {
+                out.push_sql(", ");
+            }
+            key.walk_ast(out.reborrow())?;
+            out.push_sql(if desc(key) { " DESC" } else { " ASC" });
+        }
+        out.push_sql(" LIMIT ");
         out.push_bind_param::<BigInt, _>(&self.limit)?;
-        out.push_sql(" OFFSET ");
-        out.push_bind_param::<BigInt, _>(&self.offset)?;
+        if self.keyset.is_none() {
+            out.push_sql(" OFFSET ");
+            out.push_bind_param::<BigInt, _>(&self.offset)?;
+        }
+        Ok(())
+    }
+}
+
+/// `key = value`, where NULL equals NULL.
+fn walk_equal<'b>(key: &'b SortKey, value: &'b Option<Value>, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+    key.walk_ast(out.reborrow())?;
+    match value {
+        Some(value) => {
+            out.push_sql(" = ");
+            value.walk_ast(out.reborrow())
+        }
+        None => {
+            out.push_sql(" IS NULL");
+            Ok(())
+        }
+    }
+}
+/// Whether `key` comes after `value` in the order `desc`. NULL sorts after every value ascending and before every
+/// value descending, as Postgres does by default.
+fn walk_after<'b>(
+    key: &'b SortKey,
+    value: &'b Option<Value>,
+    desc: bool,
+    mut out: AstPass<'_, 'b, Pg>,
+) -> QueryResult<()> {
+    match (value, desc) {
+        (Some(value), true) => {
+            key.walk_ast(out.reborrow())?;
+            out.push_sql(" < ");
+            value.walk_ast(out.reborrow())?;
+        }
+        (Some(value), false) => {
+            out.push_sql("(");
+            key.walk_ast(out.reborrow())?;
+            out.push_sql(" > ");
+            value.walk_ast(out.reborrow())?;
+            out.push_sql(" OR ");
+            key.walk_ast(out.reborrow())?;
+            out.push_sql(" IS NULL)");
+        }
+        (None, true) => {
+            key.walk_ast(out.reborrow())?;
+            out.push_sql(" IS NOT NULL");
+        }
+        (None, false) => out.push_sql("FALSE"),
+    }
+    Ok(())
+}
+
+/// `SELECT COUNT(*) FROM (query) t`
+pub struct Counted<T> {
+    query: T,
+}
+impl<T> QueryId for Counted<T> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+impl<T> Query for Counted<T> {
+    type SqlType = BigInt;
+}
+impl<T> RunQueryDsl<PgConnection> for Counted<T> {}
+impl<T: QueryFragment<Pg>> QueryFragment<Pg> for Counted<T> {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        out.push_sql("SELECT COUNT(*) FROM (");
+        self.query.walk_ast(out.reborrow())?;
+        out.push_sql(") t");
         Ok(())
     }
 }
+
+/// `EXPLAIN (FORMAT JSON) query`, whose top plan node has the number of rows the planner expects.
+pub struct Explained<T> {
+    query: T,
+}
+impl<T> QueryId for Explained<T> {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+impl<T> Query for Explained<T> {
+    type SqlType = Json;
+}
+impl<T> RunQueryDsl<PgConnection> for Explained<T> {}
+impl<T: QueryFragment<Pg>> QueryFragment<Pg> for Explained<T> {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        out.push_sql("EXPLAIN (FORMAT JSON) ");
+        self.query.walk_ast(out.reborrow())
+    }
+}
+
+/// Load one page of `query` as requested.
+pub fn load_page<'a, T, U>(query: &'a T, page: &PageRequest, conn: &mut PgConnection) -> AppResult<PagedData<U>>
+where
+    T: Query + QueryFragment<Pg>,
+    Paginated<&'a T>: LoadQuery<'a, PgConnection, (U, Option<i64>)>,
+    Counted<&'a T>: LoadQuery<'a, PgConnection, i64>,
+    Explained<&'a T>: LoadQuery<'a, PgConnection, serde_json::Value>,
+    U: Serialize,
+{
+    let before = page.cursor.as_ref().map(|c| c.before).unwrap_or(false);
+    let with_total = page.count == CountMode::Exact && page.cursor.is_none();
+    // One more than asked tells whether there is a further page.
+    let rows = Paginated {
+        query,
+        sort_keys: page.sort_keys.clone(),
+        keyset: page.cursor.as_ref().map(|c| c.values.clone()),
+        before,
+        limit: page.limit + 1,
+        offset: page.offset,
+        with_total,
+    }
+    .load::<(U, Option<i64>)>(conn)?;
+    let window_total = rows.first().and_then(|row| row.1);
+    let mut records = rows.into_iter().map(|row| row.0).collect::<Vec<_>>();
+    let has_more = records.len() as i64 > page.limit;
+    records.truncate(page.limit as usize);
+    if before {
+        records.reverse();
+    }
+
+    let total = match page.count {
+        CountMode::Exact if with_total => Some(window_total.unwrap_or(0)),
+        CountMode::Exact => Some(Counted { query }.get_result::<i64>(conn)?),
+        CountMode::Estimate => {
+            let plan = Explained { query }.get_result::<serde_json::Value>(conn)?;
+            plan[0]["Plan"]["Plan Rows"].as_f64().map(|rows| rows as i64)
+        }
+        CountMode::None => None,
+    };
+
+    // Reading backwards, `has_more` is about the records before the first one; the page it came from is after.
+    let (more_after, more_before) = match &page.cursor {
+        Some(_) if before => (true, has_more),
+        Some(_) => (has_more, true),
+        None => (has_more, page.offset > 0),
+    };
+    let next_cursor = records.last().filter(|_| more_after).and_then(|r| page.make_cursor(r, false));
+    let prev_cursor = records.first().filter(|_| more_before).and_then(|r| page.make_cursor(r, true));
+    Ok(PagedData {
+        records,
+        limit: page.limit,
+        offset: if page.cursor.is_some() { 0 } else { page.offset },
+        total,
+        sort: Some(page.sort()),
+        next_cursor,
+        prev_cursor,
+        fields: page.fields.clone(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use diesel::debug_query;
+    use salvo::test::TestClient;
+
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Record {
+        id: i64,
+        kind: String,
+        created_at: Option<DateTime<Utc>>,
+    }
+
+    fn fields() -> Vec<FilterField> {
+        vec![
+            FilterField::new("id", FieldKind::BigInt),
+            FilterField::new("kind", FieldKind::Text),
+            FilterField::new("created_at", FieldKind::Timestamptz),
+        ]
+    }
+    fn page(query: &[(&str, &str)]) -> Result<PageRequest, String> {
+        std::env::set_var("SECRET_KEY", "pagination-tests");
+        let req = TestClient::get("http://127.0.0.1/").queries(query.to_vec()).build();
+        PageRequest::from_request(&req, "-id", &fields(), &[])
+    }
+    fn record(created_at: Option<DateTime<Utc>>) -> Record {
+        Record {
+            id: 5,
+            kind: "email".into(),
+            created_at,
+        }
+    }
+    fn created_at() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2023, 4, 1, 8, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn cursor_round_trips() {
+        let cursor = page(&[("sort", "-created_at")])
+            .unwrap()
+            .make_cursor(&record(Some(created_at())), true)
+            .unwrap();
+        let cursor = page(&[("sort", "-created_at"), ("cursor", &cursor)])
+            .unwrap()
+            .cursor
+            .unwrap();
+        assert!(cursor.before);
+        assert_eq!(
+            cursor.values,
+            vec![Some(Value::Timestamptz(created_at())), Some(Value::BigInt(5))]
+        );
+    }
+
+    #[test]
+    fn cursor_keeps_null_sort_keys() {
+        let cursor = page(&[("sort", "created_at")])
+            .unwrap()
+            .make_cursor(&record(None), false)
+            .unwrap();
+        let cursor = page(&[("sort", "created_at"), ("cursor", &cursor)])
+            .unwrap()
+            .cursor
+            .unwrap();
+        assert_eq!(cursor.values, vec![None, Some(Value::BigInt(5))]);
+    }
+
+    #[test]
+    fn rejects_tampered_cursor() {
+        let decode = |cursor: &str| page(&[("sort", "kind"), ("cursor", cursor)]).map(|_| ()).unwrap_err();
+        let cursor = page(&[("sort", "kind")])
+            .unwrap()
+            .make_cursor(&record(None), false)
+            .unwrap();
+        let (payload, mac) = cursor.split_once('.').unwrap();
+
+        let forged = URL_SAFE_NO_PAD.encode(br#"{"s":"kind,id","k":["' or 1=1 --",5],"b":false}"#);
+        assert_eq!(decode(&format!("{}.{}", forged, mac)), "cursor is invalid");
+        let mut other_mac = URL_SAFE_NO_PAD.decode(mac).unwrap();
+        other_mac[0] ^= 1;
+        assert_eq!(
+            decode(&format!("{}.{}", payload, URL_SAFE_NO_PAD.encode(other_mac))),
+            "cursor is invalid"
+        );
+        assert_eq!(decode(payload), "cursor is invalid");
+        assert_eq!(decode("not a cursor"), "cursor is invalid");
+
+        let err = page(&[("sort", "-kind"), ("cursor", &cursor)]).map(|_| ()).unwrap_err();
+        assert_eq!(err, "cursor was made for another sort");
+    }
+
+    fn keyset_sql(sort: &str, keyset: Vec<Option<Value>>, before: bool) -> String {
+        let query = diesel::sql_query("SELECT * FROM records");
+        let paginated = Paginated {
+            query: &query,
+            sort_keys: parse_sort(sort, &fields(), &[], false).unwrap(),
+            keyset: Some(keyset),
+            before,
+            limit: 11,
+            offset: 0,
+            with_total: false,
+        };
+        let sql = debug_query::<Pg, _>(&paginated).to_string();
+        let sql = sql.split(" -- binds").next().unwrap();
+        sql.strip_prefix("SELECT *, CAST(NULL AS bigint) FROM (SELECT * FROM records) t")
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn keyset_follows_sort_direction() {
+        let keyset = || vec![Some(Value::Text("email".into())), Some(Value::BigInt(5))];
+        assert_eq!(
+            keyset_sql("-kind", keyset(), false),
+            r#" WHERE (("kind" < $1) OR ("kind" = $2 AND "id" < $3)) ORDER BY "kind" DESC, "id" DESC LIMIT $4"#
+        );
+        // Paging backwards reads the other way round, the records are reversed after loading.
+        assert_eq!(
+            keyset_sql("-kind", keyset(), true),
+            concat!(
+                r#" WHERE ((("kind" > $1 OR "kind" IS NULL)) OR ("kind" = $2 AND ("id" > $3 OR "id" IS NULL)))"#,
+                r#" ORDER BY "kind" ASC, "id" ASC LIMIT $4"#
+            )
+        );
+        assert_eq!(
+            keyset_sql("kind,-id", keyset(), true),
+            concat!(
+                r#" WHERE (("kind" < $1) OR ("kind" = $2 AND ("id" > $3 OR "id" IS NULL)))"#,
+                r#" ORDER BY "kind" DESC, "id" ASC LIMIT $4"#
+            )
+        );
+    }
+
+    #[test]
+    fn keyset_places_nulls_last_ascending() {
+        let keyset = || vec![None, Some(Value::BigInt(5))];
+        // Only other NULLs with a greater id follow a NULL ascending.
+        assert_eq!(
+            keyset_sql("created_at", keyset(), false),
+            concat!(
+                r#" WHERE ((FALSE) OR ("created_at" IS NULL AND ("id" > $1 OR "id" IS NULL)))"#,
+                r#" ORDER BY "created_at" ASC, "id" ASC LIMIT $2"#
+            )
+        );
+        // Every value comes before it.
+        assert_eq!(
+            keyset_sql("created_at", keyset(), true),
+            concat!(
+                r#" WHERE (("created_at" IS NOT NULL) OR ("created_at" IS NULL AND "id" < $1))"#,
+                r#" ORDER BY "created_at" DESC, "id" DESC LIMIT $2"#
+            )
+        );
+    }
+}
@@ -1,321 +1,868 @@
-use once_cell::sync::Lazy;
+//! The `filter` query of list endpoints, e.g. `kind = 'email' and (is_read = false or created_at >= 2023-04-01)`.
+//!
+//! It is parsed into an `Expr` which compiles to SQL with every value bound as a parameter, typed by the column it
+//! is compared to. Only the fields a model allows (`*_FILTER_FIELDS`, `*_JOINED_OPTIONS`) can be used.
+//!
+//! Supported are `and`, `or`, `not` and parentheses, the comparisons `=`/`eq`, `!=`/`<>`/`neq`, `<`/`lt`,
+//! `>`/`gt`, `<=`/`lte`, `>=`/`gte`, `[not] like`/`nlike`, `[not] ilike`/`nilike`, `[not] in (a, b)`/`nin`,
+//! `[not] between a and b` and `is [not] null|true|false`/`nis`. `field::text` compares a field as text. Values
+//! are bare words or quoted, `'it''s'` or `E'it\'s'`.
+
+use chrono::{DateTime, NaiveDate, Utc};
+use diesel::expression::{is_aggregate, AppearsOnTable, Expression, ValidGrouping};
+use diesel::pg::Pg;
+use diesel::query_builder::*;
+use diesel::result::QueryResult;
+use diesel::sql_types;
 use std::collections::HashMap;
-use std::fmt::Write;
-
-static SYMBOLS: Lazy<Vec<char>> = Lazy::new(|| vec!['=', '!', '<', '>', ':']);
-static OPERATORS: Lazy<HashMap<&'static str, &'static str>> = Lazy::new(|| {
-    let mut operators = HashMap::new();
-    // operators.insert("or", "OR");
-    // operators.insert("and", "AND");
-    operators.insert("=", "=");
-    operators.insert("eq", "=");
-    operators.insert("!=", "<>");
-    operators.insert("<>", "<>");
-    operators.insert("neq", "<>");
-    operators.insert("is", "IS");
-    operators.insert("nis", "IS NOT");
-    operators.insert("in", "IN");
-    operators.insert("nin", "NOT IN");
-    operators.insert("like", "LIKE");
-    operators.insert("nlike", "NOT LIKE");
-    operators.insert("ilike", "ILIKE");
-    operators.insert("nilike", "NOT ILIKE");
-    operators.insert("<", "<");
-    operators.insert("lt", "<");
-    operators.insert(">", ">");
-    operators.insert("gt", "gt");
-    operators.insert("<=", "<=");
-    operators.insert("lte", "<=");
-    operators.insert(">=", ">=");
-    operators.insert("gte", ">=");
-    operators
-});
-pub struct Parser {
-    pub offset: usize,
-    pub raw: String,
-    pub chars: Vec<char>,
-    pub idents: Vec<String>,
-    pub joined_options: Vec<JoinedOption>,
+use std::fmt;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FieldKind {
+    Bool,
+    Int,
+    BigInt,
+    Text,
+    Timestamptz,
+}
+
+#[derive(Clone, Debug)]
+pub struct FilterField {
+    pub name: String,
+    pub kind: FieldKind,
+}
+impl FilterField {
+    pub fn new(name: impl Into<String>, kind: FieldKind) -> Self {
+        FilterField {
+            name: name.into(),
+            kind,
+        }
+    }
 }
+
 #[derive(Clone, Debug)]
 pub struct JoinedOption {
     pub outer_table: String,
     pub outer_key: String,
     pub inner_key: String,
-    pub url_name_map: HashMap<String, String>,
+    /// Url field name, like `e.value`, to the column of `outer_table` it filters on.
+    pub url_name_map: HashMap<String, FilterField>,
+}
+
+/// Deepest nesting of parentheses and `not`, so parsing, compiling and dropping an `Expr` stay off the stack limit.
+const MAX_DEPTH: usize = 32;
+/// Most conditions in a filter, each value of an `in` list counting as one. `and`/`or` chains nest one level per
+/// condition, so this also bounds their depth.
+const MAX_TERMS: usize = 100;
+
+#[derive(thiserror::Error, Debug)]
+#[error("{message}, offset: {offset}")]
+pub struct ParseError {
+    pub offset: usize,
+    pub message: String,
+}
+fn error<T>(offset: usize, message: impl Into<String>) -> Result<T, ParseError> {
+    Err(ParseError {
+        offset,
+        message: message.into(),
+    })
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    Bool(bool),
+    Int(i32),
+    BigInt(i64),
+    Text(String),
+    Timestamptz(DateTime<Utc>),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Like,
+    NotLike,
+    ILike,
+    NotILike,
+}
+impl CompareOp {
+    fn sql(self) -> &'static str {
+        match self {
+            CompareOp::Eq => " = ",
+            CompareOp::Ne => " <> ",
+            CompareOp::Lt => " < ",
+            CompareOp::Gt => " > ",
+            CompareOp::Le => " <= ",
+            CompareOp::Ge => " >= ",
+            CompareOp::Like => " LIKE ",
+            CompareOp::NotLike => " NOT LIKE ",
+            CompareOp::ILike => " ILIKE ",
+            CompareOp::NotILike => " NOT ILIKE ",
+        }
+    }
+    fn is_like(self) -> bool {
+        matches!(
+            self,
+            CompareOp::Like | CompareOp::NotLike | CompareOp::ILike | CompareOp::NotILike
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IsValue {
+    Null,
+    True,
+    False,
+}
+
+#[derive(Clone, Debug)]
+pub struct Column {
+    pub name: String,
+    pub kind: FieldKind,
+    pub cast_text: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Compare {
+        column: Column,
+        op: CompareOp,
+        value: Value,
+    },
+    In {
+        column: Column,
+        values: Vec<Value>,
+        negated: bool,
+    },
+    Between {
+        column: Column,
+        low: Value,
+        high: Value,
+        negated: bool,
+    },
+    Is {
+        column: Column,
+        value: IsValue,
+        negated: bool,
+    },
+    /// A condition on a joined table: `inner_key IN (SELECT outer_key FROM outer_table WHERE condition)`.
+    Joined {
+        inner_key: String,
+        outer_key: String,
+        outer_table: String,
+        condition: Box<Expr>,
+    },
+}
+
+impl QueryFragment<Pg> for Column {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        if self.cast_text {
+            out.push_sql("CAST(");
+            out.push_identifier(&self.name)?;
+            out.push_sql(" AS text)");
+        } else {
+            out.push_identifier(&self.name)?;
+        }
+        Ok(())
+    }
+}
+
+impl QueryFragment<Pg> for Value {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        match self {
+            Value::Bool(v) => out.push_bind_param::<sql_types::Bool, _>(v),
+            Value::Int(v) => out.push_bind_param::<sql_types::Integer, _>(v),
+            Value::BigInt(v) => out.push_bind_param::<sql_types::BigInt, _>(v),
+            Value::Text(v) => out.push_bind_param::<sql_types::Text, _>(v),
+            Value::Timestamptz(v) => out.push_bind_param::<sql_types::Timestamptz, _>(v),
+        }
+    }
+}
+
+impl Expression for Expr {
+    type SqlType = sql_types::Bool;
+}
+impl<T> AppearsOnTable<T> for Expr {}
+impl ValidGrouping<()> for Expr {
+    type IsAggregate = is_aggregate::Never;
+}
+impl QueryId for Expr {
+    type QueryId = ();
+    const HAS_STATIC_QUERY_ID: bool = false;
+}
+
+impl QueryFragment<Pg> for Expr {
+    fn walk_ast<'b>(&'b self, mut out: AstPass<'_, 'b, Pg>) -> QueryResult<()> {
+        // Every filter is a different statement, do not let them pile up in the prepared statement cache.
+        out.unsafe_to_cache_prepared();
+        match self {
+            Expr::And(left, right) | Expr::Or(left, right) => {
+                out.push_sql("(");
+                left.walk_ast(out.reborrow())?;
+                out.push_sql(if matches!(self, Expr::And(..)) { " AND " } else { " OR " });
+                right.walk_ast(out.reborrow())?;
+                out.push_sql(")");
+            }
+            Expr::Not(expr) => {
+                out.push_sql("NOT (");
+                expr.walk_ast(out.reborrow())?;
+                out.push_sql(")");
+            }
+            Expr::Compare { column, op, value } => {
+                column.walk_ast(out.reborrow())?;
+                out.push_sql(op.sql());
+                value.walk_ast(out.reborrow())?;
+            }
+            Expr::In {
+                column,
+                values,
+                negated,
+            } => {
+                column.walk_ast(out.reborrow())?;
+                out.push_sql(if *negated { " NOT IN (" } else { " IN (" });
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        out.push_sql(", ");
+                    }
+                    value.walk_ast(out.reborrow())?;
+                }
+                out.push_sql(")");
+            }
+            Expr::Between {
+                column,
+                low,
+                high,
+                negated,
+            } => {
+                column.walk_ast(out.reborrow())?;
+                out.push_sql(if *negated { " NOT BETWEEN " } else { " BETWEEN " });
+                low.walk_ast(out.reborrow())?;
+                out.push_sql(" AND ");
+                high.walk_ast(out.reborrow())?;
+            }
+            Expr::Is { column, value, negated } => {
+                column.walk_ast(out.reborrow())?;
+                out.push_sql(if *negated { " IS NOT " } else { " IS " });
+                out.push_sql(match value {
+                    IsValue::Null => "NULL",
+                    IsValue::True => "TRUE",
+                    IsValue::False => "FALSE",
+                });
+            }
+            Expr::Joined {
+                inner_key,
+                outer_key,
+                outer_table,
+                condition,
+            } => {
+                out.push_identifier(inner_key)?;
+                out.push_sql(" IN (SELECT ");
+                out.push_identifier(outer_key)?;
+                out.push_sql(" FROM ");
+                out.push_identifier(outer_table)?;
+                out.push_sql(" WHERE ");
+                condition.walk_ast(out.reborrow())?;
+                out.push_sql(")");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+enum Token {
+    Word(String),
+    Str(String),
+    Symbol(String),
+    LParen,
+    RParen,
+    Comma,
+}
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(w) | Token::Symbol(w) => write!(f, "`{}`", w),
+            Token::Str(s) => write!(f, "'{}'", s),
+            Token::LParen => f.write_str("`(`"),
+            Token::RParen => f.write_str("`)`"),
+            Token::Comma => f.write_str("`,`"),
+        }
+    }
+}
+
+const SYMBOLS: &[char] = &['=', '!', '<', '>'];
+
+fn is_word_char(ch: char) -> bool {
+    !ch.is_whitespace() && !SYMBOLS.contains(&ch) && !matches!(ch, '(' | ')' | ',' | '\'')
+}
+
+/// Split the filter into tokens, each with the char offset it starts at.
+fn tokenize(raw: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let chars = raw.chars().collect::<Vec<_>>();
+    let mut tokens = vec![];
+    let mut offset = 0;
+    while offset < chars.len() {
+        let start = offset;
+        let ch = chars[offset];
+        if ch.is_whitespace() {
+            offset += 1;
+        } else if ch == '(' || ch == ')' || ch == ',' {
+            tokens.push((
+                start,
+                match ch {
+                    '(' => Token::LParen,
+                    ')' => Token::RParen,
+                    _ => Token::Comma,
+                },
+            ));
+            offset += 1;
+        } else if ch == '\'' || (ch == 'E' && chars.get(offset + 1) == Some(&'\'')) {
+            // In `E'..'` a backslash escapes a quote or a backslash; others are kept, so `\_` still escapes `_` in
+            // like patterns. In both kinds a doubled quote is a quote.
+            let escapes = ch == 'E';
+            offset += if escapes { 2 } else { 1 };
+            let mut value = String::new();
+            loop {
+                match chars.get(offset) {
+                    None => return error(start, "unterminated string"),
+                    Some('\\') if escapes => match chars.get(offset + 1) {
+                        Some(c @ ('\\' | '\'')) => {
+                            value.push(*c);
+                            offset += 2;
+                        }
+                        _ => {
+                            value.push('\\');
+                            offset += 1;
+                        }
+                    },
+                    Some('\'') if chars.get(offset + 1) == Some(&'\'') => {
+                        value.push('\'');
+                        offset += 2;
+                    }
+                    Some('\'') => {
+                        offset += 1;
+                        break;
+                    }
+                    Some(c) => {
+                        value.push(*c);
+                        offset += 1;
+                    }
+                }
+            }
+            tokens.push((start, Token::Str(value)));
+        } else if SYMBOLS.contains(&ch) {
+            while offset < chars.len() && SYMBOLS.contains(&chars[offset]) {
+                offset += 1;
+            }
+            tokens.push((start, Token::Symbol(chars[start..offset].iter().collect())));
+        } else {
+            while offset < chars.len() && is_word_char(chars[offset]) {
+                offset += 1;
+            }
+            tokens.push((start, Token::Word(chars[start..offset].iter().collect())));
+        }
+    }
+    Ok(tokens)
+}
+
+pub struct Parser {
+    pub raw: String,
+    pub fields: Vec<FilterField>,
+    pub joined_options: Vec<JoinedOption>,
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+    depth: usize,
+    terms: usize,
 }
 impl Parser {
-    pub fn new(raw: String, idents: Vec<String>, options: Vec<JoinedOption>) -> Parser {
+    pub fn new(raw: String, fields: Vec<FilterField>, options: Vec<JoinedOption>) -> Parser {
         Parser {
-            offset: 0,
-            chars: raw.chars().collect(),
             raw,
-            idents,
+            fields,
             joined_options: options,
+            tokens: vec![],
+            pos: 0,
+            depth: 0,
+            terms: 0,
         }
     }
-    pub fn parse(&mut self) -> Result<String, String> {
-        if self.raw.is_empty() {
-            return Ok("".into());
+    /// `None` for an empty filter.
+    pub fn parse(&mut self) -> Result<Option<Expr>, ParseError> {
+        self.tokens = tokenize(&self.raw)?;
+        self.pos = 0;
+        self.depth = 0;
+        self.terms = 0;
+        if self.tokens.is_empty() {
+            return Ok(None);
         }
-        Ok(self.scan_expr()?.trim().to_string())
-    }
-    fn next(&mut self, skip_blank: bool) -> Option<char> {
-        self.offset += 1;
-        if self.offset < self.chars.len() {
-            if skip_blank {
-                self.skip_blank();
-            }
-            Some(self.chars[self.offset])
-        } else {
-            None
+        let expr = self.parse_or()?;
+        if let Some((offset, token)) = self.tokens.get(self.pos) {
+            return error(*offset, format!("unexpected {}", token));
         }
+        Ok(Some(expr))
     }
-    fn try_next(&mut self, skip_blank: bool) -> Result<char, String> {
-        self.next(skip_blank)
-            .ok_or(format!("unexcepted end 1, offset: {}, raw: {}", self.offset, &self.raw))
+
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .map(|(offset, _)| *offset)
+            .unwrap_or_else(|| self.raw.chars().count())
     }
-    fn skip_blank(&mut self) {
-        let ch = self.curr();
-        if ch.is_none() {
-            return;
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+    fn advance(&mut self) -> Option<(usize, Token)> {
+        let token = self.tokens.get(self.pos).cloned();
+        if token.is_some() {
+            self.pos += 1;
         }
-        let mut ch = ch.unwrap();
-        while ch == ' ' || ch == '\t' {
-            if self.offset < self.chars.len() - 1 {
-                self.offset += 1;
-                ch = self.chars[self.offset];
-            } else {
-                break;
-            }
+        token
+    }
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(w)) if w.eq_ignore_ascii_case(keyword))
+    }
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        if found {
+            self.pos += 1;
         }
+        found
     }
-    fn peek(&self) -> Option<&char> {
-        if self.offset < self.chars.len() - 1 {
-            self.chars.get(self.offset + 1)
-        } else {
-            None
+    fn expect(&mut self, expected: Token) -> Result<(), ParseError> {
+        let offset = self.offset();
+        match self.advance() {
+            Some((_, token)) if token == expected => Ok(()),
+            Some((_, token)) => error(offset, format!("expected {}, found {}", expected, token)),
+            None => error(offset, format!("expected {}, found the end", expected)),
         }
     }
-    fn peek_token(&self) -> Option<String> {
-        let mut tok = "".to_owned();
-        let mut offset = self.offset;
-        while offset < self.chars.len() {
-            let ch = self.chars[offset];
-            if ch != ' ' && ch != '\t' {
-                tok.push(ch);
-            } else {
-                break;
-            }
-            offset += 1;
+
+    fn parse_or(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.parse_and()?;
+        while self.eat_keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
         }
-        if tok.is_empty() {
-            None
-        } else {
-            // println!("=============toke: {:?}", &tok);
-            Some(tok)
+        Ok(expr)
+    }
+    fn parse_and(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.parse_unary()?;
+        while self.eat_keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_unary()?));
         }
+        Ok(expr)
     }
-    fn curr(&self) -> Option<char> {
-        if self.offset < self.chars.len() {
-            Some(self.chars[self.offset])
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        if !self.is_keyword("not") && self.peek() != Some(&Token::LParen) {
+            return self.parse_condition();
+        }
+        if self.depth >= MAX_DEPTH {
+            return error(self.offset(), format!("filter is nested deeper than {} levels", MAX_DEPTH));
+        }
+        self.depth += 1;
+        let expr = if self.eat_keyword("not") {
+            Expr::Not(Box::new(self.parse_unary()?))
         } else {
-            None
+            self.pos += 1;
+            let expr = self.parse_or()?;
+            self.expect(Token::RParen)?;
+            expr
+        };
+        self.depth -= 1;
+        Ok(expr)
+    }
+    fn count_term(&mut self, offset: usize) -> Result<(), ParseError> {
+        self.terms += 1;
+        if self.terms > MAX_TERMS {
+            return error(offset, format!("filter has more than {} terms", MAX_TERMS));
         }
+        Ok(())
     }
 
-    pub fn scan_expr(&mut self) -> Result<String, String> {
-        let mut expr: String = "".into();
-        self.skip_blank();
-        if let Some(ch) = self.curr() {
-            if ch == '(' {
-                expr.push('(');
-                self.offset += 1;
-                expr.push_str(&self.scan_expr()?);
-                self.skip_blank();
-                if self.curr().unwrap_or_default() == ')' {
-                    expr.push(')');
-                    self.offset += 1;
-                } else {
-                    return Err(format!("excepted ')', offset: {}, raw: {}", self.offset, &self.raw));
-                }
-            } else {
-                let left = self.scan_ident()?;
-                // println!("==============left: {:#?}", &left);
-                let operator = self.scan_operator()?;
-                let right = self.scan_value()?;
-                // println!("==============right: {:#?}", &right);
-                if left.contains('.') {
-                    if let Some(option) = self.find_option(&left) {
-                        write!(
-                            expr,
-                            "{} in (select {} from {} where {} {} {})",
-                            option.inner_key,
-                            option.outer_key,
-                            option.outer_table,
-                            option.url_name_map[&left],
-                            &operator,
-                            right
-                        )
-                        .unwrap();
-                    } else {
-                        return Err(format!("ident is not correct, raw: {}", &self.raw));
-                    }
-                } else {
-                    expr.push_str(&left);
-                    expr.push(' ');
-                    expr.push_str(&operator);
-                    expr.push(' ');
-                    expr.push_str(&right);
-                }
+    fn parse_condition(&mut self) -> Result<Expr, ParseError> {
+        let offset = self.offset();
+        self.count_term(offset)?;
+        let ident = match self.advance() {
+            Some((_, Token::Word(word))) => word,
+            Some((_, token)) => return error(offset, format!("expected a field, found {}", token)),
+            None => return error(offset, "expected a field, found the end"),
+        };
+        let (name, cast_text) = match ident.split_once("::") {
+            Some((name, cast)) => {
+                self.parse_cast(cast, offset)?;
+                (name, true)
             }
-            self.skip_blank();
-            if let Some(tok) = self.peek_token() {
-                if tok == "and" || tok == "or" {
-                    expr.push(' ');
-                    expr.push_str(&tok);
-                    expr.push(' ');
-                    if tok == "and" {
-                        self.offset += 4;
-                    } else {
-                        self.offset += 3;
-                    }
-                    expr.push_str(&self.scan_expr()?);
+            None => (&*ident, false),
+        };
+        if name.contains('.') {
+            let option = self.joined_options.iter().find(|o| o.url_name_map.contains_key(name));
+            let (option, field) = match option {
+                Some(option) => (option.clone(), option.url_name_map[name].clone()),
+                None => return error(offset, format!("field `{}` is not allowed", name)),
+            };
+            let condition = self.parse_predicate(Self::column(&field, cast_text))?;
+            Ok(Expr::Joined {
+                inner_key: option.inner_key,
+                outer_key: option.outer_key,
+                outer_table: option.outer_table,
+                condition: Box::new(condition),
+            })
+        } else {
+            match self.fields.iter().find(|f| f.name == name) {
+                Some(field) => {
+                    let column = Self::column(field, cast_text);
+                    self.parse_predicate(column)
                 }
+                None => error(offset, format!("field `{}` is not allowed", name)),
             }
         }
-        Ok(expr)
     }
-
-    fn find_option(&self, url_name: &str) -> Option<&JoinedOption> {
-        for option in &self.joined_options {
-            if option.url_name_map.get(url_name).is_some() {
-                return Some(option);
-            }
+    fn column(field: &FilterField, cast_text: bool) -> Column {
+        Column {
+            name: field.name.clone(),
+            kind: if cast_text { FieldKind::Text } else { field.kind },
+            cast_text,
         }
-        None
-    }
-
-    fn scan_ident(&mut self) -> Result<String, String> {
-        self.skip_blank();
-        let mut ident = "".to_owned();
-        let mut ch = self.curr().unwrap();
-        while !ch.is_whitespace() && !SYMBOLS.contains(&ch) {
-            ident.push(ch);
-            if let Some(c) = self.next(false) {
-                ch = c;
-            } else {
-                break;
-            }
-        }
-        if ident.contains('.') {
-            match self.find_option(&ident) {
-                Some(_) => Ok(ident),
-                None => Err(format!(
-                    "ident is not allowed 0, offset:{}, raw: {}",
-                    self.offset, &self.raw
-                )),
-            }
-        } else if self.idents.contains(&ident) {
-            if let Some(':') = self.curr() {
-                if let Some(':') = self.peek() {
-                    self.next(false);
-                    ident.push_str("::");
-                    let mut ch = self.try_next(false)?;
-                    while !ch.is_whitespace() && !SYMBOLS.contains(&ch) {
-                        ident.push(ch);
-                        if let Some(c) = self.next(false) {
-                            ch = c;
-                        } else {
-                            break;
-                        }
+    }
+    /// Only casts to text are supported, `varchar` may have a length.
+    fn parse_cast(&mut self, cast: &str, offset: usize) -> Result<(), ParseError> {
+        match &*cast.to_ascii_lowercase() {
+            "text" => Ok(()),
+            "varchar" => {
+                if self.peek() == Some(&Token::LParen) {
+                    self.pos += 1;
+                    let offset = self.offset();
+                    match self.advance() {
+                        Some((_, Token::Word(len))) if len.parse::<u32>().is_ok() => {}
+                        _ => return error(offset, "expected the length of varchar"),
                     }
-                } else {
-                    return Err(format!(
-                        "':' is not allowed here, offset:{}, raw: {}, idents: {:#?}, joined_options: {:#?}, ident: {}",
-                        self.offset, &self.raw, &self.idents, &self.joined_options, &ident
-                    ));
+                    self.expect(Token::RParen)?;
                 }
+                Ok(())
             }
-            Ok(ident)
-        } else {
-            Err(format!(
-                "ident is not allowed 1, offset:{}, raw: {}, idents: {:#?}, joined_options: {:#?}, ident: {}",
-                self.offset, &self.raw, &self.idents, &self.joined_options, &ident
-            ))
+            _ => error(offset, format!("cast to `{}` is not supported", cast)),
         }
     }
-    fn scan_value(&mut self) -> Result<String, String> {
-        self.skip_blank();
-        let mut value = "".to_owned();
-        let mut ch = self.curr().unwrap();
-        if ch == '\'' || ch == 'E' {
-            if ch == 'E' {
-                value.push(ch);
-                ch = self.try_next(false)?;
-                if ch != '\'' {
-                    return Err("except ' after E".to_owned());
-                }
-            }
-            value.push(ch);
-            ch = self.try_next(false)?;
-            loop {
-                value.push(ch);
-                if ch == '\\' {
-                    value.push(self.try_next(false)?);
-                    ch = self.try_next(false)?;
-                } else if ch == '\'' {
-                    if let Some('\'') = self.peek() {
-                        value.push(self.try_next(false)?);
-                        ch = self.try_next(false)?;
-                    } else {
-                        self.next(false);
-                        break;
-                    }
-                } else {
-                    ch = self.try_next(false)?;
-                }
+
+    fn parse_predicate(&mut self, column: Column) -> Result<Expr, ParseError> {
+        let offset = self.offset();
+        let operator = match self.advance() {
+            Some((_, Token::Symbol(s))) => s,
+            Some((_, Token::Word(w))) => w.to_ascii_lowercase(),
+            Some((_, token)) => return error(offset, format!("expected an operator, found {}", token)),
+            None => return error(offset, "expected an operator, found the end"),
+        };
+        let (operator, negated) = if operator == "not" {
+            let offset = self.offset();
+            match self.advance() {
+                Some((_, Token::Word(w))) => (w.to_ascii_lowercase(), true),
+                _ => return error(offset, "expected `in`, `between`, `like` or `ilike` after `not`"),
             }
         } else {
-            while !ch.is_whitespace() && ch != ')' && ch != '(' {
-                value.push(ch);
-                if let Some(c) = self.next(false) {
-                    ch = c;
-                } else {
-                    break;
+            (operator, false)
+        };
+        let op = match (&*operator, negated) {
+            ("=" | "eq", false) => CompareOp::Eq,
+            ("!=" | "<>" | "neq", false) => CompareOp::Ne,
+            ("<" | "lt", false) => CompareOp::Lt,
+            (">" | "gt", false) => CompareOp::Gt,
+            ("<=" | "lte", false) => CompareOp::Le,
+            (">=" | "gte", false) => CompareOp::Ge,
+            ("like", false) => CompareOp::Like,
+            ("like", true) | ("nlike", false) => CompareOp::NotLike,
+            ("ilike", false) => CompareOp::ILike,
+            ("ilike", true) | ("nilike", false) => CompareOp::NotILike,
+            ("in", _) | ("nin", false) => {
+                self.expect(Token::LParen)?;
+                let mut values = vec![self.parse_value(&column)?];
+                while self.peek() == Some(&Token::Comma) {
+                    self.pos += 1;
+                    self.count_term(self.offset())?;
+                    values.push(self.parse_value(&column)?);
                 }
+                self.expect(Token::RParen)?;
+                return Ok(Expr::In {
+                    column,
+                    values,
+                    negated: negated || operator == "nin",
+                });
             }
-        }
-        Ok(value)
-    }
-
-    fn scan_operator(&mut self) -> Result<String, String> {
-        self.skip_blank();
-        let mut url_opt = "".to_owned();
-        let mut ch = self.curr().unwrap();
-        let is_symbol = SYMBOLS.contains(&ch);
-        if is_symbol {
-            while SYMBOLS.contains(&ch) {
-                url_opt.push(ch);
-                if let Some(c) = self.next(false) {
-                    ch = c;
-                } else {
-                    break;
+            ("between", _) => {
+                let low = self.parse_value(&column)?;
+                if !self.eat_keyword("and") {
+                    return error(self.offset(), "expected `and` in `between`");
                 }
+                let high = self.parse_value(&column)?;
+                return Ok(Expr::Between {
+                    column,
+                    low,
+                    high,
+                    negated,
+                });
             }
-        } else {
-            while !ch.is_whitespace() {
-                url_opt.push(ch);
-                if let Some(c) = self.next(false) {
-                    ch = c;
-                } else {
-                    break;
-                }
+            ("is" | "nis", false) => {
+                let negated = operator == "nis" || self.eat_keyword("not");
+                let offset = self.offset();
+                let value = match self.advance() {
+                    Some((_, Token::Word(w))) if w.eq_ignore_ascii_case("null") => IsValue::Null,
+                    Some((_, Token::Word(w))) if w.eq_ignore_ascii_case("true") && column.kind == FieldKind::Bool => {
+                        IsValue::True
+                    }
+                    Some((_, Token::Word(w))) if w.eq_ignore_ascii_case("false") && column.kind == FieldKind::Bool => {
+                        IsValue::False
+                    }
+                    _ => return error(offset, format!("expected `null` after `is` for field `{}`", column.name)),
+                };
+                return Ok(Expr::Is { column, value, negated });
             }
+            _ => return error(offset, format!("operator `{}` is not supported", operator)),
+        };
+        if op.is_like() && column.kind != FieldKind::Text {
+            return error(offset, format!("`{}` needs a text field, `{}` is not", operator, column.name));
+        }
+        let value = self.parse_value(&column)?;
+        Ok(Expr::Compare { column, op, value })
+    }
+
+    fn parse_value(&mut self, column: &Column) -> Result<Value, ParseError> {
+        let offset = self.offset();
+        let (text, quoted) = match self.advance() {
+            Some((_, Token::Word(w))) => (w, false),
+            Some((_, Token::Str(s))) => (s, true),
+            Some((_, token)) => return error(offset, format!("expected a value, found {}", token)),
+            None => return error(offset, "expected a value, found the end"),
+        };
+        if !quoted && text.eq_ignore_ascii_case("null") {
+            return error(offset, "compare with `is null` instead");
+        }
+        let value = match column.kind {
+            FieldKind::Text => Some(Value::Text(text.clone())),
+            FieldKind::Bool => match &*text.to_ascii_lowercase() {
+                "true" => Some(Value::Bool(true)),
+                "false" => Some(Value::Bool(false)),
+                _ => None,
+            },
+            FieldKind::Int => text.parse().ok().map(Value::Int),
+            FieldKind::BigInt => text.parse().ok().map(Value::BigInt),
+            FieldKind::Timestamptz => DateTime::parse_from_rfc3339(&text)
+                .map(|t| t.with_timezone(&Utc))
+                .ok()
+                .or_else(|| {
+                    NaiveDate::parse_from_str(&text, "%Y-%m-%d")
+                        .ok()
+                        .and_then(|d| d.and_hms_opt(0, 0, 0))
+                        .map(|t| t.and_utc())
+                })
+                .map(Value::Timestamptz),
+        };
+        match value {
+            Some(value) => Ok(value),
+            None => error(
+                offset,
+                format!("`{}` is not a valid {:?} value for field `{}`", text, column.kind, column.name),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::debug_query;
+
+    use super::*;
+
+    fn parser(raw: &str) -> Parser {
+        let fields = vec![
+            FilterField::new("kind", FieldKind::Text),
+            FilterField::new("is_read", FieldKind::Bool),
+            FilterField::new("count", FieldKind::Int),
+            FilterField::new("id", FieldKind::BigInt),
+            FilterField::new("created_at", FieldKind::Timestamptz),
+        ];
+        let options = vec![JoinedOption {
+            outer_table: "emails".into(),
+            outer_key: "user_id".into(),
+            inner_key: "id".into(),
+            url_name_map: HashMap::from([("e.value".to_owned(), FilterField::new("value", FieldKind::Text))]),
+        }];
+        Parser::new(raw.into(), fields, options)
+    }
+    fn parse(raw: &str) -> Result<Option<Expr>, ParseError> {
+        parser(raw).parse()
+    }
+    fn sql(raw: &str) -> String {
+        let expr = parse(raw).unwrap().unwrap();
+        debug_query::<Pg, _>(&expr).to_string()
+    }
+    fn parse_error(raw: &str) -> (usize, String) {
+        let e = parse(raw).unwrap_err();
+        (e.offset, e.message)
+    }
+
+    #[test]
+    fn tokenizes_with_offsets() {
+        let tokens = tokenize("count>=2 and(kind != 'a b',x)").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                (0, Token::Word("count".into())),
+                (5, Token::Symbol(">=".into())),
+                (7, Token::Word("2".into())),
+                (9, Token::Word("and".into())),
+                (12, Token::LParen),
+                (13, Token::Word("kind".into())),
+                (18, Token::Symbol("!=".into())),
+                (21, Token::Str("a b".into())),
+                (26, Token::Comma),
+                (27, Token::Word("x".into())),
+                (28, Token::RParen),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenizes_quoted_strings() {
+        let strings = |raw: &str| {
+            tokenize(raw)
+                .unwrap()
+                .into_iter()
+                .map(|(_, token)| token)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(strings("'it''s'"), vec![Token::Str("it's".into())]);
+        assert_eq!(strings(r"E'it\'s \\ a\_b'"), vec![Token::Str(r"it's \ a\_b".into())]);
+        // A backslash is only an escape in `E'..'`.
+        assert_eq!(strings(r"'a\_b'"), vec![Token::Str(r"a\_b".into())]);
+        assert_eq!(strings("''"), vec![Token::Str("".into())]);
+        assert_eq!(tokenize("kind = 'open").unwrap_err().offset, 7);
+        assert_eq!(tokenize(r"kind = E'open\'").unwrap_err().offset, 7);
+    }
+
+    #[test]
+    fn empty_filter_is_none() {
+        assert!(parse("").unwrap().is_none());
+        assert!(parse("   ").unwrap().is_none());
+    }
+
+    #[test]
+    fn binds_every_value() {
+        assert_eq!(
+            sql("kind = 'a' and count > 2"),
+            r#"("kind" = $1 AND "count" > $2) -- binds: ["a", 2]"#
+        );
+        assert_eq!(sql("kind::text like 'a%'"), r#"CAST("kind" AS text) LIKE $1 -- binds: ["a%"]"#);
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert_eq!(
+            sql("kind = a or kind = b and count = 1"),
+            r#"("kind" = $1 OR ("kind" = $2 AND "count" = $3)) -- binds: ["a", "b", 1]"#
+        );
+        assert_eq!(
+            sql("(kind = a or kind = b) and count = 1"),
+            r#"(("kind" = $1 OR "kind" = $2) AND "count" = $3) -- binds: ["a", "b", 1]"#
+        );
+        assert_eq!(
+            sql("not kind = a and count = 1"),
+            r#"(NOT ("kind" = $1) AND "count" = $2) -- binds: ["a", 1]"#
+        );
+        assert_eq!(
+            sql("kind = a AND is_read = false OR count = 1"),
+            r#"(("kind" = $1 AND "is_read" = $2) OR "count" = $3) -- binds: ["a", false, 1]"#
+        );
+    }
+
+    #[test]
+    fn parses_between_in_and_is() {
+        assert_eq!(
+            sql("count between 1 and 5 and id not between 2 and 3"),
+            r#"("count" BETWEEN $1 AND $2 AND "id" NOT BETWEEN $3 AND $4) -- binds: [1, 5, 2, 3]"#
+        );
+        assert_eq!(sql("count in (1, 2,3)"), r#""count" IN ($1, $2, $3) -- binds: [1, 2, 3]"#);
+        assert_eq!(sql("kind not in ('a')"), r#""kind" NOT IN ($1) -- binds: ["a"]"#);
+        assert_eq!(sql("kind nin (a, b)"), r#""kind" NOT IN ($1, $2) -- binds: ["a", "b"]"#);
+        assert_eq!(sql("kind is null"), r#""kind" IS NULL -- binds: []"#);
+        assert_eq!(sql("kind is not null"), r#""kind" IS NOT NULL -- binds: []"#);
+        assert_eq!(sql("is_read nis true"), r#""is_read" IS NOT TRUE -- binds: []"#);
+        assert_eq!(
+            sql("e.value ilike '%@example.com'"),
+            r#""id" IN (SELECT "user_id" FROM "emails" WHERE "value" ILIKE $1) -- binds: ["%@example.com"]"#
+        );
+    }
+
+    #[test]
+    fn types_values_by_field() {
+        let expr = parse("created_at >= 2023-04-01").unwrap().unwrap();
+        match expr {
+            Expr::Compare { value, .. } => assert_eq!(
+                value,
+                Value::Timestamptz(DateTime::parse_from_rfc3339("2023-04-01T00:00:00Z").unwrap().into())
+            ),
+            expr => panic!("unexpected {:?}", expr),
         }
-        OPERATORS.get(&&*url_opt).cloned().map(|s| s.to_owned()).ok_or(format!(
-            "operator is not correct, raw: {}, url_opt:{}, operators: {:#?}",
-            &self.raw, &url_opt, &*OPERATORS
-        ))
+        assert_eq!(
+            parse_error("count = abc"),
+            (8, "`abc` is not a valid Int value for field `count`".into())
+        );
+        assert_eq!(
+            parse_error("is_read = 1"),
+            (10, "`1` is not a valid Bool value for field `is_read`".into())
+        );
+        assert_eq!(
+            parse_error("id = 99999999999999999999"),
+            (5, "`99999999999999999999` is not a valid BigInt value for field `id`".into())
+        );
+        assert_eq!(parse_error("count like 1"), (6, "`like` needs a text field, `count` is not".into()));
+        assert_eq!(parse_error("kind is true"), (8, "expected `null` after `is` for field `kind`".into()));
+        assert_eq!(parse_error("kind = null"), (7, "compare with `is null` instead".into()));
+        // A quoted `null` is just text.
+        assert_eq!(sql("kind = 'null'"), r#""kind" = $1 -- binds: ["null"]"#);
+    }
+
+    #[test]
+    fn reports_offsets_in_chars() {
+        assert_eq!(parse_error("kind = 'é' and nope = 1"), (15, "field `nope` is not allowed".into()));
+        assert_eq!(parse_error("kind = a and"), (12, "expected a field, found the end".into()));
+        assert_eq!(parse_error("(kind = a"), (9, "expected `)`, found the end".into()));
+        assert_eq!(parse_error("kind = a)"), (8, "unexpected `)`".into()));
+        assert_eq!(parse_error("kind ~ a"), (5, "operator `~` is not supported".into()));
+        assert_eq!(parse_error("count between 1 or 2"), (16, "expected `and` in `between`".into()));
+        assert_eq!(parse_error("kind::int = 1"), (0, "cast to `int` is not supported".into()));
+        assert_eq!(parse_error("x.value = 1"), (0, "field `x.value` is not allowed".into()));
+    }
+
+    #[test]
+    fn limits_nesting_depth() {
+        let nested = |depth: usize| format!("{}kind = a{}", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            parse_error(&nested(MAX_DEPTH + 1)),
+            (MAX_DEPTH, format!("filter is nested deeper than {} levels", MAX_DEPTH))
+        );
+        let nots = format!("{}kind = a", "not ".repeat(MAX_DEPTH + 1));
+        assert_eq!(parse_error(&nots).0, MAX_DEPTH * 4);
+        // Deep input that would overflow the stack without the limit.
+        assert!(parse(&nested(100_000)).is_err());
+    }
+
+    #[test]
+    fn limits_term_count() {
+        let conditions = |count: usize| vec!["count = 1"; count].join(" or ");
+        assert!(parse(&conditions(MAX_TERMS)).is_ok());
+        let (offset, message) = parse_error(&conditions(MAX_TERMS + 1));
+        assert_eq!(offset, MAX_TERMS * "count = 1 or ".len());
+        assert_eq!(message, format!("filter has more than {} terms", MAX_TERMS));
+
+        let values = |count: usize| format!("count in ({})", vec!["1"; count].join(","));
+        assert!(parse(&values(MAX_TERMS)).is_ok());
+        assert!(parse(&values(MAX_TERMS + 1)).is_err());
     }
 }
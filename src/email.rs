@@ -1,31 +1,81 @@
-use handlebars::Handlebars;
+pub mod outbox;
+pub mod transport;
+
+use css_inline::CSSInliner;
+use diesel::PgConnection;
 use once_cell::sync::Lazy;
 use serde::Serialize;
 
+use crate::models::EmailOutbox;
+use crate::things::notification::TemplateContext;
 use crate::AppResult;
+pub use transport::MailTransport;
 
+const TEXT_WIDTH: usize = 78;
 
+static TRANSPORT: Lazy<Box<dyn MailTransport>> =
+    Lazy::new(|| transport::build_transport().expect("build mail transport failed"));
 
+#[derive(Serialize, Clone, Debug)]
+pub struct Mail {
+    pub recipients: Vec<String>,
+    pub subject: String,
+    pub body: String,
+    pub text_body: Option<String>,
+    pub template: Option<String>,
+}
 
-static HANDLEBARS: Lazy<Handlebars<'static>> = Lazy::new(|| {
-    let mut reg = Handlebars::new();
-    reg.register_template_file("layout", "conf/emails/layout.hbs").unwrap();
-    reg.register_template_file("security_code", "conf/emails/security_code.hbs")
-        .unwrap();
-    reg.register_template_file("verification", "conf/emails/verification.hbs")
-        .unwrap();
-    crate::helpers::handlebars::register_common_helpers(&mut reg);
-    reg
-});
-
-pub async fn send_email_with_tmpl<T>(recipients: Vec<String>, subject: &str, tpl_name: &str, data: T) -> AppResult<()>
+/// Render a mail exactly as it is sent: the HTML template with its `<style>` rules inlined, and the plain-text
+/// alternative from the `.txt` sibling template, or derived from the HTML when there is none.
+pub fn render_bodies<T>(name: &str, locale: &str, data: &T) -> AppResult<(String, String)>
 where
     T: Serialize,
 {
-    send_email(recipients, subject, HANDLEBARS.render(tpl_name, &data)?).await
+    let html = crate::templates::render_localized(name, locale, data)?;
+    let html = CSSInliner::options()
+        .load_remote_stylesheets(false)
+        .build()
+        .inline(&html)?;
+    let text = match crate::templates::resolve(name, locale, ".txt") {
+        Some(text_name) => crate::templates::render(&text_name, data)?,
+        None => html2text::from_read(html.as_bytes(), TEXT_WIDTH),
+    };
+    Ok((html, text))
+}
+
+/// Render the template and put the mail into the outbox, using the caller's connection so the mail is only
+/// queued when the surrounding transaction commits. Delivery happens in [`outbox::run_worker`].
+pub fn send_email_with_tmpl<T>(
+    recipients: Vec<String>,
+    subject: &str,
+    data: &T,
+    conn: &mut PgConnection,
+) -> AppResult<EmailOutbox>
+where
+    T: TemplateContext,
+{
+    let (body, text_body) = render_bodies(T::TEMPLATE, data.locale(), data)?;
+    let mail = Mail {
+        recipients,
+        subject: subject.into(),
+        body,
+        text_body: Some(text_body),
+        template: Some(T::TEMPLATE.into()),
+    };
+    outbox::enqueue(&mail, conn)
 }
 
-pub async fn send_email(recipients: Vec<String>, subject: &str, body: String) -> AppResult<()> {
-    // crate::aws::ses::send_email(recipients, subject, body).await
-    Ok(())
+pub async fn send_email(mail: Mail) -> AppResult<()> {
+    let transport = &*TRANSPORT;
+    let template = mail.template.as_deref().unwrap_or("-");
+    match transport.send(&mail).await {
+        Ok(()) => {
+            tracing::info!(transport = transport.name(), template = %template, recipients = ?mail.recipients, subject = %mail.subject, "email sent");
+            Ok(())
+        }
+        Err(e) => {
+            tracing::error!(error = ?e, transport = transport.name(), template = %template, recipients = ?mail.recipients, subject = %mail.subject, "send email failed");
+            Err(e)
+        }
+    }
 }
@@ -0,0 +1,171 @@
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+
+use super::Mail;
+use crate::db;
+use crate::models::*;
+use crate::schema::*;
+use crate::AppResult;
+
+pub const STATUS_PENDING: &str = "pending";
+pub const STATUS_SENDING: &str = "sending";
+pub const STATUS_SENT: &str = "sent";
+pub const STATUS_DEAD: &str = "dead";
+
+const BATCH_SIZE: i64 = 20;
+/// A mail stuck in `sending` this long is assumed to belong to a crashed worker.
+const SENDING_TIMEOUT_MINUTES: i64 = 10;
+
+impl From<&EmailOutbox> for Mail {
+    fn from(record: &EmailOutbox) -> Self {
+        Mail {
+            recipients: record.recipients.clone(),
+            subject: record.subject.clone(),
+            body: record.body.clone(),
+            text_body: record.text_body.clone(),
+            template: record.template.clone(),
+        }
+    }
+}
+
+pub fn enqueue(mail: &Mail, conn: &mut PgConnection) -> AppResult<EmailOutbox> {
+    let record = NewEmailOutbox {
+        recipients: &mail.recipients,
+        subject: &mail.subject,
+        body: &mail.body,
+        text_body: mail.text_body.as_deref(),
+        template: mail.template.as_deref(),
+        updated_by: None,
+        created_by: None,
+    };
+    let record = diesel::insert_into(email_outbox::table)
+        .values(&record)
+        .get_result::<EmailOutbox>(conn)?;
+    tracing::info!(outbox_id = record.id, template = ?record.template, recipients = ?record.recipients, "email enqueued");
+    Ok(record)
+}
+
+/// Put failed mails back into the queue so the worker picks them up on its next poll. Failed means dead, or
+/// pending a backoff after an error; a mail the worker is `sending` right now is left alone so it is not sent twice.
+pub fn retry(ids: &[i64], user_id: i64, conn: &mut PgConnection) -> AppResult<Vec<EmailOutbox>> {
+    let records = diesel::update(
+        email_outbox::table.filter(email_outbox::id.eq_any(ids)).filter(
+            email_outbox::status
+                .eq(STATUS_DEAD)
+                .or(email_outbox::status
+                    .eq(STATUS_PENDING)
+                    .and(email_outbox::last_error.is_not_null())),
+        ),
+    )
+    .set((
+        email_outbox::status.eq(STATUS_PENDING),
+        email_outbox::attempts.eq(0),
+        email_outbox::next_attempt_at.eq(Utc::now()),
+        email_outbox::updated_by.eq(user_id),
+        email_outbox::updated_at.eq(Utc::now()),
+    ))
+    .get_results::<EmailOutbox>(conn)?;
+    Ok(records)
+}
+
+/// Delay before the next attempt: 30 seconds doubled for every failed attempt, capped at 6 hours.
+pub fn backoff(attempts: i32) -> Duration {
+    let exp = (attempts - 1).clamp(0, 20) as u32;
+    Duration::seconds((30i64 << exp).min(6 * 3600))
+}
+
+pub async fn run_worker() {
+    tracing::info!("email outbox worker started");
+    let interval = std::time::Duration::from_secs(crate::email_outbox_poll_secs());
+    loop {
+        match process_due().await {
+            Ok(count) if count as i64 >= BATCH_SIZE => continue,
+            Ok(_) => {}
+            Err(e) => {
+                tracing::error!(error = ?e, "email outbox worker error");
+            }
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+async fn claim_due() -> AppResult<Vec<EmailOutbox>> {
+    db::run(|conn| {
+        conn.transaction::<_, crate::Error, _>(|conn| {
+            let now = Utc::now();
+            let ids = email_outbox::table
+                .filter(
+                    email_outbox::status
+                        .eq(STATUS_PENDING)
+                        .and(email_outbox::next_attempt_at.le(now))
+                        .or(email_outbox::status
+                            .eq(STATUS_SENDING)
+                            .and(email_outbox::updated_at.lt(now - Duration::minutes(SENDING_TIMEOUT_MINUTES)))),
+                )
+                .order(email_outbox::next_attempt_at.asc())
+                .limit(BATCH_SIZE)
+                .select(email_outbox::id)
+                .for_update()
+                .skip_locked()
+                .load::<i64>(conn)?;
+            if ids.is_empty() {
+                return Ok(vec![]);
+            }
+            let records = diesel::update(email_outbox::table.filter(email_outbox::id.eq_any(&ids)))
+                .set((email_outbox::status.eq(STATUS_SENDING), email_outbox::updated_at.eq(now)))
+                .get_results::<EmailOutbox>(conn)?;
+            Ok(records)
+        })
+    })
+    .await
+}
+
+async fn process_due() -> AppResult<usize> {
+    let records = claim_due().await?;
+    let count = records.len();
+    let max_attempts = crate::email_outbox_max_attempts();
+    for record in records {
+        let result = super::send_email(Mail::from(&record)).await.map_err(|e| e.to_string());
+        let attempts = record.attempts + 1;
+        db::run(move |conn| {
+            match result {
+                Ok(()) => {
+                    diesel::update(&record)
+                        .set((
+                            email_outbox::status.eq(STATUS_SENT),
+                            email_outbox::attempts.eq(attempts),
+                            email_outbox::last_error.eq(None::<String>),
+                            email_outbox::sent_at.eq(Utc::now()),
+                            email_outbox::updated_at.eq(Utc::now()),
+                        ))
+                        .execute(conn)?;
+                }
+                Err(e) if attempts >= max_attempts => {
+                    tracing::error!(outbox_id = record.id, attempts, "email moved to dead letter");
+                    diesel::update(&record)
+                        .set((
+                            email_outbox::status.eq(STATUS_DEAD),
+                            email_outbox::attempts.eq(attempts),
+                            email_outbox::last_error.eq(e),
+                            email_outbox::updated_at.eq(Utc::now()),
+                        ))
+                        .execute(conn)?;
+                }
+                Err(e) => {
+                    diesel::update(&record)
+                        .set((
+                            email_outbox::status.eq(STATUS_PENDING),
+                            email_outbox::attempts.eq(attempts),
+                            email_outbox::last_error.eq(e),
+                            email_outbox::next_attempt_at.eq(Utc::now() + backoff(attempts)),
+                            email_outbox::updated_at.eq(Utc::now()),
+                        ))
+                        .execute(conn)?;
+                }
+            }
+            Ok(())
+        })
+        .await?;
+    }
+    Ok(count)
+}
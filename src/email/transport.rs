@@ -0,0 +1,111 @@
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use async_trait::async_trait;
+use lettre::message::header::ContentType;
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use once_cell::sync::Lazy;
+
+use super::Mail;
+use crate::AppResult;
+
+#[async_trait]
+pub trait MailTransport: Send + Sync {
+    fn name(&self) -> &'static str;
+    async fn send(&self, mail: &Mail) -> AppResult<()>;
+}
+
+pub fn build_message(mail: &Mail) -> AppResult<Message> {
+    let mut builder = Message::builder()
+        .from(crate::mail_from().parse::<Mailbox>()?)
+        .subject(&mail.subject);
+    for recipient in &mail.recipients {
+        builder = builder.to(recipient.parse::<Mailbox>()?);
+    }
+    match &mail.text_body {
+        Some(text) => Ok(builder.multipart(MultiPart::alternative_plain_html(text.clone(), mail.body.clone()))?),
+        None => Ok(builder.header(ContentType::TEXT_HTML).body(mail.body.clone())?),
+    }
+}
+
+/// Select the transport by `MAIL_TRANSPORT` (`smtp`, `file` or `memory`).
+pub fn build_transport() -> AppResult<Box<dyn MailTransport>> {
+    let kind = crate::mail_transport();
+    match &*kind {
+        "smtp" => Ok(Box::new(SmtpTransport::from_env()?)),
+        "file" => Ok(Box::new(FileTransport::new(crate::mail_file_dir()))),
+        "memory" => Ok(Box::new(MemoryTransport)),
+        _ => Err(crate::Error::Internal(format!("unknown mail transport: {}", kind))),
+    }
+}
+
+pub struct SmtpTransport {
+    inner: AsyncSmtpTransport<Tokio1Executor>,
+}
+impl SmtpTransport {
+    pub fn from_env() -> AppResult<SmtpTransport> {
+        let mut builder = AsyncSmtpTransport::<Tokio1Executor>::relay(&crate::smtp_host())?.port(crate::smtp_port());
+        let username = crate::smtp_username();
+        if !username.is_empty() {
+            builder = builder.credentials(Credentials::new(username, crate::smtp_password()));
+        }
+        Ok(SmtpTransport { inner: builder.build() })
+    }
+}
+#[async_trait]
+impl MailTransport for SmtpTransport {
+    fn name(&self) -> &'static str {
+        "smtp"
+    }
+    async fn send(&self, mail: &Mail) -> AppResult<()> {
+        self.inner.send(build_message(mail)?).await?;
+        Ok(())
+    }
+}
+
+/// Writes every mail as an `.eml` file into a directory, useful in development.
+pub struct FileTransport {
+    dir: PathBuf,
+}
+impl FileTransport {
+    pub fn new(dir: impl Into<PathBuf>) -> FileTransport {
+        FileTransport { dir: dir.into() }
+    }
+}
+#[async_trait]
+impl MailTransport for FileTransport {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+    async fn send(&self, mail: &Mail) -> AppResult<()> {
+        let message = build_message(mail)?;
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let path = self.dir.join(format!("{}.eml", crate::utils::uuid_string()));
+        tokio::fs::write(&path, message.formatted()).await?;
+        Ok(())
+    }
+}
+
+static MEMORY_MAILS: Lazy<Mutex<Vec<Mail>>> = Lazy::new(|| Mutex::new(vec![]));
+
+/// Mails captured by [`MemoryTransport`], for tests to inspect.
+#[allow(dead_code)]
+pub fn captured_mails() -> Vec<Mail> {
+    MEMORY_MAILS.lock().unwrap().clone()
+}
+
+/// Keeps sent mails in memory instead of delivering them.
+pub struct MemoryTransport;
+#[async_trait]
+impl MailTransport for MemoryTransport {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+    async fn send(&self, mail: &Mail) -> AppResult<()> {
+        build_message(mail)?;
+        MEMORY_MAILS.lock().unwrap().push(mail.clone());
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 use crate::{ErrorWrap, StatusInfo};
 use async_trait::async_trait;
+use salvo::http::header::RETRY_AFTER;
 use salvo::http::{StatusCode, StatusError};
 use salvo::prelude::{Depot, Json, Request, Response, Writer};
 use std::borrow::Cow;
@@ -25,8 +26,8 @@ pub enum Error {
     FromUtf8(#[from] FromUtf8Error),
     #[error("decoding: `{0}`")]
     Decoding(Cow<'static, str>),
-    // #[error("url parse: `{0}`")]
-    // UrlParse(#[from] url::ParseError),
+    #[error("url parse: `{0}`")]
+    UrlParse(#[from] url::ParseError),
     #[error("serde json: `{0}`")]
     SerdeJson(#[from] serde_json::error::Error),
     #[error("diesel: `{0}`")]
@@ -41,16 +42,34 @@ pub enum Error {
     HttpParse(#[from] salvo::http::ParseError),
     // #[error("pulsar: `{0}`")]
     // Pulsar(#[from] ::pulsar::Error),
-    // #[error("reqwest: `{0}`")]
-    // Reqwest(#[from] reqwest::Error),
+    #[error("reqwest: `{0}`")]
+    Reqwest(#[from] reqwest::Error),
     #[error("r2d2: `{0}`")]
     R2d2(#[from] diesel::r2d2::PoolError),
+    #[error("join: `{0}`")]
+    Join(#[from] tokio::task::JoinError),
     #[error("handlebars render: `{0}`")]
     HandlebarsRender(#[from] handlebars::RenderError),
     // #[error("stripe: `{0}`")]
     // Stripe(#[from] stripe::StripeError),
     // #[error("stripe ParseIdError: `{0}`")]
     // ParseIdError(#[from] stripe::ParseIdError),
+    #[error("lettre: `{0}`")]
+    Lettre(#[from] lettre::error::Error),
+    #[error("smtp: `{0}`")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("mail address: `{0}`")]
+    MailAddress(#[from] lettre::address::AddressError),
+    #[error("css inline: `{0}`")]
+    CssInline(#[from] css_inline::InlineError),
+    #[error("jwt: `{0}`")]
+    Jwt(#[from] jsonwebtoken::errors::Error),
+    #[error("openssl: `{0}`")]
+    Openssl(#[from] openssl::error::ErrorStack),
+    #[error("oidc: `{0}`")]
+    Oidc(String),
+    #[error("webauthn: `{0}`")]
+    Webauthn(#[from] webauthn_rs::prelude::WebauthnError),
     #[error("utf8: `{0}`")]
     Utf8Error(#[from] std::str::Utf8Error),
     // #[error("redis: `{0}`")]
@@ -70,6 +89,8 @@ impl Writer for Error {
     async fn write(mut self, _req: &mut Request, depot: &mut Depot, res: &mut Response) {
         let code = match &self {
             Error::HttpStatus(e) => e.code,
+            Error::R2d2(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Oidc(_) => StatusCode::BAD_REQUEST,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
         res.set_status_code(code);
@@ -126,6 +147,16 @@ impl Writer for Error {
                     details: None,
                 },
             },
+            // A bad code or ID token from the client or the provider.
+            Error::Oidc(_) => ErrorWrap {
+                error: StatusInfo {
+                    code: StatusCode::BAD_REQUEST.as_u16(),
+                    name: "oidc_failed".into(),
+                    summary: "oidc failed".into(),
+                    detail: Some("The identity provider could not confirm this sign-in, please start again.".into()),
+                    details: None,
+                },
+            },
             Error::Diesel(e) => {
                 tracing::error!(error = ?e, "diesel db error");
                 let info = if let diesel::result::Error::NotFound = e {
@@ -148,6 +179,19 @@ impl Writer for Error {
                 };
                 ErrorWrap { error: info }
             }
+            // Only returned when no connection frees up within `DATABASE_ACQUIRE_TIMEOUT_SECS`.
+            Error::R2d2(_) => {
+                res.add_header(RETRY_AFTER, crate::database_acquire_timeout_secs(), true).ok();
+                ErrorWrap {
+                    error: StatusInfo {
+                        code: StatusCode::SERVICE_UNAVAILABLE.as_u16(),
+                        name: "SERVICE_UNAVAILABLE".into(),
+                        summary: "database is busy, please try again later".into(),
+                        detail: None,
+                        details: None,
+                    },
+                }
+            }
             Error::HttpStatus(e) => {
                 let StatusError {
                     code,
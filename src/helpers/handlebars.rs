@@ -1,11 +1,76 @@
 use bigdecimal::BigDecimal;
 use chrono::{DateTime, Utc};
-use handlebars::{handlebars_helper, Handlebars};
+use chrono_tz::Tz;
+use handlebars::{
+    handlebars_helper, html_escape, Context, Handlebars, Helper, HelperResult, Output, RenderContext, RenderError,
+};
+use serde_json::Value;
 
+use crate::i18n;
 
 pub fn register_common_helpers(handlebars: &mut Handlebars<'_>) {
     handlebars_helper!(format_money: |v: BigDecimal| v.with_scale(2).to_string());
-    handlebars_helper!(format_datetime: |v: str, f: str| v.parse::<DateTime<Utc>>().unwrap().format(f).to_string());
     handlebars.register_helper("format_datetime", Box::new(format_datetime));
     handlebars.register_helper("format_money", Box::new(format_money));
+    handlebars.register_helper("t", Box::new(translate));
+}
+
+/// Read a setting from the `name=` hash argument, then from the root context, then from `recipient`.
+fn context_value<'a>(h: &'a Helper, ctx: &'a Context, name: &str) -> Option<&'a str> {
+    h.hash_get(name)
+        .and_then(|v| v.value().as_str())
+        .or_else(|| ctx.data().get(name).and_then(Value::as_str))
+        .or_else(|| ctx.data().get("recipient").and_then(|r| r.get(name)).and_then(Value::as_str))
+}
+
+/// `{{t "emails.security_code.intro" name=recipient.display_name}}` renders the message for the locale of the
+/// mail, with every other hash argument available as a `{name}` placeholder.
+fn translate(h: &Helper, _: &Handlebars, ctx: &Context, _: &mut RenderContext, out: &mut dyn Output) -> HelperResult {
+    let key = h
+        .param(0)
+        .and_then(|v| v.value().as_str())
+        .ok_or_else(|| RenderError::new("t: message key is required"))?;
+    let locale = context_value(h, ctx, "locale").unwrap_or(i18n::DEFAULT_LOCALE);
+    let args = h
+        .hash()
+        .iter()
+        .filter(|(name, _)| **name != "locale")
+        .map(|(name, v)| {
+            let value = match v.value() {
+                Value::String(s) => s.clone(),
+                value => value.to_string(),
+            };
+            (*name, html_escape(&value))
+        })
+        .collect::<Vec<_>>();
+    let args = args.iter().map(|(name, value)| (*name, value.as_str())).collect::<Vec<_>>();
+    out.write(&i18n::translate_with(locale, key, &args))?;
+    Ok(())
+}
+
+/// `{{format_datetime value "%Y-%m-%d %H:%M"}}` renders in the recipient's timezone, or `tz=` when given.
+fn format_datetime(
+    h: &Helper,
+    _: &Handlebars,
+    ctx: &Context,
+    _: &mut RenderContext,
+    out: &mut dyn Output,
+) -> HelperResult {
+    let value = h
+        .param(0)
+        .and_then(|v| v.value().as_str())
+        .ok_or_else(|| RenderError::new("format_datetime: datetime is required"))?;
+    let format = h
+        .param(1)
+        .and_then(|v| v.value().as_str())
+        .ok_or_else(|| RenderError::new("format_datetime: format is required"))?;
+    let value = value
+        .parse::<DateTime<Utc>>()
+        .map_err(|e| RenderError::new(format!("format_datetime: {}", e)))?;
+    let tz = context_value(h, ctx, "tz")
+        .or_else(|| context_value(h, ctx, "timezone"))
+        .and_then(|tz| tz.parse::<Tz>().ok())
+        .unwrap_or(Tz::UTC);
+    out.write(&value.with_timezone(&tz).format(format).to_string())?;
+    Ok(())
 }
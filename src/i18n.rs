@@ -0,0 +1,108 @@
+use std::collections::HashMap;
+use std::fs;
+
+use once_cell::sync::Lazy;
+use serde_json::Value;
+
+pub const DEFAULT_LOCALE: &str = "en";
+static LOCALES_DIR: &str = "conf/locales";
+
+/// Message catalogs loaded from `conf/locales/<locale>.json`. Nested objects are flattened into dotted keys,
+/// so `{"emails": {"security_code": {"subject": "..."}}}` is looked up as `emails.security_code.subject`.
+static CATALOGS: Lazy<HashMap<String, HashMap<String, String>>> = Lazy::new(|| {
+    let mut catalogs = HashMap::new();
+    let entries = match fs::read_dir(LOCALES_DIR) {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::error!(error = ?e, dir = %LOCALES_DIR, "read locales directory failed");
+            return catalogs;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("json") {
+            continue;
+        }
+        let locale = match path.file_stem().and_then(|s| s.to_str()) {
+            Some(locale) => locale.to_owned(),
+            None => continue,
+        };
+        match fs::read(&path).map_err(crate::Error::from).and_then(|data| Ok(serde_json::from_slice::<Value>(&data)?)) {
+            Ok(value) => {
+                let mut messages = HashMap::new();
+                flatten("", &value, &mut messages);
+                catalogs.insert(locale, messages);
+            }
+            Err(e) => tracing::error!(error = ?e, path = ?path, "load locale catalog failed"),
+        }
+    }
+    catalogs
+});
+
+fn flatten(prefix: &str, value: &Value, messages: &mut HashMap<String, String>) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map {
+                let key = if prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{}", prefix, key)
+                };
+                flatten(&key, value, messages);
+            }
+        }
+        Value::String(text) => {
+            messages.insert(prefix.to_owned(), text.clone());
+        }
+        _ => {
+            messages.insert(prefix.to_owned(), value.to_string());
+        }
+    }
+}
+
+/// Locales to try in order: `zh-CN` falls back to `zh`, then to [`DEFAULT_LOCALE`].
+pub fn fallback_chain(locale: &str) -> Vec<&str> {
+    let mut chain = vec![];
+    if !locale.is_empty() {
+        chain.push(locale);
+        if let Some((language, _)) = locale.split_once('-') {
+            chain.push(language);
+        }
+    }
+    if !chain.contains(&DEFAULT_LOCALE) {
+        chain.push(DEFAULT_LOCALE);
+    }
+    chain
+}
+
+/// Look up `key` for `locale` and replace `{name}` placeholders with `args`. Returns the key itself when no
+/// catalog has it, so a missing translation is visible without breaking the mail.
+pub fn translate_with(locale: &str, key: &str, args: &[(&str, &str)]) -> String {
+    let mut text = match lookup(locale, key) {
+        Some(text) => text.clone(),
+        None => {
+            tracing::warn!(locale = %locale, key = %key, "translation not found");
+            return key.to_owned();
+        }
+    };
+    for (name, value) in args {
+        text = text.replace(&format!("{{{}}}", name), value);
+    }
+    text
+}
+
+pub fn translate(locale: &str, key: &str) -> String {
+    translate_with(locale, key, &[])
+}
+
+/// Like `translate`, for messages that have their English text in code: `default` is used when no catalog has
+/// the key.
+pub fn translate_or(locale: &str, key: &str, default: &str) -> String {
+    lookup(locale, key).cloned().unwrap_or_else(|| default.to_owned())
+}
+
+fn lookup(locale: &str, key: &str) -> Option<&'static String> {
+    fallback_chain(locale)
+        .into_iter()
+        .find_map(|locale| CATALOGS.get(locale).and_then(|messages| messages.get(key)))
+}
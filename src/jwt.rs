@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use jsonwebtoken::{self as jwt, Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation};
+use once_cell::sync::OnceCell;
+use openssl::pkey::{Id, PKey, Private, Public};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{AppResult, Error};
+
+static KEYRING: OnceCell<Keyring> = OnceCell::new();
+
+/// Keys used to sign and verify session JWTs, loaded from `JWT_KEYS_DIR`:
+///
+/// * `<kid>.pem` is an RSA (RS256) or Ed25519 (EdDSA) private key, usable for signing and verification.
+/// * `<kid>.pub.pem` is a public key, only used for verification. Keep a retired key around this way until the
+///   tokens it signed have expired.
+///
+/// `JWT_SIGNING_KID` selects the signing key, otherwise the last private key in file name order is used, so a
+/// rotation is: add the new key, restart, remove the old private key once all services picked up the new JWKS.
+/// Without any private key, tokens are signed with HS256 and `SECRET_KEY` as before. Once a private key is in
+/// use, HS256 tokens without `kid` are only accepted while `JWT_ACCEPT_LEGACY_HS256` is on, so existing sessions
+/// can survive the switch; setting `JWT_SIGNING_KID` always ends that, retiring the shared secret. OAuth clients
+/// can not verify HS256, so ID tokens are only issued once a private key is in use.
+pub struct Keyring {
+    signing: Option<SigningKey>,
+    verification: HashMap<String, VerificationKey>,
+    accept_legacy: bool,
+}
+
+struct SigningKey {
+    kid: String,
+    algorithm: Algorithm,
+    key: EncodingKey,
+}
+
+struct VerificationKey {
+    algorithm: Algorithm,
+    key: DecodingKey,
+    jwk: Value,
+}
+
+pub fn keyring() -> AppResult<&'static Keyring> {
+    KEYRING.get_or_try_init(Keyring::load)
+}
+
+impl Keyring {
+    fn load() -> AppResult<Keyring> {
+        let mut keyring = Keyring {
+            signing: None,
+            verification: HashMap::new(),
+            accept_legacy: true,
+        };
+        let dir = crate::jwt_keys_dir();
+        let dir = Path::new(&dir);
+        if !dir.is_dir() {
+            tracing::warn!(dir = ?dir, "jwt keys directory not found, signing with SECRET_KEY");
+            return Ok(keyring);
+        }
+        let mut paths = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()?;
+        paths.sort();
+        let mut signing_keys = vec![];
+        for path in paths {
+            let file_name = match path.file_name().and_then(|name| name.to_str()) {
+                Some(file_name) => file_name,
+                None => continue,
+            };
+            if let Some(kid) = file_name.strip_suffix(".pub.pem") {
+                let key = PKey::public_key_from_pem(&fs::read(&path)?)?;
+                keyring.verification.insert(kid.to_owned(), verification_key(kid, &key)?);
+            } else if let Some(kid) = file_name.strip_suffix(".pem") {
+                let pem = fs::read(&path)?;
+                let key = PKey::private_key_from_pem(&pem)?;
+                let public = PKey::public_key_from_pem(&key.public_key_to_pem()?)?;
+                let verification = verification_key(kid, &public)?;
+                signing_keys.push(signing_key(kid, &key, verification.algorithm)?);
+                keyring.verification.insert(kid.to_owned(), verification);
+            }
+        }
+        let signing_kid = crate::jwt_signing_kid();
+        keyring.accept_legacy = signing_kid.is_none() && (signing_keys.is_empty() || crate::jwt_accept_legacy_hs256());
+        keyring.signing = match signing_kid {
+            Some(kid) => Some(
+                signing_keys
+                    .into_iter()
+                    .find(|key| key.kid == kid)
+                    .ok_or_else(|| Error::Internal(format!("jwt signing key `{}` not found", kid)))?,
+            ),
+            None => signing_keys.pop(),
+        };
+        if keyring.signing.is_none() {
+            tracing::warn!(dir = ?dir, "no jwt private key found, signing with SECRET_KEY and not issuing ID tokens");
+        }
+        tracing::info!(
+            signing_kid = ?keyring.signing.as_ref().map(|key| &key.kid),
+            verification_kids = ?keyring.verification.keys().collect::<Vec<_>>(),
+            accept_legacy = keyring.accept_legacy,
+            "jwt keys loaded"
+        );
+        Ok(keyring)
+    }
+
+    pub fn encode<T: Serialize>(&self, claims: &T) -> AppResult<String> {
+        if self.signing.is_none() {
+            return Ok(jwt::encode(
+                &Header::default(),
+                claims,
+                &EncodingKey::from_secret(crate::secret_key().as_ref()),
+            )?);
+        }
+        self.encode_asymmetric(claims)
+    }
+
+    /// Like `encode`, but fails instead of falling back to HS256, for tokens verified outside this service.
+    pub fn encode_asymmetric<T: Serialize>(&self, claims: &T) -> AppResult<String> {
+        let signing = self
+            .signing
+            .as_ref()
+            .ok_or_else(|| Error::Internal("no asymmetric jwt signing key is configured".into()))?;
+        let mut header = Header::new(signing.algorithm);
+        header.kid = Some(signing.kid.clone());
+        Ok(jwt::encode(&header, claims, &signing.key)?)
+    }
+
+    /// Algorithm of the private signing key, `None` while signing with HS256.
+    pub fn signing_algorithm(&self) -> Option<Algorithm> {
+        self.signing.as_ref().map(|signing| signing.algorithm)
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, token: &str) -> AppResult<TokenData<T>> {
+        let header = jwt::decode_header(token)?;
+        let data = match &header.kid {
+            Some(kid) => {
+                let key = self
+                    .verification
+                    .get(kid)
+                    .ok_or_else(|| Error::Internal(format!("unknown jwt kid `{}`", kid)))?;
+                jwt::decode::<T>(token, &key.key, &Validation::new(key.algorithm))?
+            }
+            None if !self.accept_legacy => {
+                return Err(Error::Internal("jwt without kid is no longer accepted".into()));
+            }
+            None => jwt::decode::<T>(
+                token,
+                &DecodingKey::from_secret(crate::secret_key().as_ref()),
+                &Validation::new(Algorithm::HS256),
+            )?,
+        };
+        Ok(data)
+    }
+
+    /// Public verification keys as a JWK set, for `/.well-known/jwks.json`.
+    pub fn jwks(&self) -> Value {
+        let mut kids = self.verification.keys().collect::<Vec<_>>();
+        kids.sort();
+        json!({ "keys": kids.into_iter().map(|kid| &self.verification[kid].jwk).collect::<Vec<_>>() })
+    }
+}
+
+fn signing_key(kid: &str, key: &PKey<Private>, algorithm: Algorithm) -> AppResult<SigningKey> {
+    let pem = key.private_key_to_pem_pkcs8()?;
+    let key = match algorithm {
+        Algorithm::EdDSA => EncodingKey::from_ed_pem(&pem)?,
+        _ => EncodingKey::from_rsa_pem(&pem)?,
+    };
+    Ok(SigningKey {
+        kid: kid.to_owned(),
+        algorithm,
+        key,
+    })
+}
+
+fn verification_key(kid: &str, key: &PKey<Public>) -> AppResult<VerificationKey> {
+    let pem = key.public_key_to_pem()?;
+    match key.id() {
+        Id::RSA => {
+            let rsa = key.rsa()?;
+            Ok(VerificationKey {
+                algorithm: Algorithm::RS256,
+                key: DecodingKey::from_rsa_pem(&pem)?,
+                jwk: json!({
+                    "kty": "RSA",
+                    "use": "sig",
+                    "alg": "RS256",
+                    "kid": kid,
+                    "n": base64_url(&rsa.n().to_vec()),
+                    "e": base64_url(&rsa.e().to_vec()),
+                }),
+            })
+        }
+        Id::ED25519 => Ok(VerificationKey {
+            algorithm: Algorithm::EdDSA,
+            key: DecodingKey::from_ed_pem(&pem)?,
+            jwk: json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "use": "sig",
+                "alg": "EdDSA",
+                "kid": kid,
+                "x": base64_url(&key.raw_public_key()?),
+            }),
+        }),
+        id => Err(Error::Internal(format!("unsupported jwt key type {:?} for `{}`", id, kid))),
+    }
+}
+
+fn base64_url(bytes: &[u8]) -> String {
+    openssl::base64::encode_block(bytes)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
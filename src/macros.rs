@@ -44,13 +44,16 @@ macro_rules! get_record {
     };
 }
 
+/// Like `get_record!`, but renders access denied unless the model's `Policy` permits `$user` to do `$action`.
 #[macro_export]
 macro_rules! get_permitted_record {
     ($user:expr, $action:expr, $res:expr, $id:expr, $model:ty, $edb:path, $conn:expr) => {{
-        use $edb as edb; //https://github.com/rust-lang/rust/issues/48067
-        edb::table
-            .filter(edb::id.eq($id))
-            .get_result::<$model>($conn)?
+        use $crate::things::permission::Policy;
+        let record = get_record!($res, $id, $model, $edb, $conn);
+        if !record.is_permitted($user, $action, $conn)? {
+            return $crate::context::render_access_denied_json($res);
+        }
+        record
     }};
 }
 #[macro_export]
@@ -66,11 +69,10 @@ macro_rules! get_permitted_record_by_param {
 }
 #[macro_export]
 macro_rules! get_permitted_record_by_query {
-    ($req:expr, $res:expr, $model:ty, $edb:path, $query:expr, $conn:expr) => {{
+    ($user:expr, $action:expr, $req:expr, $res:expr, $model:ty, $edb:path, $query:expr, $conn:expr) => {{
         use diesel::prelude::*;
-        use $edb as edb;
         let id = get_id_query!($req, $res, $query);
-        get_permitted_record!($res, id, $conn, $edb, $model)
+        get_permitted_record!($user, $action, $res, id, $model, $edb, $conn)
     }};
 }
 
@@ -103,6 +105,14 @@ macro_rules! current_user {
         cuser.unwrap()
     }};
 }
+#[macro_export]
+macro_rules! require_scope {
+    ($depot:expr, $res:expr, $scope:expr) => {{
+        if !$crate::context::has_scope($depot, $scope) {
+            return $crate::context::render_insufficient_scope_json($res, $scope);
+        }
+    }};
+}
 
 #[macro_export]
 macro_rules! parse_posted_data {
@@ -121,14 +131,24 @@ macro_rules! parse_posted_data {
 macro_rules! show_record {
     ($req:expr, $depot:expr, $res:expr, $model:ty, $edb:path, $qid:expr, $conn:expr) => {
         // println!("===open db conn in show_record");
-        let record = get_record_by_param!($req, $res, $model, $edb, $conn);
         let cuser = current_user!($depot, $res);
+        let record = get_permitted_record_by_param!(
+            cuser,
+            $crate::things::permission::ACTION_VIEW,
+            $req,
+            $res,
+            $model,
+            $edb,
+            $qid,
+            $conn
+        );
         $res.render(Json(record));
     };
     ($req:expr, $depot:expr, $res:expr, $model:ty, $edb:path, $conn:expr) => {
         show_record!($req, $depot, $res, $model, $edb, "id", $conn);
     };
     ($req:expr, $depot:expr, $res:expr, $model:ty, $edb:path, $dep_edb:path, $dep_model:ty, $cfield:ident, $action:expr, $conn:expr) => {
+        use $crate::things::permission::Policy;
         use $dep_edb as dep_edb;
         // println!("===open db conn in show_record2");
         let record = get_record_by_param!($req, $res, $model, $edb, $conn);
@@ -138,18 +158,29 @@ macro_rules! show_record {
             return $crate::context::render_not_found_json($res);
         }
         let dep = dep.unwrap();
+        if !dep.is_permitted(cuser, $action, $conn)? {
+            return $crate::context::render_access_denied_json($res);
+        }
         $res.render(Json(record));
     };
 }
 
 #[macro_export]
 macro_rules! list_records {
-    ($req:expr, $res:expr, $model:ty, $query:expr, $default_sort:expr, $filter_fields:expr, $joined_options:expr, $search_tmpl:expr, $conn:expr) => {{
+    ($req:expr, $depot:expr, $res:expr, $model:ty, $query:expr, $default_sort:expr, $filter_fields:expr, $joined_options:expr, $search_tmpl:expr, $conn:expr) => {{
+        use diesel::prelude::*;
+        let cuser = current_user!($depot, $res);
+        let permit = <$model as $crate::things::permission::Policy>::permit_filter(
+            cuser,
+            $crate::things::permission::ACTION_VIEW,
+            $conn,
+        )?;
+        let query = $query.filter(permit);
         let data = query_pagation_data!(
             $req,
             $res,
             $model,
-            $query,
+            query,
             $default_sort,
             $filter_fields,
             $joined_options,
@@ -179,12 +210,21 @@ macro_rules! list_records {
 macro_rules! delete_record {
     ($req:expr, $depot:expr, $res:expr, $edb:path, $model:ty, $del:expr, $conn:expr) => {{
         let cuser = current_user!($depot, $res);
-        let record = get_record_by_param!($req, $res, $model, $edb, $conn);
+        let record = get_permitted_record_by_param!(
+            cuser,
+            $crate::things::permission::ACTION_DELETE,
+            $req,
+            $res,
+            $model,
+            $edb,
+            $conn
+        );
         $del(record.id, $conn)?;
         $crate::context::render_done_json($res).ok();
         record
     }};
     ($req:expr, $depot:expr, $res:expr, $edb:path, $model:ty, $del:expr, $dep_edb:path, $dep_model:ty, $cfield:ident, $action:expr, $conn:expr) => {{
+        use $crate::things::permission::Policy;
         use $dep_edb as dep_edb;
         let cuser = current_user!($depot, $res);
         let record = get_record_by_param!($req, $res, $model, $edb, $conn);
@@ -193,6 +233,9 @@ macro_rules! delete_record {
             return $crate::context::render_not_found_json($res);
         }
         let dep = dep.unwrap();
+        if !dep.is_permitted(cuser, $action, $conn)? {
+            return $crate::context::render_access_denied_json($res);
+        }
         $del(record.id, $conn)?;
         $crate::context::render_done_json($res).ok();
         record
@@ -200,17 +243,29 @@ macro_rules! delete_record {
 }
 #[macro_export]
 macro_rules! bulk_delete_records {
-    ($req:expr, $depot:expr, $res:expr, $edb:path, $model:ty, $del:expr, $conn:expr) => {{
+    ($ids:expr, $depot:expr, $res:expr, $edb:path, $model:ty, $del:expr, $conn:expr) => {{
         use $edb as edb;
-        let ids = $crate::context::parse_ids_from_request($req, "id", "ids").await;
+        let ids = $ids;
         let cuser = current_user!($depot, $res);
 
         let records = edb::table.filter(edb::id.eq_any(&ids)).get_results::<$model>($conn)?;
+        let permit = <$model as $crate::things::permission::Policy>::permit_filter(
+            cuser,
+            $crate::things::permission::ACTION_DELETE,
+            $conn,
+        )?;
+        let permitted_ids = edb::table
+            .filter(edb::id.eq_any(&ids))
+            .filter(permit)
+            .select(edb::id)
+            .get_results::<i64>($conn)?;
         let mut done_ids = vec![];
         let mut deined_ids = vec![];
         let mut nerr_ids = vec![];
         for record in &records {
-            if $del(record.id, $conn).is_err() {
+            if !permitted_ids.contains(&record.id) {
+                deined_ids.push(record.id);
+            } else if $del(record.id, $conn).is_err() {
                 nerr_ids.push(record.id);
             } else {
                 done_ids.push(record.id);
@@ -224,10 +279,11 @@ macro_rules! bulk_delete_records {
         );
         records
     }};
-    ($req:expr, $depot:expr, $res:expr, $edb:path, $model:ty, $del:expr, $dep_edb:path, $dep_model:ty, $cfield:tt, $action:expr, $conn:expr) => {{
+    ($ids:expr, $depot:expr, $res:expr, $edb:path, $model:ty, $del:expr, $dep_edb:path, $dep_model:ty, $cfield:tt, $action:expr, $conn:expr) => {{
+        use $crate::things::permission::Policy;
         use $dep_edb as dep_edb;
         use $edb as edb;
-        let ids = $crate::context::parse_ids_from_request($req, "id", "ids").await;
+        let ids = $ids;
         let cuser = current_user!($depot, $res);
 
         let records = edb::table.filter(edb::id.eq_any(&ids)).get_results::<$model>($conn);
@@ -235,7 +291,8 @@ macro_rules! bulk_delete_records {
             return $crate::context::render_db_error_json($res);
         }
         let records = records.unwrap();
-        let mut deps: HashMap<i64, $dep_model> = std::collections::HashMap::new();
+        // Whether the action is permitted on each dep.
+        let mut deps: std::collections::HashMap<i64, bool> = std::collections::HashMap::new();
         let mut done_ids = vec![];
         let mut deined_ids = vec![];
         let mut nerr_ids = vec![];
@@ -246,9 +303,11 @@ macro_rules! bulk_delete_records {
                     nerr_ids.push(record.id);
                     continue;
                 }
-                deps.insert(record.$cfield.clone(), dep.unwrap());
+                deps.insert(record.$cfield.clone(), dep.unwrap().is_permitted(cuser, $action, $conn)?);
             }
-            if $del(record.id, $conn).is_err() {
+            if deps.get(&record.$cfield) != Some(&true) {
+                deined_ids.push(record.id);
+            } else if $del(record.id, $conn).is_err() {
                 nerr_ids.push(record.id);
             } else {
                 done_ids.push(record.id);
@@ -264,14 +323,27 @@ macro_rules! bulk_delete_records {
     }};
 }
 #[macro_export]
+macro_rules! url_filter_fields {
+    ($($name:expr => $kind:ident),+ $(,)?) => {
+        vec![
+            $(
+                $crate::db::url_filter::FilterField::new($name, $crate::db::url_filter::FieldKind::$kind),
+            )+
+        ]
+    };
+}
+#[macro_export]
 macro_rules! url_filter_joined_options {
-    ($($outer_table:expr, $inner_key:expr=>$outer_key:expr, $($url_field:expr=>$o_field:expr),+;)*) => {
+    ($($outer_table:expr, $inner_key:expr=>$outer_key:expr, $($url_field:expr=>$o_field:expr=>$kind:ident),+;)*) => {
         {
             let mut options = vec![];
             $(
                 let mut map = std::collections::HashMap::new();
                 $(
-                    map.insert($url_field.into(), $o_field.into());
+                    map.insert(
+                        $url_field.into(),
+                        $crate::db::url_filter::FilterField::new($o_field, $crate::db::url_filter::FieldKind::$kind),
+                    );
                 )+
                 options.push($crate::db::url_filter::JoinedOption {
                     outer_table: $outer_table.into(),
@@ -290,37 +362,16 @@ macro_rules! query_pagation_data {
     ($req:expr, $res:expr, $model:ty, $query:expr, $default_sort:expr, $filter_fields:expr, $joined_options:expr, $search_tmpl:expr, $conn:expr) => {
         {
             use diesel::prelude::*;
-            use $crate::db::Paginate;
 
-            let offset = $req.query::<i64>("offset").map(|l|{
-                if l < 0 {
-                    0
-                } else {
-                    l
-                }
-            }).unwrap_or(0);
-            let limit = $req.query::<i64>("limit").map(|l|{
-                if l > 200 || l <= 0 {
-                    200
-                } else {
-                    l
-                }
-            }).unwrap_or(200);
-
-            let sort = $req.query::<String>("sort");
-            let sort = match &sort {
-                Some(sort) => {
-                    if &*sort == "" || $crate::utils::validator::validate_db_sort(sort).is_err() {
-                        $default_sort
-                    } else {
-                        sort
-                    }
-                },
-                None => $default_sort,
+            let filter_fields = $filter_fields;
+            let joined_options = $joined_options;
+            let page = match $crate::db::PageRequest::from_request($req, $default_sort, &filter_fields, &joined_options) {
+                Ok(page) => page,
+                Err(e) => return $crate::context::render_parse_query_error_json_with_detail($res, e),
             };
             let filter = $req.query::<String>("filter").unwrap_or_default();
             let mut search = $req.query::<String>("search").unwrap_or_default();
-            search.retain(|c|c != '\'' && c != '\"');
+            search.retain(|c|c != '\'' && c != '\"' && c != '\\');
             let search =search.replace("_", "\\_");
             let filter = if search.is_empty() || $search_tmpl.is_empty() {
                 filter
@@ -340,31 +391,23 @@ macro_rules! query_pagation_data {
                     }
                 }
             };
-            let mut parser = $crate::db::url_filter::Parser::new(filter, $filter_fields, $joined_options);
+            let mut parser = $crate::db::url_filter::Parser::new(filter, filter_fields, joined_options);
             let filter = match parser.parse() {
                 Ok(filter) => filter,
-                Err(msg) => {
-                    tracing::info!( error = %msg, "parse url filter error");
-                    "".into()
+                Err(e) => {
+                    tracing::info!( error = %e, "parse url filter error");
+                    return $crate::context::render_parse_query_error_json_with_detail($res, format!("invalid filter: {}", e));
                 },
             };
-            // tracing::info!( filter = %filter, "url data filter");
+            // tracing::info!( filter = ?filter, "url data filter");
 
-            let (records, total) = if !filter.is_empty() {
-                let query = $query.filter(::diesel::dsl::sql::<::diesel::sql_types::Bool>(&format!("({})", filter))).order(::diesel::dsl::sql::<::diesel::sql_types::Text>(sort)).paginate(offset).limit(limit);
+            if let Some(filter) = filter {
+                let query = $query.filter(filter);
                 // print_query!(&query);
-                query.load_and_total::<$model>($conn)?
+                $crate::db::load_page::<_, $model>(&query, &page, $conn)?
             } else {
-                let query = $query.order(::diesel::dsl::sql::<::diesel::sql_types::Text>(sort)).paginate(offset).limit(limit);
-                // print_query!(&query);
-                query.load_and_total::<$model>($conn)?
-            };
-            $crate::data::PagedData{
-                records,
-                limit,
-                offset,
-                total,
-                sort: Some(sort.to_string()),
+                let query = $query;
+                $crate::db::load_page::<_, $model>(&query, &page, $conn)?
             }
         }
     };
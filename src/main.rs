@@ -15,15 +15,22 @@ mod macros;
 extern crate diesel_migrations;
 
 pub(crate) mod context;
+pub(crate) mod csrf;
 pub(crate) mod db;
 pub(crate) mod models;
 pub(crate) mod schema;
+pub(crate) mod templates;
 pub(crate) mod error;
 pub(crate) mod helpers;
+pub(crate) mod i18n;
+pub(crate) mod jwt;
+pub(crate) mod oidc;
+pub(crate) mod rate_limit;
 pub(crate) mod routers;
 pub(crate) mod things;
 pub(crate) mod utils;
 pub(crate) mod email;
+pub(crate) mod webauthn;
 pub(crate) mod data;
 
 mod shared;
@@ -53,6 +60,22 @@ pub use error::Error;
 pub struct JwtClaims {
     user: i64,
     exp: i64,
+    /// The in_kernel user acting as `user`, only present in impersonation tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    act: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MfaPendingClaims {
+    user: i64,
+    exp: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MagicLinkClaims {
+    user: i64,
+    nonce: String,
+    exp: i64,
 }
 
 #[tokio::main]
@@ -66,12 +89,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     }
 
     println!("DATABASE_URL: {}", crate::database_url());
+    templates::check_required()?;
+    jwt::keyring()?;
+    crate::magic_link_url()?;
     tracing::info!("=========================SAVVY APP STARTING=======================================");
 
     let mut build_result = db::build_pool(&crate::database_url());
     while let Err(e) = build_result {
         tracing::error!(error = ?e, "db connect failed, will try after 10 seconds...");
-        std::thread::sleep(std::time::Duration::from_secs(10));
+        tokio::time::sleep(std::time::Duration::from_secs(10)).await;
         build_result = db::build_pool(&crate::database_url());
     }
     if crate::db::DB_POOL.set(build_result.unwrap()).is_err() {
@@ -80,10 +106,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         tracing::info!("db connected");
     }
 
-    let mut conn = db::connect().unwrap();
-    db::migrate(&mut conn);
+    db::run(|conn| {
+        db::migrate(conn);
+        Ok(())
+    })
+    .await?;
     tracing::info!("db migrated");
-    drop(conn);
+
+    tokio::spawn(email::outbox::run_worker());
 
     Server::new(TcpListener::bind("0.0.0.0:7117"))
         .serve(routers::root())
@@ -3,27 +3,26 @@ use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 
-use crate::db::url_filter::JoinedOption;
+use crate::db::url_filter::{FilterField, JoinedOption};
 use crate::schema::*;
 
 
-pub static USER_FILTER_FIELDS: Lazy<Vec<String>> = Lazy::new(|| {
-    vec![
-        "id",
-        "ident_name",
-        "display_name",
-        "in_kernel",
+pub static USER_FILTER_FIELDS: Lazy<Vec<FilterField>> = Lazy::new(|| {
+    url_filter_fields![
+        "id" => BigInt,
+        "ident_name" => Text,
+        "display_name" => Text,
+        "in_kernel" => Bool,
+        "updated_at" => Timestamptz,
+        "created_at" => Timestamptz,
     ]
-    .into_iter()
-    .map(String::from)
-    .collect()
 });
 pub static USER_JOINED_OPTIONS: Lazy<Vec<JoinedOption>> = Lazy::new(|| {
     url_filter_joined_options![
-        "emails", "id"=>"user_id", "e.value"=>"value";
+        "emails", "id"=>"user_id", "e.value"=>"value"=>Text;
     ]
 });
-pub static USER_SEARCH_TMPL: &str = "id::varchar(255)='{{data}}' or ident_name ilike E'%{{data}}%' or display_name ilike E'%{{data}}%' or e.value ilike E'%{{data}}%' or p.value ilike E'%{{data}}%'";
+pub static USER_SEARCH_TMPL: &str = "id::varchar(255)='{{data}}' or ident_name ilike E'%{{data}}%' or display_name ilike E'%{{data}}%' or e.value ilike E'%{{data}}%'";
 #[derive(Identifiable, Queryable, Serialize, Deserialize, Clone, Debug)]
 pub struct User {
     pub id: i64,
@@ -50,6 +49,17 @@ pub struct User {
     pub created_at: DateTime<Utc>,
 
     pub in_kernel: bool,
+    pub locale: String,
+    pub timezone: String,
+    #[serde(skip_serializing)]
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    #[serde(skip_serializing)]
+    pub failed_attempts: i32,
+    pub locked_until: Option<DateTime<Utc>>,
+    /// Time step of the last accepted TOTP code; codes of this step or earlier are rejected as replays.
+    #[serde(skip_serializing)]
+    pub totp_last_step: Option<i64>,
 }
 #[derive(Insertable, Deserialize, Clone, Debug)]
 #[diesel(table_name = users)]
@@ -67,18 +77,15 @@ pub struct NewUser<'a> {
 }
 
 
-pub static EMAIL_FILTER_FIELDS: Lazy<Vec<String>> = Lazy::new(|| {
-    vec![
-        "id",
-        "user_id",
-        "value",
-        "is_verified",
-        "updated_by",
-        "created_by",
+pub static EMAIL_FILTER_FIELDS: Lazy<Vec<FilterField>> = Lazy::new(|| {
+    url_filter_fields![
+        "id" => BigInt,
+        "user_id" => BigInt,
+        "value" => Text,
+        "is_verified" => Bool,
+        "updated_by" => BigInt,
+        "created_by" => BigInt,
     ]
-    .into_iter()
-    .map(String::from)
-    .collect()
 });
 pub static EMAIL_JOINED_OPTIONS: Lazy<Vec<JoinedOption>> = Lazy::new(Vec::new);
 #[derive(Identifiable, Queryable, Serialize, Deserialize, Clone, Debug)]
@@ -122,11 +129,16 @@ impl Default for PostedEmail {
     }
 }
 
-pub static ACCESS_TOKEN_FILTER_FIELDS: Lazy<Vec<String>> = Lazy::new(|| {
-    vec!["id", "user_id", "name", "kind", "value", "updated_by", "created_by"]
-        .into_iter()
-        .map(String::from)
-        .collect()
+pub static ACCESS_TOKEN_FILTER_FIELDS: Lazy<Vec<FilterField>> = Lazy::new(|| {
+    url_filter_fields![
+        "id" => BigInt,
+        "user_id" => BigInt,
+        "name" => Text,
+        "kind" => Text,
+        "value" => Text,
+        "updated_by" => BigInt,
+        "created_by" => BigInt,
+    ]
 });
 pub static ACCESS_TOKEN_JOINED_OPTIONS: Lazy<Vec<JoinedOption>> = Lazy::new(Vec::new);
 #[derive(Identifiable, Queryable, Serialize, Deserialize, Clone, Debug)]
@@ -136,6 +148,7 @@ pub struct AccessToken {
     pub user_id: i64,
     pub name: Option<String>,
     pub kind: String,
+    #[serde(skip_serializing)]
     pub value: String,
     pub device: Option<String>,
     pub expired_at: DateTime<Utc>,
@@ -144,6 +157,12 @@ pub struct AccessToken {
     pub updated_at: DateTime<Utc>,
     pub created_by: Option<i64>,
     pub created_at: DateTime<Utc>,
+
+    pub ip_address: Option<String>,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub family_id: Option<String>,
+    pub scopes: Vec<String>,
+    pub oauth_client_id: Option<i64>,
 }
 
 #[derive(Insertable, Serialize, Clone, Debug)]
@@ -155,11 +174,253 @@ pub struct NewAccessToken<'a> {
     pub value: &'a str,
     pub device: Option<&'a str>,
     pub expired_at: DateTime<Utc>,
+    pub ip_address: Option<&'a str>,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub family_id: Option<&'a str>,
+    pub scopes: &'a [String],
+    pub oauth_client_id: Option<i64>,
+
+    pub updated_by: Option<i64>,
+    pub created_by: Option<i64>,
+}
+
+#[derive(Identifiable, Queryable, Serialize, Clone, Debug)]
+pub struct RefreshToken {
+    pub id: i64,
+    pub user_id: i64,
+    pub family_id: String,
+    #[serde(skip_serializing)]
+    pub value: String,
+    pub replaced_by: Option<i64>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub expired_at: DateTime<Utc>,
+
+    pub updated_by: Option<i64>,
+    pub updated_at: DateTime<Utc>,
+    pub created_by: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = refresh_tokens)]
+pub struct NewRefreshToken<'a> {
+    pub user_id: i64,
+    pub family_id: &'a str,
+    pub value: &'a str,
+    pub expired_at: DateTime<Utc>,
+
+    pub updated_by: Option<i64>,
+    pub created_by: Option<i64>,
+}
+
+#[derive(Identifiable, Queryable, Serialize, Clone, Debug)]
+pub struct WebauthnCredential {
+    pub id: i64,
+    pub user_id: i64,
+    pub credential_id: String,
+    pub name: String,
+    #[serde(skip_serializing)]
+    pub passkey: Value,
+    pub last_used_at: Option<DateTime<Utc>>,
+
+    pub updated_by: Option<i64>,
+    pub updated_at: DateTime<Utc>,
+    pub created_by: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = webauthn_credentials)]
+pub struct NewWebauthnCredential<'a> {
+    pub user_id: i64,
+    pub credential_id: &'a str,
+    pub name: &'a str,
+    pub passkey: Value,
+
+    pub updated_by: Option<i64>,
+    pub created_by: Option<i64>,
+}
+#[derive(Identifiable, Queryable, Serialize, Clone, Debug)]
+#[diesel(table_name = user_identities)]
+pub struct UserIdentity {
+    pub id: i64,
+    pub user_id: i64,
+    pub provider: String,
+    pub subject: String,
+    pub email: Option<String>,
+    pub last_login_at: Option<DateTime<Utc>>,
+
+    pub updated_by: Option<i64>,
+    pub updated_at: DateTime<Utc>,
+    pub created_by: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = user_identities)]
+pub struct NewUserIdentity<'a> {
+    pub user_id: i64,
+    pub provider: &'a str,
+    pub subject: &'a str,
+    pub email: Option<&'a str>,
+    pub last_login_at: Option<DateTime<Utc>>,
+
+    pub updated_by: Option<i64>,
+    pub created_by: Option<i64>,
+}
+
+pub static IMPERSONATION_LOG_FILTER_FIELDS: Lazy<Vec<FilterField>> = Lazy::new(|| {
+    url_filter_fields![
+        "id" => BigInt,
+        "actor_id" => BigInt,
+        "user_id" => BigInt,
+        "access_token_id" => BigInt,
+        "method" => Text,
+        "status_code" => Int,
+        "created_at" => Timestamptz,
+    ]
+});
+pub static IMPERSONATION_LOG_JOINED_OPTIONS: Lazy<Vec<JoinedOption>> = Lazy::new(Vec::new);
+#[derive(Identifiable, Queryable, Serialize, Clone, Debug)]
+pub struct ImpersonationLog {
+    pub id: i64,
+    pub actor_id: i64,
+    pub user_id: i64,
+    pub access_token_id: Option<i64>,
+    pub method: String,
+    pub path: String,
+    pub status_code: Option<i32>,
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = impersonation_logs)]
+pub struct NewImpersonationLog<'a> {
+    pub actor_id: i64,
+    pub user_id: i64,
+    pub access_token_id: Option<i64>,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub status_code: Option<i32>,
+    pub reason: Option<&'a str>,
+}
+
+pub static OAUTH_CLIENT_FILTER_FIELDS: Lazy<Vec<FilterField>> = Lazy::new(|| {
+    url_filter_fields![
+        "id" => BigInt,
+        "client_id" => Text,
+        "name" => Text,
+        "is_disabled" => Bool,
+        "updated_by" => BigInt,
+        "created_by" => BigInt,
+    ]
+});
+pub static OAUTH_CLIENT_JOINED_OPTIONS: Lazy<Vec<JoinedOption>> = Lazy::new(Vec::new);
+#[derive(Identifiable, Queryable, Serialize, Clone, Debug)]
+pub struct OauthClient {
+    pub id: i64,
+    pub client_id: String,
+    #[serde(skip_serializing)]
+    pub secret: Option<String>,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    pub scopes: Vec<String>,
+    pub is_disabled: bool,
+
+    pub updated_by: Option<i64>,
+    pub updated_at: DateTime<Utc>,
+    pub created_by: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+impl OauthClient {
+    /// Public clients (SPAs, native apps) can not keep a secret and rely on PKCE alone.
+    pub fn is_confidential(&self) -> bool {
+        self.secret.is_some()
+    }
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = oauth_clients)]
+pub struct NewOauthClient<'a> {
+    pub client_id: &'a str,
+    pub secret: Option<&'a str>,
+    pub name: &'a str,
+    pub redirect_uris: &'a [String],
+    pub scopes: &'a [String],
+
+    pub updated_by: Option<i64>,
+    pub created_by: Option<i64>,
+}
+
+#[derive(Identifiable, Queryable, Clone, Debug)]
+pub struct OauthAuthorizationCode {
+    pub id: i64,
+    pub oauth_client_id: i64,
+    pub user_id: i64,
+    pub value: String,
+    pub redirect_uri: String,
+    pub scopes: Vec<String>,
+    pub code_challenge: String,
+    pub nonce: Option<String>,
+    pub family_id: Option<String>,
+    pub consumed_at: Option<DateTime<Utc>>,
+    pub expired_at: DateTime<Utc>,
+
+    pub updated_by: Option<i64>,
+    pub updated_at: DateTime<Utc>,
+    pub created_by: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = oauth_authorization_codes)]
+pub struct NewOauthAuthorizationCode<'a> {
+    pub oauth_client_id: i64,
+    pub user_id: i64,
+    pub value: &'a str,
+    pub redirect_uri: &'a str,
+    pub scopes: &'a [String],
+    pub code_challenge: &'a str,
+    pub nonce: Option<&'a str>,
+    pub expired_at: DateTime<Utc>,
 
     pub updated_by: Option<i64>,
     pub created_by: Option<i64>,
 }
 
+#[derive(Identifiable, Queryable, Serialize, Clone, Debug)]
+pub struct OauthConsent {
+    pub id: i64,
+    pub user_id: i64,
+    pub oauth_client_id: i64,
+    pub scopes: Vec<String>,
+
+    pub updated_by: Option<i64>,
+    pub updated_at: DateTime<Utc>,
+    pub created_by: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = oauth_consents)]
+pub struct NewOauthConsent<'a> {
+    pub user_id: i64,
+    pub oauth_client_id: i64,
+    pub scopes: &'a [String],
+
+    pub updated_by: Option<i64>,
+    pub created_by: Option<i64>,
+}
+
+#[derive(Identifiable, Queryable, Insertable, Debug)]
+#[diesel(table_name = rate_limit_buckets, primary_key(key))]
+pub struct RateLimitBucket {
+    pub key: String,
+    pub tokens: f64,
+    pub updated_at: DateTime<Utc>,
+}
+
 
 #[derive(Identifiable, Queryable, Serialize, Deserialize, Clone, Debug)]
 pub struct SecurityCode {
@@ -175,6 +436,8 @@ pub struct SecurityCode {
     pub updated_at: DateTime<Utc>,
     pub created_by: Option<i64>,
     pub created_at: DateTime<Utc>,
+
+    pub failed_attempts: i32,
 }
 
 #[derive(Insertable, Debug)]
@@ -190,20 +453,42 @@ pub struct NewSecurityCode<'a> {
     pub created_by: Option<i64>,
 }
 
+#[derive(Identifiable, Queryable, Serialize, Clone, Debug)]
+pub struct RecoveryCode {
+    pub id: i64,
+    pub user_id: i64,
+    #[serde(skip_serializing)]
+    pub value: String,
+    pub consumed_at: Option<DateTime<Utc>>,
+
+    pub updated_by: Option<i64>,
+    pub updated_at: DateTime<Utc>,
+    pub created_by: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = recovery_codes)]
+pub struct NewRecoveryCode<'a> {
+    pub user_id: i64,
+    pub value: &'a str,
 
-pub static NOTIFICATION_FILTER_FIELDS: Lazy<Vec<String>> = Lazy::new(|| {
-    vec![
-        "id",
-        "owner_id",
-        "sender_id",
-        "kind",
-        "is_read",
-        "updated_by",
-        "created_by",
+    pub updated_by: Option<i64>,
+    pub created_by: Option<i64>,
+}
+
+pub static NOTIFICATION_FILTER_FIELDS: Lazy<Vec<FilterField>> = Lazy::new(|| {
+    url_filter_fields![
+        "id" => BigInt,
+        "owner_id" => BigInt,
+        "sender_id" => BigInt,
+        "kind" => Text,
+        "is_read" => Bool,
+        "updated_by" => BigInt,
+        "created_by" => BigInt,
+        "created_at" => Timestamptz,
+        "updated_at" => Timestamptz,
     ]
-    .into_iter()
-    .map(String::from)
-    .collect()
 });
 pub static NOTIFICATION_JOINED_OPTIONS: Lazy<Vec<JoinedOption>> = Lazy::new(Vec::new);
 #[derive(Identifiable, Queryable, Serialize, Clone, Debug)]
@@ -239,6 +524,129 @@ pub struct NewNotification<'a> {
 }
 
 
+pub static EMAIL_OUTBOX_FILTER_FIELDS: Lazy<Vec<FilterField>> = Lazy::new(|| {
+    url_filter_fields![
+        "id" => BigInt,
+        "subject" => Text,
+        "template" => Text,
+        "status" => Text,
+        "attempts" => Int,
+        "updated_by" => BigInt,
+        "created_by" => BigInt,
+        "updated_at" => Timestamptz,
+    ]
+});
+pub static EMAIL_OUTBOX_JOINED_OPTIONS: Lazy<Vec<JoinedOption>> = Lazy::new(Vec::new);
+#[derive(Identifiable, Queryable, Serialize, Clone, Debug)]
+#[diesel(table_name = email_outbox)]
+pub struct EmailOutbox {
+    pub id: i64,
+    pub recipients: Vec<String>,
+    pub subject: String,
+    pub body: String,
+    pub template: Option<String>,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: DateTime<Utc>,
+    pub sent_at: Option<DateTime<Utc>>,
+
+    pub updated_by: Option<i64>,
+    pub updated_at: DateTime<Utc>,
+    pub created_by: Option<i64>,
+    pub created_at: DateTime<Utc>,
+
+    pub text_body: Option<String>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = email_outbox)]
+pub struct NewEmailOutbox<'a> {
+    pub recipients: &'a [String],
+    pub subject: &'a str,
+    pub body: &'a str,
+    pub text_body: Option<&'a str>,
+    pub template: Option<&'a str>,
+
+    pub updated_by: Option<i64>,
+    pub created_by: Option<i64>,
+}
+
+pub static ROLE_FILTER_FIELDS: Lazy<Vec<FilterField>> = Lazy::new(|| {
+    url_filter_fields![
+        "id" => BigInt,
+        "ident_name" => Text,
+        "name" => Text,
+        "updated_by" => BigInt,
+        "created_by" => BigInt,
+    ]
+});
+pub static ROLE_JOINED_OPTIONS: Lazy<Vec<JoinedOption>> = Lazy::new(Vec::new);
+#[derive(Identifiable, Queryable, Serialize, Clone, Debug)]
+pub struct Role {
+    pub id: i64,
+    pub ident_name: String,
+    pub name: String,
+    pub description: Option<String>,
+
+    pub updated_by: Option<i64>,
+    pub updated_at: DateTime<Utc>,
+    pub created_by: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = roles)]
+pub struct NewRole<'a> {
+    pub ident_name: &'a str,
+    pub name: &'a str,
+    pub description: Option<&'a str>,
+
+    pub updated_by: Option<i64>,
+    pub created_by: Option<i64>,
+}
+
+/// Grants `action` on `resource` to everyone with the role; either may be `*`.
+#[derive(Identifiable, Queryable, Serialize, Deserialize, Clone, Debug)]
+pub struct Permission {
+    pub id: i64,
+    pub role_id: i64,
+    pub resource: String,
+    pub action: String,
+
+    pub created_by: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = permissions)]
+pub struct NewPermission<'a> {
+    pub role_id: i64,
+    pub resource: &'a str,
+    pub action: &'a str,
+
+    pub created_by: Option<i64>,
+}
+
+#[derive(Identifiable, Queryable, Serialize, Clone, Debug)]
+pub struct UserRole {
+    pub id: i64,
+    pub user_id: i64,
+    pub role_id: i64,
+
+    pub created_by: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable, Debug)]
+#[diesel(table_name = user_roles)]
+pub struct NewUserRole {
+    pub user_id: i64,
+    pub role_id: i64,
+
+    pub created_by: Option<i64>,
+}
+
 #[derive(QueryableByName, Debug)]
 pub struct TableId {
     #[diesel(sql_type = ::diesel::sql_types::BigInt)]
@@ -0,0 +1,501 @@
+//! OpenID Connect relying party for social login: discovery, authorization code flow with PKCE, and ID token
+//! validation against the provider's JWKS.
+//!
+//! Providers are configured by name, e.g. `OIDC_PROVIDERS=google` with `OIDC_GOOGLE_ISSUER`,
+//! `OIDC_GOOGLE_CLIENT_ID`, `OIDC_GOOGLE_CLIENT_SECRET` and optionally `OIDC_GOOGLE_SCOPES`.
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::Mutex;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::jwk::Jwk;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::webauthn::ChallengeStore;
+use crate::AppResult;
+
+const METADATA_TTL_MINUTES: i64 = 60;
+
+/// Flows in progress, keyed by the `state` parameter. The user id is 0 for a login and the current user when
+/// linking an identity to an account.
+pub static FLOWS: Lazy<ChallengeStore<Flow>> = Lazy::new(ChallengeStore::new);
+
+static HTTP: Lazy<reqwest::Client> = Lazy::new(reqwest::Client::new);
+static METADATA: Lazy<Mutex<HashMap<String, Discovered>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static JWKS: Lazy<Mutex<HashMap<String, Vec<Jwk>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Clone, Debug)]
+pub struct Provider {
+    pub name: String,
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scopes: String,
+}
+
+/// The configured provider called `name`, if it is listed in `OIDC_PROVIDERS`.
+pub fn provider(name: &str) -> Option<Provider> {
+    if !crate::oidc_providers().iter().any(|p| p == name) {
+        return None;
+    }
+    let var = |key: &str| env::var(format!("OIDC_{}_{}", name.to_uppercase(), key)).ok();
+    Some(Provider {
+        name: name.to_owned(),
+        issuer: var("ISSUER")?.trim_end_matches('/').to_owned(),
+        client_id: var("CLIENT_ID")?,
+        client_secret: var("CLIENT_SECRET").unwrap_or_default(),
+        scopes: var("SCOPES").unwrap_or_else(|| "openid email profile".into()),
+    })
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct Metadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+struct Discovered {
+    metadata: Metadata,
+    fetched_at: DateTime<Utc>,
+}
+
+pub struct Flow {
+    provider: String,
+    code_verifier: String,
+    nonce: String,
+}
+
+/// What the provider asserted about the user in a validated ID token.
+#[derive(Deserialize, Debug)]
+pub struct Identity {
+    #[serde(rename = "sub")]
+    pub subject: String,
+    pub email: Option<String>,
+    #[serde(default)]
+    pub email_verified: bool,
+    pub name: Option<String>,
+    pub preferred_username: Option<String>,
+    nonce: Option<String>,
+}
+
+async fn discover(provider: &Provider) -> AppResult<Metadata> {
+    if let Some(discovered) = METADATA.lock().unwrap().get(&provider.name) {
+        if discovered.fetched_at + Duration::minutes(METADATA_TTL_MINUTES) > Utc::now() {
+            return Ok(discovered.metadata.clone());
+        }
+    }
+    let url = format!("{}/.well-known/openid-configuration", provider.issuer);
+    let metadata = HTTP
+        .get(url)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<Metadata>()
+        .await?;
+    if metadata.issuer.trim_end_matches('/') != provider.issuer {
+        return Err(crate::Error::Internal(format!(
+            "oidc provider {} reports issuer {}",
+            provider.name, metadata.issuer
+        )));
+    }
+    METADATA.lock().unwrap().insert(
+        provider.name.clone(),
+        Discovered {
+            metadata: metadata.clone(),
+            fetched_at: Utc::now(),
+        },
+    );
+    Ok(metadata)
+}
+
+/// Start a flow and return the URL to send the user to, along with the flow's `state`.
+pub async fn authorization_url(provider: &Provider, user_id: i64) -> AppResult<(String, String)> {
+    let metadata = discover(provider).await?;
+    let code_verifier = crate::generate_url_safe_token(64);
+    let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+    let nonce = crate::generate_url_safe_token(32);
+    let state = FLOWS.insert(
+        user_id,
+        Flow {
+            provider: provider.name.clone(),
+            code_verifier,
+            nonce: nonce.clone(),
+        },
+    );
+    let url = Url::parse_with_params(
+        &metadata.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", &provider.client_id),
+            ("redirect_uri", &crate::oidc_redirect_url()),
+            ("scope", &provider.scopes),
+            ("state", &state),
+            ("nonce", &nonce),
+            ("code_challenge", &code_challenge),
+            ("code_challenge_method", "S256"),
+        ],
+    )?;
+    Ok((url.into(), state))
+}
+
+/// Redeem the authorization `code` the provider redirected back with. Returns the user id the flow was started
+/// for and the validated identity, or `None` when `state` is unknown, expired or belongs to another provider.
+/// A code or ID token the provider does not honour fails with `Error::Oidc`.
+pub async fn finish(provider: &Provider, state: &str, code: &str) -> AppResult<Option<(i64, Identity)>> {
+    let (user_id, flow) = match FLOWS.take(state) {
+        Some((user_id, flow)) if flow.provider == provider.name => (user_id, flow),
+        _ => return Ok(None),
+    };
+    let metadata = discover(provider).await?;
+
+    #[derive(Deserialize, Debug)]
+    struct TokenResponse {
+        id_token: String,
+    }
+    let redirect_url = crate::oidc_redirect_url();
+    let response = HTTP
+        .post(&metadata.token_endpoint)
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &redirect_url),
+            ("client_id", &provider.client_id),
+            ("client_secret", &provider.client_secret),
+            ("code_verifier", &flow.code_verifier),
+        ])
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(rejected)?
+        .json::<TokenResponse>()
+        .await
+        .map_err(rejected)?;
+
+    let identity = validate_id_token(provider, &metadata, &response.id_token).await?;
+    if identity.nonce.as_deref() != Some(flow.nonce.as_str()) {
+        return Err(crate::Error::Oidc("id token nonce mismatch".into()));
+    }
+    Ok(Some((user_id, identity)))
+}
+
+async fn validate_id_token(provider: &Provider, metadata: &Metadata, id_token: &str) -> AppResult<Identity> {
+    let header = jsonwebtoken::decode_header(id_token).map_err(rejected)?;
+    if matches!(header.alg, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+        return Err(crate::Error::Oidc("id token must be signed asymmetrically".into()));
+    }
+    let kid = header.kid.unwrap_or_default();
+    let jwk = match find_jwk(&metadata.jwks_uri, &kid, false).await.map_err(rejected)? {
+        Some(jwk) => jwk,
+        // The provider may have rotated its keys since they were cached.
+        None => find_jwk(&metadata.jwks_uri, &kid, true)
+            .await
+            .map_err(rejected)?
+            .ok_or_else(|| crate::Error::Oidc(format!("signing key {} not found", kid)))?,
+    };
+    let mut validation = Validation::new(header.alg);
+    validation.set_audience(&[&provider.client_id]);
+    validation.set_issuer(&[&metadata.issuer]);
+    let key = DecodingKey::from_jwk(&jwk).map_err(rejected)?;
+    let data = jsonwebtoken::decode::<Identity>(id_token, &key, &validation).map_err(rejected)?;
+    Ok(data.claims)
+}
+
+async fn find_jwk(jwks_uri: &str, kid: &str, refresh: bool) -> AppResult<Option<Jwk>> {
+    if !refresh {
+        if let Some(keys) = JWKS.lock().unwrap().get(jwks_uri) {
+            return Ok(select_jwk(keys, kid));
+        }
+    }
+    #[derive(Deserialize, Debug)]
+    struct JwkSet {
+        keys: Vec<serde_json::Value>,
+    }
+    let set = HTTP
+        .get(jwks_uri)
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<JwkSet>()
+        .await?;
+    // Keys of kinds jsonwebtoken does not know (e.g. encryption keys) are skipped instead of failing the set.
+    let keys = set
+        .keys
+        .into_iter()
+        .filter_map(|key| serde_json::from_value::<Jwk>(key).ok())
+        .collect::<Vec<_>>();
+    let jwk = select_jwk(&keys, kid);
+    JWKS.lock().unwrap().insert(jwks_uri.to_owned(), keys);
+    Ok(jwk)
+}
+
+/// The provider or the client is at fault, not this service.
+fn rejected(e: impl std::fmt::Display) -> crate::Error {
+    crate::Error::Oidc(e.to_string())
+}
+
+fn select_jwk(keys: &[Jwk], kid: &str) -> Option<Jwk> {
+    if kid.is_empty() && keys.len() == 1 {
+        return keys.first().cloned();
+    }
+    keys.iter()
+        .find(|key| key.common.key_id.as_deref() == Some(kid))
+        .cloned()
+}
+
+/// A mock issuer serving discovery, JWKS and a token endpoint that checks PKCE, driven through the real flow.
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::SocketAddr;
+
+    use jsonwebtoken::{EncodingKey, Header};
+    use openssl::pkey::Private;
+    use openssl::rsa::Rsa;
+    use salvo::listener::TcpListener;
+    use salvo::prelude::*;
+    use serde_json::{json, Value};
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::things::user_identity::verified_email;
+
+    pub(crate) const PROVIDER: &str = "mock";
+    const CLIENT_ID: &str = "back";
+    const KID: &str = "mock-1";
+
+    /// Shared by every test, so the mock issuer and the connections cached by `HTTP` outlive any single test.
+    pub(crate) static RUNTIME: Lazy<Runtime> = Lazy::new(|| Runtime::new().unwrap());
+    static KEY: Lazy<Rsa<Private>> = Lazy::new(|| Rsa::generate(2048).unwrap());
+    /// What the token endpoint returns for a code: the ID token, if the PKCE challenge matches.
+    static GRANTS: Lazy<Mutex<HashMap<String, (String, String)>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+    pub(crate) static ISSUER: Lazy<String> = Lazy::new(|| {
+        env::set_var("OIDC_PROVIDERS", PROVIDER);
+        env::set_var("OIDC_MOCK_CLIENT_ID", CLIENT_ID);
+        env::set_var("OIDC_REDIRECT_URL", "http://localhost/login/oidc");
+        env::set_var("COOKIE_DOMAIN", "localhost");
+        let listener = RUNTIME.block_on(async { TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))) });
+        let issuer = format!("http://{}", listener.local_addr());
+        env::set_var("OIDC_MOCK_ISSUER", &issuer);
+        let router = Router::new()
+            .push(Router::with_path(".well-known/openid-configuration").get(discovery))
+            .push(Router::with_path("jwks").get(jwks))
+            .push(Router::with_path("token").post(token));
+        RUNTIME.spawn(Server::new(listener).serve(router));
+        issuer
+    });
+
+    #[handler]
+    async fn discovery(res: &mut Response) {
+        let issuer = &*ISSUER;
+        res.render(Json(json!({
+            "issuer": issuer,
+            "authorization_endpoint": format!("{}/authorize", issuer),
+            "token_endpoint": format!("{}/token", issuer),
+            "jwks_uri": format!("{}/jwks", issuer),
+        })));
+    }
+
+    #[handler]
+    async fn jwks(res: &mut Response) {
+        res.render(Json(json!({
+            "keys": [{
+                "kty": "RSA",
+                "use": "sig",
+                "alg": "RS256",
+                "kid": KID,
+                "n": URL_SAFE_NO_PAD.encode(KEY.n().to_vec()),
+                "e": URL_SAFE_NO_PAD.encode(KEY.e().to_vec()),
+            }]
+        })));
+    }
+
+    #[handler]
+    async fn token(req: &mut Request, res: &mut Response) {
+        let code = req.form::<String>("code").await.unwrap_or_default();
+        let code_verifier = req.form::<String>("code_verifier").await.unwrap_or_default();
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+        match GRANTS.lock().unwrap().remove(&code) {
+            Some((challenge, id_token)) if challenge == code_challenge => {
+                res.render(Json(json!({ "token_type": "Bearer", "access_token": "mock", "id_token": id_token })));
+            }
+            _ => {
+                res.set_status_code(StatusCode::BAD_REQUEST);
+                res.render(Json(json!({ "error": "invalid_grant" })));
+            }
+        }
+    }
+
+    pub(crate) fn provider() -> Provider {
+        Provider {
+            name: PROVIDER.into(),
+            issuer: ISSUER.clone(),
+            client_id: CLIENT_ID.into(),
+            client_secret: String::new(),
+            scopes: "openid email".into(),
+        }
+    }
+
+    /// The `nonce` and `code_challenge` the user is sent to the provider with.
+    pub(crate) fn authorization_params(url: &str) -> (String, String) {
+        let url = Url::parse(url).unwrap();
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .unwrap()
+        };
+        assert_eq!(param("code_challenge_method"), "S256");
+        assert_eq!(param("client_id"), CLIENT_ID);
+        (param("nonce"), param("code_challenge"))
+    }
+
+    pub(crate) fn claims(nonce: &str) -> Value {
+        json!({
+            "iss": &*ISSUER,
+            "aud": CLIENT_ID,
+            "sub": "subject-1",
+            "iat": Utc::now().timestamp(),
+            "exp": Utc::now().timestamp() + 300,
+            "nonce": nonce,
+            "email": "jdoe@example.com",
+            "email_verified": true,
+        })
+    }
+
+    /// Let the token endpoint redeem `code` for an ID token with `claims`, signed with the key `kid`.
+    pub(crate) fn grant(code: &str, code_challenge: &str, kid: &str, claims: &Value) {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(kid.into());
+        let key = EncodingKey::from_rsa_pem(&KEY.private_key_to_pem().unwrap()).unwrap();
+        let id_token = jsonwebtoken::encode(&header, claims, &key).unwrap();
+        GRANTS
+            .lock()
+            .unwrap()
+            .insert(code.into(), (code_challenge.into(), id_token));
+    }
+
+    /// Start a flow, let the provider issue an ID token with `claims` for it and finish it.
+    fn run_flow(claims: impl FnOnce(&str) -> Value) -> AppResult<Option<(i64, Identity)>> {
+        let provider = provider();
+        RUNTIME.block_on(async {
+            let (url, state) = authorization_url(&provider, 0).await?;
+            let (nonce, code_challenge) = authorization_params(&url);
+            grant(&state, &code_challenge, KID, &claims(&nonce));
+            finish(&provider, &state, &state).await
+        })
+    }
+
+    fn assert_rejected(result: AppResult<Option<(i64, Identity)>>) {
+        assert!(matches!(result, Err(crate::Error::Oidc(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn finishes_flow_with_pkce() {
+        let provider = provider();
+        let (user_id, identity) = run_flow(claims).unwrap().unwrap();
+        assert_eq!(user_id, 0);
+        assert_eq!(identity.subject, "subject-1");
+        assert_eq!(identity.email.as_deref(), Some("jdoe@example.com"));
+
+        RUNTIME.block_on(async {
+            let (url, state) = authorization_url(&provider, 7).await.unwrap();
+            let (nonce, code_challenge) = authorization_params(&url);
+            grant(&state, &code_challenge, KID, &claims(&nonce));
+            assert_eq!(finish(&provider, &state, &state).await.unwrap().unwrap().0, 7);
+            // Every state is good for one attempt only.
+            assert!(finish(&provider, &state, &state).await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn rejects_wrong_code_verifier() {
+        let provider = provider();
+        RUNTIME.block_on(async {
+            let (url, state) = authorization_url(&provider, 0).await.unwrap();
+            let (nonce, _) = authorization_params(&url);
+            let other_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(b"another verifier"));
+            grant(&state, &other_challenge, KID, &claims(&nonce));
+            assert_rejected(finish(&provider, &state, &state).await);
+        });
+    }
+
+    #[test]
+    fn rejects_unknown_state() {
+        let provider = provider();
+        let other = Provider {
+            name: "other".into(),
+            ..provider.clone()
+        };
+        RUNTIME.block_on(async {
+            assert!(finish(&provider, "unknown", "code").await.unwrap().is_none());
+            let (_, state) = authorization_url(&provider, 0).await.unwrap();
+            assert!(finish(&other, &state, "code").await.unwrap().is_none());
+        });
+    }
+
+    #[test]
+    fn rejects_nonce_mismatch() {
+        assert_rejected(run_flow(|_| claims("another nonce")));
+    }
+
+    #[test]
+    fn rejects_wrong_audience() {
+        assert_rejected(run_flow(|nonce| {
+            let mut claims = claims(nonce);
+            claims["aud"] = json!("another-client");
+            claims
+        }));
+    }
+
+    #[test]
+    fn rejects_wrong_issuer() {
+        assert_rejected(run_flow(|nonce| {
+            let mut claims = claims(nonce);
+            claims["iss"] = json!("https://issuer.example.com");
+            claims
+        }));
+    }
+
+    #[test]
+    fn rejects_unknown_signing_key() {
+        let provider = provider();
+        RUNTIME.block_on(async {
+            let (url, state) = authorization_url(&provider, 0).await.unwrap();
+            let (nonce, code_challenge) = authorization_params(&url);
+            grant(&state, &code_challenge, "unknown", &claims(&nonce));
+            assert_rejected(finish(&provider, &state, &state).await);
+        });
+    }
+
+    #[test]
+    fn links_by_verified_email_only() {
+        let (_, identity) = run_flow(claims).unwrap().unwrap();
+        assert_eq!(verified_email(&identity), Some("jdoe@example.com"));
+
+        let (_, identity) = run_flow(|nonce| {
+            let mut claims = claims(nonce);
+            claims["email_verified"] = json!(false);
+            claims
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(verified_email(&identity), None);
+
+        let (_, identity) = run_flow(|nonce| {
+            let mut claims = claims(nonce);
+            claims.as_object_mut().unwrap().remove("email_verified");
+            claims
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(verified_email(&identity), None);
+    }
+}
@@ -0,0 +1,313 @@
+//! Token bucket rate limiting. A `RateLimiter` is a hoop: attach one to a router in `routers::root` with its own
+//! `Quota` and the request attribute it counts by.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use once_cell::sync::Lazy;
+use salvo::http::header::{HeaderValue, RETRY_AFTER};
+use salvo::http::StatusCode;
+use salvo::prelude::*;
+use salvo::routing::FlowCtrl;
+
+use crate::models::*;
+use crate::schema::*;
+use crate::{context, db, AppResult};
+
+/// Buckets not touched for this long are full again for any sensible quota, so they can be dropped.
+const MAX_IDLE_HOURS: i64 = 24;
+const MEMORY_PRUNE_LEN: usize = 100_000;
+const POSTGRES_PRUNE_EVERY: u64 = 10_000;
+
+static STORE: Lazy<Box<dyn Store>> = Lazy::new(|| match &*crate::rate_limit_store() {
+    "postgres" => Box::new(PostgresStore::default()),
+    "memory" => Box::new(MemoryStore::default()),
+    other => {
+        tracing::warn!(store = %other, "unknown rate limit store, falling back to memory");
+        Box::new(MemoryStore::default())
+    }
+});
+
+/// `burst` requests at once, refilled evenly over `period`.
+#[derive(Clone, Copy, Debug)]
+pub struct Quota {
+    pub burst: u32,
+    pub period: StdDuration,
+}
+impl Quota {
+    pub fn per_minute(burst: u32) -> Self {
+        Quota {
+            burst,
+            period: StdDuration::from_secs(60),
+        }
+    }
+    pub fn per_hour(burst: u32) -> Self {
+        Quota {
+            burst,
+            period: StdDuration::from_secs(3600),
+        }
+    }
+    fn tokens_per_sec(&self) -> f64 {
+        self.burst as f64 / self.period.as_secs_f64()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the bucket is full again.
+    pub reset: u64,
+    /// Seconds until the next request would be allowed, zero when this one was.
+    pub retry_after: u64,
+}
+
+/// Refill a bucket holding `tokens` for `elapsed_secs`, then take one token from it if there is one.
+fn take(tokens: f64, elapsed_secs: f64, quota: &Quota) -> (f64, Decision) {
+    let rate = quota.tokens_per_sec();
+    let capacity = quota.burst as f64;
+    let tokens = (tokens + elapsed_secs.max(0.0) * rate).min(capacity);
+    let allowed = tokens >= 1.0;
+    let tokens = if allowed { tokens - 1.0 } else { tokens };
+    let decision = Decision {
+        allowed,
+        limit: quota.burst,
+        remaining: tokens.floor() as u32,
+        reset: ((capacity - tokens) / rate).ceil() as u64,
+        retry_after: if allowed { 0 } else { ((1.0 - tokens) / rate).ceil() as u64 },
+    };
+    (tokens, decision)
+}
+
+pub trait Store: Send + Sync {
+    fn acquire(&self, key: &str, quota: &Quota) -> AppResult<Decision>;
+}
+
+/// Buckets of this process only; every instance enforces the full quota on its own.
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, (f64, DateTime<Utc>)>>,
+}
+impl Store for MemoryStore {
+    fn acquire(&self, key: &str, quota: &Quota) -> AppResult<Decision> {
+        let now = Utc::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > MEMORY_PRUNE_LEN {
+            buckets.retain(|_, (_, updated_at)| *updated_at + Duration::hours(MAX_IDLE_HOURS) > now);
+        }
+        let (tokens, updated_at) = buckets.entry(key.to_owned()).or_insert((quota.burst as f64, now));
+        let elapsed = (now - *updated_at).num_milliseconds() as f64 / 1000.0;
+        let (left, decision) = take(*tokens, elapsed, quota);
+        *tokens = left;
+        *updated_at = now;
+        Ok(decision)
+    }
+}
+
+/// Buckets in `rate_limit_buckets`, shared by every instance using the database.
+#[derive(Default)]
+pub struct PostgresStore {
+    calls: AtomicU64,
+}
+impl Store for PostgresStore {
+    fn acquire(&self, key: &str, quota: &Quota) -> AppResult<Decision> {
+        let now = Utc::now();
+        // The hoop runs on an async worker, and a locked bucket row may take a while.
+        db::run_in_place(|conn| {
+            if self.calls.fetch_add(1, Ordering::Relaxed).is_multiple_of(POSTGRES_PRUNE_EVERY) {
+                diesel::delete(
+                    rate_limit_buckets::table
+                        .filter(rate_limit_buckets::updated_at.lt(now - Duration::hours(MAX_IDLE_HOURS))),
+                )
+                .execute(conn)?;
+            }
+            conn.transaction::<_, crate::Error, _>(|conn| {
+                diesel::insert_into(rate_limit_buckets::table)
+                    .values(RateLimitBucket {
+                        key: key.to_owned(),
+                        tokens: quota.burst as f64,
+                        updated_at: now,
+                    })
+                    .on_conflict_do_nothing()
+                    .execute(conn)?;
+                let bucket = rate_limit_buckets::table
+                    .find(key)
+                    .for_update()
+                    .first::<RateLimitBucket>(conn)?;
+                let elapsed = (now - bucket.updated_at).num_milliseconds() as f64 / 1000.0;
+                let (tokens, decision) = take(bucket.tokens, elapsed, quota);
+                diesel::update(&bucket)
+                    .set((
+                        rate_limit_buckets::tokens.eq(tokens),
+                        rate_limit_buckets::updated_at.eq(now.max(bucket.updated_at)),
+                    ))
+                    .execute(conn)?;
+                Ok(decision)
+            })
+        })
+    }
+}
+
+/// What requests are counted by. `User` and `Token` fall back to the client IP for anonymous requests, so they
+/// only make sense after `set_user_handler`.
+#[derive(Clone, Copy, Debug)]
+pub enum KeyBy {
+    Ip,
+    User,
+    Token,
+}
+
+pub struct RateLimiter {
+    name: &'static str,
+    quota: Quota,
+    key_by: KeyBy,
+}
+impl RateLimiter {
+    /// `name` separates the buckets of different policies, so keep it unique.
+    pub fn new(name: &'static str, quota: Quota) -> Self {
+        RateLimiter {
+            name,
+            quota,
+            key_by: KeyBy::Ip,
+        }
+    }
+    pub fn key_by(mut self, key_by: KeyBy) -> Self {
+        self.key_by = key_by;
+        self
+    }
+    fn key(&self, req: &Request, depot: &Depot) -> Option<String> {
+        let ip = || context::client_ip(req).map(|ip| format!("ip:{}", ip));
+        let user = || context::current_user(depot).map(|user| format!("user:{}", user.id));
+        let token = || context::current_access_token(depot).map(|token| format!("token:{}", token.id));
+        let key = match self.key_by {
+            KeyBy::Ip => ip(),
+            KeyBy::User => user().or_else(ip),
+            KeyBy::Token => token().or_else(user).or_else(ip),
+        };
+        key.map(|key| format!("{}:{}", self.name, key))
+    }
+}
+
+#[async_trait]
+impl Handler for RateLimiter {
+    async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+        let key = match self.key(req, depot) {
+            Some(key) => key,
+            None => {
+                ctrl.call_next(req, depot, res).await;
+                return;
+            }
+        };
+        // A broken store should not take the whole API down with it.
+        let decision = match STORE.acquire(&key, &self.quota) {
+            Ok(decision) => decision,
+            Err(e) => {
+                tracing::error!(error = ?e, key = %key, "rate limit store failed");
+                ctrl.call_next(req, depot, res).await;
+                return;
+            }
+        };
+        let headers = res.headers_mut();
+        headers.insert("ratelimit-limit", HeaderValue::from(decision.limit));
+        headers.insert("ratelimit-remaining", HeaderValue::from(decision.remaining));
+        headers.insert("ratelimit-reset", HeaderValue::from(decision.reset));
+        if let Ok(policy) = HeaderValue::from_str(&format!("{};w={}", self.quota.burst, self.quota.period.as_secs())) {
+            headers.insert("ratelimit-policy", policy);
+        }
+        if decision.allowed {
+            ctrl.call_next(req, depot, res).await;
+        } else {
+            ctrl.skip_rest();
+            res.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(decision.retry_after));
+            context::render_status_json(
+                res,
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limited",
+                "rate limited",
+                format!("too many requests, please retry after {} seconds", decision.retry_after),
+            )
+            .ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two tokens refilled over four seconds, half a token per second.
+    fn quota() -> Quota {
+        Quota {
+            burst: 2,
+            period: StdDuration::from_secs(4),
+        }
+    }
+
+    #[test]
+    fn full_bucket_allows_and_reports_remaining() {
+        let (tokens, decision) = take(2.0, 0.0, &quota());
+        assert_eq!(tokens, 1.0);
+        assert!(decision.allowed);
+        assert_eq!(decision.limit, 2);
+        assert_eq!(decision.remaining, 1);
+        assert_eq!(decision.reset, 2);
+        assert_eq!(decision.retry_after, 0);
+    }
+
+    #[test]
+    fn burst_is_exhausted() {
+        let quota = quota();
+        let (tokens, first) = take(2.0, 0.0, &quota);
+        let (tokens, second) = take(tokens, 0.0, &quota);
+        let (tokens, third) = take(tokens, 0.0, &quota);
+        assert!(first.allowed && second.allowed);
+        assert!(!third.allowed);
+        assert_eq!(tokens, 0.0);
+        assert_eq!(third.remaining, 0);
+        assert_eq!(third.retry_after, 2);
+        assert_eq!(third.reset, 4);
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let quota = quota();
+        let (tokens, decision) = take(0.0, 2.0, &quota);
+        assert!(decision.allowed);
+        assert_eq!(tokens, 0.0);
+        let (tokens, decision) = take(0.0, 1.0, &quota);
+        assert!(!decision.allowed);
+        assert_eq!(tokens, 0.5);
+    }
+
+    #[test]
+    fn refill_is_capped_at_burst() {
+        let (tokens, decision) = take(0.0, 3600.0, &quota());
+        assert!(decision.allowed);
+        assert_eq!(tokens, 1.0);
+        assert_eq!(decision.remaining, 1);
+    }
+
+    #[test]
+    fn negative_elapsed_does_not_drain() {
+        let (tokens, decision) = take(1.0, -10.0, &quota());
+        assert!(decision.allowed);
+        assert_eq!(tokens, 0.0);
+    }
+
+    #[test]
+    fn waits_are_rounded_up() {
+        // 0.25 tokens left: 1.5s until the next one and 3.5s until full.
+        let (tokens, decision) = take(0.0, 0.5, &quota());
+        assert!(!decision.allowed);
+        assert_eq!(tokens, 0.25);
+        assert_eq!(decision.remaining, 0);
+        assert_eq!(decision.retry_after, 2);
+        assert_eq!(decision.reset, 4);
+    }
+}
@@ -1,30 +1,67 @@
 mod account;
+mod admin;
 mod auth;
 mod home;
+mod oauth;
 mod user;
 
 use diesel::prelude::*;
 use salvo::http::StatusCode;
-use salvo::jwt_auth::{CookieFinder, HeaderFinder, JwtAuth, JwtAuthDepotExt, QueryFinder};
+use once_cell::sync::Lazy;
+use salvo::jwt_auth::{
+    CookieFinder, HeaderFinder, JwtAuthDepotExt, JwtAuthState, JwtTokenFinder, QueryFinder, JWT_AUTH_DATA_KEY,
+    JWT_AUTH_STATE_KEY, JWT_AUTH_TOKEN_KEY,
+};
 use salvo::prelude::*;
 use salvo::routing::FlowCtrl;
 use salvo::serve_static::StaticDir;
 use salvo::size_limiter;
 use url::Url;
 
+use crate::csrf::{self, JwtSource};
 use crate::db;
+use crate::rate_limit::{KeyBy, Quota, RateLimiter};
 use crate::models::*;
 use crate::schema::*;
-use crate::{context, AppResult, JwtClaims};
+use crate::{context, i18n, things, AppResult, JwtClaims};
 
-pub fn new_jwt_auth() -> JwtAuth<JwtClaims> {
-    JwtAuth::new(crate::secret_key())
-        .with_finders(vec![
-            Box::new(HeaderFinder::new()),
-            Box::new(QueryFinder::new("jwt_token")),
-            Box::new(CookieFinder::new("jwt_token")),
-        ])
-        .with_response_error(false)
+static JWT_FINDERS: Lazy<Vec<(JwtSource, Box<dyn JwtTokenFinder>)>> = Lazy::new(|| {
+    vec![
+        (JwtSource::Header, Box::new(HeaderFinder::new())),
+        (JwtSource::Query, Box::new(QueryFinder::new("jwt_token"))),
+        (JwtSource::Cookie, Box::new(CookieFinder::new("jwt_token"))),
+    ]
+});
+
+/// Same contract as salvo's `JwtAuth` (the decoded claims and the raw token end up in the depot under its keys),
+/// but verifies with the keyring from `crate::jwt`, so asymmetric keys and `kid` based rotation work. Where the
+/// token was found is kept as `jwt_auth_source` for `csrf::protect`.
+#[handler]
+async fn jwt_auth(req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+    let mut token = None;
+    for (source, finder) in JWT_FINDERS.iter() {
+        token = finder.find_token(req).await;
+        if token.is_some() {
+            depot.insert("jwt_auth_source", *source);
+            break;
+        }
+    }
+    if let Some(token) = token {
+        match crate::jwt::keyring().and_then(|keyring| keyring.decode::<JwtClaims>(&token)) {
+            Ok(data) => {
+                depot.insert(JWT_AUTH_DATA_KEY, data);
+                depot.insert(JWT_AUTH_STATE_KEY, JwtAuthState::Authorized);
+            }
+            Err(e) => {
+                tracing::debug!(error = ?e, "jwt verification failed");
+                depot.insert(JWT_AUTH_STATE_KEY, JwtAuthState::Forbidden);
+            }
+        }
+        depot.insert(JWT_AUTH_TOKEN_KEY, token);
+    } else {
+        depot.insert(JWT_AUTH_STATE_KEY, JwtAuthState::Unauthorized);
+    }
+    ctrl.call_next(req, depot, res).await;
 }
 
 #[handler]
@@ -37,6 +74,62 @@ async fn auth_final(req: &mut Request, depot: &mut Depot, res: &mut Response, ct
     }
 }
 
+/// Language of the status messages: the signed-in user's locale, else the first `Accept-Language` tag.
+#[handler]
+pub async fn localize(req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+    let locale = context::current_user(depot)
+        .map(|user| user.locale.clone())
+        .filter(|locale| !locale.is_empty())
+        .or_else(|| {
+            req.header::<String>("accept-language")
+                .and_then(|v| v.split([',', ';']).next().map(|tag| tag.trim().to_owned()))
+                .filter(|tag| !tag.is_empty() && tag != "*")
+        })
+        .unwrap_or_else(|| i18n::DEFAULT_LOCALE.to_owned());
+    context::with_locale(locale, ctrl.call_next(req, depot, res)).await;
+}
+
+#[handler]
+pub async fn kernel_only(req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+    if !crate::context::current_user(depot).map(|u| u.in_kernel).unwrap_or(false) {
+        ctrl.skip_rest();
+        context::render_access_denied_json(res).ok();
+    } else if !context::has_scope(depot, "admin") {
+        ctrl.skip_rest();
+        context::render_insufficient_scope_json(res, "admin").ok();
+    } else {
+        ctrl.call_next(req, depot, res).await;
+    }
+}
+
+/// Credential management is not available to API tokens, whatever their scopes, nor to impersonation sessions.
+#[handler]
+pub async fn web_session_only(req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+    if context::impersonator(depot).is_some() {
+        ctrl.skip_rest();
+        render_impersonation_denied_json(res).ok();
+    } else if context::current_access_token(depot).map(|t| t.kind == "web").unwrap_or(false) {
+        ctrl.call_next(req, depot, res).await;
+    } else {
+        ctrl.skip_rest();
+        context::render_access_denied_json_with_detail(res, "this action requires a web session").ok();
+    }
+}
+
+/// Support staff impersonating a user can look around, but not do what could lock the user out or destroy data.
+#[handler]
+pub async fn no_impersonation(req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+    if context::impersonator(depot).is_some() {
+        ctrl.skip_rest();
+        render_impersonation_denied_json(res).ok();
+    } else {
+        ctrl.call_next(req, depot, res).await;
+    }
+}
+fn render_impersonation_denied_json(res: &mut Response) -> AppResult<()> {
+    context::render_access_denied_json_with_detail(res, "this action is not allowed while impersonating a user")
+}
+
 #[handler]
 pub async fn set_user_handler(
     req: &mut Request,
@@ -44,51 +137,122 @@ pub async fn set_user_handler(
     res: &mut Response,
     ctrl: &mut FlowCtrl,
 ) -> AppResult<()> {
+    let mut impersonation = None;
     if let Some(data) = depot.jwt_auth_data::<crate::JwtClaims>() {
         // tracing::debug!("set_user_handler, open conn.....");
-        let mut conn = db::connect()?;
-        if let Ok(user) = users::table.find(data.claims.user).first::<User>(&mut conn) {
-            if let Some(token) = depot.jwt_auth_token() {
-                let query = access_tokens::table
-                    .filter(access_tokens::value.eq(&token))
-                    .filter(access_tokens::user_id.eq(user.id));
-                if !user.is_disabled && diesel_exists!(query, &mut conn) {
-                    depot.insert("current_user", user);
+        let (user_id, actor_id) = (data.claims.user, data.claims.act);
+        db::run_in_place(|conn| {
+            if let Ok(user) = users::table.find(user_id).first::<User>(conn) {
+                if let Some(token) = depot.jwt_auth_token() {
+                    let token = access_tokens::table
+                        .filter(access_tokens::value.eq(&token))
+                        .filter(access_tokens::user_id.eq(user.id))
+                        .first::<AccessToken>(conn)
+                        .optional()?;
+                    if let Some(token) = token.filter(|_| !user.is_disabled) {
+                        match actor_id {
+                            None if token.kind != "impersonation" => {
+                                if token.kind == "web" {
+                                    things::session::touch(&token, &context::client_info(req), conn)?;
+                                }
+                                depot.insert("current_access_token", token);
+                                depot.insert("current_user", user);
+                            }
+                            // The actor has to still be allowed to impersonate when using the token.
+                            Some(actor_id) if token.kind == "impersonation" && token.created_by == Some(actor_id) => {
+                                let actor = users::table.find(actor_id).first::<User>(conn).optional()?;
+                                if let Some(actor) = actor.filter(|actor| actor.in_kernel && !actor.is_disabled) {
+                                    impersonation = Some((actor.id, token.clone()));
+                                    depot.insert("impersonator", actor);
+                                    depot.insert("current_access_token", token);
+                                    depot.insert("current_user", user);
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })?;
+    } else if let Some(token) = find_api_token(req) {
+        db::run_in_place(|conn| {
+            let token = if things::oauth::is_access_token(&token) {
+                things::oauth::find_access_token(&token, conn)?
+            } else {
+                things::api_token::find(&token, conn)?
+            };
+            if let Some(token) = token {
+                if let Ok(user) = users::table.find(token.user_id).first::<User>(conn) {
+                    if !user.is_disabled {
+                        things::session::touch(&token, &context::client_info(req), conn)?;
+                        depot.insert("current_access_token", token);
+                        depot.insert("current_user", user);
+                    }
                 }
             }
+            Ok(())
+        })?;
+    }
+    if let Some((actor_id, session)) = impersonation {
+        let method = req.method().to_string();
+        let path = req.uri().path().to_owned();
+        ctrl.call_next(req, depot, res).await;
+        let status_code = res.status_code().unwrap_or(StatusCode::OK).as_u16();
+        tracing::info!(actor_id, user_id = session.user_id, %method, %path, status_code, "impersonated request");
+        // The response is already rendered, so a failure to log can only be reported.
+        let recorded = db::run(move |conn| {
+            things::impersonation::record(actor_id, &session, &method, &path, Some(status_code as i32), conn)
+        })
+        .await;
+        if let Err(e) = recorded {
+            tracing::error!(error = ?e, actor_id, "record impersonated request failed");
         }
-        drop(conn);
     } else {
-        let mut token: String = req
-            .headers()
-            .get("auth_token")
-            .and_then(|v| std::str::from_utf8(v.as_bytes()).ok())
-            .unwrap_or_default()
-            .into();
-        if token.is_empty() {
-            token = req.query::<String>("auth_token").unwrap_or_default();
-        }
+        ctrl.call_next(req, depot, res).await;
     }
-    ctrl.call_next(req, depot, res).await;
     Ok(())
 }
 
+/// Personal API tokens come as `Authorization: Bearer svy_pat_...`, or in the `auth_token` header or query.
+/// OAuth access tokens (`svy_oat_...`) come the same way.
+fn find_api_token(req: &Request) -> Option<String> {
+    req.header::<String>("authorization")
+        .and_then(|v| v.strip_prefix("Bearer ").map(|v| v.trim().to_owned()))
+        .filter(|v| things::api_token::is_api_token(v) || things::oauth::is_access_token(v))
+        .or_else(|| req.header::<String>("auth_token"))
+        .or_else(|| req.query::<String>("auth_token"))
+        .filter(|v| !v.is_empty())
+}
+
 pub fn root() -> Router {
     Router::new()
         .hoop(size_limiter::max_size(1024 * 1024 * 1024))
+        .hoop(localize)
         .get(home::index)
         .push(Router::with_path("health").get(home::index))
-        .push(auth::public_root("auth"))
-        .push(account::public_root("account"))
-        .push(user::public_root("users"))
+        .push(Router::with_path(".well-known/jwks.json").get(home::jwks))
+        .push(Router::with_path(".well-known/openid-configuration").get(home::openid_configuration))
+        .push(auth::public_root("auth").hoop(RateLimiter::new("auth", Quota::per_minute(20))))
+        .push(account::public_root("account").hoop(RateLimiter::new("account", Quota::per_hour(60))))
+        .push(user::public_root("users").hoop(RateLimiter::new("users", Quota::per_minute(60))))
+        .push(oauth::public_root("oauth").hoop(RateLimiter::new("oauth", Quota::per_minute(60))))
         .push(
             Router::new()
-                .hoop(new_jwt_auth())
+                .hoop(jwt_auth)
                 .hoop(set_user_handler)
+                .hoop(localize)
                 .hoop(auth_final)
+                .hoop(csrf::protect)
+                .hoop(RateLimiter::new("authed", Quota::per_minute(600)).key_by(KeyBy::Token))
                 .push(auth::authed_root("auth"))
                 .push(account::authed_root("account"))
                 .push(user::authed_root("users"))
+                .push(oauth::authed_root("oauth"))
+                .push(
+                    admin::authed_root("admin")
+                        .hoop(RateLimiter::new("admin", Quota::per_minute(120)).key_by(KeyBy::User)),
+                )
         )
         .push(
             Router::with_path("<*path>")
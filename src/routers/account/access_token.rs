@@ -1,50 +1,62 @@
-use chrono::{Duration, Utc};
+use chrono::{DateTime, Duration, Utc};
 use diesel::prelude::*;
+use salvo::http::StatusError;
 use salvo::prelude::*;
-use serde::Deserialize;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
 
 use crate::db;
 use crate::models::*;
 use crate::schema::*;
-use crate::utils::validator;
+use crate::things::api_token;
+use crate::utils::{hash_str_sha256, validator};
 use crate::{context, AppResult};
 
+const DEFAULT_EXPIRES_IN_DAYS: i64 = 90;
+const MAX_EXPIRES_IN_DAYS: i64 = 365;
+
 #[handler]
 pub async fn delete(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
-    let mut conn = db::connect()?;
-    delete_record!(
-        req,
-        depot,
-        res,
-        access_tokens,
-        AccessToken,
-        db::delete_access_token,
-        users,
-        User,
-        user_id,
-        "edit",
-        &mut conn
-    );
-    Ok(())
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let token = get_record_by_param!(req, res, AccessToken, access_tokens, conn);
+        if token.user_id != cuser.id || token.kind != "api" {
+            return context::render_parse_param_error_json_with_detail(res, "access token is not correct");
+        }
+        db::delete_access_token(token.id, conn)?;
+        context::render_done_json(res)
+    })
 }
 #[handler]
 pub async fn bulk_delete(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
-    let mut conn = db::connect()?;
-    bulk_delete_records!(
-        req,
-        depot,
-        res,
-        access_tokens,
-        AccessToken,
-        db::delete_access_token,
-        users,
-        User,
-        user_id,
-        "edit",
-        &mut conn
-    );
-    Ok(())
+    let ids = context::parse_ids_from_request(req, "id", "ids").await;
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let tokens = access_tokens::table
+            .filter(access_tokens::id.eq_any(&ids))
+            .filter(access_tokens::user_id.eq(cuser.id))
+            .filter(access_tokens::kind.eq("api"))
+            .get_results::<AccessToken>(conn)?;
+        let mut done_ids = vec![];
+        let mut nerr_ids = vec![];
+        for token in &tokens {
+            if db::delete_access_token(token.id, conn).is_err() {
+                nerr_ids.push(token.id);
+            } else {
+                done_ids.push(token.id);
+            }
+        }
+        let deined_ids = ids
+            .into_iter()
+            .filter(|id| !tokens.iter().any(|token| token.id == *id))
+            .collect::<Vec<_>>();
+        render_bulk_action_json!(
+            res,
+            done_ids,
+            (deined_ids, "denied_access", "denied access", "denied access"),
+            (nerr_ids, "unknown_error", "unknown error", "unknown error")
+        );
+        Ok(())
+    })
 }
 
 #[handler]
@@ -52,22 +64,24 @@ pub async fn list(_req: &mut Request, depot: &mut Depot, res: &mut Response) ->
     let cuser = current_user!(depot, res);
     let query = access_tokens::table
         .filter(access_tokens::user_id.eq(cuser.id))
-        .filter(access_tokens::kind.eq("api"));
-    let mut conn = db::connect()?;
-    res.render(Json(query.get_results::<AccessToken>(&mut conn)?));
-    Ok(())
+        .filter(access_tokens::kind.eq("api"))
+        .order(access_tokens::id.asc());
+    db::run_in_place(|conn| {
+        res.render(Json(query.get_results::<AccessToken>(conn)?));
+        Ok(())
+    })
 }
 
+/// Create a personal API token. The plain value is only returned here; what is stored is its hash.
 #[handler]
 pub async fn create(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
     #[derive(Deserialize, Debug)]
     struct PostedData {
-        user_id: i64,
         #[serde(default)]
         name: String,
         #[serde(default)]
-        value: String,
-        device: Option<String>,
+        scopes: Vec<String>,
+        expires_in_days: Option<i64>,
     }
     let pdata = parse_posted_data!(req, res, PostedData);
     if pdata.name.is_empty() {
@@ -76,84 +90,116 @@ pub async fn create(req: &mut Request, depot: &mut Depot, res: &mut Response) ->
     if let Err(e) = validator::validate_generic_name(&pdata.name) {
         return context::render_parse_param_error_json_with_detail(res, e);
     }
-    let cuser = current_user!(depot, res);
-    let exp = Utc::now() + Duration::days(7);
-    let jwt_token = crate::create_jwt_token(cuser, &exp);
-    if jwt_token.is_err() {
-        return context::render_internal_server_error_json_with_detail(res, "create jwt token error");
+    if let Err(e) = api_token::validate_scopes(&pdata.scopes) {
+        return context::render_parse_data_error_json_with_detail(res, e);
     }
-    let jwt_token = jwt_token.unwrap();
-    let mut conn = db::connect()?;
-    let output = conn.transaction::<_, crate::Error, _>(|conn| {
-        let query = access_tokens::table
-            .filter(access_tokens::user_id.eq(cuser.id))
-            .filter(access_tokens::name.eq(&pdata.name));
-        if diesel_exists!(query, conn) {
-            return Err(StatusError::conflict()
-                .with_summary("token conflict")
-                .with_detail("this name is already taken, please try another.")
-                .into());
+    let expires_in_days = pdata.expires_in_days.unwrap_or(DEFAULT_EXPIRES_IN_DAYS);
+    if !(1..=MAX_EXPIRES_IN_DAYS).contains(&expires_in_days) {
+        return context::render_parse_data_error_json_with_detail(
+            res,
+            format!("expires_in_days must be between 1 and {}", MAX_EXPIRES_IN_DAYS),
+        );
+    }
+    let cuser = current_user!(depot, res);
+    let value = api_token::generate();
+    db::run_in_place(|conn| {
+        let token = conn.transaction::<_, crate::Error, _>(|conn| {
+            let query = access_tokens::table
+                .filter(access_tokens::user_id.eq(cuser.id))
+                .filter(access_tokens::kind.eq("api"))
+                .filter(access_tokens::name.eq(&pdata.name));
+            if diesel_exists!(query, conn) {
+                return Err(StatusError::conflict()
+                    .with_summary("token conflict")
+                    .with_detail("this name is already taken, please try another.")
+                    .into());
+            }
+            let token = NewAccessToken {
+                user_id: cuser.id,
+                name: Some(&pdata.name),
+                value: &hash_str_sha256(&value),
+                kind: "api",
+                device: None,
+                expired_at: Utc::now() + Duration::days(expires_in_days),
+                ip_address: None,
+                last_seen_at: None,
+                family_id: None,
+                scopes: &pdata.scopes,
+                oauth_client_id: None,
+                updated_by: Some(cuser.id),
+                created_by: Some(cuser.id),
+            };
+            Ok(diesel::insert_into(access_tokens::table)
+                .values(&token)
+                .get_result::<AccessToken>(conn)?)
+        })?;
+
+        #[derive(Serialize, Debug)]
+        struct ResultData<'a> {
+            id: i64,
+            name: Option<&'a str>,
+            scopes: &'a [String],
+            expired_at: DateTime<Utc>,
+            value: &'a str,
         }
-        let token = NewAccessToken {
-            user_id: cuser.id,
-            name: Some(&pdata.name),
-            value: jwt_token.split('.').collect::<Vec<&str>>()[2],
-            kind: "api",
-            device: None,
-            expired_at: exp,
-            updated_by: Some(cuser.id),
-            created_by: Some(cuser.id),
-        };
-        diesel::insert_into(access_tokens::table).values(&token).execute(conn)?;
-        let mut output = HashMap::new();
-        output.insert("value", token.value);
-        Ok(output)
-    })?;
-    res.render(Json(output));
-    Ok(())
+        res.render(Json(ResultData {
+            id: token.id,
+            name: token.name.as_deref(),
+            scopes: &token.scopes,
+            expired_at: token.expired_at,
+            value: &value,
+        }));
+        Ok(())
+    })
 }
 
 #[handler]
 pub async fn update(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
     #[derive(Deserialize, Debug)]
     struct PostedData {
-        user_id: i64,
         #[serde(default)]
         name: String,
-        #[serde(default)]
-        value: String,
-        device: Option<String>,
+        scopes: Option<Vec<String>>,
     }
     let pdata = parse_posted_data!(req, res, PostedData);
     let cuser = current_user!(depot, res);
-    let mut conn = db::connect()?;
-    let exist_token = get_record_by_param!(req, res, AccessToken, access_tokens, &mut conn);
-    if exist_token.user_id != cuser.id {
-        return context::render_parse_param_error_json_with_detail(res, "access token is not correct");
-    }
-    if pdata.name.is_empty() {
-        return context::render_parse_param_error_json_with_detail(res, "access token's name is not provide");
-    }
-    let token = conn.transaction::<AccessToken, crate::Error, _>(|conn| {
-        let query = access_tokens::table
-            .filter(access_tokens::user_id.eq(cuser.id))
-            .filter(access_tokens::id.ne(exist_token.id))
-            .filter(access_tokens::name.eq(&pdata.name));
-        if diesel_exists!(query, conn) {
-            return Err(StatusError::conflict()
-                .with_summary("token conflict")
-                .with_detail("this name is already taken, please try another.")
-                .into());
+    db::run_in_place(|conn| {
+        let exist_token = get_record_by_param!(req, res, AccessToken, access_tokens, conn);
+        if exist_token.user_id != cuser.id || exist_token.kind != "api" {
+            return context::render_parse_param_error_json_with_detail(res, "access token is not correct");
+        }
+        if pdata.name.is_empty() {
+            return context::render_parse_param_error_json_with_detail(res, "access token's name is not provide");
+        }
+        if let Err(e) = validator::validate_generic_name(&pdata.name) {
+            return context::render_parse_param_error_json_with_detail(res, e);
+        }
+        if let Some(Err(e)) = pdata.scopes.as_deref().map(api_token::validate_scopes) {
+            return context::render_parse_data_error_json_with_detail(res, e);
         }
-        let token = diesel::update(&exist_token)
-            .set((
-                access_tokens::name.eq(&pdata.name),
-                access_tokens::updated_by.eq(cuser.id),
-                access_tokens::updated_at.eq(Utc::now()),
-            ))
-            .get_result::<AccessToken>(conn)?;
-        Ok(token)
-    })?;
-    res.render(Json(token));
-    Ok(())
+        let token = conn.transaction::<AccessToken, crate::Error, _>(|conn| {
+            let query = access_tokens::table
+                .filter(access_tokens::user_id.eq(cuser.id))
+                .filter(access_tokens::kind.eq("api"))
+                .filter(access_tokens::id.ne(exist_token.id))
+                .filter(access_tokens::name.eq(&pdata.name));
+            if diesel_exists!(query, conn) {
+                return Err(StatusError::conflict()
+                    .with_summary("token conflict")
+                    .with_detail("this name is already taken, please try another.")
+                    .into());
+            }
+            let token = diesel::update(&exist_token)
+                .set((
+                    access_tokens::name.eq(&pdata.name),
+                    access_tokens::scopes.eq(pdata.scopes.as_ref().unwrap_or(&exist_token.scopes)),
+                    access_tokens::updated_by.eq(cuser.id),
+                    access_tokens::updated_at.eq(Utc::now()),
+                ))
+                .get_result::<AccessToken>(conn)?;
+            Ok(token)
+        })?;
+        res.render(Json(token));
+        Ok(())
+    })
 }
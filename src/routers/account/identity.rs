@@ -0,0 +1,94 @@
+use diesel::prelude::*;
+use salvo::http::StatusCode;
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::db;
+use crate::models::*;
+use crate::oidc;
+use crate::routers::auth::render_oidc_state_invalid_json;
+use crate::schema::*;
+use crate::things::user_identity;
+use crate::{context, AppResult};
+
+#[handler]
+pub async fn list(_req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let identities = user_identities::table
+            .filter(user_identities::user_id.eq(cuser.id))
+            .order(user_identities::id.asc())
+            .get_results::<UserIdentity>(conn)?;
+        res.render(Json(identities));
+        Ok(())
+    })
+}
+
+/// Start linking an identity of `provider` to the current user. The flow is the same as signing in, except that
+/// the redirect page posts `code` and `state` to `finish` here instead of to `auth/oidc/<provider>/callback`.
+#[handler]
+pub async fn start(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let cuser = current_user!(depot, res);
+    let provider = match oidc::provider(req.param::<String>("provider").unwrap_or_default().as_str()) {
+        Some(provider) => provider,
+        None => return context::render_not_found_json(res),
+    };
+    #[derive(Serialize, Debug)]
+    struct ResultData {
+        authorization_url: String,
+    }
+    let (authorization_url, _) = oidc::authorization_url(&provider, cuser.id).await?;
+    res.render(Json(ResultData { authorization_url }));
+    Ok(())
+}
+
+#[handler]
+pub async fn finish(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    #[derive(Deserialize, Debug)]
+    struct PostedData {
+        code: String,
+        state: String,
+    }
+    let pdata = parse_posted_data!(req, res, PostedData);
+    let cuser = current_user!(depot, res);
+    let provider = match oidc::provider(req.param::<String>("provider").unwrap_or_default().as_str()) {
+        Some(provider) => provider,
+        None => return context::render_not_found_json(res),
+    };
+    let identity = match oidc::finish(&provider, &pdata.state, &pdata.code).await? {
+        Some((user_id, identity)) if user_id == cuser.id => identity,
+        _ => return render_oidc_state_invalid_json(res),
+    };
+    db::run_in_place(|conn| {
+        if !user_identity::link(cuser, &provider.name, &identity, conn)? {
+            return context::render_status_json(
+                res,
+                StatusCode::CONFLICT,
+                "identity_conflict",
+                "identity conflict",
+                "This account of the provider is already linked to another user.",
+            );
+        }
+        let identities = user_identities::table
+            .filter(user_identities::user_id.eq(cuser.id))
+            .order(user_identities::id.asc())
+            .get_results::<UserIdentity>(conn)?;
+        res.render(Json(identities));
+        Ok(())
+    })
+}
+
+/// Unlink an identity. The user can still sign in with a password, which users created by a provider can set
+/// with the reset password flow.
+#[handler]
+pub async fn delete(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let identity = get_record_by_param!(req, res, UserIdentity, user_identities, conn);
+        if identity.user_id != cuser.id {
+            return context::render_parse_param_error_json_with_detail(res, "identity is not correct");
+        }
+        db::delete_user_identity(identity.id, conn)?;
+        context::render_done_json(res)
+    })
+}
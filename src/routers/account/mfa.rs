@@ -0,0 +1,143 @@
+use chrono::Utc;
+use diesel::prelude::*;
+use salvo::http::StatusCode;
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::db;
+use crate::schema::*;
+use crate::utils::{password, totp};
+use crate::{context, AppResult};
+
+#[derive(Serialize, Debug)]
+struct RecoveryCodesData {
+    recovery_codes: Vec<String>,
+}
+
+/// Start enrollment: store a new pending secret and return it with the `otpauth://` URI for the QR code.
+/// 2FA is not enforced until the first code is confirmed.
+#[handler]
+pub async fn setup(_req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let cuser = current_user!(depot, res);
+    if cuser.totp_enabled {
+        return context::render_status_json(
+            res,
+            StatusCode::CONFLICT,
+            "totp_enabled",
+            "totp enabled",
+            "Two-factor authentication is already enabled.",
+        );
+    }
+    let secret = totp::generate_secret();
+    let otpauth_uri = totp::otpauth_uri(&secret, &cuser.ident_name)?;
+    db::run_in_place(|conn| {
+        diesel::update(cuser)
+            .set((
+                users::totp_secret.eq(&secret),
+                users::updated_by.eq(cuser.id),
+                users::updated_at.eq(Utc::now()),
+            ))
+            .execute(conn)?;
+
+        #[derive(Serialize, Debug)]
+        struct ResultData {
+            secret: String,
+            otpauth_uri: String,
+        }
+        res.render(Json(ResultData { secret, otpauth_uri }));
+        Ok(())
+    })
+}
+
+#[handler]
+pub async fn confirm(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    #[derive(Deserialize, Debug)]
+    struct PostedData {
+        #[serde(default)]
+        code: String,
+    }
+    let pdata = parse_posted_data!(req, res, PostedData);
+    let cuser = current_user!(depot, res);
+    if cuser.totp_enabled {
+        return context::render_status_json(
+            res,
+            StatusCode::CONFLICT,
+            "totp_enabled",
+            "totp enabled",
+            "Two-factor authentication is already enabled.",
+        );
+    }
+    if cuser.totp_secret.is_none() {
+        return context::render_parse_data_error_json_with_detail(res, "totp setup is not started");
+    }
+    if !db::run_in_place(|conn| cuser.consume_totp_code(&pdata.code, conn))? {
+        return context::render_status_json(
+            res,
+            StatusCode::BAD_REQUEST,
+            "validate_failed",
+            "validate failed",
+            "Incorrect verification code.",
+        );
+    }
+    db::run_in_place(|conn| {
+        let recovery_codes = conn.transaction::<_, crate::Error, _>(|conn| {
+            diesel::update(cuser)
+                .set((
+                    users::totp_enabled.eq(true),
+                    users::updated_by.eq(cuser.id),
+                    users::updated_at.eq(Utc::now()),
+                ))
+                .execute(conn)?;
+            cuser.regenerate_recovery_codes(conn)
+        })?;
+        res.render(Json(RecoveryCodesData { recovery_codes }));
+        Ok(())
+    })
+}
+
+#[derive(Deserialize, Debug)]
+struct PasswordData {
+    #[serde(default)]
+    password: String,
+}
+
+#[handler]
+pub async fn disable(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let pdata = parse_posted_data!(req, res, PasswordData);
+    let cuser = current_user!(depot, res);
+    if !password::compare_async(&pdata.password, &cuser.password).await {
+        return context::render_parse_data_error_json_with_detail(res, "password is not correct");
+    }
+    db::run_in_place(|conn| {
+        conn.transaction::<_, crate::Error, _>(|conn| {
+            diesel::update(cuser)
+                .set((
+                    users::totp_secret.eq(None::<String>),
+                    users::totp_enabled.eq(false),
+                    users::updated_by.eq(cuser.id),
+                    users::updated_at.eq(Utc::now()),
+                ))
+                .execute(conn)?;
+            diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(cuser.id))).execute(conn)?;
+            Ok(())
+        })?;
+        context::render_done_json(res)
+    })
+}
+
+#[handler]
+pub async fn regenerate_recovery_codes(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let pdata = parse_posted_data!(req, res, PasswordData);
+    let cuser = current_user!(depot, res);
+    if !cuser.totp_enabled {
+        return context::render_parse_data_error_json_with_detail(res, "two-factor authentication is not enabled");
+    }
+    if !password::compare_async(&pdata.password, &cuser.password).await {
+        return context::render_parse_data_error_json_with_detail(res, "password is not correct");
+    }
+    db::run_in_place(|conn| {
+        let recovery_codes = cuser.regenerate_recovery_codes(conn)?;
+        res.render(Json(RecoveryCodesData { recovery_codes }));
+        Ok(())
+    })
+}
@@ -8,89 +8,103 @@ use crate::{context, db, AppResult};
 
 #[handler]
 pub async fn show(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
-    let mut conn = db::connect()?;
-    show_record!(req, depot, res, Notification, notifications, &mut conn);
-    Ok(())
+    require_scope!(depot, res, "notifications:read");
+    db::run_in_place(|conn| {
+        show_record!(req, depot, res, Notification, notifications, conn);
+        Ok(())
+    })
 }
 #[handler]
 pub async fn delete(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
-    let mut conn = db::connect()?;
-    delete_record!(
-        req,
-        depot,
-        res,
-        notifications,
-        Notification,
-        db::delete_notification,
-        &mut conn
-    );
-    Ok(())
+    require_scope!(depot, res, "notifications:write");
+    db::run_in_place(|conn| {
+        delete_record!(
+            req,
+            depot,
+            res,
+            notifications,
+            Notification,
+            db::delete_notification,
+            conn
+        );
+        Ok(())
+    })
 }
 #[handler]
 pub async fn bulk_delete(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
-    let mut conn = db::connect()?;
-    bulk_delete_records!(
-        req,
-        depot,
-        res,
-        notifications,
-        Notification,
-        db::delete_notification,
-        &mut conn
-    );
-    Ok(())
+    require_scope!(depot, res, "notifications:write");
+    let ids = context::parse_ids_from_request(req, "id", "ids").await;
+    db::run_in_place(|conn| {
+        bulk_delete_records!(
+            ids,
+            depot,
+            res,
+            notifications,
+            Notification,
+            db::delete_notification,
+            conn
+        );
+        Ok(())
+    })
 }
 
 #[handler]
 pub async fn list(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    require_scope!(depot, res, "notifications:read");
     let cuser = current_user!(depot, res);
     let query = notifications::table.filter(notifications::owner_id.eq(cuser.id));
-    let mut conn = db::connect()?;
-    list_records!(
-        req,
-        res,
-        Notification,
-        query,
-        "updated_at desc",
-        NOTIFICATION_FILTER_FIELDS.clone(),
-        NOTIFICATION_JOINED_OPTIONS.clone(),
-        ID_NAME_SEARCH_TMPL,
-        &mut conn
-    );
-    Ok(())
+    db::run_in_place(|conn| {
+        list_records!(
+            req,
+            depot,
+            res,
+            Notification,
+            query,
+            "updated_at desc",
+            NOTIFICATION_FILTER_FIELDS.clone(),
+            NOTIFICATION_JOINED_OPTIONS.clone(),
+            ID_NAME_SEARCH_TMPL,
+            conn
+        );
+        Ok(())
+    })
 }
 #[handler]
 pub async fn mark_read(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    require_scope!(depot, res, "notifications:write");
     let cuser = current_user!(depot, res);
     let notification_id: i64 = req.query("id").or_else(|| req.query("notification_id")).unwrap_or(0);
-    let mut conn = db::connect()?;
-    if notification_id > 0 {
-        diesel::update(
-            notifications::table
-                .filter(notifications::id.eq(notification_id))
-                .filter(notifications::owner_id.eq(cuser.id)),
-        )
-        .set((
-            notifications::is_read.eq(true),
-            notifications::updated_by.eq(cuser.id),
-            notifications::updated_at.eq(Utc::now()),
-        ))
-        .execute(&mut conn)?;
-    }
+    db::run_in_place(|conn| {
+        if notification_id > 0 {
+            diesel::update(
+                notifications::table
+                    .filter(notifications::id.eq(notification_id))
+                    .filter(notifications::owner_id.eq(cuser.id)),
+            )
+            .set((
+                notifications::is_read.eq(true),
+                notifications::updated_by.eq(cuser.id),
+                notifications::updated_at.eq(Utc::now()),
+            ))
+            .execute(conn)?;
+        }
     
-    context::render_done_json(res)
+        context::render_done_json(res)
+    })
 }
 #[handler]
 pub async fn mark_all_read(_req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    require_scope!(depot, res, "notifications:write");
     let cuser = current_user!(depot, res);
-    let mut conn = db::connect()?;
-    diesel::update(notifications::table.filter(notifications::owner_id.eq(cuser.id)))
-        .filter(notifications::is_read.eq(false))
-        .set((
-            notifications::is_read.eq(true),
-            notifications::updated_by.eq(cuser.id),
-            notifications::updated_at.eq(Utc::now()),
-        ))
-        .execute(&mut conn)?;
-    context::render_done_json(res)
+    db::run_in_place(|conn| {
+        diesel::update(notifications::table.filter(notifications::owner_id.eq(cuser.id)))
+            .filter(notifications::is_read.eq(false))
+            .set((
+                notifications::is_read.eq(true),
+                notifications::updated_by.eq(cuser.id),
+                notifications::updated_at.eq(Utc::now()),
+            ))
+            .execute(conn)?;
+        context::render_done_json(res)
+    })
 }
@@ -0,0 +1,47 @@
+use diesel::prelude::*;
+use salvo::prelude::*;
+use serde::Serialize;
+
+use crate::db;
+use crate::models::*;
+use crate::schema::*;
+use crate::things::oauth;
+use crate::{context, AppResult};
+
+/// Apps the user has authorized with OAuth, for the account settings page.
+#[handler]
+pub async fn list(_req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    #[derive(Serialize, Debug)]
+    struct ResultData {
+        #[serde(flatten)]
+        consent: OauthConsent,
+        client: OauthClient,
+    }
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let consents = oauth_consents::table
+            .inner_join(oauth_clients::table.on(oauth_clients::id.eq(oauth_consents::oauth_client_id)))
+            .filter(oauth_consents::user_id.eq(cuser.id))
+            .order(oauth_consents::id.asc())
+            .get_results::<(OauthConsent, OauthClient)>(conn)?
+            .into_iter()
+            .map(|(consent, client)| ResultData { consent, client })
+            .collect::<Vec<_>>();
+        res.render(Json(consents));
+        Ok(())
+    })
+}
+
+/// Revoke an app's access: its tokens stop working and it has to ask for consent again.
+#[handler]
+pub async fn delete(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let consent = get_record_by_param!(req, res, OauthConsent, oauth_consents, conn);
+        if consent.user_id != cuser.id {
+            return context::render_parse_param_error_json_with_detail(res, "consent is not correct");
+        }
+        oauth::revoke_consent(&consent, conn)?;
+        context::render_done_json(res)
+    })
+}
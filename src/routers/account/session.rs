@@ -0,0 +1,84 @@
+use chrono::{DateTime, Utc};
+use diesel::prelude::*;
+use salvo::prelude::*;
+use serde::Serialize;
+
+use crate::db;
+use crate::models::*;
+use crate::schema::*;
+use crate::things::session;
+use crate::{context, AppResult};
+
+#[derive(Serialize, Debug)]
+struct SessionData<'a> {
+    id: i64,
+    device: Option<&'a str>,
+    ip_address: Option<&'a str>,
+    last_seen_at: Option<DateTime<Utc>>,
+    created_at: DateTime<Utc>,
+    current: bool,
+}
+
+/// Web sessions of the current user, most recently used first. Legacy rows without a refresh token family are
+/// only listed while their JWT is still valid.
+#[handler]
+pub async fn list(_req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let cuser = current_user!(depot, res);
+    let current_id = context::current_access_token(depot).map(|token| token.id);
+    db::run_in_place(|conn| {
+        let sessions = access_tokens::table
+            .filter(access_tokens::user_id.eq(cuser.id))
+            .filter(access_tokens::kind.eq("web"))
+            .filter(
+                access_tokens::family_id
+                    .is_not_null()
+                    .or(access_tokens::expired_at.gt(Utc::now())),
+            )
+            .order(access_tokens::last_seen_at.desc().nulls_last())
+            .get_results::<AccessToken>(conn)?;
+        let sessions = sessions
+            .iter()
+            .map(|token| SessionData {
+                id: token.id,
+                device: token.device.as_deref(),
+                ip_address: token.ip_address.as_deref(),
+                last_seen_at: token.last_seen_at,
+                created_at: token.created_at,
+                current: Some(token.id) == current_id,
+            })
+            .collect::<Vec<_>>();
+        res.render(Json(sessions));
+        Ok(())
+    })
+}
+
+#[handler]
+pub async fn delete(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let token = get_record_by_param!(req, res, AccessToken, access_tokens, conn);
+        if token.user_id != cuser.id || token.kind != "web" {
+            return context::render_parse_param_error_json_with_detail(res, "session is not correct");
+        }
+        session::revoke(&token, conn)?;
+        context::render_done_json(res)
+    })
+}
+
+/// Log out everywhere else: revoke every web session of the user except the one making this request.
+#[handler]
+pub async fn revoke_others(_req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let cuser = current_user!(depot, res);
+    let current_id = context::current_access_token(depot).map(|token| token.id).unwrap_or_default();
+    db::run_in_place(|conn| {
+        let others = access_tokens::table
+            .filter(access_tokens::user_id.eq(cuser.id))
+            .filter(access_tokens::kind.eq("web"))
+            .filter(access_tokens::id.ne(current_id))
+            .get_results::<AccessToken>(conn)?;
+        for token in &others {
+            session::revoke(token, conn)?;
+        }
+        context::render_done_json(res)
+    })
+}
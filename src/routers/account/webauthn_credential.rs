@@ -0,0 +1,160 @@
+use chrono::Utc;
+use diesel::prelude::*;
+use salvo::http::{StatusCode, StatusError};
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+use webauthn_rs::prelude::{CreationChallengeResponse, CredentialID, RegisterPublicKeyCredential};
+
+use crate::db;
+use crate::models::*;
+use crate::schema::*;
+use crate::utils::validator;
+use crate::webauthn::{self, REGISTRATIONS, WEBAUTHN};
+use crate::{context, AppResult};
+
+#[handler]
+pub async fn list(_req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let credentials = webauthn_credentials::table
+            .filter(webauthn_credentials::user_id.eq(cuser.id))
+            .order(webauthn_credentials::id.asc())
+            .get_results::<WebauthnCredential>(conn)?;
+        res.render(Json(credentials));
+        Ok(())
+    })
+}
+
+#[handler]
+pub async fn start_registration(_req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let exclude_credentials = webauthn_credentials::table
+            .filter(webauthn_credentials::user_id.eq(cuser.id))
+            .get_results::<WebauthnCredential>(conn)?
+            .iter()
+            .map(|credential| Ok(webauthn::load_passkey(credential)?.cred_id().clone()))
+            .collect::<AppResult<Vec<CredentialID>>>()?;
+        let (options, state) = WEBAUTHN.start_passkey_registration(
+            webauthn::user_unique_id(cuser.id),
+            &cuser.ident_name,
+            &cuser.display_name,
+            Some(exclude_credentials),
+        )?;
+
+        #[derive(Serialize, Debug)]
+        struct ResultData {
+            challenge_id: String,
+            options: CreationChallengeResponse,
+        }
+        res.render(Json(ResultData {
+            challenge_id: REGISTRATIONS.insert(cuser.id, state),
+            options,
+        }));
+        Ok(())
+    })
+}
+
+#[handler]
+pub async fn finish_registration(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    #[derive(Deserialize, Debug)]
+    struct PostedData {
+        challenge_id: String,
+        #[serde(default)]
+        name: String,
+        credential: RegisterPublicKeyCredential,
+    }
+    let pdata = parse_posted_data!(req, res, PostedData);
+    let cuser = current_user!(depot, res);
+    let name = if pdata.name.is_empty() { "Passkey" } else { &pdata.name };
+    if let Err(e) = validator::validate_generic_name(name) {
+        return context::render_parse_data_error_json_with_detail(res, e);
+    }
+    let state = match REGISTRATIONS.take(&pdata.challenge_id) {
+        Some((user_id, state)) if user_id == cuser.id => state,
+        _ => return context::render_parse_data_error_json_with_detail(res, "challenge is expired or not found"),
+    };
+    let passkey = match WEBAUTHN.finish_passkey_registration(&pdata.credential, &state) {
+        Ok(passkey) => passkey,
+        Err(e) => {
+            tracing::info!(error = ?e, user_id = cuser.id, "webauthn registration failed");
+            return context::render_status_json(
+                res,
+                StatusCode::BAD_REQUEST,
+                "webauthn_failed",
+                "webauthn failed",
+                "The passkey could not be verified.",
+            );
+        }
+    };
+    let credential_id = webauthn::encode_credential_id(passkey.cred_id().as_ref());
+    db::run_in_place(|conn| {
+        let credential = conn.transaction::<_, crate::Error, _>(|conn| {
+            let query = webauthn_credentials::table.filter(webauthn_credentials::credential_id.eq(&credential_id));
+            if diesel_exists!(query, conn) {
+                return Err(StatusError::conflict()
+                    .with_summary("credential conflict")
+                    .with_detail("this passkey is already registered.")
+                    .into());
+            }
+            let credential = NewWebauthnCredential {
+                user_id: cuser.id,
+                credential_id: &credential_id,
+                name,
+                passkey: serde_json::to_value(&passkey)?,
+                updated_by: Some(cuser.id),
+                created_by: Some(cuser.id),
+            };
+            Ok(diesel::insert_into(webauthn_credentials::table)
+                .values(&credential)
+                .get_result::<WebauthnCredential>(conn)?)
+        })?;
+        res.render(Json(credential));
+        Ok(())
+    })
+}
+
+#[handler]
+pub async fn update(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    #[derive(Deserialize, Debug)]
+    struct PostedData {
+        #[serde(default)]
+        name: String,
+    }
+    let pdata = parse_posted_data!(req, res, PostedData);
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let exist_credential = get_record_by_param!(req, res, WebauthnCredential, webauthn_credentials, conn);
+        if exist_credential.user_id != cuser.id {
+            return context::render_parse_param_error_json_with_detail(res, "credential is not correct");
+        }
+        if pdata.name.is_empty() {
+            return context::render_parse_data_error_json_with_detail(res, "credential's name is not provide");
+        }
+        if let Err(e) = validator::validate_generic_name(&pdata.name) {
+            return context::render_parse_data_error_json_with_detail(res, e);
+        }
+        let credential = diesel::update(&exist_credential)
+            .set((
+                webauthn_credentials::name.eq(&pdata.name),
+                webauthn_credentials::updated_by.eq(cuser.id),
+                webauthn_credentials::updated_at.eq(Utc::now()),
+            ))
+            .get_result::<WebauthnCredential>(conn)?;
+        res.render(Json(credential));
+        Ok(())
+    })
+}
+
+#[handler]
+pub async fn delete(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let credential = get_record_by_param!(req, res, WebauthnCredential, webauthn_credentials, conn);
+        if credential.user_id != cuser.id {
+            return context::render_parse_param_error_json_with_detail(res, "credential is not correct");
+        }
+        db::delete_webauthn_credential(credential.id, conn)?;
+        context::render_done_json(res)
+    })
+}
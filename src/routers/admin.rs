@@ -0,0 +1,53 @@
+use salvo::prelude::*;
+
+pub mod db_pool;
+pub mod email_outbox;
+pub mod email_template;
+pub mod impersonation_log;
+pub mod oauth_client;
+pub mod role;
+
+pub fn authed_root(path: impl Into<String>) -> Router {
+    Router::with_path(path)
+        .hoop(super::kernel_only)
+        .push(Router::with_path("db_pool").get(db_pool::show))
+        .push(
+            Router::with_path("email_outbox")
+                .get(email_outbox::list)
+                .push(Router::with_path("retry").post(email_outbox::retry)),
+        )
+        .push(
+            Router::with_path("email_templates")
+                .get(email_template::list)
+                .push(Router::with_path("preview").post(email_template::preview)),
+        )
+        .push(Router::with_path("impersonation_logs").get(impersonation_log::list))
+        .push(
+            Router::with_path("oauth_clients")
+                .get(oauth_client::list)
+                .post(oauth_client::create)
+                .push(
+                    Router::with_path(r"<id:/\d+/>")
+                        .patch(oauth_client::update)
+                        .delete(oauth_client::delete)
+                        .push(Router::with_path("rotate_secret").post(oauth_client::rotate_secret)),
+                ),
+        )
+        .push(
+            Router::with_path("roles")
+                .get(role::list)
+                .post(role::create)
+                .push(
+                    Router::with_path(r"<id:/\d+/>")
+                        .get(role::show)
+                        .patch(role::update)
+                        .delete(role::delete)
+                        .push(
+                            Router::with_path("users")
+                                .get(role::list_users)
+                                .post(role::add_user)
+                                .push(Router::with_path(r"<user_id:/\d+/>").delete(role::remove_user)),
+                        ),
+                ),
+        )
+}
@@ -0,0 +1,11 @@
+use salvo::prelude::*;
+
+use crate::{db, AppResult};
+
+/// Connections in use and idle, plus how often and how long requests waited for one. A growing
+/// `acquire_timeouts` means `DATABASE_CONNS` is too small for the load.
+#[handler]
+pub async fn show(res: &mut Response) -> AppResult<()> {
+    res.render(Json(db::pool_status()));
+    Ok(())
+}
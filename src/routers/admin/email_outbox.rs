@@ -0,0 +1,43 @@
+use diesel::prelude::*;
+use salvo::prelude::*;
+
+use crate::db;
+use crate::email::outbox;
+use crate::models::*;
+use crate::schema::*;
+use crate::AppResult;
+
+/// List mails which failed at least once and are not delivered yet, including dead letters.
+#[handler]
+pub async fn list(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let query = email_outbox::table
+        .filter(email_outbox::status.ne(outbox::STATUS_SENT))
+        .filter(email_outbox::last_error.is_not_null());
+    db::run_in_place(|conn| {
+        list_records!(
+            req,
+            depot,
+            res,
+            EmailOutbox,
+            query,
+            "updated_at desc",
+            EMAIL_OUTBOX_FILTER_FIELDS.clone(),
+            EMAIL_OUTBOX_JOINED_OPTIONS.clone(),
+            ID_SUBJECT_SEARCH_TMPL,
+            conn
+        );
+        Ok(())
+    })
+}
+
+#[handler]
+pub async fn retry(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let ids = crate::context::parse_ids_from_request(req, "id", "ids").await;
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let records = outbox::retry(&ids, cuser.id, conn)?;
+        let done_ids = records.iter().map(|r| r.id).collect::<Vec<_>>();
+        render_bulk_action_json!(res, done_ids);
+        Ok(())
+    })
+}
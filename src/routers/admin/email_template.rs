@@ -0,0 +1,38 @@
+use salvo::prelude::*;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::{context, i18n, templates, AppResult};
+
+#[handler]
+pub async fn list(res: &mut Response) -> AppResult<()> {
+    res.render(Json(templates::names()));
+    Ok(())
+}
+
+/// Render any registered template with posted sample context, the same way it would be sent.
+#[handler]
+pub async fn preview(req: &mut Request, res: &mut Response) -> AppResult<()> {
+    #[derive(Deserialize, Debug)]
+    struct PostedData {
+        template: String,
+        #[serde(default)]
+        locale: Option<String>,
+        #[serde(default)]
+        format: Option<String>,
+        #[serde(default)]
+        context: Value,
+    }
+    let pdata = parse_posted_data!(req, res, PostedData);
+    if !templates::exists(&pdata.template) {
+        return context::render_not_found_json_with_detail(res, "template is not found");
+    }
+    let locale = pdata.locale.as_deref().unwrap_or(i18n::DEFAULT_LOCALE);
+    let (html, text) = crate::email::render_bodies(&pdata.template, locale, &pdata.context)?;
+    match pdata.format.as_deref() {
+        None | Some("html") => res.render(Text::Html(html)),
+        Some("text") => res.render(Text::Plain(text)),
+        Some(_) => return context::render_parse_data_error_json_with_detail(res, "format must be html or text"),
+    }
+    Ok(())
+}
@@ -0,0 +1,27 @@
+use salvo::prelude::*;
+
+use crate::db;
+use crate::models::*;
+use crate::schema::*;
+use crate::AppResult;
+
+/// The audit trail of impersonation: who started it for whom and why, and every request made with it.
+#[handler]
+pub async fn list(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let query = impersonation_logs::table;
+    db::run_in_place(|conn| {
+        list_records!(
+            req,
+            depot,
+            res,
+            ImpersonationLog,
+            query,
+            "created_at desc",
+            IMPERSONATION_LOG_FILTER_FIELDS.clone(),
+            IMPERSONATION_LOG_JOINED_OPTIONS.clone(),
+            ID_SEARCH_TMPL,
+            conn
+        );
+        Ok(())
+    })
+}
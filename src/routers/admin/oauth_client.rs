@@ -0,0 +1,161 @@
+use chrono::Utc;
+use diesel::prelude::*;
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::db;
+use crate::models::*;
+use crate::schema::*;
+use crate::things::oauth;
+use crate::utils::{hash_str_sha256, validator};
+use crate::{context, AppResult};
+
+#[handler]
+pub async fn list(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let query = oauth_clients::table;
+    db::run_in_place(|conn| {
+        list_records!(
+            req,
+            depot,
+            res,
+            OauthClient,
+            query,
+            "id asc",
+            OAUTH_CLIENT_FILTER_FIELDS.clone(),
+            OAUTH_CLIENT_JOINED_OPTIONS.clone(),
+            ID_NAME_SEARCH_TMPL,
+            conn
+        );
+        Ok(())
+    })
+}
+
+#[derive(Deserialize, Debug)]
+struct PostedData {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    redirect_uris: Vec<String>,
+    #[serde(default)]
+    scopes: Vec<String>,
+    #[serde(default)]
+    is_disabled: bool,
+}
+fn validate_posted_data(pdata: &PostedData) -> Result<(), String> {
+    validator::validate_generic_name(&pdata.name)?;
+    if pdata.redirect_uris.is_empty() {
+        return Err("at least one redirect uri is required".into());
+    }
+    for uri in &pdata.redirect_uris {
+        oauth::validate_redirect_uri(uri)?;
+    }
+    oauth::validate_scopes(&pdata.scopes)
+}
+
+#[derive(Serialize, Debug)]
+struct CreatedData<'a> {
+    #[serde(flatten)]
+    client: &'a OauthClient,
+    client_secret: Option<&'a str>,
+}
+
+/// Register a client app. Confidential clients (the default) get a secret, returned here only; public clients
+/// (`"is_public": true`, for SPAs and native apps) have none and must use PKCE alone.
+#[handler]
+pub async fn create(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    #[derive(Deserialize, Debug)]
+    struct CreateData {
+        #[serde(flatten)]
+        data: PostedData,
+        #[serde(default)]
+        is_public: bool,
+    }
+    let pdata = parse_posted_data!(req, res, CreateData);
+    if let Err(e) = validate_posted_data(&pdata.data) {
+        return context::render_parse_data_error_json_with_detail(res, e);
+    }
+    let cuser = current_user!(depot, res);
+    let secret = if pdata.is_public {
+        None
+    } else {
+        Some(oauth::generate_client_secret())
+    };
+    db::run_in_place(|conn| {
+        let client = diesel::insert_into(oauth_clients::table)
+            .values(&NewOauthClient {
+                client_id: &oauth::generate_client_id(),
+                secret: secret.as_deref().map(hash_str_sha256).as_deref(),
+                name: &pdata.data.name,
+                redirect_uris: &pdata.data.redirect_uris,
+                scopes: &pdata.data.scopes,
+                updated_by: Some(cuser.id),
+                created_by: Some(cuser.id),
+            })
+            .get_result::<OauthClient>(conn)?;
+        res.render(Json(CreatedData {
+            client: &client,
+            client_secret: secret.as_deref(),
+        }));
+        Ok(())
+    })
+}
+
+/// Update a client. Disabling it stops its tokens from working until it is enabled again.
+#[handler]
+pub async fn update(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let pdata = parse_posted_data!(req, res, PostedData);
+    if let Err(e) = validate_posted_data(&pdata) {
+        return context::render_parse_data_error_json_with_detail(res, e);
+    }
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let client = get_record_by_param!(req, res, OauthClient, oauth_clients, conn);
+        let client = diesel::update(&client)
+            .set((
+                oauth_clients::name.eq(&pdata.name),
+                oauth_clients::redirect_uris.eq(&pdata.redirect_uris),
+                oauth_clients::scopes.eq(&pdata.scopes),
+                oauth_clients::is_disabled.eq(pdata.is_disabled),
+                oauth_clients::updated_by.eq(cuser.id),
+                oauth_clients::updated_at.eq(Utc::now()),
+            ))
+            .get_result::<OauthClient>(conn)?;
+        res.render(Json(client));
+        Ok(())
+    })
+}
+
+/// Replace the secret of a confidential client, e.g. after it leaked. The old one stops working immediately.
+#[handler]
+pub async fn rotate_secret(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let client = get_record_by_param!(req, res, OauthClient, oauth_clients, conn);
+        if !client.is_confidential() {
+            return context::render_parse_param_error_json_with_detail(res, "public clients have no secret");
+        }
+        let secret = oauth::generate_client_secret();
+        let client = diesel::update(&client)
+            .set((
+                oauth_clients::secret.eq(hash_str_sha256(&secret)),
+                oauth_clients::updated_by.eq(cuser.id),
+                oauth_clients::updated_at.eq(Utc::now()),
+            ))
+            .get_result::<OauthClient>(conn)?;
+        res.render(Json(CreatedData {
+            client: &client,
+            client_secret: Some(&secret),
+        }));
+        Ok(())
+    })
+}
+
+/// Delete a client with everything it was granted.
+#[handler]
+pub async fn delete(req: &mut Request, res: &mut Response) -> AppResult<()> {
+    db::run_in_place(|conn| {
+        let client = get_record_by_param!(req, res, OauthClient, oauth_clients, conn);
+        db::delete_oauth_client(client.id, conn)?;
+        context::render_done_json(res)
+    })
+}
@@ -0,0 +1,229 @@
+use chrono::Utc;
+use diesel::prelude::*;
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::db;
+use crate::models::*;
+use crate::schema::*;
+use crate::things::permission;
+use crate::utils::validator;
+use crate::{context, AppResult};
+
+#[derive(Serialize, Debug)]
+struct RoleData {
+    #[serde(flatten)]
+    role: Role,
+    permissions: Vec<Permission>,
+}
+fn role_data(role: Role, conn: &mut PgConnection) -> AppResult<RoleData> {
+    let permissions = permissions::table
+        .filter(permissions::role_id.eq(role.id))
+        .order(permissions::id.asc())
+        .get_results::<Permission>(conn)?;
+    Ok(RoleData { role, permissions })
+}
+
+#[handler]
+pub async fn list(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let query = roles::table;
+    db::run_in_place(|conn| {
+        list_records!(
+            req,
+            depot,
+            res,
+            Role,
+            query,
+            "id asc",
+            ROLE_FILTER_FIELDS.clone(),
+            ROLE_JOINED_OPTIONS.clone(),
+            ID_NAME_SEARCH_TMPL,
+            conn
+        );
+        Ok(())
+    })
+}
+
+#[handler]
+pub async fn show(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let role = get_permitted_record_by_param!(cuser, permission::ACTION_VIEW, req, res, Role, roles, conn);
+        res.render(Json(role_data(role, conn)?));
+        Ok(())
+    })
+}
+
+#[derive(Deserialize, Debug)]
+struct PostedGrant {
+    resource: String,
+    action: String,
+}
+#[derive(Deserialize, Debug)]
+struct PostedData {
+    #[serde(default)]
+    ident_name: String,
+    #[serde(default)]
+    name: String,
+    description: Option<String>,
+    #[serde(default)]
+    permissions: Vec<PostedGrant>,
+}
+fn validate_posted_data(pdata: &PostedData) -> Result<(), String> {
+    validator::validate_ident_name(&pdata.ident_name)?;
+    validator::validate_generic_name(&pdata.name)?;
+    for grant in &pdata.permissions {
+        permission::validate_grant(&grant.resource, &grant.action)?;
+    }
+    Ok(())
+}
+fn set_permissions(role_id: i64, grants: &[PostedGrant], user_id: i64, conn: &mut PgConnection) -> AppResult<()> {
+    diesel::delete(permissions::table.filter(permissions::role_id.eq(role_id))).execute(conn)?;
+    let grants = grants
+        .iter()
+        .map(|grant| NewPermission {
+            role_id,
+            resource: &grant.resource,
+            action: &grant.action,
+            created_by: Some(user_id),
+        })
+        .collect::<Vec<_>>();
+    diesel::insert_into(permissions::table)
+        .values(&grants)
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Create a role with its permissions, e.g. `{"resource": "users", "action": "update"}`; either may be `*`.
+#[handler]
+pub async fn create(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let pdata = parse_posted_data!(req, res, PostedData);
+    if let Err(e) = validate_posted_data(&pdata) {
+        return context::render_parse_data_error_json_with_detail(res, e);
+    }
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        if diesel_exists!(roles::table.filter(roles::ident_name.eq(&pdata.ident_name)), conn) {
+            return Err(StatusError::conflict()
+                .with_summary("role conflict")
+                .with_detail("a role with this ident name already exists")
+                .into());
+        }
+        let role = conn.transaction::<_, crate::Error, _>(|conn| {
+            let role = diesel::insert_into(roles::table)
+                .values(&NewRole {
+                    ident_name: &pdata.ident_name,
+                    name: &pdata.name,
+                    description: pdata.description.as_deref(),
+                    updated_by: Some(cuser.id),
+                    created_by: Some(cuser.id),
+                })
+                .get_result::<Role>(conn)?;
+            set_permissions(role.id, &pdata.permissions, cuser.id, conn)?;
+            Ok(role)
+        })?;
+        res.render(Json(role_data(role, conn)?));
+        Ok(())
+    })
+}
+
+/// Update a role; its permissions are replaced by the posted ones.
+#[handler]
+pub async fn update(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let pdata = parse_posted_data!(req, res, PostedData);
+    if let Err(e) = validate_posted_data(&pdata) {
+        return context::render_parse_data_error_json_with_detail(res, e);
+    }
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let role = get_permitted_record_by_param!(cuser, permission::ACTION_UPDATE, req, res, Role, roles, conn);
+        let query = roles::table
+            .filter(roles::ident_name.eq(&pdata.ident_name))
+            .filter(roles::id.ne(role.id));
+        if diesel_exists!(query, conn) {
+            return Err(StatusError::conflict()
+                .with_summary("role conflict")
+                .with_detail("a role with this ident name already exists")
+                .into());
+        }
+        let role = conn.transaction::<_, crate::Error, _>(|conn| {
+            let role = diesel::update(&role)
+                .set((
+                    roles::ident_name.eq(&pdata.ident_name),
+                    roles::name.eq(&pdata.name),
+                    roles::description.eq(&pdata.description),
+                    roles::updated_by.eq(cuser.id),
+                    roles::updated_at.eq(Utc::now()),
+                ))
+                .get_result::<Role>(conn)?;
+            set_permissions(role.id, &pdata.permissions, cuser.id, conn)?;
+            Ok(role)
+        })?;
+        res.render(Json(role_data(role, conn)?));
+        Ok(())
+    })
+}
+
+/// Delete a role; users who had it lose its permissions immediately.
+#[handler]
+pub async fn delete(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    db::run_in_place(|conn| {
+        delete_record!(req, depot, res, roles, Role, db::delete_role, conn);
+        Ok(())
+    })
+}
+
+/// Users having the role.
+#[handler]
+pub async fn list_users(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let role = get_permitted_record_by_param!(cuser, permission::ACTION_VIEW, req, res, Role, roles, conn);
+        let users = users::table
+            .filter(users::id.eq_any(user_roles::table.filter(user_roles::role_id.eq(role.id)).select(user_roles::user_id)))
+            .order(users::id.asc())
+            .get_results::<User>(conn)?;
+        res.render(Json(users));
+        Ok(())
+    })
+}
+
+#[handler]
+pub async fn add_user(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    #[derive(Deserialize, Debug)]
+    struct PostedData {
+        user_id: i64,
+    }
+    let pdata = parse_posted_data!(req, res, PostedData);
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let role = get_permitted_record_by_param!(cuser, permission::ACTION_UPDATE, req, res, Role, roles, conn);
+        let user = get_record!(res, pdata.user_id, User, users, conn);
+        diesel::insert_into(user_roles::table)
+            .values(&NewUserRole {
+                user_id: user.id,
+                role_id: role.id,
+                created_by: Some(cuser.id),
+            })
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+        context::render_done_json(res)
+    })
+}
+
+#[handler]
+pub async fn remove_user(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let cuser = current_user!(depot, res);
+    let user_id = get_id_param!(req, res, "user_id");
+    db::run_in_place(|conn| {
+        let role = get_permitted_record_by_param!(cuser, permission::ACTION_UPDATE, req, res, Role, roles, conn);
+        diesel::delete(
+            user_roles::table
+                .filter(user_roles::role_id.eq(role.id))
+                .filter(user_roles::user_id.eq(user_id)),
+        )
+        .execute(conn)?;
+        context::render_done_json(res)
+    })
+}
@@ -1,24 +1,61 @@
-use chrono::{DateTime, Duration, Utc};
+use chrono::{Duration, Utc};
 use cookie::Expiration;
 use diesel::prelude::*;
-use salvo::http::cookie::Cookie;
+use salvo::http::cookie::{Cookie, SameSite};
+use salvo::http::header::{HeaderValue, CACHE_CONTROL};
 use salvo::http::StatusCode;
 use salvo::prelude::*;
 use serde::{Deserialize, Serialize};
+use webauthn_rs::prelude::{PublicKeyCredential, RequestChallengeResponse};
 
 use crate::db::{self, lower};
 use crate::models::*;
 use crate::schema::*;
-use crate::utils::{password, validator};
-use crate::{context, AppResult, StatusInfo};
+use crate::things::lockout;
+use crate::things::refresh_token::Rotation;
+use crate::things::session::{self, ClientInfo};
+use crate::things::user_identity;
+use crate::things;
+use crate::utils::{hash_str_sha256, password, validator};
+use crate::webauthn::{self, AUTHENTICATIONS, WEBAUTHN};
+use crate::{context, csrf, oidc, AppResult, StatusInfo};
+
+/// How long the `mfa_pending` token from the first login step stays valid.
+const MFA_PENDING_MINUTES: i64 = 5;
 
 pub fn public_root(path: impl Into<String>) -> Router {
-    Router::with_path(path).push(Router::with_path("login").post(login))
+    Router::with_path(path)
+        .push(
+            Router::with_path("login")
+                .post(login)
+                .push(Router::with_path("mfa").post(login_mfa))
+                .push(
+                    Router::with_path("magic_link")
+                        .post(send_magic_link)
+                        .push(Router::with_path("consume").post(consume_magic_link)),
+                )
+                .push(
+                    Router::with_path("webauthn")
+                        .push(Router::with_path("start").post(start_webauthn_login))
+                        .push(Router::with_path("finish").post(finish_webauthn_login)),
+                ),
+        )
+        .push(
+            Router::with_path("oidc/<provider>")
+                .push(Router::with_path("start").post(start_oidc_login))
+                .push(Router::with_path("callback").post(finish_oidc_login)),
+        )
+        .push(Router::with_path("token").post(issue_token))
 }
 pub fn authed_root(path: impl Into<String>) -> Router {
     Router::with_path(path)
+        .push(Router::with_path("csrf").get(csrf_token))
         .push(Router::with_path("logout").post(logout))
-        .push(Router::with_path("refresh_token").post(refresh_token))
+        .push(
+            Router::with_path("refresh_token")
+                .hoop(super::web_session_only)
+                .post(refresh_token),
+        )
 }
 #[derive(Serialize, Deserialize, Debug)]
 struct PostedLoginData {
@@ -28,6 +65,26 @@ struct PostedLoginData {
     email: Option<String>,
     password: String,
 }
+fn find_login_user(ident_name: Option<&str>, email: Option<&str>, conn: &mut PgConnection) -> Option<User> {
+    if let Some(ident_name) = ident_name {
+        users::table
+            .filter(lower(users::ident_name).eq(ident_name.to_lowercase()))
+            .first::<User>(conn)
+            .ok()
+    } else if let Some(email) = email {
+        users::table
+            .filter(
+                users::id.nullable().eq(emails::table
+                    .filter(lower(emails::value).eq(email.to_lowercase()))
+                    .select(emails::user_id)
+                    .single_value()),
+            )
+            .first::<User>(conn)
+            .ok()
+    } else {
+        None
+    }
+}
 #[handler]
 pub async fn login(req: &mut Request, _depot: &mut Depot, res: &mut Response) -> AppResult<()> {
     let mut pdata = parse_posted_data!(req, res, PostedLoginData);
@@ -49,26 +106,18 @@ pub async fn login(req: &mut Request, _depot: &mut Depot, res: &mut Response) ->
         );
     }
 
-    let mut conn = db::connect()?;
-    let user = if let Some(ident_name) = pdata.ident_name {
-        users::table
-            .filter(lower(users::ident_name).eq(ident_name.to_lowercase()))
-            .first::<User>(&mut conn)
-            .ok()
-    } else if let Some(email) = &pdata.email {
-        users::table
-            .filter(
-                users::id.nullable().eq(emails::table
-                    .filter(lower(emails::value).eq(email.to_lowercase()))
-                    .select(emails::user_id)
-                    .single_value()),
-            )
-            .first::<User>(&mut conn)
-            .ok()
-    } else {
-        None
-    };
+    let ip = context::client_ip(req);
+    if lockout::is_ip_blocked(ip.as_deref()) {
+        return context::render_too_many_attempts_json_with_detail(
+            res,
+            "Too many failed login attempts from your network, please try again later.",
+        );
+    }
+    let user = db::run_in_place(|conn| {
+        Ok(find_login_user(pdata.ident_name.as_deref(), pdata.email.as_deref(), conn))
+    })?;
     if user.is_none() {
+        lockout::delay(lockout::record_ip_failure(ip.as_deref()) as i32).await;
         return context::render_status_json(
             res,
             StatusCode::BAD_REQUEST,
@@ -78,18 +127,33 @@ pub async fn login(req: &mut Request, _depot: &mut Depot, res: &mut Response) ->
         );
     }
     let user = user.unwrap();
-    if password::compare(&pdata.password, &user.password) {
+    if lockout::is_locked(&user) {
+        return render_user_locked_json(&user, res);
+    }
+    if password::compare_async(&pdata.password, &user.password).await {
+        if password::needs_rehash(&user.password) {
+            db::run_in_place(|conn| {
+                rehash_password(&user, &pdata.password, conn);
+                Ok(())
+            })?;
+        }
         #[derive(Serialize, Debug)]
         struct ResponsedData<'a> {
             user: &'a User,
             error: Option<StatusInfo>,
             token: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            refresh_token: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            mfa_token: Option<&'a str>,
         }
 
         let mut data = ResponsedData {
             user: &user,
             error: None,
             token: None,
+            refresh_token: None,
+            mfa_token: None,
         };
         if !user.is_verified {
             data.error = Some(StatusInfo {
@@ -114,17 +178,31 @@ pub async fn login(req: &mut Request, _depot: &mut Depot, res: &mut Response) ->
             res.render(Json(data));
             return Ok(());
         }
-        
-        match create_token(&user, &mut conn) {
-            Ok(jwt_token) => {
-                res.add_cookie(create_token_cookie(jwt_token.clone()));
-                data.token = Some(&jwt_token);
-                res.render(Json(data));
-                Ok(())
-            }
-            Err(msg) => context::render_internal_server_error_json_with_detail(res, msg),
+
+        if user.totp_enabled {
+            // No session yet: the client has to post this token with a TOTP or recovery code to `login/mfa`.
+            let exp = Utc::now() + Duration::minutes(MFA_PENDING_MINUTES);
+            return match crate::create_mfa_pending_token(&user, &exp) {
+                Ok(mfa_token) => {
+                    data.mfa_token = Some(&mfa_token);
+                    res.render(Json(data));
+                    Ok(())
+                }
+                Err(_) => context::render_internal_server_error_json_with_detail(res, "create mfa token error"),
+            };
         }
+
+        let tokens = db::run_in_place(|conn| {
+            lockout::record_success(&user, conn)?;
+            session::create(&user, &context::client_info(req), conn)
+        })?;
+        res.add_cookie(create_token_cookie(tokens.token.clone()));
+        data.token = Some(&tokens.token);
+        data.refresh_token = Some(&tokens.refresh_token);
+        res.render(Json(data));
+        Ok(())
     } else {
+        record_login_failure(&user, ip.as_deref()).await?;
         context::render_status_json(
             res,
             StatusCode::BAD_REQUEST,
@@ -134,78 +212,673 @@ pub async fn login(req: &mut Request, _depot: &mut Depot, res: &mut Response) ->
         )
     }
 }
-pub fn insert_token_to_db(
-    user_id: i64,
-    jwt_token: &str,
-    expire: DateTime<Utc>,
-    conn: &mut PgConnection,
-) -> Result<usize, diesel::result::Error> {
-    let new_token = NewAccessToken {
-        user_id,
-        kind: "web",
-        value: jwt_token,
-        device: None,
-        name: None,
-        expired_at: expire,
-        updated_by: Some(user_id),
-        created_by: Some(user_id),
+
+/// Second login step for users with 2FA enabled: exchange the `mfa_pending` token from `login` and a TOTP code
+/// (or an unused recovery code) for the session JWT.
+#[handler]
+pub async fn login_mfa(req: &mut Request, _depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    #[derive(Deserialize, Debug)]
+    struct PostedData {
+        mfa_token: String,
+        #[serde(default)]
+        code: String,
+        #[serde(default)]
+        recovery_code: String,
+    }
+    let pdata = parse_posted_data!(req, res, PostedData);
+    let claims = match crate::decode_mfa_pending_token(&pdata.mfa_token) {
+        Ok(claims) => claims,
+        Err(_) => {
+            return context::render_status_json(
+                res,
+                StatusCode::UNAUTHORIZED,
+                "mfa_token_invalid",
+                "mfa token invalid",
+                "Your login session has expired, please sign in again.",
+            )
+        }
+    };
+    let user = db::run_in_place(|conn| Ok(users::table.find(claims.user).first::<User>(conn).optional()?))?;
+    let user = match user {
+        Some(user) if user.totp_enabled && !user.is_disabled => user,
+        _ => return context::render_access_denied_json(res),
+    };
+    if lockout::is_locked(&user) {
+        return render_user_locked_json(&user, res);
+    }
+    let passed = if !pdata.code.is_empty() {
+        db::run_in_place(|conn| user.consume_totp_code(&pdata.code, conn))?
+    } else if !pdata.recovery_code.is_empty() {
+        db::run_in_place(|conn| user.consume_recovery_code(&pdata.recovery_code, conn))?
+    } else {
+        return context::render_parse_data_error_json_with_detail(res, "code or recovery code is not provided");
     };
-    diesel::insert_into(access_tokens::table)
-        .values(&new_token)
-        .execute(conn)
+    if !passed {
+        record_login_failure(&user, context::client_ip(req).as_deref()).await?;
+        return context::render_status_json(
+            res,
+            StatusCode::BAD_REQUEST,
+            "validate_failed",
+            "validate failed",
+            "Incorrect verification code.",
+        );
+    }
+    db::run_in_place(|conn| create_and_send_session(&user, &context::client_info(req), res, conn))
+}
+
+/// Email a sign-in link to a verified address of the user. The response is the same whether or not the user
+/// exists, so this can not be used to probe for accounts.
+#[handler]
+pub async fn send_magic_link(req: &mut Request, _depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    #[derive(Deserialize, Debug)]
+    struct PostedData {
+        #[serde(default)]
+        user: String,
+    }
+    let pdata = parse_posted_data!(req, res, PostedData);
+    if pdata.user.is_empty() {
+        return context::render_parse_data_error_json_with_detail(res, "user identifier is not provided");
+    }
+    let is_email = validator::validate_email(&pdata.user).is_ok();
+    db::run_in_place(|conn| {
+        let user = if is_email {
+            find_login_user(None, Some(&pdata.user), conn)
+        } else {
+            find_login_user(Some(&pdata.user), None, conn)
+        };
+        if let Some(user) = user.filter(|user| !user.is_disabled && !lockout::is_locked(user)) {
+            let mut query = emails::table
+                .filter(emails::user_id.eq(user.id))
+                .filter(emails::is_verified.eq(true))
+                .order(emails::id.asc())
+                .into_boxed();
+            if is_email {
+                query = query.filter(lower(emails::value).eq(pdata.user.to_lowercase()));
+            }
+            if let Some(email) = query.first::<Email>(conn).optional()? {
+                // Telling a throttled request apart would reveal that the account exists.
+                match user.send_magic_link_email(&email.value, conn) {
+                    Err(crate::Error::FrequentlyRequest) => {
+                        tracing::info!(user_id = user.id, "magic link throttled");
+                    }
+                    result => result?,
+                }
+            }
+        }
+        context::render_done_json_with_detail(res, "if the account exists, a sign-in link has been sent to its email")
+    })
 }
+
+/// Exchange the token from a magic link for a session, or for an `mfa_token` when 2FA is enabled, just like
+/// `login`. The link is consumed either way.
+#[handler]
+pub async fn consume_magic_link(req: &mut Request, _depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    #[derive(Deserialize, Debug)]
+    struct PostedData {
+        token: String,
+    }
+    let pdata = parse_posted_data!(req, res, PostedData);
+    let claims = crate::decode_magic_link_token(&pdata.token).ok();
+    db::run_in_place(|conn| {
+        let code = match claims {
+            Some(claims) => conn.transaction::<_, crate::Error, _>(|conn| {
+                let code = security_codes::table
+                    .filter(security_codes::user_id.eq(claims.user))
+                    .filter(security_codes::send_method.eq("magic_link"))
+                    .filter(security_codes::value.eq(hash_str_sha256(&claims.nonce)))
+                    .filter(security_codes::consumed_at.is_null())
+                    .filter(security_codes::expired_at.gt(Utc::now()))
+                    .for_update()
+                    .first::<SecurityCode>(conn)
+                    .optional()?;
+                if let Some(code) = &code {
+                    diesel::update(code)
+                        .set((
+                            security_codes::consumed_at.eq(Utc::now()),
+                            security_codes::updated_at.eq(Utc::now()),
+                        ))
+                        .execute(conn)?;
+                }
+                Ok(code)
+            })?,
+            None => None,
+        };
+        let code = match code {
+            Some(code) => code,
+            None => {
+                return context::render_status_json(
+                    res,
+                    StatusCode::BAD_REQUEST,
+                    "link_invalid",
+                    "link invalid",
+                    "This sign-in link is invalid, expired or has already been used.",
+                )
+            }
+        };
+        let user = users::table.find(code.user_id).first::<User>(conn)?;
+        if user.is_disabled || lockout::is_locked(&user) {
+            return context::render_locked_or_disabled_json(res);
+        }
+        if user.totp_enabled {
+            return render_mfa_pending_json(&user, res);
+        }
+        create_and_send_session(&user, &context::client_info(req), res, conn)
+    })
+}
+
+/// Respond with an `mfa_token` instead of a session, to be posted with a TOTP or recovery code to `login/mfa`.
+pub fn render_mfa_pending_json(user: &User, res: &mut Response) -> AppResult<()> {
+    #[derive(Serialize, Debug)]
+    struct ResponsedData<'a> {
+        user: &'a User,
+        mfa_token: &'a str,
+    }
+    let exp = Utc::now() + Duration::minutes(MFA_PENDING_MINUTES);
+    match crate::create_mfa_pending_token(user, &exp) {
+        Ok(mfa_token) => {
+            res.render(Json(ResponsedData {
+                user,
+                mfa_token: &mfa_token,
+            }));
+            Ok(())
+        }
+        Err(_) => context::render_internal_server_error_json_with_detail(res, "create mfa token error"),
+    }
+}
+
+/// Start signing in with an OpenID Connect provider. The client sends the user to `authorization_url`, the
+/// provider sends them back to `OIDC_REDIRECT_URL`, which posts `code` and `state` to `callback`. The state is
+/// also set in an HttpOnly cookie, so only the browser that started the flow can finish it.
+#[handler]
+pub async fn start_oidc_login(req: &mut Request, _depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let provider = match oidc::provider(req.param::<String>("provider").unwrap_or_default().as_str()) {
+        Some(provider) => provider,
+        None => return context::render_not_found_json(res),
+    };
+    #[derive(Serialize, Debug)]
+    struct ResponsedData {
+        authorization_url: String,
+    }
+    let (authorization_url, state) = oidc::authorization_url(&provider, 0).await?;
+    res.add_cookie(create_oidc_state_cookie(state));
+    res.render(Json(ResponsedData { authorization_url }));
+    Ok(())
+}
+
+#[handler]
+pub async fn finish_oidc_login(req: &mut Request, _depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    #[derive(Deserialize, Debug)]
+    struct PostedData {
+        code: String,
+        state: String,
+    }
+    let pdata = parse_posted_data!(req, res, PostedData);
+    let provider = match oidc::provider(req.param::<String>("provider").unwrap_or_default().as_str()) {
+        Some(provider) => provider,
+        None => return context::render_not_found_json(res),
+    };
+    let bound_state = req.cookie(OIDC_STATE_COOKIE).map(|cookie| cookie.value().to_owned());
+    let mut removal = create_oidc_state_cookie(String::new());
+    removal.make_removal();
+    res.add_cookie(removal);
+    // Otherwise an attacker could have a victim's browser finish the attacker's own flow.
+    if !bound_state
+        .map(|bound| bound.len() == pdata.state.len() && openssl::memcmp::eq(bound.as_bytes(), pdata.state.as_bytes()))
+        .unwrap_or(false)
+    {
+        return render_oidc_state_invalid_json(res);
+    }
+    let identity = match oidc::finish(&provider, &pdata.state, &pdata.code).await? {
+        // A state started by `account/identities` links an identity and must not sign anybody in.
+        Some((0, identity)) => identity,
+        _ => return render_oidc_state_invalid_json(res),
+    };
+    db::run_in_place(|conn| {
+        let user = match user_identity::sign_in(&provider.name, &identity, conn)? {
+            Some(user) => user,
+            None => {
+                return context::render_status_json(
+                    res,
+                    StatusCode::FORBIDDEN,
+                    "email_not_verified",
+                    "email not verified",
+                    "The provider did not confirm a verified email for this account.",
+                )
+            }
+        };
+        if user.is_disabled || lockout::is_locked(&user) {
+            return context::render_locked_or_disabled_json(res);
+        }
+        if user.totp_enabled {
+            return render_mfa_pending_json(&user, res);
+        }
+        create_and_send_session(&user, &context::client_info(req), res, conn)
+    })
+}
+
+const OIDC_STATE_COOKIE: &str = "oidc_state";
+
+/// Lives as long as the flow it belongs to.
+fn create_oidc_state_cookie(state: String) -> Cookie<'static> {
+    let expires =
+        cookie::time::OffsetDateTime::now_utc() + cookie::time::Duration::seconds(webauthn::CHALLENGE_TTL_SECONDS);
+    Cookie::build(OIDC_STATE_COOKIE, state)
+        .path("/")
+        .domain(crate::cookie_domain())
+        .secure(true)
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .expires(Expiration::from(expires))
+        .finish()
+}
+
+pub fn render_oidc_state_invalid_json(res: &mut Response) -> AppResult<()> {
+    context::render_status_json(
+        res,
+        StatusCode::BAD_REQUEST,
+        "state_invalid",
+        "state invalid",
+        "This sign-in attempt is invalid or has expired, please start again.",
+    )
+}
+
+/// Start a passwordless login: returns the assertion options for every passkey the user has registered.
+#[handler]
+pub async fn start_webauthn_login(req: &mut Request, _depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    #[derive(Deserialize, Debug)]
+    struct PostedData {
+        #[serde(default)]
+        user: String,
+    }
+    let pdata = parse_posted_data!(req, res, PostedData);
+    db::run_in_place(|conn| {
+        let user = if validator::validate_email(&pdata.user).is_ok() {
+            find_login_user(None, Some(&pdata.user), conn)
+        } else {
+            find_login_user(Some(&pdata.user), None, conn)
+        };
+        let user = match user {
+            Some(user) if !user.is_disabled => user,
+            _ => {
+                return context::render_status_json(
+                    res,
+                    StatusCode::BAD_REQUEST,
+                    "validate_failed",
+                    "validate failed",
+                    "No passkey is registered for this user.",
+                )
+            }
+        };
+        let passkeys = webauthn_credentials::table
+            .filter(webauthn_credentials::user_id.eq(user.id))
+            .get_results::<WebauthnCredential>(conn)?
+            .iter()
+            .map(webauthn::load_passkey)
+            .collect::<AppResult<Vec<_>>>()?;
+        if passkeys.is_empty() {
+            return context::render_status_json(
+                res,
+                StatusCode::BAD_REQUEST,
+                "validate_failed",
+                "validate failed",
+                "No passkey is registered for this user.",
+            );
+        }
+        let (options, state) = WEBAUTHN.start_passkey_authentication(&passkeys)?;
+
+        #[derive(Serialize, Debug)]
+        struct ResultData {
+            challenge_id: String,
+            options: RequestChallengeResponse,
+        }
+        res.render(Json(ResultData {
+            challenge_id: AUTHENTICATIONS.insert(user.id, state),
+            options,
+        }));
+        Ok(())
+    })
+}
+
+#[handler]
+pub async fn finish_webauthn_login(req: &mut Request, _depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    #[derive(Deserialize, Debug)]
+    struct PostedData {
+        challenge_id: String,
+        credential: PublicKeyCredential,
+    }
+    let pdata = parse_posted_data!(req, res, PostedData);
+    let (user_id, state) = match AUTHENTICATIONS.take(&pdata.challenge_id) {
+        Some(entry) => entry,
+        None => return context::render_parse_data_error_json_with_detail(res, "challenge is expired or not found"),
+    };
+    let result = match WEBAUTHN.finish_passkey_authentication(&pdata.credential, &state) {
+        Ok(result) => result,
+        Err(e) => {
+            tracing::info!(error = ?e, user_id, "webauthn authentication failed");
+            return context::render_status_json(
+                res,
+                StatusCode::BAD_REQUEST,
+                "webauthn_failed",
+                "webauthn failed",
+                "The passkey could not be verified.",
+            );
+        }
+    };
+    db::run_in_place(|conn| {
+        let credential = webauthn_credentials::table
+            .filter(webauthn_credentials::user_id.eq(user_id))
+            .filter(webauthn_credentials::credential_id.eq(webauthn::encode_credential_id(result.cred_id().as_ref())))
+            .first::<WebauthnCredential>(conn)
+            .optional()?;
+        let credential = match credential {
+            Some(credential) => credential,
+            None => return context::render_access_denied_json(res),
+        };
+        // Keep the signature counter current so a cloned authenticator is detected on its next use.
+        let mut passkey = webauthn::load_passkey(&credential)?;
+        passkey.update_credential(&result);
+        diesel::update(&credential)
+            .set((
+                webauthn_credentials::passkey.eq(serde_json::to_value(&passkey)?),
+                webauthn_credentials::last_used_at.eq(Utc::now()),
+            ))
+            .execute(conn)?;
+
+        let user = users::table.find(user_id).first::<User>(conn)?;
+        if user.is_disabled || !user.is_verified {
+            return context::render_access_denied_json(res);
+        }
+        create_and_send_session(&user, &context::client_info(req), res, conn)
+    })
+}
+
+/// The session cookie is out of reach of scripts and not sent along with cross-site subrequests; unsafe requests
+/// authenticated by it also need the token from `csrf`.
 pub fn create_token_cookie(jwt_token: String) -> Cookie<'static> {
-    let expires = cookie::time::OffsetDateTime::now_utc() + cookie::time::Duration::days(7);
+    let expires =
+        cookie::time::OffsetDateTime::now_utc() + cookie::time::Duration::minutes(crate::access_token_ttl_minutes());
     Cookie::build("jwt_token", jwt_token)
         .path("/")
         .domain(crate::cookie_domain())
         .secure(true)
-        .http_only(false)
+        .http_only(true)
+        .same_site(SameSite::Lax)
         .expires(Expiration::from(expires))
         .finish()
 }
 
+/// Scripts can not clear an HttpOnly cookie, so the server has to expire it.
+fn remove_token_cookie() -> Cookie<'static> {
+    let mut cookie = create_token_cookie(String::new());
+    cookie.make_removal();
+    cookie
+}
+
+/// End the current session only; other devices stay signed in. Also ends an impersonation session.
 #[handler]
 pub async fn logout(_req: &Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
-    if let Some(user) = context::current_user(depot) {
-        let mut conn = db::connect()?;
-        diesel::delete(
-            access_tokens::table
-                .filter(access_tokens::user_id.eq(user.id))
-                .filter(access_tokens::kind.eq("web")),
-        )
-        .execute(&mut conn)?;
+    if let Some(current) = context::current_access_token(depot) {
+        // API and OAuth tokens are revoked where they were created, not by logging out with them.
+        if current.kind != "web" && current.kind != "impersonation" {
+            return context::render_access_denied_json_with_detail(res, "this action requires a web session");
+        }
+        db::run_in_place(|conn| session::revoke(current, conn))?;
     }
+    res.add_cookie(remove_token_cookie());
     context::render_done_json(res)
 }
 
+/// The CSRF token of the current session, which SPAs send as `x-csrf-token` with every unsafe request.
+#[handler]
+pub async fn csrf_token(_req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let current = match context::current_access_token(depot) {
+        Some(current) => current,
+        None => return context::render_invalid_user_json(res),
+    };
+    #[derive(Serialize, Debug)]
+    struct ResultData {
+        csrf_token: String,
+        header_name: &'static str,
+    }
+    res.headers_mut().insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    res.render(Json(ResultData {
+        csrf_token: csrf::token_for(current)?,
+        header_name: csrf::HEADER_NAME,
+    }));
+    Ok(())
+}
+
+/// Swap the JWT of the current session for a new one. The presented JWT stops working, so it can not be used to
+/// mint further tokens.
 #[handler]
-pub async fn refresh_token(_req: &Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+pub async fn refresh_token(req: &Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
     let cuser = current_user!(depot, res);
-    let mut conn = db::connect()?;
-    create_and_send_token(cuser, res, &mut conn)
+    let current = match context::current_access_token(depot) {
+        Some(current) => current,
+        None => return context::render_invalid_user_json(res),
+    };
+    db::run_in_place(|conn| {
+        match session::renew(cuser, current, &context::client_info(req), conn)? {
+            Some(jwt_token) => {
+                #[derive(Serialize, Debug)]
+                struct ResultData<'a> {
+                    token: &'a str,
+                }
+                res.add_cookie(create_token_cookie(jwt_token.clone()));
+                res.render(Json(ResultData { token: &jwt_token }));
+                Ok(())
+            }
+            None => context::render_invalid_user_json(res),
+        }
+    })
 }
 
-pub fn create_token(user: &User, conn: &mut PgConnection) -> Result<String, String> {
-    let exp = Utc::now() + Duration::days(7);
-    if let Ok(jwt_token) = crate::create_jwt_token(user, &exp) {
-        insert_token_to_db(user.id, &jwt_token, exp, conn).map_err(|_| "db error when insert token".to_owned())?;
-        Ok(jwt_token)
-    } else {
-        Err("create jwt token error".into())
+/// OAuth2 style token endpoint. `grant_type=refresh_token` exchanges a refresh token for a new access token and
+/// a new refresh token; the presented one is rotated out, and presenting it again revokes its whole family.
+#[handler]
+pub async fn issue_token(req: &mut Request, _depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    #[derive(Deserialize, Debug)]
+    struct PostedData {
+        #[serde(default)]
+        grant_type: String,
+        #[serde(default)]
+        refresh_token: String,
     }
-}
-pub fn create_and_send_token(user: &User, res: &mut Response, conn: &mut PgConnection) -> AppResult<()> {
-    match create_token(user, conn) {
-        Ok(jwt_token) => {
-            #[derive(Serialize, Debug)]
-            struct ResultData<'a> {
-                token: &'a str,
+    let pdata = parse_posted_data!(req, res, PostedData);
+    if pdata.grant_type != "refresh_token" {
+        return context::render_status_json(
+            res,
+            StatusCode::BAD_REQUEST,
+            "unsupported_grant_type",
+            "unsupported grant type",
+            "grant_type must be refresh_token",
+        );
+    }
+    if pdata.refresh_token.is_empty() {
+        return context::render_parse_data_error_json_with_detail(res, "refresh_token is not provided");
+    }
+    db::run_in_place(|conn| {
+        let renewed = match things::refresh_token::rotate(&pdata.refresh_token, conn)? {
+            Rotation::Rotated {
+                user_id,
+                family_id,
+                value,
+            } => {
+                let user = users::table.find(user_id).first::<User>(conn)?;
+                match session::find_by_family(&family_id, conn)? {
+                    Some(current) if !user.is_disabled => {
+                        session::renew(&user, &current, &context::client_info(req), conn)?
+                            .map(|access_token| (access_token, value))
+                    }
+                    _ => {
+                        things::refresh_token::revoke_family(&family_id, conn)?;
+                        None
+                    }
+                }
             }
-            res.add_cookie(create_token_cookie(jwt_token.clone()));
-            res.render(Json(ResultData { token: &jwt_token }));
-            Ok(())
+            Rotation::Invalid | Rotation::Reused => None,
+        };
+        let (access_token, refresh) = match renewed {
+            Some(renewed) => renewed,
+            None => {
+                return context::render_status_json(
+                    res,
+                    StatusCode::BAD_REQUEST,
+                    "invalid_grant",
+                    "invalid grant",
+                    "refresh token is invalid, expired or revoked",
+                )
+            }
+        };
+
+        #[derive(Serialize, Debug)]
+        struct ResultData<'a> {
+            access_token: &'a str,
+            token_type: &'a str,
+            expires_in: i64,
+            refresh_token: &'a str,
+        }
+        res.add_cookie(create_token_cookie(access_token.clone()));
+        res.render(Json(ResultData {
+            access_token: &access_token,
+            token_type: "Bearer",
+            expires_in: crate::access_token_ttl_minutes() * 60,
+            refresh_token: &refresh,
+        }));
+        Ok(())
+    })
+}
+
+/// Replace a hash made with an old algorithm, parameters or pepper while the plain password is at hand. Failing
+/// here must not fail the login, the old hash keeps working.
+fn rehash_password(user: &User, plain: &str, conn: &mut PgConnection) {
+    let result = password::hash(plain).map_err(crate::Error::Internal).and_then(|hashed| {
+        diesel::update(user)
+            .set(users::password.eq(hashed))
+            .execute(conn)
+            .map_err(crate::Error::from)
+    });
+    match result {
+        Ok(_) => tracing::info!(user_id = user.id, "password hash upgraded"),
+        Err(e) => tracing::error!(error = ?e, user_id = user.id, "upgrade password hash failed"),
+    }
+}
+
+/// Count a wrong password or second factor against both the user and the client IP, then hold the response
+/// back for longer the more failures there were.
+pub async fn record_login_failure(user: &User, ip: Option<&str>) -> AppResult<()> {
+    let user = db::run_in_place(|conn| lockout::record_failure(user, conn))?;
+    let ip_failures = lockout::record_ip_failure(ip);
+    lockout::delay(user.failed_attempts.max(ip_failures as i32)).await;
+    Ok(())
+}
+
+pub fn render_user_locked_json(user: &User, res: &mut Response) -> AppResult<()> {
+    let detail = match user.locked_until {
+        Some(until) => format!(
+            "Too many failed login attempts, please try again after {}.",
+            until.to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+        ),
+        None => "Too many failed login attempts, please try again later.".into(),
+    };
+    context::render_locked_or_disabled_json_with_detail(res, detail)
+}
+
+/// Respond to a completed login with the user and a new session token, the same way `login` does.
+fn create_and_send_session(
+    user: &User,
+    client: &ClientInfo,
+    res: &mut Response,
+    conn: &mut PgConnection,
+) -> AppResult<()> {
+    lockout::record_success(user, conn)?;
+    let tokens = session::create(user, client, conn)?;
+
+    #[derive(Serialize, Debug)]
+    struct ResponsedData<'a> {
+        user: &'a User,
+        token: &'a str,
+        refresh_token: &'a str,
+    }
+    res.add_cookie(create_token_cookie(tokens.token.clone()));
+    res.render(Json(ResponsedData {
+        user,
+        token: &tokens.token,
+        refresh_token: &tokens.refresh_token,
+    }));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::http::header::{COOKIE, SET_COOKIE};
+    use salvo::test::{ResponseExt, TestClient};
+    use serde_json::{json, Value};
+
+    use super::*;
+    use crate::oidc::tests::{ISSUER, PROVIDER, RUNTIME};
+
+    fn service() -> Service {
+        once_cell::sync::Lazy::force(&ISSUER);
+        Service::new(
+            Router::with_path("oidc/<provider>")
+                .push(Router::with_path("start").post(start_oidc_login))
+                .push(Router::with_path("callback").post(finish_oidc_login)),
+        )
+    }
+
+    fn url(action: &str) -> String {
+        format!("http://127.0.0.1/oidc/{}/{}", PROVIDER, action)
+    }
+
+    /// Start a login and return the `oidc_state` cookie set for it.
+    async fn start(service: &Service) -> Cookie<'static> {
+        let res = TestClient::post(url("start")).send(service).await;
+        assert_eq!(res.status_code(), Some(StatusCode::OK));
+        res.headers()
+            .get_all(SET_COOKIE)
+            .iter()
+            .filter_map(|value| Cookie::parse(value.to_str().ok()?.to_owned()).ok())
+            .find(|cookie| cookie.name() == OIDC_STATE_COOKIE)
+            .unwrap()
+    }
+
+    async fn callback(service: &Service, state: &str, cookie: Option<&str>) -> (Option<StatusCode>, Value) {
+        let mut req = TestClient::post(url("callback")).json(&json!({ "code": "bad-code", "state": state }));
+        if let Some(cookie) = cookie {
+            req = req.add_header(COOKIE, format!("{}={}", OIDC_STATE_COOKIE, cookie), true);
         }
-        Err(msg) => context::render_internal_server_error_json_with_detail(res, msg),
+        let mut res = req.send(service).await;
+        let body = serde_json::from_str(&res.take_string().await.unwrap()).unwrap();
+        (res.status_code(), body)
+    }
+
+    #[test]
+    fn state_is_bound_to_the_browser() {
+        let service = service();
+        RUNTIME.block_on(async {
+            let cookie = start(&service).await;
+            assert!(cookie.http_only().unwrap_or(false));
+            let state = cookie.value();
+
+            let (status, body) = callback(&service, state, None).await;
+            assert_eq!(status, Some(StatusCode::BAD_REQUEST));
+            assert_eq!(body["error"]["name"], "state_invalid");
+
+            let (status, body) = callback(&service, state, Some("another-state")).await;
+            assert_eq!(status, Some(StatusCode::BAD_REQUEST));
+            assert_eq!(body["error"]["name"], "state_invalid");
+        });
+    }
+
+    #[test]
+    fn provider_rejection_is_a_bad_request() {
+        let service = service();
+        RUNTIME.block_on(async {
+            let cookie = start(&service).await;
+            let (status, body) = callback(&service, cookie.value(), Some(cookie.value())).await;
+            assert_eq!(status, Some(StatusCode::BAD_REQUEST));
+            assert_eq!(body["error"]["name"], "oidc_failed");
+        });
     }
 }
@@ -1,46 +1,52 @@
+use salvo::http::header::{HeaderValue, CACHE_CONTROL};
 use salvo::prelude::*;
+use serde_json::json;
 
-use crate::AppResult;
-
-
-use lettre::message::header::ContentType;
-use lettre::transport::smtp::authentication::Credentials;
-use lettre::{Message, SmtpTransport, Transport};
-
-
-
+use crate::{things, AppResult};
 
 #[handler]
 pub async fn index(res: &mut Response) -> AppResult<()> {
+    res.render("Hello world");
+    Ok(())
+}
 
+/// Public keys that verify our JWTs, so other services do not need to share a secret with us.
+#[handler]
+pub async fn jwks(res: &mut Response) -> AppResult<()> {
+    res.headers_mut()
+        .insert(CACHE_CONTROL, HeaderValue::from_static("public, max-age=300"));
+    res.render(Json(crate::jwt::keyring()?.jwks()));
+    Ok(())
+}
 
-    // let email = Message::builder()
-    // .from("yy <theyy.me@gmail.com>".parse().unwrap())
-    // // .reply_to("Yuin <yuin@domain.tld>".parse().unwrap())
-    // .to("Alan <alan@fpi.cc>".parse().unwrap())
-    // .subject("Happy new year")
-    // .header(ContentType::TEXT_PLAIN)
-    // .body(String::from("Be happy!"))
-    // .unwrap();
-
-    // // 
-    // let creds = Credentials::new("theyy.me@gmail.com".to_owned(), "!@#123Xicore".to_owned());
-
-    // // Open a remote connection to gmail
-    // let mailer = SmtpTransport::relay("smtp-relay.gmail.com")
-    // .unwrap()
-    // .credentials(creds)
-    // .build();
-
-    // // Send the email
-    // match mailer.send(&email) {
-    //     Ok(_) => println!("Email sent successfully!"),
-    //     Err(e) => panic!("Could not send email: {e:?}"),
-    // }
-
-
-
-
-    res.render("Hello world");
+/// OpenID Connect discovery for apps signing in with us, see `routers::oauth`.
+#[handler]
+pub async fn openid_configuration(res: &mut Response) -> AppResult<()> {
+    let issuer = crate::oauth_issuer();
+    // Without a private key no ID tokens are issued, so `openid` is not offered.
+    let algorithm = crate::jwt::keyring()?.signing_algorithm();
+    let mut scopes = things::oauth::OPENID_SCOPES.to_vec();
+    if algorithm.is_none() {
+        scopes.retain(|scope| *scope != "openid");
+    }
+    scopes.extend(things::api_token::SCOPES);
+    res.headers_mut()
+        .insert(CACHE_CONTROL, HeaderValue::from_static("public, max-age=300"));
+    res.render(Json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/oauth/authorize", issuer),
+        "token_endpoint": format!("{}/oauth/token", issuer),
+        "revocation_endpoint": format!("{}/oauth/revoke", issuer),
+        "introspection_endpoint": format!("{}/oauth/introspect", issuer),
+        "userinfo_endpoint": format!("{}/oauth/userinfo", issuer),
+        "jwks_uri": format!("{}/.well-known/jwks.json", issuer),
+        "scopes_supported": scopes,
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code", "refresh_token"],
+        "code_challenge_methods_supported": ["S256"],
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": algorithm.into_iter().collect::<Vec<_>>(),
+    })));
     Ok(())
 }
@@ -0,0 +1,447 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::Utc;
+use diesel::prelude::*;
+use salvo::http::header::{HeaderValue, CACHE_CONTROL, PRAGMA, WWW_AUTHENTICATE};
+use salvo::http::StatusCode;
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+use url::{form_urlencoded, Url};
+
+use crate::db;
+use crate::models::*;
+use crate::schema::*;
+use crate::things::oauth::{self, AuthorizationRequest, IssuedTokens};
+use crate::{context, AppResult};
+
+pub fn public_root(path: impl Into<String>) -> Router {
+    Router::with_path(path)
+        .push(Router::with_path("authorize").get(authorize))
+        .push(Router::with_path("token").post(issue_token))
+        .push(Router::with_path("revoke").post(revoke_token))
+        .push(Router::with_path("introspect").post(introspect_token))
+}
+pub fn authed_root(path: impl Into<String>) -> Router {
+    Router::with_path(path)
+        .push(
+            Router::with_path("authorize/consent")
+                .hoop(super::web_session_only)
+                .get(show_consent)
+                .post(decide_consent),
+        )
+        .push(Router::with_path("userinfo").get(userinfo).post(userinfo))
+}
+
+/// Query of an authorization request, as sent by the client to `authorize` and passed on unchanged by the
+/// consent page to `authorize/consent`.
+#[derive(Deserialize, Debug)]
+struct AuthorizeParams {
+    #[serde(default)]
+    response_type: String,
+    #[serde(default)]
+    client_id: String,
+    #[serde(default)]
+    redirect_uri: String,
+    #[serde(default)]
+    scope: String,
+    state: Option<String>,
+    #[serde(default)]
+    code_challenge: String,
+    #[serde(default)]
+    code_challenge_method: String,
+    nonce: Option<String>,
+}
+
+enum AuthorizeError {
+    /// The client or redirect uri can not be trusted, so the user must not be sent back to it.
+    Fatal(String),
+    /// Reported to the client at its redirect uri.
+    Redirect(&'static str, String),
+}
+
+struct ValidAuthorize {
+    client: OauthClient,
+    scopes: Vec<String>,
+}
+
+fn validate_authorize(
+    params: &AuthorizeParams,
+    conn: &mut PgConnection,
+) -> AppResult<Result<ValidAuthorize, AuthorizeError>> {
+    let client = match oauth::find_client(&params.client_id, conn)? {
+        Some(client) => client,
+        None => return Ok(Err(AuthorizeError::Fatal("client_id is not valid".into()))),
+    };
+    if !client.redirect_uris.contains(&params.redirect_uri) {
+        return Ok(Err(AuthorizeError::Fatal("redirect_uri is not registered for this client".into())));
+    }
+    if params.response_type != "code" {
+        return Ok(Err(AuthorizeError::Redirect(
+            "unsupported_response_type",
+            "response_type must be code".into(),
+        )));
+    }
+    if params.code_challenge.is_empty() || params.code_challenge_method != "S256" {
+        return Ok(Err(AuthorizeError::Redirect(
+            "invalid_request",
+            "code_challenge with code_challenge_method S256 is required".into(),
+        )));
+    }
+    let scopes = oauth::parse_scope(&params.scope);
+    if let Some(scope) = scopes.iter().find(|scope| !client.scopes.contains(scope)) {
+        return Ok(Err(AuthorizeError::Redirect(
+            "invalid_scope",
+            format!("scope `{}` is not allowed for this client", scope),
+        )));
+    }
+    // The ID token could not be signed, better to refuse now than at the token endpoint.
+    if scopes.iter().any(|scope| scope == "openid") && crate::jwt::keyring()?.signing_algorithm().is_none() {
+        return Ok(Err(AuthorizeError::Redirect(
+            "invalid_scope",
+            "scope `openid` is not available".into(),
+        )));
+    }
+    Ok(Ok(ValidAuthorize { client, scopes }))
+}
+
+/// `redirect_uri` with the given response parameters and the client's `state` appended.
+fn redirect_with(params: &AuthorizeParams, pairs: &[(&str, &str)]) -> AppResult<String> {
+    let mut url = Url::parse(&params.redirect_uri)?;
+    {
+        let mut query = url.query_pairs_mut();
+        for (key, value) in pairs {
+            query.append_pair(key, value);
+        }
+        if let Some(state) = &params.state {
+            query.append_pair("state", state);
+        }
+    }
+    Ok(url.into())
+}
+
+fn render_authorize_error_json(res: &mut Response, params: &AuthorizeParams, error: AuthorizeError) -> AppResult<()> {
+    match error {
+        AuthorizeError::Fatal(detail) => context::render_parse_param_error_json_with_detail(res, detail),
+        AuthorizeError::Redirect(error, description) => {
+            #[derive(Serialize, Debug)]
+            struct ResultData<'a> {
+                error: &'a str,
+                error_description: &'a str,
+                redirect_to: String,
+            }
+            res.set_status_code(StatusCode::BAD_REQUEST);
+            res.render(Json(ResultData {
+                error,
+                error_description: &description,
+                redirect_to: redirect_with(params, &[("error", error), ("error_description", &description)])?,
+            }));
+            Ok(())
+        }
+    }
+}
+
+/// Entry point of the authorization code flow, opened by the client in the user's browser. Valid requests are
+/// passed on to the consent page of the web app, which talks to `authorize/consent` on behalf of the signed in
+/// user.
+#[handler]
+pub async fn authorize(req: &mut Request, _depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let params = match req.parse_queries::<AuthorizeParams>() {
+        Ok(params) => params,
+        Err(_) => return context::render_parse_param_error_json(res),
+    };
+    db::run_in_place(|conn| {
+        let location = match validate_authorize(&params, conn)? {
+            Ok(_) => {
+                let mut url = Url::parse(&crate::oauth_consent_url())?;
+                url.set_query(req.uri().query());
+                String::from(url)
+            }
+            Err(AuthorizeError::Fatal(detail)) => return context::render_parse_param_error_json_with_detail(res, detail),
+            Err(AuthorizeError::Redirect(error, description)) => {
+                redirect_with(&params, &[("error", error), ("error_description", &description)])?
+            }
+        };
+        res.render(Redirect::found(location));
+        Ok(())
+    })
+}
+
+/// What the consent page shows: which app asks for which scopes, and whether the user already agreed to them.
+#[handler]
+pub async fn show_consent(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let params = match req.parse_queries::<AuthorizeParams>() {
+        Ok(params) => params,
+        Err(_) => return context::render_parse_param_error_json(res),
+    };
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let ValidAuthorize { client, scopes } = match validate_authorize(&params, conn)? {
+            Ok(valid) => valid,
+            Err(e) => return render_authorize_error_json(res, &params, e),
+        };
+
+        #[derive(Serialize, Debug)]
+        struct ResultData<'a> {
+            client: &'a OauthClient,
+            scopes: &'a [String],
+            consented: bool,
+        }
+        res.render(Json(ResultData {
+            client: &client,
+            scopes: &scopes,
+            consented: oauth::has_consent(cuser.id, &client, &scopes, conn)?,
+        }));
+        Ok(())
+    })
+}
+
+/// The user's answer on the consent page. Either way the response tells the page where to send the browser:
+/// back to the client with an authorization code, or with `error=access_denied`.
+#[handler]
+pub async fn decide_consent(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    #[derive(Deserialize, Debug)]
+    struct PostedData {
+        approve: bool,
+    }
+    let params = match req.parse_queries::<AuthorizeParams>() {
+        Ok(params) => params,
+        Err(_) => return context::render_parse_param_error_json(res),
+    };
+    let pdata = parse_posted_data!(req, res, PostedData);
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let ValidAuthorize { client, scopes } = match validate_authorize(&params, conn)? {
+            Ok(valid) => valid,
+            Err(e) => return render_authorize_error_json(res, &params, e),
+        };
+        let redirect_to = if pdata.approve {
+            oauth::grant_consent(cuser.id, &client, &scopes, conn)?;
+            let request = AuthorizationRequest {
+                redirect_uri: &params.redirect_uri,
+                scopes: &scopes,
+                code_challenge: &params.code_challenge,
+                nonce: params.nonce.as_deref(),
+            };
+            let code = oauth::create_code(cuser.id, &client, &request, conn)?;
+            redirect_with(&params, &[("code", &code)])?
+        } else {
+            redirect_with(
+                &params,
+                &[("error", "access_denied"), ("error_description", "the user denied the request")],
+            )?
+        };
+
+        #[derive(Serialize, Debug)]
+        struct ResultData {
+            redirect_to: String,
+        }
+        res.render(Json(ResultData { redirect_to }));
+        Ok(())
+    })
+}
+
+/// Body of the token, revocation and introspection endpoints, form encoded as RFC 6749 asks (JSON is accepted
+/// too). Client credentials may come here or with HTTP Basic authentication.
+#[derive(Deserialize, Debug)]
+struct PostedTokenData {
+    #[serde(default)]
+    grant_type: String,
+    #[serde(default)]
+    code: String,
+    #[serde(default)]
+    redirect_uri: String,
+    #[serde(default)]
+    code_verifier: String,
+    #[serde(default)]
+    refresh_token: String,
+    #[serde(default)]
+    token: String,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+/// Errors of the token endpoint family use the RFC 6749 format, which clients libraries expect, instead of
+/// `StatusInfo`.
+fn render_oauth_error(res: &mut Response, code: StatusCode, error: &str, description: &str) -> AppResult<()> {
+    #[derive(Serialize, Debug)]
+    struct ErrorData<'a> {
+        error: &'a str,
+        error_description: &'a str,
+    }
+    if code == StatusCode::UNAUTHORIZED {
+        res.headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Basic realm=\"oauth\""));
+    }
+    res.set_status_code(code);
+    res.render(Json(ErrorData {
+        error,
+        error_description: description,
+    }));
+    Ok(())
+}
+
+/// Client credentials from `Authorization: Basic`, falling back to `client_id`/`client_secret` in the body.
+fn client_credentials(req: &Request, pdata: &PostedTokenData) -> Option<(String, Option<String>)> {
+    let basic = req
+        .header::<String>("authorization")
+        .and_then(|v| v.strip_prefix("Basic ").map(|v| v.trim().to_owned()))
+        .and_then(|v| STANDARD.decode(v).ok())
+        .and_then(|v| String::from_utf8(v).ok());
+    if let Some(basic) = basic {
+        let (id, secret) = basic.split_once(':')?;
+        let decode = |v: &str| {
+            form_urlencoded::parse(v.as_bytes())
+                .next()
+                .map(|(v, _)| v.into_owned())
+                .unwrap_or_default()
+        };
+        return Some((decode(id), Some(decode(secret))));
+    }
+    let client_id = pdata.client_id.clone().filter(|id| !id.is_empty())?;
+    Some((client_id, pdata.client_secret.clone().filter(|secret| !secret.is_empty())))
+}
+
+async fn authenticate_client(
+    req: &mut Request,
+    res: &mut Response,
+) -> AppResult<Option<(PostedTokenData, OauthClient)>> {
+    let pdata = match req.parse_body::<PostedTokenData>().await {
+        Ok(pdata) => pdata,
+        Err(_) => {
+            render_oauth_error(res, StatusCode::BAD_REQUEST, "invalid_request", "request body is not valid")?;
+            return Ok(None);
+        }
+    };
+    let client = match client_credentials(req, &pdata) {
+        Some((client_id, secret)) => {
+            db::run_in_place(|conn| oauth::authenticate_client(&client_id, secret.as_deref(), conn))?
+        }
+        None => None,
+    };
+    match client {
+        Some(client) => Ok(Some((pdata, client))),
+        None => {
+            render_oauth_error(res, StatusCode::UNAUTHORIZED, "invalid_client", "client authentication failed")?;
+            Ok(None)
+        }
+    }
+}
+
+/// Token endpoint for the `authorization_code` and `refresh_token` grants.
+#[handler]
+pub async fn issue_token(req: &mut Request, _depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    res.headers_mut().insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    res.headers_mut().insert(PRAGMA, HeaderValue::from_static("no-cache"));
+    let (pdata, client) = match authenticate_client(req, res).await? {
+        Some(authenticated) => authenticated,
+        None => return Ok(()),
+    };
+    db::run_in_place(|conn| {
+        let tokens = match &*pdata.grant_type {
+            "authorization_code" => {
+                oauth::redeem_code(&client, &pdata.code, &pdata.redirect_uri, &pdata.code_verifier, conn)?
+            }
+            "refresh_token" => oauth::refresh(&client, &pdata.refresh_token, conn)?,
+            _ => {
+                return render_oauth_error(
+                    res,
+                    StatusCode::BAD_REQUEST,
+                    "unsupported_grant_type",
+                    "grant_type must be authorization_code or refresh_token",
+                )
+            }
+        };
+        let tokens = match tokens {
+            Some(tokens) => tokens,
+            None => {
+                return render_oauth_error(
+                    res,
+                    StatusCode::BAD_REQUEST,
+                    "invalid_grant",
+                    "the grant is invalid, expired, revoked or was issued to another client",
+                )
+            }
+        };
+        let id_token = if pdata.grant_type == "authorization_code" && tokens.scopes.iter().any(|s| s == "openid") {
+            let user = users::table.find(tokens.user_id).first::<User>(conn)?;
+            Some(oauth::create_id_token(&user, &client, &tokens, conn)?)
+        } else {
+            None
+        };
+        render_tokens_json(res, &tokens, id_token)
+    })
+}
+
+fn render_tokens_json(res: &mut Response, tokens: &IssuedTokens, id_token: Option<String>) -> AppResult<()> {
+    #[derive(Serialize, Debug)]
+    struct ResultData<'a> {
+        access_token: &'a str,
+        token_type: &'a str,
+        expires_in: i64,
+        refresh_token: &'a str,
+        scope: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        id_token: Option<String>,
+    }
+    res.render(Json(ResultData {
+        access_token: &tokens.access_token,
+        token_type: "Bearer",
+        expires_in: (tokens.expired_at - Utc::now()).num_seconds(),
+        refresh_token: &tokens.refresh_token,
+        scope: tokens.scopes.join(" "),
+        id_token,
+    }));
+    Ok(())
+}
+
+/// RFC 7009 revocation: ends the authorization of an access or refresh token. The response is empty and
+/// successful for unknown tokens too.
+#[handler]
+pub async fn revoke_token(req: &mut Request, _depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let (pdata, client) = match authenticate_client(req, res).await? {
+        Some(authenticated) => authenticated,
+        None => return Ok(()),
+    };
+    db::run_in_place(|conn| {
+        oauth::revoke(&client, &pdata.token, conn)?;
+        res.set_status_code(StatusCode::OK);
+        Ok(())
+    })
+}
+
+/// RFC 7662 introspection, for resource servers holding a client secret.
+#[handler]
+pub async fn introspect_token(req: &mut Request, _depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    res.headers_mut().insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    let (pdata, client) = match authenticate_client(req, res).await? {
+        Some(authenticated) => authenticated,
+        None => return Ok(()),
+    };
+    db::run_in_place(|conn| {
+        if !client.is_confidential() {
+            return render_oauth_error(
+                res,
+                StatusCode::UNAUTHORIZED,
+                "invalid_client",
+                "public clients can not introspect tokens",
+            );
+        }
+        res.render(Json(oauth::introspect(&pdata.token, conn)?));
+        Ok(())
+    })
+}
+
+/// OpenID Connect userinfo, with the claims the token's scopes allow.
+#[handler]
+pub async fn userinfo(_req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    let token = match context::current_access_token(depot).filter(|token| token.kind == "oauth") {
+        Some(token) => token,
+        None => return context::render_access_denied_json_with_detail(res, "an oauth access token is required"),
+    };
+    require_scope!(depot, res, "openid");
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        res.render(Json(oauth::user_claims(cuser, &token.scopes, conn)?));
+        Ok(())
+    })
+}
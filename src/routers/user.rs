@@ -1,4 +1,4 @@
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use diesel::prelude::*;
 use salvo::prelude::*;
 use serde::{Deserialize, Serialize};
@@ -6,20 +6,31 @@ use serde::{Deserialize, Serialize};
 use crate::db;
 use crate::models::*;
 use crate::schema::*;
+use crate::things::{impersonation, permission};
 use crate::utils::{validator};
 use crate::{context, AppResult};
 
 pub fn authed_root(path: impl Into<String>) -> Router {
     Router::with_path(path)
         .get(list)
-        .delete(bulk_delete)
+        .push(Router::new().hoop(super::no_impersonation).delete(bulk_delete))
         .push(
             Router::with_path(r"<id:/\d+/>")
                 .get(show)
-                .patch(update)
-                .delete(delete)
-                .push(Router::with_path("set_disabled").post(set_disabled))
+                .push(
+                    Router::new()
+                        .hoop(super::no_impersonation)
+                        .patch(update)
+                        .delete(delete)
+                        .push(Router::with_path("set_disabled").post(set_disabled)),
+                )
                 .push(Router::with_path("emails").get(list_emails))
+                .push(
+                    Router::with_path("impersonate")
+                        .hoop(super::kernel_only)
+                        .hoop(super::web_session_only)
+                        .post(impersonate),
+                )
         )
 }
 
@@ -29,58 +40,70 @@ pub fn public_root(path: impl Into<String>) -> Router {
 
 #[handler]
 pub async fn show(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
-    let mut conn = db::connect()?;
-    show_record!(req, depot, res, User, users, &mut conn);
-    Ok(())
+    require_scope!(depot, res, "users:read");
+    db::run_in_place(|conn| {
+        show_record!(req, depot, res, User, users, conn);
+        Ok(())
+    })
 }
 #[handler]
 pub async fn list(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
-    let cuser = current_user!(depot, res);
-    let mut conn = db::connect()?;
-    let query = users::table.filter(users::is_disabled.eq(false));
-    list_records!(
-        req,
-        res,
-        User,
-        query,
-        "updated_at desc",
-        USER_FILTER_FIELDS.clone(),
-        USER_JOINED_OPTIONS.clone(),
-        USER_SEARCH_TMPL,
-        &mut conn
-    );
-    Ok(())
+    require_scope!(depot, res, "users:read");
+    db::run_in_place(|conn| {
+        let query = users::table.filter(users::is_disabled.eq(false));
+        list_records!(
+            req,
+            depot,
+            res,
+            User,
+            query,
+            "updated_at desc",
+            USER_FILTER_FIELDS.clone(),
+            USER_JOINED_OPTIONS.clone(),
+            USER_SEARCH_TMPL,
+            conn
+        );
+        Ok(())
+    })
 }
 
 #[handler]
 pub async fn delete(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
-    let mut conn = db::connect()?;
-    delete_record!(req, depot, res, users, User, db::delete_user, &mut conn);
-    Ok(())
+    require_scope!(depot, res, "users:admin");
+    db::run_in_place(|conn| {
+        delete_record!(req, depot, res, users, User, db::delete_user, conn);
+        Ok(())
+    })
 }
 #[handler]
 pub async fn bulk_delete(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
-    let mut conn = db::connect()?;
-    bulk_delete_records!(req, depot, res, users, User, db::delete_user, &mut conn);
-    Ok(())
+    require_scope!(depot, res, "users:admin");
+    let ids = context::parse_ids_from_request(req, "id", "ids").await;
+    db::run_in_place(|conn| {
+        bulk_delete_records!(ids, depot, res, users, User, db::delete_user, conn);
+        Ok(())
+    })
 }
 
 
 #[handler]
 pub async fn list_emails(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    require_scope!(depot, res, "users:read");
     let cuser = current_user!(depot, res);
-    let mut conn = db::connect()?;
-    let user = get_record_by_param!(req, res, User, users, &mut conn);
+    db::run_in_place(|conn| {
+        let user = get_permitted_record_by_param!(cuser, permission::ACTION_UPDATE, req, res, User, users, conn);
 
-    let uemails = emails::table
-        .filter(emails::user_id.eq(user.id))
-        .get_results::<Email>(&mut conn)?;
-    res.render(Json(uemails));
-    Ok(())
+        let uemails = emails::table
+            .filter(emails::user_id.eq(user.id))
+            .get_results::<Email>(conn)?;
+        res.render(Json(uemails));
+        Ok(())
+    })
 }
 
 #[handler]
 pub async fn update(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    require_scope!(depot, res, "users:admin");
     #[derive(AsChangeset, Deserialize, Debug)]
     #[diesel(table_name = users)]
     struct PostedData {
@@ -88,38 +111,85 @@ pub async fn update(req: &mut Request, depot: &mut Depot, res: &mut Response) ->
     }
     let pdata = parse_posted_data!(req, res, PostedData);
     let cuser = current_user!(depot, res);
-    let mut conn = db::connect()?;
-    let user = get_record_by_param!(req, res, User, users, &mut conn);
+    db::run_in_place(|conn| {
+        let user = get_permitted_record_by_param!(cuser, permission::ACTION_UPDATE, req, res, User, users, conn);
 
-    let user = diesel::update(&user).set(&pdata).get_result::<User>(&mut conn)?;
-    res.render(Json(user));
-    Ok(())
+        let user = diesel::update(&user).set(&pdata).get_result::<User>(conn)?;
+        res.render(Json(user));
+        Ok(())
+    })
 }
 
 #[handler]
 pub async fn set_disabled(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    require_scope!(depot, res, "users:admin");
     #[derive(Deserialize, Debug)]
     struct PostedData {
         value: bool,
     }
     let pdata = parse_posted_data!(req, res, PostedData);
     let cuser = current_user!(depot, res);
-    let mut conn = db::connect()?;
-    let user = get_record_by_param!(req, res, User, users, &mut conn);
-    if user.id == cuser.id  || !cuser.in_kernel {
-        return context::render_access_denied_json(res);
+    db::run_in_place(|conn| {
+        let user = get_permitted_record_by_param!(cuser, permission::ACTION_DISABLE, req, res, User, users, conn);
+        if user.id == cuser.id {
+            return context::render_access_denied_json(res);
+        }
+        let user = if pdata.value {
+            diesel::update(&user)
+                .set((users::is_disabled.eq(pdata.value), users::disabled_at.eq(Utc::now())))
+                .get_result::<User>(conn)?
+        } else {
+            diesel::update(&user)
+                .set(users::is_disabled.eq(pdata.value))
+                .get_result::<User>(conn)?
+        };
+        res.render(Json(user));
+        Ok(())
+    })
+}
+
+/// Let support staff see the app as the user. The returned token is not set as cookie, so the staff member's own
+/// session stays untouched; it works until `expired_at` or until it is used to `auth/logout`.
+#[handler]
+pub async fn impersonate(req: &mut Request, depot: &mut Depot, res: &mut Response) -> AppResult<()> {
+    #[derive(Deserialize, Debug)]
+    struct PostedData {
+        #[serde(default)]
+        reason: String,
     }
-    let user = if pdata.value {
-        diesel::update(&user)
-            .set((users::is_disabled.eq(pdata.value), users::disabled_at.eq(Utc::now())))
-            .get_result::<User>(&mut conn)?
-    } else {
-        diesel::update(&user)
-            .set(users::is_disabled.eq(pdata.value))
-            .get_result::<User>(&mut conn)?
-    };
-    res.render(Json(user));
-    Ok(())
+    let pdata = parse_posted_data!(req, res, PostedData);
+    if pdata.reason.trim().is_empty() {
+        return context::render_parse_data_error_json_with_detail(res, "a reason is required to impersonate a user");
+    }
+    let cuser = current_user!(depot, res);
+    db::run_in_place(|conn| {
+        let user = get_record_by_param!(req, res, User, users, conn);
+        if user.id == cuser.id || user.in_kernel || user.is_disabled {
+            return context::render_access_denied_json_with_detail(res, "this user can not be impersonated");
+        }
+        let path = req.uri().path().to_owned();
+        let (token, expired_at) = impersonation::start(
+            cuser,
+            &user,
+            pdata.reason.trim(),
+            &path,
+            &context::client_info(req),
+            conn,
+        )?;
+
+        #[derive(Serialize, Debug)]
+        struct ResultData<'a> {
+            user: &'a User,
+            token: &'a str,
+            expired_at: DateTime<Utc>,
+        }
+        res.render(Json(ResultData {
+            user: &user,
+            token: &token,
+            expired_at,
+        }));
+        Ok(())
+    })
 }
 
 #[handler]
@@ -128,17 +198,18 @@ pub async fn is_other_taken(req: &mut Request, _depot: &mut Depot, res: &mut Res
     let ident_name: String = req.query("ident_name").unwrap_or_default();
     let email_value: String = req.query("email").unwrap_or_default();
     let mut taken = false;
-    let mut conn = db::connect()?;
-    if !ident_name.is_empty() {
-        taken = validator::is_ident_name_other_taken(user_id, &ident_name, &mut conn)?;
-    }
-    if !taken && !email_value.is_empty() {
-        taken = validator::is_email_other_taken(user_id, &email_value, &mut conn)?;
-    }
-    #[derive(Serialize, Debug)]
-    struct ResultData {
-        taken: bool,
-    }
-    res.render(Json(ResultData { taken }));
-    Ok(())
+    db::run_in_place(|conn| {
+        if !ident_name.is_empty() {
+            taken = validator::is_ident_name_other_taken(user_id, &ident_name, conn)?;
+        }
+        if !taken && !email_value.is_empty() {
+            taken = validator::is_email_other_taken(user_id, &email_value, conn)?;
+        }
+        #[derive(Serialize, Debug)]
+        struct ResultData {
+            taken: bool,
+        }
+        res.render(Json(ResultData { taken }));
+        Ok(())
+    })
 }
\ No newline at end of file
@@ -13,6 +13,31 @@ diesel::table! {
         updated_at -> Timestamptz,
         created_by -> Nullable<Int8>,
         created_at -> Timestamptz,
+        ip_address -> Nullable<Varchar>,
+        last_seen_at -> Nullable<Timestamptz>,
+        family_id -> Nullable<Varchar>,
+        scopes -> Array<Text>,
+        oauth_client_id -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    email_outbox (id) {
+        id -> Int8,
+        recipients -> Array<Text>,
+        subject -> Varchar,
+        body -> Varchar,
+        template -> Nullable<Varchar>,
+        status -> Varchar,
+        attempts -> Int4,
+        last_error -> Nullable<Varchar>,
+        next_attempt_at -> Timestamptz,
+        sent_at -> Nullable<Timestamptz>,
+        updated_by -> Nullable<Int8>,
+        updated_at -> Timestamptz,
+        created_by -> Nullable<Int8>,
+        created_at -> Timestamptz,
+        text_body -> Nullable<Varchar>,
     }
 }
 
@@ -30,6 +55,20 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    impersonation_logs (id) {
+        id -> Int8,
+        actor_id -> Int8,
+        user_id -> Int8,
+        access_token_id -> Nullable<Int8>,
+        method -> Varchar,
+        path -> Text,
+        status_code -> Nullable<Int4>,
+        reason -> Nullable<Text>,
+        created_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     messages (id) {
         id -> Int8,
@@ -61,6 +100,116 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    oauth_authorization_codes (id) {
+        id -> Int8,
+        oauth_client_id -> Int8,
+        user_id -> Int8,
+        value -> Varchar,
+        redirect_uri -> Text,
+        scopes -> Array<Text>,
+        code_challenge -> Varchar,
+        nonce -> Nullable<Varchar>,
+        family_id -> Nullable<Varchar>,
+        consumed_at -> Nullable<Timestamptz>,
+        expired_at -> Timestamptz,
+        updated_by -> Nullable<Int8>,
+        updated_at -> Timestamptz,
+        created_by -> Nullable<Int8>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    oauth_clients (id) {
+        id -> Int8,
+        client_id -> Varchar,
+        secret -> Nullable<Varchar>,
+        name -> Varchar,
+        redirect_uris -> Array<Text>,
+        scopes -> Array<Text>,
+        is_disabled -> Bool,
+        updated_by -> Nullable<Int8>,
+        updated_at -> Timestamptz,
+        created_by -> Nullable<Int8>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    oauth_consents (id) {
+        id -> Int8,
+        user_id -> Int8,
+        oauth_client_id -> Int8,
+        scopes -> Array<Text>,
+        updated_by -> Nullable<Int8>,
+        updated_at -> Timestamptz,
+        created_by -> Nullable<Int8>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    permissions (id) {
+        id -> Int8,
+        role_id -> Int8,
+        resource -> Varchar,
+        action -> Varchar,
+        created_by -> Nullable<Int8>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    rate_limit_buckets (key) {
+        key -> Varchar,
+        tokens -> Float8,
+        updated_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    recovery_codes (id) {
+        id -> Int8,
+        user_id -> Int8,
+        value -> Varchar,
+        consumed_at -> Nullable<Timestamptz>,
+        updated_by -> Nullable<Int8>,
+        updated_at -> Timestamptz,
+        created_by -> Nullable<Int8>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    refresh_tokens (id) {
+        id -> Int8,
+        user_id -> Int8,
+        family_id -> Varchar,
+        value -> Varchar,
+        replaced_by -> Nullable<Int8>,
+        revoked_at -> Nullable<Timestamptz>,
+        expired_at -> Timestamptz,
+        updated_by -> Nullable<Int8>,
+        updated_at -> Timestamptz,
+        created_by -> Nullable<Int8>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    roles (id) {
+        id -> Int8,
+        ident_name -> Varchar,
+        name -> Varchar,
+        description -> Nullable<Text>,
+        updated_by -> Nullable<Int8>,
+        updated_at -> Timestamptz,
+        created_by -> Nullable<Int8>,
+        created_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     security_codes (id) {
         id -> Int8,
@@ -74,6 +223,7 @@ diesel::table! {
         updated_at -> Timestamptz,
         created_by -> Nullable<Int8>,
         created_at -> Timestamptz,
+        failed_attempts -> Int4,
     }
 }
 
@@ -89,6 +239,31 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    user_identities (id) {
+        id -> Int8,
+        user_id -> Int8,
+        provider -> Varchar,
+        subject -> Varchar,
+        email -> Nullable<Varchar>,
+        last_login_at -> Nullable<Timestamptz>,
+        updated_by -> Nullable<Int8>,
+        updated_at -> Timestamptz,
+        created_by -> Nullable<Int8>,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    user_roles (id) {
+        id -> Int8,
+        user_id -> Int8,
+        role_id -> Int8,
+        created_by -> Nullable<Int8>,
+        created_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
     users (id) {
         id -> Int8,
@@ -105,15 +280,50 @@ diesel::table! {
         created_by -> Nullable<Int8>,
         created_at -> Timestamptz,
         in_kernel -> Bool,
+        locale -> Varchar,
+        timezone -> Varchar,
+        totp_secret -> Nullable<Varchar>,
+        totp_enabled -> Bool,
+        failed_attempts -> Int4,
+        locked_until -> Nullable<Timestamptz>,
+        totp_last_step -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    webauthn_credentials (id) {
+        id -> Int8,
+        user_id -> Int8,
+        credential_id -> Varchar,
+        name -> Varchar,
+        passkey -> Jsonb,
+        last_used_at -> Nullable<Timestamptz>,
+        updated_by -> Nullable<Int8>,
+        updated_at -> Timestamptz,
+        created_by -> Nullable<Int8>,
+        created_at -> Timestamptz,
     }
 }
 
 diesel::allow_tables_to_appear_in_same_query!(
     access_tokens,
+    email_outbox,
     emails,
+    impersonation_logs,
     messages,
     notifications,
+    oauth_authorization_codes,
+    oauth_clients,
+    oauth_consents,
+    permissions,
+    rate_limit_buckets,
+    recovery_codes,
+    refresh_tokens,
+    roles,
     security_codes,
     user_friends,
+    user_identities,
+    user_roles,
     users,
+    webauthn_credentials,
 );
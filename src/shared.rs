@@ -6,7 +6,7 @@ use strum_macros::{Display, EnumString};
 use chrono::{DateTime, Utc};
 use diesel::prelude::*;
 use jsonwebtoken as jwt;
-use jsonwebtoken::EncodingKey;
+use jsonwebtoken::{DecodingKey, EncodingKey};
 use once_cell::sync::Lazy;
 use salvo::http::{StatusCode, StatusError};
 use serde::Serialize;
@@ -15,7 +15,7 @@ use serde_json::Value;
 use crate::db::{self, lower};
 use crate::models::*;
 use crate::schema::*;
-use crate::JwtClaims;
+use crate::{JwtClaims, MagicLinkClaims, MfaPendingClaims};
 
 pub type AppResult<T> = Result<T, crate::Error>;
 
@@ -86,12 +86,253 @@ pub fn database_conns() -> u32 {
         .parse::<u32>()
         .expect("DATABASE_CONNS must be i32")
 }
+/// How long a request waits for a pooled connection before it gets a 503.
+pub fn database_acquire_timeout_secs() -> u64 {
+    env::var("DATABASE_ACQUIRE_TIMEOUT_SECS")
+        .unwrap_or_else(|_| "5".into())
+        .parse::<u64>()
+        .expect("DATABASE_ACQUIRE_TIMEOUT_SECS must be u64")
+}
 pub fn space_path() -> String {
     env::var("SPACE_PATH").expect("SPACE_PATH must be set")
 }
 pub fn cookie_domain() -> String {
     env::var("COOKIE_DOMAIN").expect("COOKIE_DOMAIN must be set")
 }
+pub fn dev_mode() -> bool {
+    env::var("DEV_MODE")
+        .map(|v| crate::utils::str_to_bool(&v))
+        .unwrap_or(false)
+}
+pub fn mail_transport() -> String {
+    env::var("MAIL_TRANSPORT").unwrap_or_else(|_| "smtp".into())
+}
+pub fn mail_from() -> String {
+    env::var("MAIL_FROM").expect("MAIL_FROM must be set")
+}
+pub fn mail_file_dir() -> String {
+    env::var("MAIL_FILE_DIR").unwrap_or_else(|_| "./tmp/emails".into())
+}
+pub fn smtp_host() -> String {
+    env::var("SMTP_HOST").expect("SMTP_HOST must be set")
+}
+pub fn smtp_port() -> u16 {
+    env::var("SMTP_PORT")
+        .unwrap_or_else(|_| "465".into())
+        .parse::<u16>()
+        .expect("SMTP_PORT must be u16")
+}
+pub fn smtp_username() -> String {
+    env::var("SMTP_USERNAME").unwrap_or_default()
+}
+pub fn smtp_password() -> String {
+    env::var("SMTP_PASSWORD").unwrap_or_default()
+}
+pub fn email_outbox_poll_secs() -> u64 {
+    env::var("EMAIL_OUTBOX_POLL_SECS")
+        .unwrap_or_else(|_| "5".into())
+        .parse::<u64>()
+        .expect("EMAIL_OUTBOX_POLL_SECS must be u64")
+}
+pub fn email_outbox_max_attempts() -> i32 {
+    env::var("EMAIL_OUTBOX_MAX_ATTEMPTS")
+        .unwrap_or_else(|_| "8".into())
+        .parse::<i32>()
+        .expect("EMAIL_OUTBOX_MAX_ATTEMPTS must be i32")
+}
+pub fn access_token_ttl_minutes() -> i64 {
+    env::var("ACCESS_TOKEN_TTL_MINUTES")
+        .unwrap_or_else(|_| "15".into())
+        .parse::<i64>()
+        .expect("ACCESS_TOKEN_TTL_MINUTES must be i64")
+}
+pub fn refresh_token_ttl_days() -> i64 {
+    env::var("REFRESH_TOKEN_TTL_DAYS")
+        .unwrap_or_else(|_| "30".into())
+        .parse::<i64>()
+        .expect("REFRESH_TOKEN_TTL_DAYS must be i64")
+}
+pub fn password_pepper() -> String {
+    env::var("PASSWORD_PEPPER").expect("PASSWORD_PEPPER must be set")
+}
+/// Short name (at most 8 bytes) of `PASSWORD_PEPPER`, stored with every hash so the pepper can be rotated.
+pub fn password_pepper_id() -> String {
+    env::var("PASSWORD_PEPPER_ID").unwrap_or_else(|_| "1".into())
+}
+/// Retired peppers still needed to verify older hashes, as `id:pepper` pairs separated by commas.
+pub fn password_old_peppers() -> Vec<(String, String)> {
+    env::var("PASSWORD_OLD_PEPPERS")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|pair| pair.trim().split_once(':'))
+        .map(|(id, pepper)| (id.to_owned(), pepper.to_owned()))
+        .collect()
+}
+pub fn password_argon2_memory_kib() -> u32 {
+    env::var("PASSWORD_ARGON2_MEMORY_KIB")
+        .unwrap_or_else(|_| "19456".into())
+        .parse::<u32>()
+        .expect("PASSWORD_ARGON2_MEMORY_KIB must be u32")
+}
+pub fn password_argon2_iterations() -> u32 {
+    env::var("PASSWORD_ARGON2_ITERATIONS")
+        .unwrap_or_else(|_| "2".into())
+        .parse::<u32>()
+        .expect("PASSWORD_ARGON2_ITERATIONS must be u32")
+}
+pub fn password_argon2_parallelism() -> u32 {
+    env::var("PASSWORD_ARGON2_PARALLELISM")
+        .unwrap_or_else(|_| "1".into())
+        .parse::<u32>()
+        .expect("PASSWORD_ARGON2_PARALLELISM must be u32")
+}
+pub fn password_min_length() -> usize {
+    env::var("PASSWORD_MIN_LENGTH")
+        .unwrap_or_else(|_| "8".into())
+        .parse::<usize>()
+        .expect("PASSWORD_MIN_LENGTH must be usize")
+}
+pub fn password_max_length() -> usize {
+    env::var("PASSWORD_MAX_LENGTH")
+        .unwrap_or_else(|_| "64".into())
+        .parse::<usize>()
+        .expect("PASSWORD_MAX_LENGTH must be usize")
+}
+pub fn password_require_lowercase() -> bool {
+    env::var("PASSWORD_REQUIRE_LOWERCASE")
+        .map(|v| crate::utils::str_to_bool(&v))
+        .unwrap_or(true)
+}
+pub fn password_require_uppercase() -> bool {
+    env::var("PASSWORD_REQUIRE_UPPERCASE")
+        .map(|v| crate::utils::str_to_bool(&v))
+        .unwrap_or(true)
+}
+pub fn password_require_digit() -> bool {
+    env::var("PASSWORD_REQUIRE_DIGIT")
+        .map(|v| crate::utils::str_to_bool(&v))
+        .unwrap_or(true)
+}
+pub fn password_require_symbol() -> bool {
+    env::var("PASSWORD_REQUIRE_SYMBOL")
+        .map(|v| crate::utils::str_to_bool(&v))
+        .unwrap_or(false)
+}
+pub fn password_min_distinct_chars() -> usize {
+    env::var("PASSWORD_MIN_DISTINCT_CHARS")
+        .unwrap_or_else(|_| "4".into())
+        .parse::<usize>()
+        .expect("PASSWORD_MIN_DISTINCT_CHARS must be usize")
+}
+/// Lowest accepted zxcvbn score, from 0 (anything goes) to 4.
+pub fn password_min_score() -> u8 {
+    env::var("PASSWORD_MIN_SCORE")
+        .unwrap_or_else(|_| "2".into())
+        .parse::<u8>()
+        .expect("PASSWORD_MIN_SCORE must be u8")
+}
+/// Directory of breached password SHA-1 prefix files, empty to skip the check.
+pub fn password_breached_dir() -> String {
+    env::var("PASSWORD_BREACHED_DIR").unwrap_or_else(|_| "conf/breached_passwords".into())
+}
+pub fn login_max_failed_attempts() -> i32 {
+    env::var("LOGIN_MAX_FAILED_ATTEMPTS")
+        .unwrap_or_else(|_| "5".into())
+        .parse::<i32>()
+        .expect("LOGIN_MAX_FAILED_ATTEMPTS must be i32")
+}
+pub fn login_lockout_minutes() -> i64 {
+    env::var("LOGIN_LOCKOUT_MINUTES")
+        .unwrap_or_else(|_| "15".into())
+        .parse::<i64>()
+        .expect("LOGIN_LOCKOUT_MINUTES must be i64")
+}
+pub fn login_max_ip_failures() -> u32 {
+    env::var("LOGIN_MAX_IP_FAILURES")
+        .unwrap_or_else(|_| "50".into())
+        .parse::<u32>()
+        .expect("LOGIN_MAX_IP_FAILURES must be u32")
+}
+pub fn security_code_max_attempts() -> i32 {
+    env::var("SECURITY_CODE_MAX_ATTEMPTS")
+        .unwrap_or_else(|_| "5".into())
+        .parse::<i32>()
+        .expect("SECURITY_CODE_MAX_ATTEMPTS must be i32")
+}
+pub fn trust_proxy_headers() -> bool {
+    env::var("TRUST_PROXY_HEADERS")
+        .map(|v| v == "true" || v == "1")
+        .unwrap_or(false)
+}
+/// How long an impersonation token works. It can not be refreshed, staff has to start over.
+pub fn impersonation_ttl_minutes() -> i64 {
+    env::var("IMPERSONATION_TTL_MINUTES")
+        .unwrap_or_else(|_| "30".into())
+        .parse::<i64>()
+        .expect("IMPERSONATION_TTL_MINUTES must be i64")
+}
+/// `memory` keeps rate limit buckets per process, `postgres` shares them between instances.
+pub fn rate_limit_store() -> String {
+    env::var("RATE_LIMIT_STORE").unwrap_or_else(|_| "memory".into())
+}
+pub fn jwt_keys_dir() -> String {
+    env::var("JWT_KEYS_DIR").unwrap_or_else(|_| "conf/jwt_keys".into())
+}
+pub fn jwt_signing_kid() -> Option<String> {
+    env::var("JWT_SIGNING_KID").ok().filter(|kid| !kid.is_empty())
+}
+/// Keep accepting HS256 JWTs without `kid` after switching to asymmetric keys. Ignored once `JWT_SIGNING_KID`
+/// is set.
+pub fn jwt_accept_legacy_hs256() -> bool {
+    env::var("JWT_ACCEPT_LEGACY_HS256")
+        .map(|v| crate::utils::str_to_bool(&v))
+        .unwrap_or(false)
+}
+/// Page of the web app that receives `?token=...` from a magic sign-in link and posts it to
+/// `auth/magic_link/consume`. Checked at startup, so an error here means the environment changed since.
+pub fn magic_link_url() -> AppResult<String> {
+    env::var("MAGIC_LINK_URL")
+        .ok()
+        .filter(|url| !url.is_empty())
+        .ok_or_else(|| crate::Error::Internal("MAGIC_LINK_URL must be set".into()))
+}
+/// Public base url of this service, the `iss` of ID tokens issued to OAuth clients.
+pub fn oauth_issuer() -> String {
+    env::var("OAUTH_ISSUER")
+        .expect("OAUTH_ISSUER must be set")
+        .trim_end_matches('/')
+        .to_owned()
+}
+/// Page of the web app showing the consent screen, see `routers::oauth::authorize`.
+pub fn oauth_consent_url() -> String {
+    env::var("OAUTH_CONSENT_URL").expect("OAUTH_CONSENT_URL must be set")
+}
+/// Names of the configured OpenID Connect providers, see `oidc`.
+pub fn oidc_providers() -> Vec<String> {
+    env::var("OIDC_PROVIDERS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+/// Page of the web app the provider redirects back to with `?code=...&state=...`. It posts them to
+/// `auth/oidc/<provider>/callback`, or to `account/identities/<provider>/finish` when linking.
+pub fn oidc_redirect_url() -> String {
+    env::var("OIDC_REDIRECT_URL").expect("OIDC_REDIRECT_URL must be set")
+}
+pub fn totp_issuer() -> String {
+    env::var("TOTP_ISSUER").unwrap_or_else(|_| "Savvy".into())
+}
+pub fn webauthn_rp_id() -> String {
+    env::var("WEBAUTHN_RP_ID").expect("WEBAUTHN_RP_ID must be set")
+}
+pub fn webauthn_rp_origin() -> String {
+    env::var("WEBAUTHN_RP_ORIGIN").expect("WEBAUTHN_RP_ORIGIN must be set")
+}
+pub fn webauthn_rp_name() -> String {
+    env::var("WEBAUTHN_RP_NAME").unwrap_or_else(|_| webauthn_rp_id())
+}
 pub fn is_ident_name_preserved(name: &str) -> bool {
     PRESERVED_IDENT_NAMES.contains(&name)
 }
@@ -209,16 +450,67 @@ pub fn rand_bytes(bytes: &[u8], len: usize) -> Vec<u8> {
     value
 }
 
-pub fn create_jwt_token(user: &User, expire: &DateTime<Utc>) -> jwt::errors::Result<String> {
+pub fn create_jwt_token(user: &User, expire: &DateTime<Utc>) -> AppResult<String> {
     let claim = JwtClaims {
         user: user.id,
         exp: expire.timestamp(),
+        act: None,
+    };
+    crate::jwt::keyring()?.encode(&claim)
+}
+/// A session JWT for `user` that records `actor` as the one actually using it.
+pub fn create_impersonation_token(user: &User, actor: &User, expire: &DateTime<Utc>) -> AppResult<String> {
+    let claim = JwtClaims {
+        user: user.id,
+        exp: expire.timestamp(),
+        act: Some(actor.id),
+    };
+    crate::jwt::keyring()?.encode(&claim)
+}
+
+/// The `mfa_pending` token handed out by the first login step. It is signed with a key derived from
+/// `SECRET_KEY`, so it can never be mistaken for a session JWT.
+pub fn create_mfa_pending_token(user: &User, expire: &DateTime<Utc>) -> jwt::errors::Result<String> {
+    let claim = MfaPendingClaims {
+        user: user.id,
+        exp: expire.timestamp(),
     };
     jwt::encode(
         &jwt::Header::default(),
         &claim,
-        &EncodingKey::from_secret(env::var("SECRET_KEY").expect("SECRET_KEY must be set").as_ref()),
+        &EncodingKey::from_secret(format!("{}:mfa_pending", secret_key()).as_ref()),
+    )
+}
+pub fn decode_mfa_pending_token(token: &str) -> jwt::errors::Result<MfaPendingClaims> {
+    jwt::decode::<MfaPendingClaims>(
+        token,
+        &DecodingKey::from_secret(format!("{}:mfa_pending", secret_key()).as_ref()),
+        &jwt::Validation::default(),
+    )
+    .map(|data| data.claims)
+}
+
+/// The token in a magic sign-in link. `nonce` is what the `security_codes` row stores (hashed), which makes the
+/// link single use; the signature keeps anyone from forging a link for a nonce they do not know the user of.
+pub fn create_magic_link_token(user: &User, nonce: &str, expire: &DateTime<Utc>) -> jwt::errors::Result<String> {
+    let claim = MagicLinkClaims {
+        user: user.id,
+        nonce: nonce.to_owned(),
+        exp: expire.timestamp(),
+    };
+    jwt::encode(
+        &jwt::Header::default(),
+        &claim,
+        &EncodingKey::from_secret(format!("{}:magic_link", secret_key()).as_ref()),
+    )
+}
+pub fn decode_magic_link_token(token: &str) -> jwt::errors::Result<MagicLinkClaims> {
+    jwt::decode::<MagicLinkClaims>(
+        token,
+        &DecodingKey::from_secret(format!("{}:magic_link", secret_key()).as_ref()),
+        &jwt::Validation::default(),
     )
+    .map(|data| data.claims)
 }
 
 pub fn mask_email(email: impl AsRef<str>) -> String {
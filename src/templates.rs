@@ -0,0 +1,96 @@
+use std::fs;
+use std::path::Path;
+
+use handlebars::Handlebars;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::AppResult;
+
+/// Every `.hbs` file under these directories is registered as `<namespace>/<relative path without extension>`,
+/// e.g. `conf/emails/security_code.hbs` becomes `emails/security_code`. Templates are also usable as partials,
+/// which is how bodies get wrapped in `{{#> emails/layout}}`.
+static TEMPLATE_DIRS: &[(&str, &str)] = &[("emails", "conf/emails"), ("notifications", "conf/notifications")];
+
+static REGISTRY: Lazy<Handlebars<'static>> = Lazy::new(|| {
+    let mut reg = Handlebars::new();
+    reg.set_dev_mode(crate::dev_mode());
+    crate::helpers::handlebars::register_common_helpers(&mut reg);
+    for (namespace, dir) in TEMPLATE_DIRS {
+        if let Err(e) = register_dir(&mut reg, namespace, Path::new(dir), Path::new(dir)) {
+            tracing::error!(error = ?e, dir = %dir, "load template directory failed");
+        }
+    }
+    reg
+});
+
+fn register_dir(reg: &mut Handlebars<'static>, namespace: &str, root: &Path, dir: &Path) -> AppResult<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            register_dir(reg, namespace, root, &path)?;
+            continue;
+        }
+        if path.extension().and_then(|e| e.to_str()) != Some("hbs") {
+            continue;
+        }
+        let relative = path.strip_prefix(root).unwrap_or(&path).with_extension("");
+        let name = join_path!(namespace, relative);
+        match reg.register_template_file(&name, &path) {
+            Ok(()) => tracing::debug!(name = %name, path = ?path, "template registered"),
+            Err(e) => tracing::error!(error = ?e, name = %name, path = ?path, "register template failed"),
+        }
+    }
+    Ok(())
+}
+
+pub fn render<T>(name: &str, data: &T) -> AppResult<String>
+where
+    T: Serialize,
+{
+    Ok(REGISTRY.render(name, data)?)
+}
+
+/// Render the most specific template for `locale`: `emails/verification.zh-CN`, then `emails/verification.zh`,
+/// then `emails/verification`.
+pub fn render_localized<T>(name: &str, locale: &str, data: &T) -> AppResult<String>
+where
+    T: Serialize,
+{
+    render(&resolve(name, locale, "").unwrap_or_else(|| name.to_owned()), data)
+}
+
+/// Find the registered variant of `name` for `locale`, where `suffix` selects a sibling such as `.txt`:
+/// `emails/verification.zh-CN.txt`, then `emails/verification.zh.txt`, then `emails/verification.txt`.
+pub fn resolve(name: &str, locale: &str, suffix: &str) -> Option<String> {
+    crate::i18n::fallback_chain(locale)
+        .into_iter()
+        .map(|locale| format!("{}.{}{}", name, locale, suffix))
+        .chain(std::iter::once(format!("{}{}", name, suffix)))
+        .find(|name| REGISTRY.has_template(name))
+}
+
+pub fn exists(name: &str) -> bool {
+    REGISTRY.has_template(name)
+}
+
+pub fn names() -> Vec<String> {
+    let mut names = REGISTRY.get_templates().keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+/// Make sure every template referenced by `things::notification` is registered, so a missing file fails at
+/// startup instead of on the first mail.
+pub fn check_required() -> AppResult<()> {
+    let missing = crate::things::notification::required_templates()
+        .into_iter()
+        .filter(|name| !REGISTRY.has_template(name))
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        tracing::info!(count = REGISTRY.get_templates().len(), "templates loaded");
+        Ok(())
+    } else {
+        Err(crate::Error::Internal(format!("missing templates: {}", missing.join(", "))))
+    }
+}
@@ -1,2 +1,11 @@
 pub mod user;
 pub mod notification;
+pub mod api_token;
+pub mod refresh_token;
+pub mod session;
+pub mod lockout;
+pub mod security_code;
+pub mod user_identity;
+pub mod oauth;
+pub mod impersonation;
+pub mod permission;
@@ -0,0 +1,51 @@
+use chrono::Utc;
+use diesel::prelude::*;
+
+use crate::models::*;
+use crate::schema::*;
+use crate::utils::hash_str_sha256;
+use crate::AppResult;
+
+/// Personal API tokens look like `svy_pat_<40 alphanumerics>`, so secret scanners can recognize a leaked one.
+pub const PREFIX: &str = "svy_pat_";
+
+/// Scopes an API token can be granted. Web sessions implicitly have all of them; managing credentials (tokens,
+/// sessions, 2FA, passkeys, password) is not grantable and stays limited to web sessions.
+pub const SCOPES: &[&str] = &[
+    "account:write",
+    "notifications:read",
+    "notifications:write",
+    "users:read",
+    "users:admin",
+    "admin",
+];
+
+pub fn generate() -> String {
+    format!("{}{}", PREFIX, crate::generate_token(40))
+}
+
+pub fn is_api_token(value: &str) -> bool {
+    value.starts_with(PREFIX)
+}
+
+pub fn validate_scopes(scopes: &[String]) -> Result<(), String> {
+    match scopes.iter().find(|scope| !SCOPES.contains(&scope.as_str())) {
+        Some(scope) => Err(format!("unknown scope `{}`", scope)),
+        None => Ok(()),
+    }
+}
+
+/// Look up an unexpired API token by its plain value. Only the SHA-256 hash is stored.
+pub fn find(value: &str, conn: &mut PgConnection) -> AppResult<Option<AccessToken>> {
+    Ok(access_tokens::table
+        .filter(access_tokens::kind.eq("api"))
+        .filter(access_tokens::value.eq(hash_str_sha256(value)))
+        .filter(access_tokens::expired_at.gt(Utc::now()))
+        .first::<AccessToken>(conn)
+        .optional()?)
+}
+
+/// Web and impersonation sessions have every scope, API and OAuth tokens only the ones they were granted.
+pub fn has_scope(token: &AccessToken, scope: &str) -> bool {
+    matches!(token.kind.as_str(), "web" | "impersonation") || token.scopes.iter().any(|s| s == scope)
+}
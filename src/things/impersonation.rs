@@ -0,0 +1,77 @@
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+
+use crate::models::*;
+use crate::schema::*;
+use crate::things::session::ClientInfo;
+use crate::AppResult;
+
+/// An impersonation session is an `access_tokens` row of kind `impersonation` created by the actor. It has no
+/// refresh token, so it ends at `expired_at` at the latest. `path` is the request starting it, which is logged
+/// with the reason.
+pub fn start(
+    actor: &User,
+    user: &User,
+    reason: &str,
+    path: &str,
+    client: &ClientInfo,
+    conn: &mut PgConnection,
+) -> AppResult<(String, DateTime<Utc>)> {
+    let exp = Utc::now() + Duration::minutes(crate::impersonation_ttl_minutes());
+    let token = crate::create_impersonation_token(user, actor, &exp)?;
+    conn.transaction::<_, crate::Error, _>(|conn| {
+        let session = diesel::insert_into(access_tokens::table)
+            .values(&NewAccessToken {
+                user_id: user.id,
+                name: Some(&actor.ident_name),
+                kind: "impersonation",
+                value: &token,
+                device: client.user_agent.as_deref(),
+                expired_at: exp,
+                ip_address: client.ip_address.as_deref(),
+                last_seen_at: None,
+                family_id: None,
+                scopes: &[],
+                oauth_client_id: None,
+                updated_by: Some(actor.id),
+                created_by: Some(actor.id),
+            })
+            .get_result::<AccessToken>(conn)?;
+        tracing::warn!(actor_id = actor.id, user_id = user.id, reason = %reason, "impersonation started");
+        diesel::insert_into(impersonation_logs::table)
+            .values(&NewImpersonationLog {
+                actor_id: actor.id,
+                user_id: user.id,
+                access_token_id: Some(session.id),
+                method: "POST",
+                path,
+                status_code: None,
+                reason: Some(reason),
+            })
+            .execute(conn)?;
+        Ok((token, exp))
+    })
+}
+
+/// Append a request made with an impersonation session to the audit trail.
+pub fn record(
+    actor_id: i64,
+    session: &AccessToken,
+    method: &str,
+    path: &str,
+    status_code: Option<i32>,
+    conn: &mut PgConnection,
+) -> AppResult<()> {
+    diesel::insert_into(impersonation_logs::table)
+        .values(&NewImpersonationLog {
+            actor_id,
+            user_id: session.user_id,
+            access_token_id: Some(session.id),
+            method,
+            path,
+            status_code,
+            reason: None,
+        })
+        .execute(conn)?;
+    Ok(())
+}
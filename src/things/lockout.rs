@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration as StdDuration;
+
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use once_cell::sync::Lazy;
+
+use crate::models::*;
+use crate::schema::*;
+use crate::AppResult;
+
+/// Failures after which responses start being delayed.
+const FREE_FAILURES: i32 = 2;
+const MAX_DELAY_MILLIS: u64 = 8_000;
+/// Longest lockout a user can get however many times the threshold is hit.
+const MAX_LOCKOUT_HOURS: i64 = 24;
+
+/// Failed attempts per client IP, counted in windows of `login_lockout_minutes`. They live in memory only: a
+/// restart forgets them, which is acceptable since the per user counters are stored with the user.
+static IP_FAILURES: Lazy<Mutex<HashMap<String, IpFailures>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct IpFailures {
+    count: u32,
+    window_started_at: DateTime<Utc>,
+}
+impl IpFailures {
+    fn is_current(&self, now: DateTime<Utc>) -> bool {
+        self.window_started_at + Duration::minutes(crate::login_lockout_minutes()) > now
+    }
+}
+
+pub fn is_locked(user: &User) -> bool {
+    user.locked_until.map(|until| until > Utc::now()).unwrap_or(false)
+}
+
+/// Count a failed login for `user`. Every `login_max_failed_attempts` failures lock the user for
+/// `login_lockout_minutes`, doubling each time the threshold is hit again before a successful login.
+pub fn record_failure(user: &User, conn: &mut PgConnection) -> AppResult<User> {
+    conn.transaction(|conn| {
+        // Increment in the database so concurrent failures can't all write the same count.
+        let user = diesel::update(user)
+            .set(users::failed_attempts.eq(users::failed_attempts + 1))
+            .get_result::<User>(conn)?;
+        let failed_attempts = user.failed_attempts;
+        let max_attempts = crate::login_max_failed_attempts().max(1);
+        if failed_attempts % max_attempts != 0 {
+            return Ok(user);
+        }
+        let factor = 2i64.saturating_pow((failed_attempts / max_attempts - 1).min(16) as u32);
+        let minutes = (crate::login_lockout_minutes() * factor).min(MAX_LOCKOUT_HOURS * 60);
+        tracing::warn!(user_id = user.id, failed_attempts, minutes, "user locked after failed logins");
+        let user = diesel::update(&user)
+            .set(users::locked_until.eq(Some(Utc::now() + Duration::minutes(minutes))))
+            .get_result::<User>(conn)?;
+        Ok(user)
+    })
+}
+
+/// Reset the failure counter once the user has fully signed in.
+pub fn record_success(user: &User, conn: &mut PgConnection) -> AppResult<()> {
+    if user.failed_attempts != 0 || user.locked_until.is_some() {
+        diesel::update(user)
+            .set((users::failed_attempts.eq(0), users::locked_until.eq(None::<DateTime<Utc>>)))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Whether `ip` has failed too often in the current window to be allowed another attempt.
+pub fn is_ip_blocked(ip: Option<&str>) -> bool {
+    let ip = match ip {
+        Some(ip) => ip,
+        None => return false,
+    };
+    let failures = IP_FAILURES.lock().unwrap();
+    failures
+        .get(ip)
+        .map(|f| f.is_current(Utc::now()) && f.count >= crate::login_max_ip_failures())
+        .unwrap_or(false)
+}
+
+/// Count a failed attempt from `ip` and return the number of failures in the current window.
+pub fn record_ip_failure(ip: Option<&str>) -> u32 {
+    let ip = match ip {
+        Some(ip) => ip,
+        None => return 0,
+    };
+    let now = Utc::now();
+    let mut failures = IP_FAILURES.lock().unwrap();
+    if failures.len() > 10_000 {
+        failures.retain(|_, f| f.is_current(now));
+    }
+    let entry = failures.entry(ip.to_owned()).or_insert(IpFailures {
+        count: 0,
+        window_started_at: now,
+    });
+    if !entry.is_current(now) {
+        entry.count = 0;
+        entry.window_started_at = now;
+    }
+    entry.count += 1;
+    entry.count
+}
+
+/// Slow down the response to a failed attempt: nothing for the first few failures, then doubling up to
+/// `MAX_DELAY_MILLIS`.
+pub async fn delay(failures: i32) {
+    if failures <= FREE_FAILURES {
+        return;
+    }
+    let exp = (failures - FREE_FAILURES - 1).min(16) as u32;
+    let millis = (500u64 << exp).min(MAX_DELAY_MILLIS);
+    tokio::time::sleep(StdDuration::from_millis(millis)).await;
+}
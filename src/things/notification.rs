@@ -1,36 +1,73 @@
-use handlebars::Handlebars;
-use once_cell::sync::Lazy;
 use serde::Serialize;
 use crate::{ AppResult};
 
-static HANDLEBARS: Lazy<Handlebars<'static>> = Lazy::new(|| {
-    let mut reg = Handlebars::new();
-    crate::helpers::handlebars::register_common_helpers(&mut reg);
-    reg
-});
+/// A context rendered by exactly one template, so callers never repeat the template name and
+/// `templates::check_required` can verify the file exists at startup.
+pub trait TemplateContext: Serialize {
+    const TEMPLATE: &'static str;
+
+    /// Locale used to pick the template variant and translate messages in it.
+    fn locale(&self) -> &str {
+        crate::i18n::DEFAULT_LOCALE
+    }
+}
+
+pub fn required_templates() -> Vec<&'static str> {
+    vec![
+        user::SecurityCodeContext::TEMPLATE,
+        user::VerificationContext::TEMPLATE,
+        user::MagicLinkContext::TEMPLATE,
+    ]
+}
+
 pub mod user {
+    use super::TemplateContext;
     use crate::models::*;
     #[derive(Serialize, Debug)]
     pub struct SecurityCodeContext<'a> {
         pub recipient: &'a User,
         pub code: String,
     }
+    impl TemplateContext for SecurityCodeContext<'_> {
+        const TEMPLATE: &'static str = "emails/security_code";
+        fn locale(&self) -> &str {
+            &self.recipient.locale
+        }
+    }
 
     #[derive(Serialize, Debug)]
     pub struct VerificationContext<'a> {
         pub recipient: &'a User,
         pub token: &'a str,
     }
+    impl TemplateContext for VerificationContext<'_> {
+        const TEMPLATE: &'static str = "emails/verification";
+        fn locale(&self) -> &str {
+            &self.recipient.locale
+        }
+    }
+
+    #[derive(Serialize, Debug)]
+    pub struct MagicLinkContext<'a> {
+        pub recipient: &'a User,
+        pub link: &'a str,
+    }
+    impl TemplateContext for MagicLinkContext<'_> {
+        const TEMPLATE: &'static str = "emails/magic_link";
+        fn locale(&self) -> &str {
+            &self.recipient.locale
+        }
+    }
 }
 
-pub fn render_body<T>(tpl_name: &str, data: T) -> AppResult<String>
+pub fn render_body<T>(data: &T) -> AppResult<String>
 where
-    T: Serialize,
+    T: TemplateContext,
 {
-    match HANDLEBARS.render(tpl_name, &data) {
+    match crate::templates::render_localized(T::TEMPLATE, data.locale(), data) {
         Ok(data) => Ok(data),
         Err(e) => {
-            tracing::error!(error = ?e, tpl_name = %tpl_name, "render notification template error");
+            tracing::error!(error = ?e, tpl_name = %T::TEMPLATE, "render notification template error");
             Err(crate::Error::Internal("render notification template error".into()))
         }
     }
@@ -0,0 +1,496 @@
+//! OAuth 2.1 authorization server for our other apps: authorization code grant with mandatory PKCE, refresh
+//! token rotation, revocation (RFC 7009) and introspection (RFC 7662).
+//!
+//! An authorization is an `access_tokens` row of kind `oauth` plus a refresh token family, just like a web
+//! session, so revoking it, rotating its refresh token and detecting refresh token reuse work the same way.
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::models::*;
+use crate::schema::*;
+use crate::things::refresh_token::{self, Rotation};
+use crate::things::{api_token, session};
+use crate::utils::hash_str_sha256;
+use crate::AppResult;
+
+/// OAuth access tokens look like `svy_oat_<40 alphanumerics>`, next to `svy_pat_` for personal API tokens.
+pub const ACCESS_TOKEN_PREFIX: &str = "svy_oat_";
+pub const CLIENT_SECRET_PREFIX: &str = "svy_ocs_";
+const CODE_MINUTES: i64 = 10;
+
+/// OpenID Connect scopes, grantable on top of the API scopes of `api_token::SCOPES`.
+pub const OPENID_SCOPES: &[&str] = &["openid", "profile", "email"];
+
+pub fn generate_client_id() -> String {
+    crate::generate_token(24)
+}
+
+pub fn generate_client_secret() -> String {
+    format!("{}{}", CLIENT_SECRET_PREFIX, crate::generate_token(40))
+}
+
+pub fn is_access_token(value: &str) -> bool {
+    value.starts_with(ACCESS_TOKEN_PREFIX)
+}
+
+/// Split a space separated `scope` parameter, dropping duplicates.
+pub fn parse_scope(scope: &str) -> Vec<String> {
+    let mut scopes = Vec::<String>::new();
+    for scope in scope.split_whitespace() {
+        if !scopes.iter().any(|s| s == scope) {
+            scopes.push(scope.to_owned());
+        }
+    }
+    scopes
+}
+
+pub fn validate_scopes(scopes: &[String]) -> Result<(), String> {
+    match scopes
+        .iter()
+        .find(|scope| !OPENID_SCOPES.contains(&scope.as_str()) && !api_token::SCOPES.contains(&scope.as_str()))
+    {
+        Some(scope) => Err(format!("unknown scope `{}`", scope)),
+        None => Ok(()),
+    }
+}
+
+/// Redirect URIs must be absolute, without fragment, and use https unless they point to a loopback address.
+pub fn validate_redirect_uri(uri: &str) -> Result<(), String> {
+    let url = Url::parse(uri).map_err(|_| format!("redirect uri `{}` is not a valid url", uri))?;
+    if url.fragment().is_some() {
+        return Err(format!("redirect uri `{}` must not have a fragment", uri));
+    }
+    let is_loopback = matches!(url.host_str(), Some("localhost") | Some("127.0.0.1") | Some("[::1]"));
+    match url.scheme() {
+        "https" => Ok(()),
+        "http" if is_loopback => Ok(()),
+        // Private-use schemes of native apps, e.g. `com.example.app:/callback`.
+        scheme if scheme.contains('.') => Ok(()),
+        _ => Err(format!("redirect uri `{}` must use https", uri)),
+    }
+}
+
+pub fn find_client(client_id: &str, conn: &mut PgConnection) -> AppResult<Option<OauthClient>> {
+    Ok(oauth_clients::table
+        .filter(oauth_clients::client_id.eq(client_id))
+        .filter(oauth_clients::is_disabled.eq(false))
+        .first::<OauthClient>(conn)
+        .optional()?)
+}
+
+/// Authenticate a client at the token, revocation or introspection endpoint. Confidential clients must present
+/// their secret, public clients must not have one.
+pub fn authenticate_client(
+    client_id: &str,
+    secret: Option<&str>,
+    conn: &mut PgConnection,
+) -> AppResult<Option<OauthClient>> {
+    let client = match find_client(client_id, conn)? {
+        Some(client) => client,
+        None => return Ok(None),
+    };
+    let authenticated = match (&client.secret, secret) {
+        (Some(hashed), Some(secret)) => *hashed == hash_str_sha256(secret),
+        (None, None) => true,
+        _ => false,
+    };
+    Ok(if authenticated { Some(client) } else { None })
+}
+
+/// Whether the user already agreed to give `client` all of `scopes`, so the consent screen can be skipped.
+pub fn has_consent(user_id: i64, client: &OauthClient, scopes: &[String], conn: &mut PgConnection) -> AppResult<bool> {
+    let consent = oauth_consents::table
+        .filter(oauth_consents::user_id.eq(user_id))
+        .filter(oauth_consents::oauth_client_id.eq(client.id))
+        .first::<OauthConsent>(conn)
+        .optional()?;
+    Ok(consent
+        .map(|consent| scopes.iter().all(|scope| consent.scopes.contains(scope)))
+        .unwrap_or(false))
+}
+
+/// Record the user's consent, adding `scopes` to what was granted before.
+pub fn grant_consent(user_id: i64, client: &OauthClient, scopes: &[String], conn: &mut PgConnection) -> AppResult<()> {
+    conn.transaction::<_, crate::Error, _>(|conn| {
+        let consent = oauth_consents::table
+            .filter(oauth_consents::user_id.eq(user_id))
+            .filter(oauth_consents::oauth_client_id.eq(client.id))
+            .for_update()
+            .first::<OauthConsent>(conn)
+            .optional()?;
+        match consent {
+            Some(consent) => {
+                let mut granted = consent.scopes.clone();
+                granted.extend(scopes.iter().filter(|scope| !consent.scopes.contains(scope)).cloned());
+                diesel::update(&consent)
+                    .set((
+                        oauth_consents::scopes.eq(granted),
+                        oauth_consents::updated_by.eq(user_id),
+                        oauth_consents::updated_at.eq(Utc::now()),
+                    ))
+                    .execute(conn)?;
+            }
+            None => {
+                diesel::insert_into(oauth_consents::table)
+                    .values(&NewOauthConsent {
+                        user_id,
+                        oauth_client_id: client.id,
+                        scopes,
+                        updated_by: Some(user_id),
+                        created_by: Some(user_id),
+                    })
+                    .execute(conn)?;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Withdraw the consent and end every authorization the user gave the client.
+pub fn revoke_consent(consent: &OauthConsent, conn: &mut PgConnection) -> AppResult<()> {
+    conn.transaction::<_, crate::Error, _>(|conn| {
+        let tokens = access_tokens::table
+            .filter(access_tokens::kind.eq("oauth"))
+            .filter(access_tokens::user_id.eq(consent.user_id))
+            .filter(access_tokens::oauth_client_id.eq(consent.oauth_client_id))
+            .get_results::<AccessToken>(conn)?;
+        for token in &tokens {
+            session::revoke(token, conn)?;
+        }
+        diesel::delete(consent).execute(conn)?;
+        Ok(())
+    })
+}
+
+pub struct AuthorizationRequest<'a> {
+    pub redirect_uri: &'a str,
+    pub scopes: &'a [String],
+    pub code_challenge: &'a str,
+    pub nonce: Option<&'a str>,
+}
+
+/// Issue a single use authorization code. Only its hash is stored.
+pub fn create_code(
+    user_id: i64,
+    client: &OauthClient,
+    request: &AuthorizationRequest,
+    conn: &mut PgConnection,
+) -> AppResult<String> {
+    let value = crate::generate_url_safe_token(48);
+    diesel::insert_into(oauth_authorization_codes::table)
+        .values(&NewOauthAuthorizationCode {
+            oauth_client_id: client.id,
+            user_id,
+            value: &hash_str_sha256(&value),
+            redirect_uri: request.redirect_uri,
+            scopes: request.scopes,
+            code_challenge: request.code_challenge,
+            nonce: request.nonce,
+            expired_at: Utc::now() + Duration::minutes(CODE_MINUTES),
+            updated_by: Some(user_id),
+            created_by: Some(user_id),
+        })
+        .execute(conn)?;
+    Ok(value)
+}
+
+pub struct IssuedTokens {
+    pub user_id: i64,
+    pub family_id: String,
+    pub access_token: String,
+    pub refresh_token: String,
+    pub expired_at: DateTime<Utc>,
+    pub scopes: Vec<String>,
+    pub nonce: Option<String>,
+}
+
+/// Exchange an authorization code for tokens. Returns `None` for any invalid code, including one presented a
+/// second time, in which case the tokens issued for it the first time are revoked as well.
+pub fn redeem_code(
+    client: &OauthClient,
+    value: &str,
+    redirect_uri: &str,
+    code_verifier: &str,
+    conn: &mut PgConnection,
+) -> AppResult<Option<IssuedTokens>> {
+    conn.transaction::<_, crate::Error, _>(|conn| {
+        let code = oauth_authorization_codes::table
+            .filter(oauth_authorization_codes::value.eq(hash_str_sha256(value)))
+            .for_update()
+            .first::<OauthAuthorizationCode>(conn)
+            .optional()?;
+        let code = match code {
+            Some(code) if code.oauth_client_id == client.id => code,
+            _ => return Ok(None),
+        };
+        if code.consumed_at.is_some() {
+            tracing::warn!(user_id = code.user_id, client_id = %client.client_id, "authorization code reused");
+            if let Some(family_id) = &code.family_id {
+                refresh_token::revoke_family(family_id, conn)?;
+            }
+            return Ok(None);
+        }
+        if code.expired_at < Utc::now() || code.redirect_uri != redirect_uri || !verify_pkce(code_verifier, &code) {
+            return Ok(None);
+        }
+        let tokens = issue(code.user_id, client, code.scopes.clone(), code.nonce.clone(), conn)?;
+        diesel::update(&code)
+            .set((
+                oauth_authorization_codes::consumed_at.eq(Utc::now()),
+                oauth_authorization_codes::family_id.eq(&tokens.family_id),
+                oauth_authorization_codes::updated_at.eq(Utc::now()),
+            ))
+            .execute(conn)?;
+        Ok(Some(tokens))
+    })
+}
+
+/// Only `S256` is supported, `plain` is not allowed by OAuth 2.1.
+fn verify_pkce(code_verifier: &str, code: &OauthAuthorizationCode) -> bool {
+    (43..=128).contains(&code_verifier.len())
+        && URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes())) == code.code_challenge
+}
+
+fn issue(
+    user_id: i64,
+    client: &OauthClient,
+    scopes: Vec<String>,
+    nonce: Option<String>,
+    conn: &mut PgConnection,
+) -> AppResult<IssuedTokens> {
+    let (refresh, refresh_token) = refresh_token::issue(user_id, None, conn)?;
+    let access_token = format!("{}{}", ACCESS_TOKEN_PREFIX, crate::generate_token(40));
+    let expired_at = access_token_expire();
+    diesel::insert_into(access_tokens::table)
+        .values(&NewAccessToken {
+            user_id,
+            name: Some(&client.name),
+            kind: "oauth",
+            value: &hash_str_sha256(&access_token),
+            device: None,
+            expired_at,
+            ip_address: None,
+            last_seen_at: None,
+            family_id: Some(&refresh.family_id),
+            scopes: &scopes,
+            oauth_client_id: Some(client.id),
+            updated_by: Some(user_id),
+            created_by: Some(user_id),
+        })
+        .execute(conn)?;
+    Ok(IssuedTokens {
+        user_id,
+        family_id: refresh.family_id,
+        access_token,
+        refresh_token,
+        expired_at,
+        scopes,
+        nonce,
+    })
+}
+
+/// Rotate a refresh token of `client` and put a new access token on its authorization. Returns `None` when the
+/// token is invalid or was issued to another client.
+pub fn refresh(client: &OauthClient, value: &str, conn: &mut PgConnection) -> AppResult<Option<IssuedTokens>> {
+    let authorization = match find_authorization_by_refresh_token(value, conn)? {
+        Some(authorization) if authorization.oauth_client_id == Some(client.id) => authorization,
+        _ => return Ok(None),
+    };
+    let (user_id, family_id, refresh_token) = match refresh_token::rotate(value, conn)? {
+        Rotation::Rotated {
+            user_id,
+            family_id,
+            value,
+        } => (user_id, family_id, value),
+        Rotation::Invalid | Rotation::Reused => return Ok(None),
+    };
+    let user = users::table.find(user_id).first::<User>(conn)?;
+    if user.is_disabled {
+        session::revoke(&authorization, conn)?;
+        return Ok(None);
+    }
+    let access_token = format!("{}{}", ACCESS_TOKEN_PREFIX, crate::generate_token(40));
+    let expired_at = access_token_expire();
+    let count = diesel::update(&authorization)
+        .set((
+            access_tokens::value.eq(hash_str_sha256(&access_token)),
+            access_tokens::expired_at.eq(expired_at),
+            access_tokens::updated_at.eq(Utc::now()),
+        ))
+        .execute(conn)?;
+    if count == 0 {
+        return Ok(None);
+    }
+    Ok(Some(IssuedTokens {
+        user_id,
+        family_id,
+        access_token,
+        refresh_token,
+        expired_at,
+        scopes: authorization.scopes,
+        nonce: None,
+    }))
+}
+
+/// Look up an unexpired OAuth access token by its plain value, as long as its client is enabled.
+pub fn find_access_token(value: &str, conn: &mut PgConnection) -> AppResult<Option<AccessToken>> {
+    Ok(access_tokens::table
+        .filter(access_tokens::kind.eq("oauth"))
+        .filter(access_tokens::value.eq(hash_str_sha256(value)))
+        .filter(access_tokens::expired_at.gt(Utc::now()))
+        .filter(
+            access_tokens::oauth_client_id.eq_any(
+                oauth_clients::table
+                    .filter(oauth_clients::is_disabled.eq(false))
+                    .select(oauth_clients::id.nullable()),
+            ),
+        )
+        .first::<AccessToken>(conn)
+        .optional()?)
+}
+
+/// The authorization a refresh token belongs to, whether or not the token is still usable.
+fn find_authorization_by_refresh_token(value: &str, conn: &mut PgConnection) -> AppResult<Option<AccessToken>> {
+    let family_id = refresh_tokens::table
+        .filter(refresh_tokens::value.eq(hash_str_sha256(value)))
+        .select(refresh_tokens::family_id)
+        .first::<String>(conn)
+        .optional()?;
+    match family_id {
+        Some(family_id) => Ok(access_tokens::table
+            .filter(access_tokens::kind.eq("oauth"))
+            .filter(access_tokens::family_id.eq(family_id))
+            .first::<AccessToken>(conn)
+            .optional()?),
+        None => Ok(None),
+    }
+}
+
+/// Revoke the authorization an access or refresh token of `client` belongs to. Unknown tokens and tokens of
+/// other clients are ignored, as RFC 7009 asks.
+pub fn revoke(client: &OauthClient, value: &str, conn: &mut PgConnection) -> AppResult<()> {
+    let authorization = if is_access_token(value) {
+        access_tokens::table
+            .filter(access_tokens::kind.eq("oauth"))
+            .filter(access_tokens::value.eq(hash_str_sha256(value)))
+            .first::<AccessToken>(conn)
+            .optional()?
+    } else {
+        find_authorization_by_refresh_token(value, conn)?
+    };
+    if let Some(authorization) = authorization.filter(|a| a.oauth_client_id == Some(client.id)) {
+        session::revoke(&authorization, conn)?;
+    }
+    Ok(())
+}
+
+/// RFC 7662 response for `value`, `{"active": false}` for anything not currently usable.
+pub fn introspect(value: &str, conn: &mut PgConnection) -> AppResult<Value> {
+    #[derive(Serialize, Debug)]
+    struct Introspection<'a> {
+        active: bool,
+        scope: String,
+        client_id: &'a str,
+        username: &'a str,
+        token_type: &'a str,
+        exp: i64,
+        iat: i64,
+        sub: String,
+        aud: &'a str,
+        iss: String,
+    }
+    let inactive = Ok(serde_json::json!({ "active": false }));
+    let (authorization, token_type, exp) = if is_access_token(value) {
+        match find_access_token(value, conn)? {
+            Some(token) => {
+                let exp = token.expired_at;
+                (token, "access_token", exp)
+            }
+            None => return inactive,
+        }
+    } else {
+        let refresh = refresh_tokens::table
+            .filter(refresh_tokens::value.eq(hash_str_sha256(value)))
+            .filter(refresh_tokens::replaced_by.is_null())
+            .filter(refresh_tokens::revoked_at.is_null())
+            .filter(refresh_tokens::expired_at.gt(Utc::now()))
+            .first::<RefreshToken>(conn)
+            .optional()?;
+        let refresh = match refresh {
+            Some(refresh) => refresh,
+            None => return inactive,
+        };
+        match find_authorization_by_refresh_token(value, conn)? {
+            Some(token) => (token, "refresh_token", refresh.expired_at),
+            None => return inactive,
+        }
+    };
+    let client = match authorization.oauth_client_id {
+        Some(id) => oauth_clients::table.find(id).first::<OauthClient>(conn)?,
+        None => return inactive,
+    };
+    let user = users::table.find(authorization.user_id).first::<User>(conn)?;
+    if client.is_disabled || user.is_disabled {
+        return inactive;
+    }
+    Ok(serde_json::to_value(Introspection {
+        active: true,
+        scope: authorization.scopes.join(" "),
+        client_id: &client.client_id,
+        username: &user.ident_name,
+        token_type,
+        exp: exp.timestamp(),
+        iat: authorization.updated_at.timestamp(),
+        sub: user.id.to_string(),
+        aud: &client.client_id,
+        iss: crate::oauth_issuer(),
+    })?)
+}
+
+/// OpenID Connect claims about `user` the granted `scopes` allow, shared by the ID token and `userinfo`.
+pub fn user_claims(user: &User, scopes: &[String], conn: &mut PgConnection) -> AppResult<Map<String, Value>> {
+    let mut claims = Map::new();
+    claims.insert("sub".into(), user.id.to_string().into());
+    if scopes.iter().any(|s| s == "profile") {
+        claims.insert("preferred_username".into(), user.ident_name.clone().into());
+        claims.insert("name".into(), user.display_name.clone().into());
+    }
+    if scopes.iter().any(|s| s == "email") {
+        let email = emails::table
+            .filter(emails::user_id.eq(user.id))
+            .order((emails::is_verified.desc(), emails::id.asc()))
+            .first::<Email>(conn)
+            .optional()?;
+        if let Some(email) = email {
+            claims.insert("email".into(), email.value.into());
+            claims.insert("email_verified".into(), email.is_verified.into());
+        }
+    }
+    Ok(claims)
+}
+
+/// ID token for an authorization with the `openid` scope, signed with the keys published at
+/// `/.well-known/jwks.json`.
+pub fn create_id_token(user: &User, client: &OauthClient, tokens: &IssuedTokens, conn: &mut PgConnection) -> AppResult<String> {
+    let mut claims = user_claims(user, &tokens.scopes, conn)?;
+    let now = Utc::now();
+    claims.insert("iss".into(), crate::oauth_issuer().into());
+    claims.insert("aud".into(), client.client_id.clone().into());
+    claims.insert("iat".into(), now.timestamp().into());
+    claims.insert("exp".into(), tokens.expired_at.timestamp().into());
+    if let Some(nonce) = &tokens.nonce {
+        claims.insert("nonce".into(), nonce.clone().into());
+    }
+    crate::jwt::keyring()?.encode_asymmetric(&claims)
+}
+
+fn access_token_expire() -> DateTime<Utc> {
+    Utc::now() + Duration::minutes(crate::access_token_ttl_minutes())
+}
@@ -0,0 +1,117 @@
+use diesel::prelude::*;
+
+use crate::db::PermitFilter;
+use crate::models::*;
+use crate::schema::*;
+use crate::AppResult;
+
+pub const ACTION_VIEW: &str = "view";
+pub const ACTION_UPDATE: &str = "update";
+pub const ACTION_DELETE: &str = "delete";
+pub const ACTION_DISABLE: &str = "disable";
+pub const ACTIONS: &[&str] = &[ACTION_VIEW, ACTION_UPDATE, ACTION_DELETE, ACTION_DISABLE];
+
+/// Resources a role can be granted actions on, named like their tables.
+pub const RESOURCES: &[&str] = &[
+    User::RESOURCE,
+    Notification::RESOURCE,
+    EmailOutbox::RESOURCE,
+    ImpersonationLog::RESOURCE,
+    OauthClient::RESOURCE,
+    Role::RESOURCE,
+];
+
+pub fn validate_grant(resource: &str, action: &str) -> Result<(), String> {
+    if resource != "*" && !RESOURCES.contains(&resource) {
+        return Err(format!("unknown resource `{}`", resource));
+    }
+    if action != "*" && !ACTIONS.contains(&action) {
+        return Err(format!("unknown action `{}`", action));
+    }
+    Ok(())
+}
+
+/// Whether one of the user's roles grants `action` on `resource`. `in_kernel` users are granted everything.
+pub fn is_granted(user: &User, resource: &str, action: &str, conn: &mut PgConnection) -> AppResult<bool> {
+    if user.in_kernel {
+        return Ok(true);
+    }
+    let query = user_roles::table
+        .inner_join(permissions::table.on(permissions::role_id.eq(user_roles::role_id)))
+        .filter(user_roles::user_id.eq(user.id))
+        .filter(permissions::resource.eq_any(vec![resource, "*"]))
+        .filter(permissions::action.eq_any(vec![action, "*"]));
+    Ok(diesel_exists!(query, conn))
+}
+
+/// Who may do what with the records of a model. `list_records!` restricts its query with `permit_filter`, the
+/// single record macros (`show_record!`, `delete_record!`, `bulk_delete_records!`) ask `is_permitted`.
+///
+/// By default only users granted the action on `RESOURCE` by a role may do anything.
+pub trait Policy {
+    const RESOURCE: &'static str;
+
+    fn permit_filter(user: &User, action: &str, conn: &mut PgConnection) -> AppResult<PermitFilter> {
+        if is_granted(user, Self::RESOURCE, action, conn)? {
+            Ok(PermitFilter::Allowed)
+        } else {
+            Ok(PermitFilter::Denied)
+        }
+    }
+    fn is_permitted(&self, user: &User, action: &str, conn: &mut PgConnection) -> AppResult<bool> {
+        is_granted(user, Self::RESOURCE, action, conn)
+    }
+}
+
+/// Everyone signed in may see other users; users may update themselves. Anything else needs a grant, and a grant
+/// does not reach `in_kernel` users: only `in_kernel` users may change those.
+impl Policy for User {
+    const RESOURCE: &'static str = "users";
+
+    fn permit_filter(user: &User, action: &str, conn: &mut PgConnection) -> AppResult<PermitFilter> {
+        if action == ACTION_VIEW || user.in_kernel {
+            Ok(PermitFilter::Allowed)
+        } else if is_granted(user, Self::RESOURCE, action, conn)? {
+            Ok(PermitFilter::Query(vec![Box::new(users::in_kernel.eq(false))]))
+        } else if action == ACTION_UPDATE {
+            Ok(PermitFilter::Query(vec![Box::new(users::id.eq(user.id))]))
+        } else {
+            Ok(PermitFilter::Denied)
+        }
+    }
+    fn is_permitted(&self, user: &User, action: &str, conn: &mut PgConnection) -> AppResult<bool> {
+        if action == ACTION_VIEW || user.in_kernel || (action == ACTION_UPDATE && self.id == user.id) {
+            return Ok(true);
+        }
+        Ok(!self.in_kernel && is_granted(user, Self::RESOURCE, action, conn)?)
+    }
+}
+
+/// Notifications belong to their owner.
+impl Policy for Notification {
+    const RESOURCE: &'static str = "notifications";
+
+    fn permit_filter(user: &User, action: &str, conn: &mut PgConnection) -> AppResult<PermitFilter> {
+        if is_granted(user, Self::RESOURCE, action, conn)? {
+            Ok(PermitFilter::Allowed)
+        } else {
+            Ok(PermitFilter::Query(vec![Box::new(notifications::owner_id.eq(user.id))]))
+        }
+    }
+    fn is_permitted(&self, user: &User, action: &str, conn: &mut PgConnection) -> AppResult<bool> {
+        Ok(self.owner_id == user.id || is_granted(user, Self::RESOURCE, action, conn)?)
+    }
+}
+
+impl Policy for EmailOutbox {
+    const RESOURCE: &'static str = "email_outbox";
+}
+impl Policy for ImpersonationLog {
+    const RESOURCE: &'static str = "impersonation_logs";
+}
+impl Policy for OauthClient {
+    const RESOURCE: &'static str = "oauth_clients";
+}
+impl Policy for Role {
+    const RESOURCE: &'static str = "roles";
+}
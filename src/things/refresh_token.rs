@@ -0,0 +1,104 @@
+use chrono::{Duration, Utc};
+use diesel::prelude::*;
+
+use crate::models::*;
+use crate::schema::*;
+use crate::utils::{hash_str_sha256, uuid_string};
+use crate::AppResult;
+
+/// Outcome of presenting a refresh token to `rotate`.
+pub enum Rotation {
+    /// The token was valid; it has been replaced by the returned one.
+    Rotated {
+        user_id: i64,
+        family_id: String,
+        value: String,
+    },
+    /// Unknown, expired or revoked token.
+    Invalid,
+    /// The token was already rotated, so someone is replaying it. The whole family has been revoked.
+    Reused,
+}
+
+/// Issue a new opaque refresh token. Without `family_id` this starts a new family, i.e. a new login.
+/// Only the SHA-256 hash is stored; the returned plain value is handed to the client once.
+pub fn issue(user_id: i64, family_id: Option<&str>, conn: &mut PgConnection) -> AppResult<(RefreshToken, String)> {
+    let value = crate::generate_url_safe_token(48);
+    let family_id = family_id.map(ToOwned::to_owned).unwrap_or_else(uuid_string);
+    let token = NewRefreshToken {
+        user_id,
+        family_id: &family_id,
+        value: &hash_str_sha256(&value),
+        expired_at: Utc::now() + Duration::days(crate::refresh_token_ttl_days()),
+        updated_by: Some(user_id),
+        created_by: Some(user_id),
+    };
+    let token = diesel::insert_into(refresh_tokens::table)
+        .values(&token)
+        .get_result::<RefreshToken>(conn)?;
+    Ok((token, value))
+}
+
+pub fn rotate(value: &str, conn: &mut PgConnection) -> AppResult<Rotation> {
+    conn.transaction::<_, crate::Error, _>(|conn| {
+        let token = refresh_tokens::table
+            .filter(refresh_tokens::value.eq(hash_str_sha256(value)))
+            .for_update()
+            .first::<RefreshToken>(conn)
+            .optional()?;
+        let token = match token {
+            Some(token) => token,
+            None => return Ok(Rotation::Invalid),
+        };
+        if token.replaced_by.is_some() {
+            tracing::warn!(user_id = token.user_id, family_id = %token.family_id, "refresh token reused, family revoked");
+            revoke_family(&token.family_id, conn)?;
+            return Ok(Rotation::Reused);
+        }
+        if token.revoked_at.is_some() || token.expired_at < Utc::now() {
+            return Ok(Rotation::Invalid);
+        }
+        let (next, value) = issue(token.user_id, Some(&token.family_id), conn)?;
+        diesel::update(&token)
+            .set((
+                refresh_tokens::replaced_by.eq(next.id),
+                refresh_tokens::updated_at.eq(Utc::now()),
+            ))
+            .execute(conn)?;
+        Ok(Rotation::Rotated {
+            user_id: token.user_id,
+            family_id: token.family_id,
+            value,
+        })
+    })
+}
+
+/// Revoke every token of the family and end the session it belongs to.
+pub fn revoke_family(family_id: &str, conn: &mut PgConnection) -> AppResult<usize> {
+    diesel::delete(access_tokens::table.filter(access_tokens::family_id.eq(family_id))).execute(conn)?;
+    Ok(diesel::update(
+        refresh_tokens::table
+            .filter(refresh_tokens::family_id.eq(family_id))
+            .filter(refresh_tokens::revoked_at.is_null()),
+    )
+    .set((
+        refresh_tokens::revoked_at.eq(Utc::now()),
+        refresh_tokens::updated_at.eq(Utc::now()),
+    ))
+    .execute(conn)?)
+}
+
+/// Revoke every refresh token of the user, e.g. on logout or password change.
+pub fn revoke_all(user_id: i64, conn: &mut PgConnection) -> AppResult<usize> {
+    Ok(diesel::update(
+        refresh_tokens::table
+            .filter(refresh_tokens::user_id.eq(user_id))
+            .filter(refresh_tokens::revoked_at.is_null()),
+    )
+    .set((
+        refresh_tokens::revoked_at.eq(Utc::now()),
+        refresh_tokens::updated_by.eq(user_id),
+        refresh_tokens::updated_at.eq(Utc::now()),
+    ))
+    .execute(conn)?)
+}
@@ -0,0 +1,69 @@
+use chrono::Utc;
+use diesel::prelude::*;
+
+use crate::models::*;
+use crate::schema::*;
+use crate::AppResult;
+
+/// Outcome of checking a posted security code with `check`.
+pub enum Check {
+    Valid(SecurityCode),
+    /// No code was sent or the value is wrong.
+    Invalid,
+    /// The code was guessed wrong `security_code_max_attempts` times and can not be used anymore.
+    Exhausted,
+    Consumed,
+    Expired,
+}
+
+/// Check `value` against the latest code sent to the user (to `email` when given). A wrong value counts against
+/// that code instead of just missing a row, so a 6-digit code can not be enumerated.
+pub fn check(user_id: i64, email: Option<&str>, value: &str, conn: &mut PgConnection) -> AppResult<Check> {
+    conn.transaction::<_, crate::Error, _>(|conn| {
+        let mut query = security_codes::table
+            .filter(security_codes::user_id.eq(user_id))
+            .filter(security_codes::send_method.eq("email"))
+            .order(security_codes::id.desc())
+            .select(security_codes::id)
+            .into_boxed();
+        if let Some(email) = email {
+            query = query.filter(security_codes::email.eq(email));
+        }
+        let code_id = match query.first::<i64>(conn).optional()? {
+            Some(code_id) => code_id,
+            None => return Ok(Check::Invalid),
+        };
+        let code = security_codes::table
+            .find(code_id)
+            .for_update()
+            .first::<SecurityCode>(conn)?;
+        let max_attempts = crate::security_code_max_attempts();
+        if code.failed_attempts >= max_attempts {
+            return Ok(Check::Exhausted);
+        }
+        if code.value != value {
+            if code.consumed_at.is_some() || code.expired_at < Utc::now() {
+                return Ok(Check::Invalid);
+            }
+            let failed_attempts = code.failed_attempts + 1;
+            diesel::update(&code)
+                .set((
+                    security_codes::failed_attempts.eq(failed_attempts),
+                    security_codes::updated_at.eq(Utc::now()),
+                ))
+                .execute(conn)?;
+            return Ok(if failed_attempts >= max_attempts {
+                Check::Exhausted
+            } else {
+                Check::Invalid
+            });
+        }
+        if code.consumed_at.is_some() {
+            Ok(Check::Consumed)
+        } else if code.expired_at < Utc::now() {
+            Ok(Check::Expired)
+        } else {
+            Ok(Check::Valid(code))
+        }
+    })
+}
@@ -0,0 +1,113 @@
+use chrono::{DateTime, Duration, Utc};
+use diesel::prelude::*;
+
+use crate::models::*;
+use crate::schema::*;
+use crate::things::refresh_token;
+use crate::AppResult;
+
+/// `last_seen_at` and the client details of a session are written at most this often.
+const TOUCH_INTERVAL_SECONDS: i64 = 300;
+
+/// Where a request came from, recorded on the session's `access_tokens` row.
+#[derive(Default, Debug)]
+pub struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip_address: Option<String>,
+}
+
+pub struct SessionTokens {
+    pub token: String,
+    pub refresh_token: String,
+}
+
+/// A web session is one `access_tokens` row of kind `web`, linked to its refresh token family. Refreshing the
+/// session swaps the JWT on that row instead of adding rows, so the row lives as long as the login does.
+pub fn create(user: &User, client: &ClientInfo, conn: &mut PgConnection) -> AppResult<SessionTokens> {
+    let exp = access_token_expire();
+    let token = crate::create_jwt_token(user, &exp)?;
+    conn.transaction::<_, crate::Error, _>(|conn| {
+        let (refresh, refresh_token) = refresh_token::issue(user.id, None, conn)?;
+        let new_token = NewAccessToken {
+            user_id: user.id,
+            kind: "web",
+            value: &token,
+            device: client.user_agent.as_deref(),
+            name: None,
+            expired_at: exp,
+            ip_address: client.ip_address.as_deref(),
+            last_seen_at: Some(Utc::now()),
+            family_id: Some(&refresh.family_id),
+            scopes: &[],
+            oauth_client_id: None,
+            updated_by: Some(user.id),
+            created_by: Some(user.id),
+        };
+        diesel::insert_into(access_tokens::table)
+            .values(&new_token)
+            .execute(conn)?;
+        Ok(SessionTokens { token, refresh_token })
+    })
+}
+
+/// Put a new JWT on an existing session. Returns `None` if the session has been revoked in the meantime.
+pub fn renew(
+    user: &User,
+    session: &AccessToken,
+    client: &ClientInfo,
+    conn: &mut PgConnection,
+) -> AppResult<Option<String>> {
+    let exp = access_token_expire();
+    let token = crate::create_jwt_token(user, &exp)?;
+    let count = diesel::update(session)
+        .set((
+            access_tokens::value.eq(&token),
+            access_tokens::expired_at.eq(exp),
+            access_tokens::device.eq(client.user_agent.as_deref()),
+            access_tokens::ip_address.eq(client.ip_address.as_deref()),
+            access_tokens::last_seen_at.eq(Utc::now()),
+            access_tokens::updated_by.eq(user.id),
+            access_tokens::updated_at.eq(Utc::now()),
+        ))
+        .execute(conn)?;
+    Ok(if count > 0 { Some(token) } else { None })
+}
+
+pub fn find_by_family(family_id: &str, conn: &mut PgConnection) -> AppResult<Option<AccessToken>> {
+    Ok(access_tokens::table
+        .filter(access_tokens::kind.eq("web"))
+        .filter(access_tokens::family_id.eq(family_id))
+        .first::<AccessToken>(conn)
+        .optional()?)
+}
+
+/// Record that the session is in use. Throttled so an authenticated request does not always cost a write.
+pub fn touch(session: &AccessToken, client: &ClientInfo, conn: &mut PgConnection) -> AppResult<()> {
+    let threshold = Utc::now() - Duration::seconds(TOUCH_INTERVAL_SECONDS);
+    if session.last_seen_at.map(|t| t > threshold).unwrap_or(false) {
+        return Ok(());
+    }
+    diesel::update(session)
+        .set((
+            access_tokens::last_seen_at.eq(Utc::now()),
+            access_tokens::device.eq(client.user_agent.as_deref()),
+            access_tokens::ip_address.eq(client.ip_address.as_deref()),
+        ))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// End a session: its JWT stops working immediately and its refresh tokens can no longer be used.
+pub fn revoke(session: &AccessToken, conn: &mut PgConnection) -> AppResult<()> {
+    conn.transaction::<_, crate::Error, _>(|conn| {
+        diesel::delete(session).execute(conn)?;
+        if let Some(family_id) = &session.family_id {
+            refresh_token::revoke_family(family_id, conn)?;
+        }
+        Ok(())
+    })
+}
+
+fn access_token_expire() -> DateTime<Utc> {
+    Utc::now() + Duration::minutes(crate::access_token_ttl_minutes())
+}
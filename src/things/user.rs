@@ -4,7 +4,11 @@ use diesel::prelude::*;
 use crate::email::send_email_with_tmpl;
 use crate::models::*;
 use crate::schema::*;
-use crate::{db, things, AppResult};
+use crate::utils::{hash_str_sha256, totp};
+use crate::{i18n, things, AppResult};
+
+/// How long a magic sign-in link stays valid.
+const MAGIC_LINK_MINUTES: i64 = 15;
 
 
 // pub fn avatar_base_dir(id: i64, abs: bool) -> String {
@@ -20,7 +24,7 @@ impl User {
     //     avatar_base_dir(self.id, abs)
     // }
    
-    pub async fn send_verification_email(&self, address: &str) -> AppResult<()> {
+    pub fn send_verification_email(&self, address: &str, conn: &mut PgConnection) -> AppResult<()> {
         let code_value = crate::generate_digit_code(6);
         let code = NewSecurityCode {
             user_id: self.id,
@@ -31,36 +35,37 @@ impl User {
             updated_by: Some(self.id),
             created_by: Some(self.id),
         };
-        let mut conn = db::connect()?;
         let query = security_codes::table
             .filter(security_codes::user_id.eq(self.id))
             .filter(security_codes::created_at.ge(Utc::now() - Duration::minutes(1)));
-        if diesel_exists!(query, &mut conn) {
+        if diesel_exists!(query, conn) {
             return Err(crate::Error::FrequentlyRequest);
         }
-        diesel::delete(
-            security_codes::table
-                .filter(security_codes::user_id.eq(self.id))
-                .filter(security_codes::send_method.eq(&code.send_method)),
-        )
-        .execute(&mut conn)?;
-        diesel::insert_into(security_codes::table)
-            .values(&code)
-            .execute(&mut conn)?;
+        conn.transaction::<_, crate::Error, _>(|conn| {
+            diesel::delete(
+                security_codes::table
+                    .filter(security_codes::user_id.eq(self.id))
+                    .filter(security_codes::send_method.eq(&code.send_method)),
+            )
+            .execute(conn)?;
+            diesel::insert_into(security_codes::table)
+                .values(&code)
+                .execute(conn)?;
 
-        let data = things::notification::user::VerificationContext {
-            recipient: self,
-            token: code.value,
-        };
-        send_email_with_tmpl(
-            vec![address.to_owned()],
-            "Please verify your email address",
-            "verification",
-            &data,
-        )
-        .await
+            let data = things::notification::user::VerificationContext {
+                recipient: self,
+                token: code.value,
+            };
+            send_email_with_tmpl(
+                vec![address.to_owned()],
+                &i18n::translate(&self.locale, "emails.verification.subject"),
+                &data,
+                conn,
+            )?;
+            Ok(())
+        })
     }
-    pub async fn send_security_code_email(&self, address: &str) -> AppResult<()> {
+    pub fn send_security_code_email(&self, address: &str, conn: &mut PgConnection) -> AppResult<()> {
         let code_value = crate::generate_digit_code(6);
         let code = NewSecurityCode {
             user_id: self.id,
@@ -71,22 +76,143 @@ impl User {
             updated_by: Some(self.id),
             created_by: Some(self.id),
         };
-        let mut conn = db::connect()?;
         let query = security_codes::table
             .filter(security_codes::user_id.eq(self.id))
             .filter(security_codes::created_at.ge(Utc::now() - Duration::minutes(1)));
-        if diesel_exists!(query, &mut conn) {
+        if diesel_exists!(query, conn) {
+            return Err(crate::Error::FrequentlyRequest);
+        }
+        conn.transaction::<_, crate::Error, _>(|conn| {
+            diesel::delete(security_codes::table.filter(security_codes::user_id.eq(self.id))).execute(conn)?;
+            diesel::insert_into(security_codes::table)
+                .values(&code)
+                .execute(conn)?;
+
+            let data = things::notification::user::SecurityCodeContext {
+                code: code_value.clone(),
+                recipient: self,
+            };
+            send_email_with_tmpl(
+                vec![address.to_owned()],
+                &i18n::translate(&self.locale, "emails.security_code.subject"),
+                &data,
+                conn,
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Email a single use sign-in link valid for `MAGIC_LINK_MINUTES`. Throttled together with the other codes.
+    pub fn send_magic_link_email(&self, address: &str, conn: &mut PgConnection) -> AppResult<()> {
+        let nonce = crate::generate_url_safe_token(32);
+        let expired_at = Utc::now() + Duration::minutes(MAGIC_LINK_MINUTES);
+        let code_value = hash_str_sha256(&nonce);
+        let code = NewSecurityCode {
+            user_id: self.id,
+            value: &code_value,
+            email: Some(address),
+            send_method: "magic_link",
+            expired_at,
+            updated_by: Some(self.id),
+            created_by: Some(self.id),
+        };
+        let token = crate::create_magic_link_token(self, &nonce, &expired_at)?;
+        let link = format!("{}?token={}", crate::magic_link_url()?, token);
+        let query = security_codes::table
+            .filter(security_codes::user_id.eq(self.id))
+            .filter(security_codes::created_at.ge(Utc::now() - Duration::minutes(1)));
+        if diesel_exists!(query, conn) {
             return Err(crate::Error::FrequentlyRequest);
         }
-        diesel::delete(security_codes::table.filter(security_codes::user_id.eq(self.id))).execute(&mut conn)?;
-        diesel::insert_into(security_codes::table)
-            .values(&code)
-            .execute(&mut conn)?;
-        
-        let data = things::notification::user::SecurityCodeContext {
-            code: code_value,
-            recipient: self,
+        conn.transaction::<_, crate::Error, _>(|conn| {
+            diesel::delete(
+                security_codes::table
+                    .filter(security_codes::user_id.eq(self.id))
+                    .filter(security_codes::send_method.eq(&code.send_method)),
+            )
+            .execute(conn)?;
+            diesel::insert_into(security_codes::table)
+                .values(&code)
+                .execute(conn)?;
+
+            let data = things::notification::user::MagicLinkContext {
+                recipient: self,
+                link: &link,
+            };
+            send_email_with_tmpl(
+                vec![address.to_owned()],
+                &i18n::translate(&self.locale, "emails.magic_link.subject"),
+                &data,
+                conn,
+            )?;
+            Ok(())
+        })
+    }
+
+    /// What a new password of the user must not be built from: names and email addresses.
+    pub fn password_inputs(&self, conn: &mut PgConnection) -> AppResult<Vec<String>> {
+        let mut inputs = emails::table
+            .filter(emails::user_id.eq(self.id))
+            .select(emails::value)
+            .get_results::<String>(conn)?;
+        inputs.push(self.ident_name.clone());
+        inputs.push(self.display_name.clone());
+        Ok(inputs)
+    }
+
+    /// Replace every recovery code of the user with a fresh batch and return the plain codes, which are shown
+    /// to the user exactly once.
+    pub fn regenerate_recovery_codes(&self, conn: &mut PgConnection) -> AppResult<Vec<String>> {
+        let codes = totp::generate_recovery_codes();
+        let hashes = codes.iter().map(|code| totp::hash_recovery_code(code)).collect::<Vec<_>>();
+        let records = hashes
+            .iter()
+            .map(|value| NewRecoveryCode {
+                user_id: self.id,
+                value,
+                updated_by: Some(self.id),
+                created_by: Some(self.id),
+            })
+            .collect::<Vec<_>>();
+        conn.transaction::<_, crate::Error, _>(|conn| {
+            diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(self.id))).execute(conn)?;
+            diesel::insert_into(recovery_codes::table).values(&records).execute(conn)?;
+            Ok(())
+        })?;
+        Ok(codes)
+    }
+
+    /// Accept a TOTP code once: its time step has to be later than the last accepted one. The check and the
+    /// update are one statement, so two requests racing with the same code can not both pass.
+    pub fn consume_totp_code(&self, code: &str, conn: &mut PgConnection) -> AppResult<bool> {
+        let step = match self.totp_secret.as_deref().and_then(|secret| totp::verify(secret, code)) {
+            Some(step) => step,
+            None => return Ok(false),
         };
-        send_email_with_tmpl(vec![address.to_owned()], "Security code", "security_code", &data).await
+        let count = diesel::update(
+            users::table
+                .filter(users::id.eq(self.id))
+                .filter(users::totp_last_step.is_null().or(users::totp_last_step.lt(step))),
+        )
+        .set(users::totp_last_step.eq(step))
+        .execute(conn)?;
+        Ok(count > 0)
+    }
+
+    /// Mark a matching unused recovery code as consumed. Returns false if there is none.
+    pub fn consume_recovery_code(&self, code: &str, conn: &mut PgConnection) -> AppResult<bool> {
+        let count = diesel::update(
+            recovery_codes::table
+                .filter(recovery_codes::user_id.eq(self.id))
+                .filter(recovery_codes::value.eq(totp::hash_recovery_code(code)))
+                .filter(recovery_codes::consumed_at.is_null()),
+        )
+        .set((
+            recovery_codes::consumed_at.eq(Utc::now()),
+            recovery_codes::updated_by.eq(self.id),
+            recovery_codes::updated_at.eq(Utc::now()),
+        ))
+        .execute(conn)?;
+        Ok(count > 0)
     }
 }
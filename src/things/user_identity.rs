@@ -0,0 +1,126 @@
+use chrono::Utc;
+use diesel::prelude::*;
+
+use crate::db::lower;
+use crate::models::*;
+use crate::oidc::Identity;
+use crate::schema::*;
+use crate::utils::password;
+use crate::{AppResult, Error};
+
+/// The user signing in with `identity` from `provider`. An identity seen before signs in its linked user,
+/// otherwise it is linked to the user owning the same verified email, or to a new user when nobody does.
+/// Returns `None` when the identity is new and the provider did not assert a verified email for it.
+pub fn sign_in(provider: &str, identity: &Identity, conn: &mut PgConnection) -> AppResult<Option<User>> {
+    conn.transaction::<_, Error, _>(|conn| {
+        let linked = user_identities::table
+            .filter(user_identities::provider.eq(provider))
+            .filter(user_identities::subject.eq(&identity.subject))
+            .first::<UserIdentity>(conn)
+            .optional()?;
+        if let Some(linked) = linked {
+            diesel::update(&linked)
+                .set((
+                    user_identities::last_login_at.eq(Utc::now()),
+                    user_identities::email.eq(&identity.email),
+                ))
+                .execute(conn)?;
+            let user = users::table.find(linked.user_id).first::<User>(conn)?;
+            return Ok(Some(user));
+        }
+
+        let email = match verified_email(identity) {
+            Some(email) => email,
+            None => return Ok(None),
+        };
+        let user_id = emails::table
+            .filter(lower(emails::value).eq(email.to_lowercase()))
+            .filter(emails::is_verified.eq(true))
+            .select(emails::user_id)
+            .first::<i64>(conn)
+            .optional()?;
+        let user = match user_id {
+            Some(user_id) => users::table.find(user_id).first::<User>(conn)?,
+            None => create_user(identity, email, conn)?,
+        };
+        insert(user.id, provider, identity, conn)?;
+        Ok(Some(user))
+    })
+}
+
+/// Link `identity` to `user`. Returns `false` when it is already linked to another user.
+pub fn link(user: &User, provider: &str, identity: &Identity, conn: &mut PgConnection) -> AppResult<bool> {
+    let owner_id = user_identities::table
+        .filter(user_identities::provider.eq(provider))
+        .filter(user_identities::subject.eq(&identity.subject))
+        .select(user_identities::user_id)
+        .first::<i64>(conn)
+        .optional()?;
+    match owner_id {
+        Some(owner_id) => Ok(owner_id == user.id),
+        None => {
+            insert(user.id, provider, identity, conn)?;
+            Ok(true)
+        }
+    }
+}
+
+/// The email a new identity may be linked by, or create a user with: only one the provider verified.
+pub(crate) fn verified_email(identity: &Identity) -> Option<&str> {
+    identity
+        .email
+        .as_deref()
+        .filter(|email| identity.email_verified && !email.is_empty())
+}
+
+fn insert(user_id: i64, provider: &str, identity: &Identity, conn: &mut PgConnection) -> AppResult<UserIdentity> {
+    let new_identity = NewUserIdentity {
+        user_id,
+        provider,
+        subject: &identity.subject,
+        email: identity.email.as_deref(),
+        last_login_at: Some(Utc::now()),
+        updated_by: Some(user_id),
+        created_by: Some(user_id),
+    };
+    let identity = diesel::insert_into(user_identities::table)
+        .values(&new_identity)
+        .get_result::<UserIdentity>(conn)?;
+    Ok(identity)
+}
+
+fn create_user(identity: &Identity, email: &str, conn: &mut PgConnection) -> AppResult<User> {
+    let ident_name = crate::generate_ident_name(conn)?;
+    let display_name = identity
+        .name
+        .as_deref()
+        .or(identity.preferred_username.as_deref())
+        .unwrap_or_else(|| email.split('@').next().unwrap_or_default());
+    // Nobody knows this password; the user can set one with the reset password flow.
+    let pwd = password::hash(crate::generate_password(32)).map_err(Error::Internal)?;
+    let new_user = NewUser {
+        ident_name: &ident_name,
+        display_name,
+        password: &pwd,
+        in_kernel: false,
+        is_verified: true,
+
+        updated_by: None,
+        created_by: None,
+    };
+    let user = diesel::insert_into(users::table)
+        .values(&new_user)
+        .get_result::<User>(conn)?;
+    let new_email = NewEmail {
+        user_id: user.id,
+        value: email,
+        domain: crate::get_email_domain(email),
+        is_verified: true,
+        updated_by: Some(user.id),
+        created_by: Some(user.id),
+    };
+    diesel::insert_into(emails::table)
+        .values(&new_email)
+        .execute(conn)?;
+    Ok(user)
+}
@@ -1,5 +1,7 @@
+pub mod breached_passwords;
 pub mod fs;
 pub mod password;
+pub mod totp;
 pub mod validator;
 
 use std::borrow::Cow;
@@ -29,6 +31,22 @@ pub fn hash_str_md5(value: impl AsRef<str>) -> Result<String, std::io::Error> {
     let mut bytes = value.as_ref().as_bytes();
     hash_reader_md5(&mut bytes)
 }
+/// Opaque tokens (recovery codes, refresh tokens...) are random enough that a plain SHA-256 is sufficient, and
+/// unlike bcrypt it lets the hash be looked up directly.
+pub fn hash_str_sha256(value: impl AsRef<str>) -> String {
+    use sha2::{Digest, Sha256};
+    hash_string(&Sha256::digest(value.as_ref().as_bytes()))
+}
+/// HMAC-SHA256 keyed with `SECRET_KEY`, for values the server hands out and must recognize as its own later.
+pub fn sign_with_secret_key(data: &[u8]) -> AppResult<Vec<u8>> {
+    use openssl::hash::MessageDigest;
+    use openssl::pkey::PKey;
+    use openssl::sign::Signer;
+    let key = PKey::hmac(crate::secret_key().as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
 //https://docs.rs/crate/checksums/0.6.0/source/src/hashing/mod.rs
 pub fn hash_string(bytes: &[u8]) -> String {
     let mut result = String::with_capacity(bytes.len() * 2);
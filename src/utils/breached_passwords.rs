@@ -0,0 +1,47 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use super::hash_string;
+
+/// Look `password` up in the SHA-1 prefix files under `dir` (`PASSWORD_BREACHED_DIR`), see
+/// `conf/breached_passwords/README.md`. An empty `dir` or a missing directory or prefix file means not breached.
+pub fn is_breached_in(dir: &str, password: &str) -> bool {
+    if dir.is_empty() {
+        return false;
+    }
+    let hash = hash_string(&openssl::sha::sha1(password.as_bytes()));
+    let (prefix, suffix) = hash.split_at(5);
+    let file = match File::open(Path::new(dir).join(prefix)) {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+    BufReader::new(file)
+        .lines()
+        .map_while(Result::ok)
+        .any(|line| line.split(':').next().map(|s| s.trim().eq_ignore_ascii_case(suffix)).unwrap_or(false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIR: &str = "conf/breached_passwords";
+
+    #[test]
+    fn finds_breached_password() {
+        // SHA-1 5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8
+        assert!(is_breached_in(DIR, "password"));
+    }
+
+    #[test]
+    fn misses_unknown_password() {
+        assert!(!is_breached_in(DIR, "Tr0ub4dour&3-horse-staple"));
+    }
+
+    #[test]
+    fn skips_lookup_without_dir() {
+        assert!(!is_breached_in("", "password"));
+        assert!(!is_breached_in("conf/no_such_dir", "password"));
+    }
+}
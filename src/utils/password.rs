@@ -1,12 +1,160 @@
-static SECRET_KEY: &str = "ZA1XSWSekret128cdevfraASDFlkjhHg";
+//! Passwords are stored as PHC strings, e.g. `$argon2id$v=19$m=19456,t=2,p=1,keyid=MQ$<salt>$<hash>`, so the
+//! algorithm, its version and parameters, and which pepper was used are all recorded with each hash. `keyid` names
+//! the pepper: `PASSWORD_PEPPER_ID` for new hashes, older ones are looked up in `PASSWORD_OLD_PEPPERS`.
+//!
+//! Hashes from before the switch are bcrypt with a pepper that used to live in this file. They still verify, and
+//! `login` replaces them as soon as the user signs in, see `needs_rehash`.
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, KeyId, Params, ParamsBuilder, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+
+/// Only kept to verify legacy bcrypt hashes, it is never used for new ones.
+static LEGACY_BCRYPT_PEPPER: &str = "ZA1XSWSekret128cdevfraASDFlkjhHg";
+
+fn current_params() -> Result<Params, String> {
+    let keyid = KeyId::new(crate::password_pepper_id().as_bytes()).map_err(|e| e.to_string())?;
+    ParamsBuilder::new()
+        .m_cost(crate::password_argon2_memory_kib())
+        .t_cost(crate::password_argon2_iterations())
+        .p_cost(crate::password_argon2_parallelism())
+        .keyid(keyid)
+        .build()
+        .map_err(|e| e.to_string())
+}
+
+fn pepper_for(keyid: &[u8]) -> Option<String> {
+    let keyid = std::str::from_utf8(keyid).ok()?;
+    if keyid == crate::password_pepper_id() {
+        return Some(crate::password_pepper());
+    }
+    crate::password_old_peppers()
+        .into_iter()
+        .find(|(id, _)| id == keyid)
+        .map(|(_, pepper)| pepper)
+}
 
 pub fn hash<T: AsRef<str>>(pwd: T) -> Result<String, String> {
-    bcrypt::hash(&format!("{}{}", pwd.as_ref(), SECRET_KEY), 10).map_err(|_| "hash password error".into())
+    let pepper = crate::password_pepper();
+    let argon2 = Argon2::new_with_secret(pepper.as_bytes(), Algorithm::Argon2id, Version::V0x13, current_params()?)
+        .map_err(|e| e.to_string())?;
+    let salt = SaltString::generate(&mut OsRng);
+    argon2
+        .hash_password(pwd.as_ref().as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| "hash password error".into())
 }
 
 pub fn compare<H: AsRef<str>, P: AsRef<str>>(pwd: P, hash: H) -> bool {
-    matches!(
-        bcrypt::verify(&format!("{}{}", pwd.as_ref(), SECRET_KEY), hash.as_ref()),
-        Ok(true)
-    )
+    let hash = hash.as_ref();
+    if is_bcrypt(hash) {
+        return matches!(
+            bcrypt::verify(format!("{}{}", pwd.as_ref(), LEGACY_BCRYPT_PEPPER), hash),
+            Ok(true)
+        );
+    }
+    let parsed = match PasswordHash::new(hash) {
+        Ok(parsed) => parsed,
+        Err(_) => return false,
+    };
+    let pepper = match Params::try_from(&parsed).ok().and_then(|params| pepper_for(params.keyid())) {
+        Some(pepper) => pepper,
+        None => {
+            tracing::error!("password hash uses an unknown pepper");
+            return false;
+        }
+    };
+    match Argon2::new_with_secret(pepper.as_bytes(), Algorithm::Argon2id, Version::V0x13, Params::default()) {
+        Ok(argon2) => argon2.verify_password(pwd.as_ref().as_bytes(), &parsed).is_ok(),
+        Err(_) => false,
+    }
+}
+
+/// `hash` on tokio's blocking threads. Argon2 is slow on purpose, too slow to run on an async worker; code already
+/// in `db::run_in_place` can call `hash` directly.
+pub async fn hash_async(pwd: impl Into<String>) -> Result<String, String> {
+    let pwd = pwd.into();
+    tokio::task::spawn_blocking(move || hash(pwd))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/// `compare` on tokio's blocking threads, see `hash_async`.
+pub async fn compare_async(pwd: impl Into<String>, hash: impl Into<String>) -> bool {
+    let (pwd, hash) = (pwd.into(), hash.into());
+    tokio::task::spawn_blocking(move || compare(pwd, hash))
+        .await
+        .unwrap_or(false)
+}
+
+/// Whether `hash` was made with another algorithm, other parameters or another pepper than `hash` would use
+/// now. Call it after a successful `compare` and store a fresh hash of the plain password if so.
+pub fn needs_rehash<H: AsRef<str>>(hash: H) -> bool {
+    let parsed = match PasswordHash::new(hash.as_ref()) {
+        Ok(parsed) => parsed,
+        Err(_) => return true,
+    };
+    if parsed.algorithm != argon2::ARGON2ID_IDENT || parsed.version != Some(Version::V0x13.into()) {
+        return true;
+    }
+    match (Params::try_from(&parsed), current_params()) {
+        (Ok(params), Ok(current)) => {
+            params.m_cost() != current.m_cost()
+                || params.t_cost() != current.t_cost()
+                || params.p_cost() != current.p_cost()
+                || params.keyid() != current.keyid()
+        }
+        _ => true,
+    }
+}
+
+fn is_bcrypt(hash: &str) -> bool {
+    ["$2a$", "$2b$", "$2x$", "$2y$"].iter().any(|prefix| hash.starts_with(prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash_test(pwd: &str) -> String {
+        std::env::set_var("PASSWORD_PEPPER", "password-tests");
+        hash(pwd).unwrap()
+    }
+
+    #[test]
+    fn hash_round_trips() {
+        let hashed = hash_test("Correct horse 1");
+        assert!(hashed.starts_with("$argon2id$v=19$"));
+        assert!(compare("Correct horse 1", &hashed));
+    }
+
+    #[test]
+    fn wrong_password_fails() {
+        let hashed = hash_test("Correct horse 1");
+        assert!(!compare("Correct horse 2", &hashed));
+        assert!(!compare("", &hashed));
+    }
+
+    #[test]
+    fn legacy_bcrypt_verifies_and_needs_rehash() {
+        let hashed = bcrypt::hash(format!("{}{}", "Correct horse 1", LEGACY_BCRYPT_PEPPER), 4).unwrap();
+        assert!(compare("Correct horse 1", &hashed));
+        assert!(!compare("Correct horse 2", &hashed));
+        assert!(needs_rehash(&hashed));
+    }
+
+    #[test]
+    fn unknown_pepper_is_rejected() {
+        let hashed = hash_test("Correct horse 1");
+        // `MQ` is "1", the default `PASSWORD_PEPPER_ID`, `OQ` is "9" which no pepper is configured for.
+        let unknown = hashed.replace("keyid=MQ", "keyid=OQ");
+        assert_ne!(hashed, unknown);
+        assert!(!compare("Correct horse 1", &unknown));
+    }
+
+    #[test]
+    fn current_hash_needs_no_rehash() {
+        let hashed = hash_test("Correct horse 1");
+        assert!(!needs_rehash(&hashed));
+    }
 }
@@ -0,0 +1,72 @@
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use crate::{AppResult, Error};
+
+/// Number of recovery codes issued when 2FA is confirmed or the codes are regenerated.
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+/// A new random 160 bit secret, base32 encoded as authenticator apps expect it.
+pub fn generate_secret() -> String {
+    match Secret::Raw(rand::random::<[u8; 20]>().to_vec()).to_encoded() {
+        Secret::Encoded(secret) => secret,
+        Secret::Raw(_) => unreachable!(),
+    }
+}
+
+pub fn build(secret: &str, account_name: &str) -> AppResult<TOTP> {
+    let secret = Secret::Encoded(secret.to_owned())
+        .to_bytes()
+        .map_err(|e| Error::Internal(format!("invalid totp secret: {:?}", e)))?;
+    TOTP::new(
+        Algorithm::SHA1,
+        6,
+        1,
+        30,
+        secret,
+        Some(crate::totp_issuer()),
+        account_name.to_owned(),
+    )
+    .map_err(|e| Error::Internal(format!("create totp failed: {:?}", e)))
+}
+
+/// The `otpauth://` URI rendered as a QR code by the client.
+pub fn otpauth_uri(secret: &str, account_name: &str) -> AppResult<String> {
+    Ok(build(secret, account_name)?.get_url())
+}
+
+/// Check `code` against the current time step, allowing one step of clock skew either way. Returns the step the
+/// code belongs to, which the caller must record so the code can not be used twice, see `User::consume_totp_code`.
+pub fn verify(secret: &str, code: &str) -> Option<i64> {
+    let code = code.trim().replace(' ', "");
+    let totp = match build(secret, "") {
+        Ok(totp) => totp,
+        Err(e) => {
+            tracing::error!(error = ?e, "verify totp code failed");
+            return None;
+        }
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    let step = now / totp.step;
+    [step.saturating_sub(1), step, step + 1].into_iter().find_map(|step| {
+        let expected = totp.generate(step * totp.step);
+        (expected.len() == code.len() && openssl::memcmp::eq(expected.as_bytes(), code.as_bytes()))
+            .then_some(step as i64)
+    })
+}
+
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let token = crate::generate_token(10).to_lowercase();
+            format!("{}-{}", &token[..5], &token[5..])
+        })
+        .collect()
+}
+
+/// Recovery codes are stored hashed; normalize what the user typed before hashing it for lookup.
+pub fn hash_recovery_code(code: &str) -> String {
+    super::hash_str_sha256(code.trim().to_lowercase())
+}
@@ -1,7 +1,7 @@
 use diesel::prelude::*;
 use once_cell::sync::Lazy;
 use regex::Regex;
-use std::collections::HashMap;
+use std::collections::HashSet;
 
 use crate::db::lower;
 use crate::schema::*;
@@ -11,10 +11,13 @@ pub fn validate_db_sort(sort: &str) -> Result<(), String> {
     if sort.is_empty() {
         return Err("sort is empty".into());
     }
-    if sort.len() > 50 {
+    if sort.len() > 200 {
         return Err("sort is too long".into());
     }
-    static RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^\w+(\s(asc|desc))?$").unwrap());
+    // `-updated_at,id` or `updated_at desc,id asc`, fields may be joined ones like `e.value`.
+    static RE: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r"^\s*([+-]?[\w.]+(\s+(asc|desc))?)(\s*,\s*[+-]?[\w.]+(\s+(asc|desc))?)*\s*$").unwrap()
+    });
     if !RE.is_match(sort) {
         return Err("sort format is invalid".into());
     }
@@ -52,34 +55,109 @@ pub fn validate_ident_name<T: AsRef<str>>(username: T) -> Result<(), String> {
     Ok(())
 }
 
-pub fn validate_password<T: AsRef<str>>(password: T) -> Result<(), String> {
-    let password = password.as_ref();
-    if password.len() < 8 {
-        return Err("password is too short".into());
-    }
-    if password.len() > 64 {
-        return Err("password is too long".into());
-    }
-    static RE_LC: Lazy<Regex> = Lazy::new(|| Regex::new(r"[a-z]+?").unwrap());
-    static RE_UC: Lazy<Regex> = Lazy::new(|| Regex::new(r"[A-Z]+?").unwrap());
-    static RE_DG: Lazy<Regex> = Lazy::new(|| Regex::new(r"\d+?").unwrap());
-    if !RE_LC.is_match(password) {
-        return Err("password must contains lowercase characters".into());
-    }
-    if !RE_UC.is_match(password) {
-        return Err("password must contains uppercase characters".into());
-    }
-    if !RE_DG.is_match(password) {
-        return Err("password must contains digits".into());
-    }
-    let mut dict = HashMap::with_capacity(64);
-    for c in password.chars() {
-        dict.insert(c, true);
+/// Check `password` against the configured policy and return every rule it breaks, not just the first one.
+/// `user_inputs` are the user's names and email addresses, which the password must not contain and which also
+/// lower its strength score.
+pub fn validate_password<T: AsRef<str>, I: AsRef<str>>(password: T, user_inputs: &[I]) -> Result<(), Vec<String>> {
+    PasswordPolicy::from_env().check(password, user_inputs)
+}
+
+/// The password rules, read from the `PASSWORD_*` settings by `from_env`.
+#[derive(Clone, Debug)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    pub require_lowercase: bool,
+    pub require_uppercase: bool,
+    pub require_digit: bool,
+    pub require_symbol: bool,
+    pub min_distinct_chars: usize,
+    pub min_score: u8,
+    /// Empty to skip the breached password lookup.
+    pub breached_dir: String,
+}
+impl PasswordPolicy {
+    pub fn from_env() -> Self {
+        PasswordPolicy {
+            min_length: crate::password_min_length(),
+            max_length: crate::password_max_length(),
+            require_lowercase: crate::password_require_lowercase(),
+            require_uppercase: crate::password_require_uppercase(),
+            require_digit: crate::password_require_digit(),
+            require_symbol: crate::password_require_symbol(),
+            min_distinct_chars: crate::password_min_distinct_chars(),
+            min_score: crate::password_min_score(),
+            breached_dir: crate::password_breached_dir(),
+        }
     }
-    if dict.len() < 4 {
-        return Err("password contains at least 4 different characters, symbols or digits".into());
+
+    pub fn check<T: AsRef<str>, I: AsRef<str>>(&self, password: T, user_inputs: &[I]) -> Result<(), Vec<String>> {
+        let password = password.as_ref();
+        let mut errors = vec![];
+        let len = password.chars().count();
+        if len < self.min_length {
+            errors.push(format!("password must be at least {} characters", self.min_length));
+        }
+        if len > self.max_length {
+            errors.push(format!("password must be at most {} characters", self.max_length));
+        }
+        if self.require_lowercase && !password.chars().any(char::is_lowercase) {
+            errors.push("password must contains lowercase characters".into());
+        }
+        if self.require_uppercase && !password.chars().any(char::is_uppercase) {
+            errors.push("password must contains uppercase characters".into());
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            errors.push("password must contains digits".into());
+        }
+        if self.require_symbol && password.chars().all(char::is_alphanumeric) {
+            errors.push("password must contains symbols".into());
+        }
+        if password.chars().collect::<HashSet<_>>().len() < self.min_distinct_chars {
+            errors.push(format!(
+                "password contains at least {} different characters, symbols or digits",
+                self.min_distinct_chars
+            ));
+        }
+
+        let lower_password = password.to_lowercase();
+        let mut inputs = vec![];
+        for input in user_inputs {
+            let input = input.as_ref().to_lowercase();
+            if let Some((local, _)) = input.split_once('@') {
+                inputs.push(local.to_owned());
+            }
+            inputs.push(input);
+        }
+        if inputs
+            .iter()
+            .any(|input| input.chars().count() >= 3 && lower_password.contains(input.as_str()))
+        {
+            errors.push("password must not contain your username or email".into());
+        }
+
+        // Scoring very long input is slow, and it is rejected anyway.
+        if len > 0 && len <= self.max_length {
+            let inputs = inputs.iter().map(String::as_str).collect::<Vec<_>>();
+            if let Ok(entropy) = zxcvbn::zxcvbn(password, &inputs) {
+                if entropy.score() < self.min_score {
+                    let warning = entropy.feedback().as_ref().and_then(|feedback| feedback.warning());
+                    errors.push(match warning {
+                        Some(warning) => format!("password is too easy to guess: {}", warning),
+                        None => "password is too easy to guess".into(),
+                    });
+                }
+            }
+            if super::breached_passwords::is_breached_in(&self.breached_dir, password) {
+                errors.push("password has appeared in a data breach, please choose another one".into());
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
     }
-    Ok(())
 }
 
 pub fn validate_generic_name<T: AsRef<str>>(name: T) -> Result<(), String> {
@@ -97,6 +175,25 @@ pub fn validate_generic_name<T: AsRef<str>>(name: T) -> Result<(), String> {
     Ok(())
 }
 
+pub fn validate_locale<T: AsRef<str>>(locale: T) -> Result<(), String> {
+    let locale = locale.as_ref();
+    if locale.is_empty() {
+        return Err("locale is empty".into());
+    }
+    static RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[a-z]{2,3}(-[A-Za-z0-9]{2,8})*$").unwrap());
+    if locale.len() > 20 || !RE.is_match(locale) {
+        return Err("locale format is invalid".into());
+    }
+    Ok(())
+}
+
+pub fn validate_timezone<T: AsRef<str>>(timezone: T) -> Result<(), String> {
+    if timezone.as_ref().parse::<chrono_tz::Tz>().is_err() {
+        return Err("timezone is invalid".into());
+    }
+    Ok(())
+}
+
 pub fn is_email_other_taken(user_id: Option<i64>, email: &str, conn: &mut PgConnection) -> AppResult<bool> {
     let taken = if let Some(user_id) = user_id {
         diesel_exists!(
@@ -125,3 +222,101 @@ pub fn is_ident_name_other_taken(user_id: Option<i64>, ident_name: &str, conn: &
         Ok(diesel_exists!(query, conn))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 8,
+            max_length: 64,
+            require_lowercase: true,
+            require_uppercase: true,
+            require_digit: true,
+            require_symbol: false,
+            min_distinct_chars: 4,
+            min_score: 0,
+            breached_dir: String::new(),
+        }
+    }
+    const NO_INPUTS: &[&str] = &[];
+
+    #[test]
+    fn accepts_password_following_rules() {
+        assert!(policy().check("Quartz9Lantern", NO_INPUTS).is_ok());
+    }
+
+    #[test]
+    fn collects_every_violation() {
+        let errors = policy().check("aaa", NO_INPUTS).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "password must be at least 8 characters".to_owned(),
+                "password must contains uppercase characters".to_owned(),
+                "password must contains digits".to_owned(),
+                "password contains at least 4 different characters, symbols or digits".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn rules_are_configurable() {
+        let relaxed = PasswordPolicy {
+            min_length: 3,
+            require_uppercase: false,
+            require_digit: false,
+            min_distinct_chars: 1,
+            ..policy()
+        };
+        assert!(relaxed.check("aaa", NO_INPUTS).is_ok());
+
+        let strict = PasswordPolicy {
+            require_symbol: true,
+            max_length: 10,
+            ..policy()
+        };
+        let errors = strict.check("Quartz9Lantern", NO_INPUTS).unwrap_err();
+        assert_eq!(
+            errors,
+            vec![
+                "password must be at most 10 characters".to_owned(),
+                "password must contains symbols".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_user_inputs() {
+        let inputs = ["Kestrel", "jdoe@example.com"];
+        let error = "password must not contain your username or email".to_owned();
+        assert!(policy().check("MyKESTREL9x", &inputs).unwrap_err().contains(&error));
+        // The local part of an email counts on its own.
+        assert!(policy().check("Jdoe2024xyz", &inputs).unwrap_err().contains(&error));
+        // Inputs shorter than three characters are ignored.
+        assert!(policy().check("Quartz9Lantern", &["an"]).is_ok());
+    }
+
+    #[test]
+    fn rejects_weak_and_breached_passwords() {
+        let scored = PasswordPolicy {
+            min_score: 3,
+            ..policy()
+        };
+        let errors = scored.check("Password1", NO_INPUTS).unwrap_err();
+        assert!(errors.iter().any(|e| e.starts_with("password is too easy to guess")));
+
+        let breached = PasswordPolicy {
+            min_length: 1,
+            require_uppercase: false,
+            require_digit: false,
+            breached_dir: "conf/breached_passwords".into(),
+            ..policy()
+        };
+        assert_eq!(
+            breached.check("password", NO_INPUTS).unwrap_err(),
+            vec!["password has appeared in a data breach, please choose another one".to_owned()]
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Duration, Utc};
+use once_cell::sync::Lazy;
+use url::Url;
+use uuid::Uuid;
+use webauthn_rs::prelude::{Passkey, PasskeyAuthentication, PasskeyRegistration};
+use webauthn_rs::{Webauthn, WebauthnBuilder};
+
+use crate::models::*;
+use crate::AppResult;
+
+/// How long a started ceremony can be finished before its challenge is dropped.
+pub(crate) const CHALLENGE_TTL_SECONDS: i64 = 300;
+/// Most challenges a store holds. Starting a ceremony needs no login, so past this the oldest are dropped
+/// rather than letting anonymous clients grow the store for the whole TTL.
+const CHALLENGE_STORE_CAP: usize = 10_000;
+
+pub static WEBAUTHN: Lazy<Webauthn> = Lazy::new(|| {
+    let rp_id = crate::webauthn_rp_id();
+    let rp_origin = Url::parse(&crate::webauthn_rp_origin()).expect("WEBAUTHN_RP_ORIGIN must be a valid url");
+    let rp_name = crate::webauthn_rp_name();
+    WebauthnBuilder::new(&rp_id, &rp_origin)
+        .expect("WEBAUTHN_RP_ORIGIN must be on WEBAUTHN_RP_ID")
+        .rp_name(&rp_name)
+        .build()
+        .expect("build webauthn failed")
+});
+
+/// Ceremonies in progress, keyed by the `challenge_id` handed to the client. Each entry also records the user it
+/// was started for, so one user can not finish a ceremony started by another.
+pub static REGISTRATIONS: Lazy<ChallengeStore<PasskeyRegistration>> = Lazy::new(ChallengeStore::new);
+pub static AUTHENTICATIONS: Lazy<ChallengeStore<PasskeyAuthentication>> = Lazy::new(ChallengeStore::new);
+
+/// In-process store of ceremony states. Challenges do not survive a restart, which only means the user has to
+/// start the ceremony again.
+pub struct ChallengeStore<T> {
+    states: Mutex<States<T>>,
+}
+
+struct States<T> {
+    challenges: HashMap<String, Challenge<T>>,
+    /// Challenge ids oldest first. All challenges live equally long, so this is also the order they expire in.
+    /// Ids already taken stay here until they come up, or until they pile up and get compacted away.
+    order: VecDeque<(DateTime<Utc>, String)>,
+}
+
+struct Challenge<T> {
+    user_id: i64,
+    expired_at: DateTime<Utc>,
+    state: T,
+}
+
+impl<T> ChallengeStore<T> {
+    pub fn new() -> Self {
+        Self {
+            states: Mutex::new(States {
+                challenges: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    pub fn insert(&self, user_id: i64, state: T) -> String {
+        let challenge_id = crate::generate_url_safe_token(32);
+        let now = Utc::now();
+        let expired_at = now + Duration::seconds(CHALLENGE_TTL_SECONDS);
+        let mut guard = self.states.lock().unwrap();
+        let States { challenges, order } = &mut *guard;
+        while let Some((oldest_expired_at, oldest_id)) = order.front() {
+            if *oldest_expired_at > now && challenges.len() < CHALLENGE_STORE_CAP {
+                break;
+            }
+            challenges.remove(oldest_id);
+            order.pop_front();
+        }
+        if order.len() >= 2 * CHALLENGE_STORE_CAP {
+            order.retain(|(_, id)| challenges.contains_key(id));
+        }
+        challenges.insert(
+            challenge_id.clone(),
+            Challenge {
+                user_id,
+                expired_at,
+                state,
+            },
+        );
+        order.push_back((expired_at, challenge_id.clone()));
+        challenge_id
+    }
+
+    /// Remove and return the state, so every challenge can be answered once at most.
+    pub fn take(&self, challenge_id: &str) -> Option<(i64, T)> {
+        let challenge = self.states.lock().unwrap().challenges.remove(challenge_id)?;
+        if challenge.expired_at > Utc::now() {
+            Some((challenge.user_id, challenge.state))
+        } else {
+            None
+        }
+    }
+}
+
+/// The user handle stored by authenticators. It is derived from the user id, so it stays stable across
+/// credentials of the same user.
+pub fn user_unique_id(user_id: i64) -> Uuid {
+    Uuid::from_u64_pair(0, user_id as u64)
+}
+
+/// Credential ids are stored hex encoded, the same way they are looked up after an authentication.
+pub fn encode_credential_id(raw: &[u8]) -> String {
+    crate::utils::hash_string(raw)
+}
+
+pub fn load_passkey(credential: &WebauthnCredential) -> AppResult<Passkey> {
+    Ok(serde_json::from_value(credential.passkey.clone())?)
+}